@@ -0,0 +1,415 @@
+//! Balanced minimum evolution (BME) tree search, as in FastME.
+//!
+//! Starting from the BIONJ tree, the subtree prune and regraft (SPR) move shortening most the balanced
+//! tree length (Pauplin, 2000) is applied until no move shortens it. Moves are scored from the balanced
+//! averages of the distances between all pairs of disjoint subtrees (Desper and Gascuel, 2002): moving a
+//! subtree one edge further is a nearest neighbor interchange, whose length change follows from four
+//! averages, so that all moves of a subtree are scored in one walk over the tree.
+//! The averages take O(n^2) memory and are recomputed in O(n^2) time after each move.
+//! Edge lengths are the balanced estimates of the final topology.
+//!
+//! The same averages, unweighted instead of balanced, give the ordinary least squares (OLS) criterion
+//! of the nearest neighbor interchange search, see [crate::nni].
+
+use rayon::prelude::*;
+
+use crate::matrix::DistMatrix;
+use crate::phylo::Tree;
+use crate::tree::{bionj, leaf_distance};
+
+// length changes below this are rounding noise
+const MIN_GAIN: f64 = 1e-10;
+const NONE: usize = usize::MAX;
+
+// Subtree of the tree rooted at leaf 0: below a node, or above it (all but the subtree below)
+#[derive(Debug, Clone, Copy)]
+enum Subtree {
+    Down(usize),
+    Up(usize),
+}
+
+// Averages between the disjoint subtrees of a topology rooted at leaf 0, balanced (each side of
+// a node weighted 1/2) or, for OLS, over all pairs of leaves
+pub(crate) struct Averages {
+    parent: Vec<usize>,
+    children: Vec<Vec<usize>>,
+    // leaves below each node, and in all
+    size: Vec<usize>,
+    nb_leaves: usize,
+    ols: bool,
+    // average between the subtrees below a and b, for disjoint subtrees
+    down: Vec<Vec<f64>>,
+    // average between the subtree below a and the subtree above b, for a below b or equal
+    up: Vec<Vec<f64>>,
+}
+
+impl Averages {
+    pub(crate) fn new(neighbors: &[Vec<usize>], leaf_dist: &(impl Fn(usize, usize) -> f64 + Sync), ols: bool) -> Self {
+        let m = neighbors.len();
+        let mut parent = vec![NONE; m];
+        let mut children = vec![Vec::new(); m];
+        let mut pre_order = Vec::with_capacity(m);
+        let mut stack = vec![0];
+        while let Some(node) = stack.pop() {
+            pre_order.push(node);
+            for &next in &neighbors[node] {
+                if next != parent[node] {
+                    parent[next] = node;
+                    children[node].push(next);
+                    stack.push(next);
+                }
+            }
+        }
+        let mut height = vec![0usize; m];
+        let mut size = vec![1usize; m];
+        for &node in pre_order.iter().rev() {
+            height[node] = children[node].iter().map(|&c| height[c] + 1).max().unwrap_or(0);
+            if !children[node].is_empty() {
+                size[node] = children[node].iter().map(|&c| size[c]).sum();
+            }
+        }
+        // leaf 0 and the subtree below it
+        let nb_leaves = 1 + children[0].iter().map(|&c| size[c]).sum::<usize>();
+        let mut levels: Vec<Vec<usize>> = vec![Vec::new(); height.iter().max().map_or(0, |h| h + 1)];
+        for &node in &pre_order[1..] {
+            levels[height[node]].push(node);
+        }
+        // b is below a, or equal
+        let mut entry = vec![0; m];
+        let mut exit = vec![0; m];
+        let mut clock = 0;
+        let mut stack = vec![(0, false)];
+        while let Some((node, done)) = stack.pop() {
+            if done {
+                exit[node] = clock;
+                continue;
+            }
+            entry[node] = clock;
+            clock += 1;
+            stack.push((node, true));
+            stack.extend(children[node].iter().map(|&c| (c, false)));
+        }
+        let below = |b: usize, a: usize| entry[a] <= entry[b] && exit[b] <= exit[a];
+        let post_order: Vec<usize> = pre_order.iter().rev().copied().filter(|&node| node != 0).collect();
+
+        let mut averages = Averages {
+            parent,
+            children,
+            size,
+            nb_leaves,
+            ols,
+            down: vec![Vec::new(); m],
+            up: vec![Vec::new(); m],
+        };
+        // a node's row needs the rows of its children, so rows are filled by height
+        for level in &levels {
+            let rows: Vec<(usize, Vec<f64>)> = level
+                .par_iter()
+                .map(|&a| {
+                    let mut row = vec![f64::NAN; m];
+                    for &b in &post_order {
+                        if below(a, b) || below(b, a) {
+                            continue;
+                        }
+                        row[b] = match (averages.children[a].as_slice(), averages.children[b].as_slice()) {
+                            (&[a1, a2], _) => {
+                                let (w1, w2) = averages.weights(averages.size[a1], averages.size[a2]);
+                                w1 * averages.down[a1][b] + w2 * averages.down[a2][b]
+                            }
+                            (_, &[b1, b2]) => {
+                                let (w1, w2) = averages.weights(averages.size[b1], averages.size[b2]);
+                                w1 * row[b1] + w2 * row[b2]
+                            }
+                            _ => leaf_dist(a, b),
+                        };
+                    }
+                    (a, row)
+                })
+                .collect();
+            for (a, row) in rows {
+                averages.down[a] = row;
+            }
+        }
+        for level in &levels {
+            let rows: Vec<(usize, Vec<f64>)> = level
+                .par_iter()
+                .map(|&a| {
+                    let mut row = vec![f64::NAN; m];
+                    let mut path = vec![a];
+                    while averages.parent[*path.last().unwrap()] != 0 {
+                        path.push(averages.parent[*path.last().unwrap()]);
+                    }
+                    // from the top down, the subtree above b being made of its sibling and the subtree above its parent
+                    for &b in path.iter().rev() {
+                        row[b] = match averages.children[a].as_slice() {
+                            &[a1, a2] => {
+                                let (w1, w2) = averages.weights(averages.size[a1], averages.size[a2]);
+                                w1 * averages.up[a1][b] + w2 * averages.up[a2][b]
+                            }
+                            _ => {
+                                let p = averages.parent[b];
+                                if p == 0 {
+                                    leaf_dist(a, 0)
+                                } else {
+                                    let sibling = averages.children[p].iter().copied().find(|&s| s != b).unwrap();
+                                    let (w1, w2) = averages.weights(averages.size[sibling], nb_leaves - averages.size[p]);
+                                    w1 * averages.down[a][sibling] + w2 * row[p]
+                                }
+                            }
+                        };
+                    }
+                    (a, row)
+                })
+                .collect();
+            for (a, row) in rows {
+                averages.up[a] = row;
+            }
+        }
+        averages
+    }
+
+    // weights of two disjoint subtrees of these sizes in the average over their union
+    fn weights(&self, size1: usize, size2: usize) -> (f64, f64) {
+        if self.ols {
+            let total = (size1 + size2) as f64;
+            (size1 as f64 / total, size2 as f64 / total)
+        } else {
+            (0.5, 0.5)
+        }
+    }
+
+    fn size(&self, s: Subtree) -> usize {
+        match s {
+            Subtree::Down(a) => self.size[a],
+            Subtree::Up(b) => self.nb_leaves - self.size[b],
+        }
+    }
+
+    // subtree containing `to` once the edge (from, to) is cut
+    fn subtree(&self, from: usize, to: usize) -> Subtree {
+        if self.parent[to] == from {
+            Subtree::Down(to)
+        } else {
+            Subtree::Up(from)
+        }
+    }
+
+    fn average(&self, s: Subtree, t: Subtree) -> f64 {
+        match (s, t) {
+            (Subtree::Down(a), Subtree::Down(b)) => self.down[a][b],
+            (Subtree::Down(a), Subtree::Up(b)) | (Subtree::Up(b), Subtree::Down(a)) => self.up[a][b],
+            (Subtree::Up(_), Subtree::Up(_)) => unreachable!("subtrees above two nodes always overlap"),
+        }
+    }
+
+    // Best regraft of the subtree x attached at v, as (length change, target edge)
+    fn best_move(&self, neighbors: &[Vec<usize>], v: usize, x: usize) -> Option<(f64, usize, usize)> {
+        let mut best: Option<(f64, usize, usize)> = None;
+        let others: Vec<usize> = neighbors[v].iter().copied().filter(|&o| o != x).collect();
+        let moved = self.subtree(v, x);
+        for (p, q) in [(others[0], others[1]), (others[1], others[0])] {
+            // x walks away from p through q, the subtree above it growing at each step
+            let first_above = self.subtree(q, v);
+            let mut stack = vec![(q, v, 0i32, self.average(moved, self.subtree(v, p)), 0.0)];
+            while let Some((w, from, k, moved_above, change)) = stack.pop() {
+                let below: Vec<usize> = neighbors[w].iter().copied().filter(|&o| o != from).collect();
+                if below.len() != 2 {
+                    continue;
+                }
+                let above = self.subtree(w, from);
+                for (b, c) in [(below[0], below[1]), (below[1], below[0])] {
+                    let (sb, sc) = (self.subtree(w, b), self.subtree(w, c));
+                    let moved_c = self.average(moved, sc);
+                    // the subtree above w without x
+                    let above_c = self.average(above, sc) + 0.5f64.powi(k) * (self.average(first_above, sc) - moved_c);
+                    let step = 0.25 * ((self.average(moved, sb) + above_c) - (moved_above + self.average(sb, sc)));
+                    let total = change + step;
+                    if best.is_none_or(|(gain, _, _)| total < gain) {
+                        best = Some((total, w, b));
+                    }
+                    stack.push((b, w, k + 1, 0.5 * (moved_above + moved_c), total));
+                }
+            }
+        }
+        best
+    }
+
+    // Subtrees on each side of the edge (u, w), but for the one of a leaf
+    fn sides(&self, neighbors: &[Vec<usize>], u: usize, w: usize) -> (Vec<Subtree>, Vec<Subtree>) {
+        let sides = |a: usize, b: usize| -> Vec<Subtree> {
+            neighbors[a].iter().filter(|&&o| o != b).map(|&o| self.subtree(a, o)).collect()
+        };
+        (sides(u, w), sides(w, u))
+    }
+
+    // Weight of the pairs (a, c) and (b, d) in the length of the internal edge between a, b and c, d,
+    // the pairs (a, d) and (b, c) taking the rest
+    fn lambda(&self, a: Subtree, b: Subtree, c: Subtree, d: Subtree) -> f64 {
+        if !self.ols {
+            return 0.5;
+        }
+        let (a, b, c, d) = (self.size(a) as f64, self.size(b) as f64, self.size(c) as f64, self.size(d) as f64);
+        (a * d + b * c) / ((a + b) * (c + d))
+    }
+
+    // Change of the tree length when the subtrees b and c are swapped across the internal edge (u, w)
+    // separating a, b from c, d
+    fn swap_change(&self, a: Subtree, b: Subtree, c: Subtree, d: Subtree) -> f64 {
+        let (ab, cd) = (self.average(a, b), self.average(c, d));
+        let (ac, bd) = (self.average(a, c), self.average(b, d));
+        if !self.ols {
+            return 0.25 * ((ac + bd) - (ab + cd));
+        }
+        let (ad, bc) = (self.average(a, d), self.average(b, c));
+        let lambda = self.lambda(a, b, c, d);
+        let lambda_swapped = self.lambda(a, c, b, d);
+        -0.5 * ((lambda - 1.0) * (ac + bd) - (lambda_swapped - 1.0) * (ab + cd) - (lambda - lambda_swapped) * (ad + bc))
+    }
+
+    // Best nearest neighbor interchange across the internal edge (u, w), as (length change, neighbor
+    // of u, neighbor of w) to swap
+    pub(crate) fn best_swap(&self, neighbors: &[Vec<usize>], u: usize, w: usize) -> Option<(f64, usize, usize)> {
+        let left: Vec<usize> = neighbors[u].iter().copied().filter(|&o| o != w).collect();
+        let right: Vec<usize> = neighbors[w].iter().copied().filter(|&o| o != u).collect();
+        let (a, b) = match left.as_slice() {
+            &[a, b] => (a, b),
+            _ => return None,
+        };
+        let (c, d) = match right.as_slice() {
+            &[c, d] => (c, d),
+            _ => return None,
+        };
+        let (sa, sb, sc, sd) = (self.subtree(u, a), self.subtree(u, b), self.subtree(w, c), self.subtree(w, d));
+        [(self.swap_change(sa, sb, sc, sd), b, c), (self.swap_change(sa, sb, sd, sc), b, d)]
+            .into_iter()
+            .min_by(|x, y| x.0.total_cmp(&y.0))
+    }
+
+    // Length of the edge (u, w), balanced or OLS estimate
+    fn edge_length(&self, neighbors: &[Vec<usize>], u: usize, w: usize) -> f64 {
+        let (left, right) = self.sides(neighbors, u, w);
+        match (left.as_slice(), right.as_slice()) {
+            (&[a, b], &[c, d]) if !self.ols => {
+                let across = self.average(a, c) + self.average(a, d) + self.average(b, c) + self.average(b, d);
+                0.25 * across - 0.5 * (self.average(a, b) + self.average(c, d))
+            }
+            (&[a, b], &[c, d]) => {
+                let lambda = self.lambda(a, b, c, d);
+                let (ac_bd, ad_bc) = (self.average(a, c) + self.average(b, d), self.average(a, d) + self.average(b, c));
+                0.5 * (lambda * ac_bd + (1.0 - lambda) * ad_bc - (self.average(a, b) + self.average(c, d)))
+            }
+            (&[a, b], _) => {
+                let leaf = self.subtree(u, w);
+                0.5 * (self.average(leaf, a) + self.average(leaf, b) - self.average(a, b))
+            }
+            (_, &[c, d]) => {
+                let leaf = self.subtree(w, u);
+                0.5 * (self.average(leaf, c) + self.average(leaf, d) - self.average(c, d))
+            }
+            _ => 2.0 * self.average(self.subtree(u, w), self.subtree(w, u)),
+        }
+    }
+}
+
+pub(crate) fn replace(neighbors: &mut [usize], old: usize, new: usize) {
+    *neighbors.iter_mut().find(|o| **o == old).unwrap() = new;
+}
+
+/// Balanced minimum evolution tree of a symmetric matrix, unrooted with a trifurcation at the top as
+/// for neighbor-joining. Distances are rounded as in [crate::tree::build_tree] unless `exact`, and read
+/// from the matrix as they are needed.
+pub fn bme(matrix: &DistMatrix, exact: bool) -> Result<Tree, String> {
+    let n = matrix.len();
+    let start = bionj(matrix, exact)?;
+    if n < 4 {
+        return Ok(start);
+    }
+    let leaf_dist = |i: usize, j: usize| leaf_distance(matrix, exact, i, j);
+    // unrooted topology, leaves keeping their index in the matrix
+    let mut neighbors: Vec<Vec<usize>> = vec![Vec::new(); start.len()];
+    for parent in 0..start.len() {
+        for &child in &start.node(parent).children {
+            neighbors[parent].push(child);
+            neighbors[child].push(parent);
+        }
+    }
+    let mut nb_moves = 0;
+    let averages = loop {
+        let averages = Averages::new(&neighbors, &leaf_dist, false);
+        let best = (n..neighbors.len())
+            .into_par_iter()
+            .flat_map_iter(|v| neighbors[v].iter().map(move |&x| (v, x)))
+            .filter_map(|(v, x)| averages.best_move(&neighbors, v, x).map(|(gain, w, b)| (gain, v, x, w, b)))
+            .min_by(|a, b| a.partial_cmp(b).unwrap());
+        match best {
+            Some((gain, v, x, w, b)) if gain < -MIN_GAIN => {
+                // prune: the other two neighbors of v are joined
+                let others: Vec<usize> = neighbors[v].iter().copied().filter(|&o| o != x).collect();
+                replace(&mut neighbors[others[0]], v, others[1]);
+                replace(&mut neighbors[others[1]], v, others[0]);
+                // regraft on the edge (w, b)
+                replace(&mut neighbors[w], b, v);
+                replace(&mut neighbors[b], w, v);
+                neighbors[v] = vec![x, w, b];
+                nb_moves += 1;
+            }
+            _ => break averages,
+        }
+    };
+    log::info!("balanced minimum evolution: {} SPR moves from the BIONJ tree", nb_moves);
+
+    Ok(to_tree(&neighbors, &averages, matrix.labels()))
+}
+
+/// Tree of the unrooted topology `neighbors`, whose leaves are the first nodes in the order of `labels`,
+/// with the edge lengths estimated from `averages`. It is rooted at the node next to leaf 0.
+pub(crate) fn to_tree(neighbors: &[Vec<usize>], averages: &Averages, labels: &[String]) -> Tree {
+    // children before their parent
+    let top = neighbors[0][0];
+    let mut tree = Tree::new();
+    let mut index = vec![NONE; neighbors.len()];
+    let mut stack = vec![(top, NONE, false)];
+    while let Some((node, from, done)) = stack.pop() {
+        let below: Vec<usize> = neighbors[node].iter().copied().filter(|&o| o != from).collect();
+        if below.is_empty() {
+            index[node] = tree.add_leaf(&labels[node]);
+        } else if done {
+            let children: Vec<(usize, f64)> =
+                below.iter().map(|&c| (index[c], averages.edge_length(neighbors, node, c))).collect();
+            index[node] = tree.join(&children);
+        } else {
+            stack.push((node, from, true));
+            stack.extend(below.iter().rev().map(|&c| (c, node, false)));
+        }
+    }
+    tree
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit::cophenetic_matrix;
+    use std::collections::{HashMap, HashSet};
+
+    const NEWICK: &str = "((((a:0.1,b:0.2):0.05,c:0.3):0.1,d:0.15):0.02,((e:0.1,f:0.05):0.2,(g:0.3,h:0.1):0.08):0.03);";
+
+    fn splits(tree: &Tree, labels: &[String]) -> HashSet<Vec<u64>> {
+        let leaves: HashMap<String, usize> = labels.iter().enumerate().map(|(i, label)| (label.clone(), i)).collect();
+        tree.splits(&leaves).unwrap().into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn recovers_additive_tree() {
+        let labels: Vec<String> = "abcdefgh".chars().map(|c| c.to_string()).collect();
+        let matrix = cophenetic_matrix(NEWICK, &labels).unwrap();
+        let tree = bme(&matrix, true).unwrap();
+        assert_eq!(splits(&tree, &labels), splits(&Tree::from_newick(NEWICK).unwrap(), &labels));
+        // balanced lengths on the true topology give back the distances
+        let fitted = cophenetic_matrix(&tree.to_newick(), &labels).unwrap();
+        for i in 0..labels.len() {
+            for j in 0..labels.len() {
+                assert!((fitted.get(i, j) - matrix.get(i, j)).abs() < 1e-9, "{} {}", labels[i], labels[j]);
+            }
+        }
+    }
+}
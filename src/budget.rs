@@ -0,0 +1,125 @@
+//! Rough memory and time needs of a run, estimated from the number and size of the genome files
+//! before any of them is sketched, to size a job or catch settings that would not fit.
+//!
+//! Throughputs are those of a single core on bacterial genomes and the dense distance matrix; the
+//! tree is built by neighbor-joining in cubic time at worst. Estimates are orders of magnitude.
+
+use crate::dist::DistanceKind;
+use crate::pipeline::PipelineConfig;
+
+// bases read and sketched per second and thread
+const BASES_PER_SECOND: f64 = 4e7;
+// registers compared per second and thread
+const REGISTERS_PER_SECOND: f64 = 7e8;
+// neighbor-joining time per cube of the number of genomes
+const TREE_SECONDS: f64 = 2e-9;
+// neighbor-joining memory per square of the number of genomes: working copies of the matrix and
+// sorted rows
+const TREE_BYTES: f64 = 40.0;
+// bases per byte of a gzip compressed FASTA file
+const GZIP_RATIO: f64 = 3.5;
+
+/// Estimated memory (bytes) and time (seconds) of the stages of a run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Budget {
+    pub nb_genomes: usize,
+    pub nb_bases: f64,
+    pub sketch_bytes: f64,
+    pub matrix_bytes: f64,
+    /// the matrix is memory mapped to a file (--matrix_file) instead of held in memory
+    pub matrix_on_disk: bool,
+    pub tree_bytes: f64,
+    pub sketch_seconds: f64,
+    pub distance_seconds: f64,
+    pub tree_seconds: f64,
+}
+
+impl Budget {
+    /// Peak memory: the sketches and the matrix held in memory, plus the tree construction.
+    pub fn memory(&self) -> f64 {
+        self.sketch_bytes + if self.matrix_on_disk { 0.0 } else { self.matrix_bytes } + self.tree_bytes
+    }
+
+    /// Wall-clock time of all the stages.
+    pub fn time(&self) -> f64 {
+        self.sketch_seconds + self.distance_seconds + self.tree_seconds
+    }
+}
+
+impl std::fmt::Display for Budget {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} genomes, about {:.3e} bases", self.nb_genomes, self.nb_bases)?;
+        writeln!(
+            f,
+            "Memory: about {} (sketches {}, distance matrix {}{}, tree {})",
+            bytes(self.memory()),
+            bytes(self.sketch_bytes),
+            bytes(self.matrix_bytes),
+            if self.matrix_on_disk { " on disk" } else { "" },
+            bytes(self.tree_bytes)
+        )?;
+        write!(
+            f,
+            "Time: about {} (sketching {}, distances {}, tree {})",
+            duration(self.time()),
+            duration(self.sketch_seconds),
+            duration(self.distance_seconds),
+            duration(self.tree_seconds)
+        )
+    }
+}
+
+fn bytes(n: f64) -> String {
+    let units = ["B", "KB", "MB", "GB", "TB"];
+    let power = ((n.max(1.0).log10() / 3.0) as usize).min(units.len() - 1);
+    format!("{:.1} {}", n / 1000f64.powi(power as i32), units[power])
+}
+
+fn duration(seconds: f64) -> String {
+    match seconds {
+        s if s < 1.0 => "< 1 s".to_string(),
+        s if s < 60.0 => format!("{:.0} s", s),
+        s if s < 3600.0 => format!("{:.0} min", s / 60.0),
+        s => format!("{:.1} h", s / 3600.0),
+    }
+}
+
+/// Budget of sketching the `genomes` (paths and file sizes in bytes) with `config` on `threads`
+/// threads, computing their distance matrix at each k-mer size and, if `tree`, their trees with
+/// the replicates.
+pub fn estimate_budget(config: &PipelineConfig, genomes: &[(String, u64)], threads: usize, tree: bool) -> Budget {
+    let n = genomes.len() as f64;
+    let nb_bases: f64 = genomes
+        .iter()
+        .map(|(path, size)| if path.ends_with(".gz") { *size as f64 * GZIP_RATIO } else { *size as f64 })
+        .sum();
+    let mean_bases = nb_bases / n.max(1.0);
+    // bytes of a sketch and registers compared per pair
+    let (sketch, compared) = if config.distance_kind != DistanceKind::MinHash {
+        let entries = mean_bases.min(4f64.powi(config.kmer_size as i32));
+        (entries * 12.0, 2.0 * entries)
+    } else if config.scaled > 0 {
+        let hashes = mean_bases / config.scaled as f64;
+        (hashes * 8.0, 2.0 * hashes)
+    } else if config.bbits > 0 {
+        let words = (config.sketch_size * config.bbits as usize).div_ceil(64) as f64;
+        (words * 8.0, words * 2.0)
+    } else {
+        (config.sketch_size as f64 * 4.0, config.sketch_size as f64)
+    };
+    let nb_kmer_sizes = config.kmer_sizes.len().max(1) as f64;
+    let threads = threads.max(1) as f64;
+    let nb_pairs = n * (n - 1.0).max(0.0) / 2.0;
+    let nb_trees = if tree { 1.0 + config.nb_replicates() as f64 } else { 0.0 };
+    Budget {
+        nb_genomes: genomes.len(),
+        nb_bases,
+        sketch_bytes: n * sketch * nb_kmer_sizes,
+        matrix_bytes: n * (n + 1.0) / 2.0 * 8.0,
+        matrix_on_disk: config.matrix_file.is_some(),
+        tree_bytes: if tree { n * n * TREE_BYTES } else { 0.0 },
+        sketch_seconds: nb_bases / BASES_PER_SECOND / threads,
+        distance_seconds: nb_kmer_sizes * (1.0 + config.nb_replicates() as f64) * nb_pairs * compared / REGISTERS_PER_SECOND / threads,
+        tree_seconds: nb_kmer_sizes * nb_trees * n.powi(3) * TREE_SECONDS,
+    }
+}
@@ -0,0 +1,164 @@
+//! Checkpoints of the distance matrix computation, for long all-vs-all runs.
+//!
+//! The checkpoint file starts with a header line `#checkpoint <n> <first row> <fingerprint>`, the
+//! fingerprint identifying the genomes and the settings of the distances. Completed rows of the matrix
+//! follow from the first row (0 unless computing a shard, see [crate::shard]), as little-endian `f64`
+//! values in the order of the matrix storage, appended and synced to disk after each batch of rows.
+//! A run resumed from the file reloads the rows found there and computes the others; a row cut by
+//! the interruption is computed again.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::matrix::DistMatrix;
+use crate::pipeline::PipelineConfig;
+
+const CHECKPOINT_MARKER: &str = "#checkpoint";
+const VALUE_BYTES: usize = std::mem::size_of::<f64>();
+
+/// Identifies the genomes, in order, and the settings their distances depend on.
+pub fn fingerprint(config: &PipelineConfig, genomes: &[String]) -> u64 {
+    let settings = format!(
+        "{} {} {} {} {:?} {:?} {:?} {:?} {:?} {} {} {} {} {} {} {}",
+        config.kmer_size,
+        config.sketch_size,
+        config.scaled,
+        config.bbits,
+        config.algo,
+        config.densification,
+        config.hash,
+        config.distance_kind,
+        config.distance_model,
+        config.seed,
+        config.max_dist,
+        config.min_shared,
+        config.prescreen,
+        config.lsh_bands,
+        config.lsh_rows,
+        config.lsh_background,
+    );
+    let text = std::iter::once(settings).chain(genomes.iter().cloned()).collect::<Vec<String>>().join("\n");
+    twox_hash::XxHash3_64::oneshot(text.as_bytes())
+}
+
+/// Checkpoint file being written, holding consecutive rows of a matrix.
+pub struct Checkpoint {
+    file: File,
+    first_row: usize,
+    end_row: usize,
+}
+
+impl Checkpoint {
+    /// Creates the checkpoint file at `path` for the rows of `matrix` from `first_row`, or with `resume`
+    /// continues an existing one, loading its rows into `matrix`.
+    pub fn open(path: &Path, matrix: &mut DistMatrix, first_row: usize, fingerprint: u64, resume: bool) -> Result<Self, String> {
+        let header = format!("{}\t{}\t{}\t{:016x}\n", CHECKPOINT_MARKER, matrix.len(), first_row, fingerprint);
+        if resume && path.exists() {
+            let mut reader = BufReader::new(File::open(path).map_err(|e| e.to_string())?);
+            let mut line = String::new();
+            reader.read_line(&mut line).map_err(|e| e.to_string())?;
+            if line != header {
+                return Err(format!(
+                    "Cannot resume from {}: it was written for other genomes or settings",
+                    path.display()
+                ));
+            }
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+            let nb_values = bytes.len() / VALUE_BYTES;
+            // only complete rows are kept
+            let start = matrix.row_start(first_row);
+            let end_row = (first_row..=matrix.len())
+                .rev()
+                .find(|&r| matrix.row_start(r) - start <= nb_values)
+                .unwrap_or(first_row);
+            let values = matrix.rows_values_mut(first_row..end_row);
+            for (v, b) in values.iter_mut().zip(bytes.chunks_exact(VALUE_BYTES)) {
+                *v = f64::from_le_bytes(b.try_into().unwrap());
+            }
+            let file = OpenOptions::new().write(true).open(path).map_err(|e| e.to_string())?;
+            file.set_len((header.len() + values.len() * VALUE_BYTES) as u64).map_err(|e| e.to_string())?;
+            let file = OpenOptions::new().append(true).open(path).map_err(|e| e.to_string())?;
+            log::info!("resuming from checkpoint {} at row {} of {}", path.display(), end_row, matrix.len());
+            return Ok(Checkpoint { file, first_row, end_row });
+        }
+        let mut file = File::create(path).map_err(|e| format!("Cannot create checkpoint {}: {}", path.display(), e))?;
+        file.write_all(header.as_bytes()).map_err(|e| e.to_string())?;
+        Ok(Checkpoint { file, first_row, end_row: first_row })
+    }
+
+    /// Rows in the checkpoint.
+    pub fn rows(&self) -> Range<usize> {
+        self.first_row..self.end_row
+    }
+
+    /// Appends the completed `rows` of `matrix` not yet in the checkpoint, then syncs it to disk.
+    pub fn append(&mut self, matrix: &DistMatrix, rows: Range<usize>) -> Result<(), String> {
+        if rows.end <= self.end_row {
+            return Ok(());
+        }
+        if rows.start > self.end_row {
+            return Err(format!("Rows {} to {} are missing from the checkpoint", self.end_row, rows.start));
+        }
+        let bytes: Vec<u8> = matrix.rows_values(self.end_row..rows.end).iter().flat_map(|v| v.to_le_bytes()).collect();
+        self.file.write_all(&bytes).map_err(|e| e.to_string())?;
+        self.file.sync_data().map_err(|e| e.to_string())?;
+        self.end_row = rows.end;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dist::build_distance_matrix;
+    use crate::pipeline::PipelineBuilder;
+    use crate::sketch::{mix64, Signature};
+    use std::collections::HashMap;
+
+    // Checkpoint of the rows `0..rows` of `matrix` and of part of the next row, as an interrupted run leaves it
+    fn interrupted(path: &Path, config: &PipelineConfig, genomes: &[String], matrix: &DistMatrix, rows: usize) {
+        let mut empty = DistMatrix::new(matrix.labels().to_vec(), true);
+        let mut checkpoint = Checkpoint::open(path, &mut empty, 0, fingerprint(config, genomes), false).unwrap();
+        checkpoint.append(matrix, 0..rows).unwrap();
+        checkpoint.file.write_all(&[0u8; 3 * VALUE_BYTES]).unwrap();
+    }
+
+    #[test]
+    fn resume_matches_uninterrupted_run() {
+        let genomes: Vec<String> = (0..40).map(|g| format!("g{}.fa", g)).collect();
+        // genomes share the registers k where their indices fall in the same group of 1 + k % 4
+        let sketches: HashMap<String, Signature> = genomes
+            .iter()
+            .enumerate()
+            .map(|(g, genome)| {
+                let registers = (0..512u64).map(|k| (mix64(k * 100 + g as u64 / (1 + k % 4)) >> 40) as f32).collect();
+                (genome.clone(), Signature::Registers(registers))
+            })
+            .collect();
+        let dir = std::env::temp_dir().join(format!("bindashtree_checkpoint_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("matrix.checkpoint");
+        let plain = PipelineBuilder::new().kmer(16).sketch_size(512).build().unwrap();
+        let config = PipelineBuilder::new().kmer(16).sketch_size(512).checkpoint(&path).resume(true).build().unwrap();
+        let expected = build_distance_matrix(&plain, &sketches, &genomes).unwrap();
+
+        interrupted(&path, &config, &genomes, &expected, 15);
+        let resumed = build_distance_matrix(&config, &sketches, &genomes).unwrap();
+        for i in 0..genomes.len() {
+            for j in 0..genomes.len() {
+                assert_eq!(resumed.get(i, j), expected.get(i, j));
+            }
+        }
+        assert!(!path.exists());
+
+        // the rows of the checkpoint are read, not computed again
+        let mut marked = DistMatrix::new(expected.labels().to_vec(), true);
+        marked.set(0, 1, 0.5);
+        interrupted(&path, &config, &genomes, &marked, 15);
+        assert_eq!(build_distance_matrix(&config, &sketches, &genomes).unwrap().get(1, 0), 0.5);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,286 @@
+//! Clustering of genomes from a distance matrix.
+
+use rayon::prelude::*;
+use std::str::FromStr;
+
+use crate::matrix::DistMatrix;
+use crate::tree::pair_index;
+
+fn find(parent: &mut [usize], mut i: usize) -> usize {
+    while parent[i] != i {
+        parent[i] = parent[parent[i]];
+        i = parent[i];
+    }
+    i
+}
+
+/// Distance between two clusters in agglomerative clustering.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Linkage {
+    /// nearest members
+    Single,
+    /// mean of the distances between members (UPGMA)
+    Average,
+    /// farthest members
+    Complete,
+}
+
+impl FromStr for Linkage {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Linkage, String> {
+        match s.to_lowercase().as_str() {
+            "single" => Ok(Linkage::Single),
+            "average" => Ok(Linkage::Average),
+            "complete" => Ok(Linkage::Complete),
+            _ => Err(format!("Unknown linkage: {}", s)),
+        }
+    }
+}
+
+/// Choice of the representative genome of a cluster.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representative {
+    /// largest genome
+    Size,
+    /// lowest mean distance to the other members
+    Medoid,
+    /// first in the input
+    First,
+}
+
+impl FromStr for Representative {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Representative, String> {
+        match s.to_lowercase().as_str() {
+            "size" | "largest" => Ok(Representative::Size),
+            "medoid" => Ok(Representative::Medoid),
+            "first" => Ok(Representative::First),
+            _ => Err(format!("Unknown representative: {}", s)),
+        }
+    }
+}
+
+/// Single linkage clusters at `threshold`: two items are in the same cluster if a chain of
+/// distances at most `threshold` joins them. Returns the cluster index of each item,
+/// clusters being numbered in order of first appearance.
+pub fn single_linkage(matrix: &DistMatrix, threshold: f64) -> Vec<usize> {
+    let n = matrix.len();
+    let mut parent: Vec<usize> = (0..n).collect();
+    for i in 0..n {
+        for j in (i + 1)..n {
+            if matrix.get(i, j).min(matrix.get(j, i)) <= threshold {
+                let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+                if a != b {
+                    parent[b] = a;
+                }
+            }
+        }
+    }
+    numbered(&mut parent)
+}
+
+/// Flat clusters of the agglomerative clustering of `matrix` with `linkage`, cut at `threshold`: the
+/// clusters merged at a distance at most `threshold`. Numbered as by [single_linkage].
+/// The hierarchy is built by the nearest neighbor chain algorithm, in O(n^2) time.
+pub fn linkage_clusters(matrix: &DistMatrix, linkage: Linkage, threshold: f64) -> Vec<usize> {
+    if linkage == Linkage::Single {
+        return single_linkage(matrix, threshold);
+    }
+    let n = matrix.len();
+    let index = |i: usize, j: usize| pair_index(n, i, j);
+    let mut dist: Vec<f64> = (0..n).flat_map(|i| (i + 1..n).map(move |j| matrix.get(i, j).min(matrix.get(j, i)))).collect();
+    // clusters are held by one of their items, merged ones being inactive
+    let mut active = vec![true; n];
+    let mut size = vec![1usize; n];
+    let mut parent: Vec<usize> = (0..n).collect();
+    let mut chain: Vec<usize> = Vec::new();
+    for _ in 1..n {
+        if chain.is_empty() {
+            chain.push(active.iter().position(|&a| a).unwrap());
+        }
+        // follow nearest neighbors until two clusters are each other's, the previous one winning ties
+        let (a, b, height) = loop {
+            let a = *chain.last().unwrap();
+            let previous = chain.len().checked_sub(2).map(|k| chain[k]);
+            let mut nearest = previous.map_or((f64::INFINITY, usize::MAX), |p| (dist[index(a, p)], p));
+            for c in (0..n).filter(|&c| active[c] && c != a) {
+                if dist[index(a, c)] < nearest.0 {
+                    nearest = (dist[index(a, c)], c);
+                }
+            }
+            if Some(nearest.1) == previous {
+                chain.truncate(chain.len() - 2);
+                break (a, nearest.1, nearest.0);
+            }
+            chain.push(nearest.1);
+        };
+        // b holds the merged cluster
+        for c in (0..n).filter(|&c| active[c] && c != a && c != b) {
+            let (da, db) = (dist[index(a, c)], dist[index(b, c)]);
+            dist[index(b, c)] = match linkage {
+                Linkage::Single => da.min(db),
+                Linkage::Complete => da.max(db),
+                Linkage::Average => (size[a] as f64 * da + size[b] as f64 * db) / (size[a] + size[b]) as f64,
+            };
+        }
+        active[a] = false;
+        size[b] += size[a];
+        if height <= threshold {
+            let (ra, rb) = (find(&mut parent, a), find(&mut parent, b));
+            parent[ra] = rb;
+        }
+    }
+    numbered(&mut parent)
+}
+
+// Cluster index of each item from the union-find `parent`, in order of first appearance
+fn numbered(parent: &mut [usize]) -> Vec<usize> {
+    let n = parent.len();
+    let mut ids = vec![usize::MAX; n];
+    let mut nb_clusters = 0;
+    (0..n)
+        .map(|i| {
+            let root = find(parent, i);
+            if ids[root] == usize::MAX {
+                ids[root] = nb_clusters;
+                nb_clusters += 1;
+            }
+            ids[root]
+        })
+        .collect()
+}
+
+/// Representative of each cluster of the items 0 to n - 1, `clusters` giving the cluster index of
+/// each item (numbered from 0) and `sizes` their sizes. Ties go to the first item. Medoids take the
+/// distances between all the members of each cluster.
+pub fn representatives<F>(clusters: &[usize], representative: Representative, sizes: &[usize], distance: F) -> Vec<usize>
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    let nb_clusters = clusters.iter().max().map_or(0, |&c| c + 1);
+    let mut members: Vec<Vec<usize>> = vec![Vec::new(); nb_clusters];
+    for (i, &c) in clusters.iter().enumerate() {
+        members[c].push(i);
+    }
+    let best = |members: &[usize], score: &dyn Fn(usize) -> f64| -> usize {
+        members.iter().fold((usize::MAX, f64::INFINITY), |best, &i| {
+            let s = score(i);
+            if s < best.1 { (i, s) } else { best }
+        })
+        .0
+    };
+    members
+        .par_iter()
+        .map(|members| match representative {
+            Representative::First => members[0],
+            Representative::Size => best(members, &|i| -(sizes[i] as f64)),
+            Representative::Medoid if members.len() < 3 => members[0],
+            Representative::Medoid => {
+                best(members, &|i| members.iter().filter(|&&j| j != i).map(|&j| distance(i, j)).sum())
+            }
+        })
+        .collect()
+}
+
+/// Greedy clustering of the items 0 to n - 1 taken in the order of `priority`, a permutation of them:
+/// an item farther than `threshold` from all the representatives chosen before it becomes one, any
+/// other joins the nearest of them. Returns the representative of each item and its distance to it.
+pub fn greedy_clusters<F>(priority: &[usize], threshold: f64, distance: F) -> Vec<(usize, f64)>
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    let mut clusters = vec![(usize::MAX, f64::INFINITY); priority.len()];
+    let mut representatives: Vec<usize> = Vec::new();
+    for &i in priority {
+        let nearest = representatives
+            .par_iter()
+            .map(|&r| (distance(i, r), r))
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        clusters[i] = match nearest {
+            Some((d, r)) if d <= threshold => (r, d),
+            _ => {
+                representatives.push(i);
+                (i, 0.0)
+            }
+        };
+    }
+    clusters
+}
+
+/// Edges of the graph of the items 0 to n - 1 joining the pairs at a distance at most `threshold`,
+/// as the two items, the lower first, and their distance. Only the edges are kept in memory.
+pub fn threshold_graph<F>(n: usize, threshold: f64, distance: F) -> Vec<(usize, usize, f64)>
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    (0..n)
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let distance = &distance;
+            (i + 1..n).filter_map(move |j| {
+                let d = distance(i, j);
+                (d <= threshold).then_some((i, j, d))
+            })
+        })
+        .collect()
+}
+
+/// Connected components of the graph of the items 0 to n - 1 with `edges`, numbered as by [single_linkage].
+pub fn connected_components(n: usize, edges: &[(usize, usize, f64)]) -> Vec<usize> {
+    let mut parent: Vec<usize> = (0..n).collect();
+    for &(i, j, _) in edges {
+        let (a, b) = (find(&mut parent, i), find(&mut parent, j));
+        if a != b {
+            parent[b] = a;
+        }
+    }
+    numbered(&mut parent)
+}
+
+/// Greedy partition of the graph of the items 0 to n - 1 with `edges` into cliques, sets of items
+/// all joined to each other: by decreasing degree, an item not yet taken starts a clique, to which
+/// its neighbors not yet taken are added, by decreasing degree, when joined to all its members.
+/// Returns the clique index of each item, cliques being numbered in order of creation.
+pub fn clique_partition(n: usize, edges: &[(usize, usize, f64)]) -> Vec<usize> {
+    let mut neighbors: Vec<Vec<usize>> = vec![Vec::new(); n];
+    for &(i, j, _) in edges {
+        neighbors[i].push(j);
+        neighbors[j].push(i);
+    }
+    let mut order: Vec<usize> = (0..n).collect();
+    order.sort_by_key(|&i| (std::cmp::Reverse(neighbors[i].len()), i));
+    let mut rank = vec![0; n];
+    for (r, &i) in order.iter().enumerate() {
+        rank[i] = r;
+    }
+    for list in neighbors.iter_mut() {
+        list.sort_by_key(|&j| rank[j]);
+    }
+    let mut cliques = vec![usize::MAX; n];
+    let mut nb_cliques = 0;
+    let mut joined = vec![false; n];
+    for &i in &order {
+        if cliques[i] != usize::MAX {
+            continue;
+        }
+        let id = nb_cliques;
+        nb_cliques += 1;
+        let mut members = vec![i];
+        for &j in neighbors[i].iter().filter(|&&j| cliques[j] == usize::MAX) {
+            // j is joined to every member when all of them are among its neighbors
+            for &k in &neighbors[j] {
+                joined[k] = true;
+            }
+            if members.iter().all(|&k| joined[k]) {
+                members.push(j);
+            }
+            for &k in &neighbors[j] {
+                joined[k] = false;
+            }
+        }
+        for &k in &members {
+            cliques[k] = id;
+        }
+    }
+    cliques
+}
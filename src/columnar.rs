@@ -0,0 +1,128 @@
+//! Parquet and Arrow IPC output of the pairwise table, built with the `arrow` feature.
+//!
+//! Columns are those of the tab separated table (see [crate::pairs::write_pairs]), genome labels as strings,
+//! values as float64, shared hashes as uint64 and pairs sharing too few hashes as nulls.
+//! Each block of pairs is written as one record batch (one Parquet row group).
+
+use arrow_array::{ArrayRef, Float64Array, RecordBatch, StringArray, UInt64Array};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema};
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::file::properties::WriterProperties;
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::Arc;
+
+use crate::dist::genome_label;
+use crate::matrix::DistMatrix;
+use crate::pairs::{pair_blocks, value_column, PairRecord, PairsFormat};
+use crate::pipeline::PipelineConfig;
+use crate::sketch::Signature;
+
+enum ColumnarWriter {
+    Parquet(ArrowWriter<File>),
+    Arrow(FileWriter<File>),
+}
+
+fn schema(config: &PipelineConfig) -> Schema {
+    let column = value_column(config);
+    let mut fields = vec![
+        Field::new("genome_a", DataType::Utf8, false),
+        Field::new("genome_b", DataType::Utf8, false),
+        Field::new(column, DataType::Float64, true),
+    ];
+    if config.pair_details {
+        fields.push(Field::new("jaccard", DataType::Float64, false));
+        fields.push(Field::new("shared_hashes", DataType::UInt64, false));
+    }
+    if config.pvalues {
+        fields.push(Field::new("p_value", DataType::Float64, false));
+    }
+    if config.aligned_fraction {
+        fields.push(Field::new("af_a", DataType::Float64, false));
+        fields.push(Field::new("af_b", DataType::Float64, false));
+    }
+    if config.confidence > 0.0 {
+        fields.push(Field::new(format!("{}_low", column), DataType::Float64, false));
+        fields.push(Field::new(format!("{}_high", column), DataType::Float64, false));
+    }
+    Schema::new(fields)
+}
+
+fn float_column<'a>(records: &[&'a PairRecord], value: impl Fn(&'a PairRecord) -> Option<f64>) -> ArrayRef {
+    Arc::new(records.iter().map(|r| value(r)).collect::<Float64Array>())
+}
+
+fn record_batch(
+    schema: &Arc<Schema>,
+    config: &PipelineConfig,
+    records: &[&PairRecord],
+    labels: &[String],
+) -> Result<RecordBatch, String> {
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(records.iter().map(|r| Some(labels[r.i].as_str())).collect::<StringArray>()),
+        Arc::new(records.iter().map(|r| Some(labels[r.j].as_str())).collect::<StringArray>()),
+        float_column(records, |r| r.value),
+    ];
+    if config.pair_details {
+        columns.push(float_column(records, |r| r.details.map(|d| d.0)));
+        columns.push(Arc::new(records.iter().map(|r| r.details.map(|d| d.1 as u64)).collect::<UInt64Array>()));
+    }
+    if config.pvalues {
+        columns.push(float_column(records, |r| r.pvalue));
+    }
+    if config.aligned_fraction {
+        columns.push(float_column(records, |r| r.fractions.map(|f| f.0)));
+        columns.push(float_column(records, |r| r.fractions.map(|f| f.1)));
+    }
+    if config.confidence > 0.0 {
+        columns.push(float_column(records, |r| r.interval.map(|c| c.0)));
+        columns.push(float_column(records, |r| r.interval.map(|c| c.1)));
+    }
+    RecordBatch::try_new(schema.clone(), columns).map_err(|e| e.to_string())
+}
+
+/// Writes all pairwise distances of `matrix` to `path` as a Parquet or Arrow IPC file.
+pub fn write_pairs_columnar(
+    config: &PipelineConfig,
+    matrix: &DistMatrix,
+    sketches: &HashMap<String, Signature>,
+    genomes: &[String],
+    genome_sizes: Option<&[usize]>,
+    path: &str,
+    format: PairsFormat,
+) -> Result<(), String> {
+    let schema = Arc::new(schema(config));
+    let file = File::create(path).map_err(|e| format!("Cannot create pairwise file {}: {}", path, e))?;
+    let write_error = |e: String| format!("Error writing pairwise file {}: {}", path, e);
+    let mut writer = match format {
+        PairsFormat::Parquet => {
+            let properties = WriterProperties::builder()
+                .set_compression(Compression::ZSTD(ZstdLevel::default()))
+                .build();
+            ColumnarWriter::Parquet(
+                ArrowWriter::try_new(file, schema.clone(), Some(properties)).map_err(|e| write_error(e.to_string()))?,
+            )
+        }
+        PairsFormat::Arrow => ColumnarWriter::Arrow(FileWriter::try_new(file, &schema).map_err(|e| write_error(e.to_string()))?),
+        PairsFormat::Tsv | PairsFormat::Jsonl => return Err(format!("{} is written as text by write_pairs", path)),
+    };
+
+    let labels: Vec<String> = genomes.iter().map(|g| genome_label(g)).collect();
+    pair_blocks(config, matrix, sketches, genomes, genome_sizes, 0, |block, _| {
+        let records: Vec<&PairRecord> = block.iter().flatten().collect();
+        if records.is_empty() {
+            return Ok(());
+        }
+        let batch = record_batch(&schema, config, &records, &labels).map_err(write_error)?;
+        match &mut writer {
+            ColumnarWriter::Parquet(w) => w.write(&batch).map_err(|e| write_error(e.to_string())),
+            ColumnarWriter::Arrow(w) => w.write(&batch).map_err(|e| write_error(e.to_string())),
+        }
+    })?;
+    match writer {
+        ColumnarWriter::Parquet(w) => w.close().map(|_| ()).map_err(|e| write_error(e.to_string())),
+        ColumnarWriter::Arrow(mut w) => w.finish().map_err(|e| write_error(e.to_string())),
+    }
+}
@@ -0,0 +1,100 @@
+//! Topological comparison of two trees of the same leaves by the Robinson-Foulds distance, the
+//! number of splits found in only one of them. Trees are compared unrooted, on their non-trivial
+//! splits (at least two leaves on each side).
+
+use std::collections::{HashMap, HashSet};
+
+use crate::phylo::Tree;
+
+/// Splits of two trees and their Robinson-Foulds distance.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeComparison {
+    pub nb_leaves: usize,
+    pub nb_splits1: usize,
+    pub nb_splits2: usize,
+    pub nb_shared: usize,
+    pub rf: usize,
+    /// RF divided by its maximum, the total number of splits of the two trees
+    pub normalized_rf: f64,
+}
+
+impl TreeComparison {
+    /// Tab separated lines of statistic and value.
+    pub fn to_tsv(&self) -> String {
+        format!(
+            "statistic\tvalue\nleaves\t{}\nsplits_tree1\t{}\nsplits_tree2\t{}\nshared_splits\t{}\nrf\t{}\nnormalized_rf\t{}\n",
+            self.nb_leaves, self.nb_splits1, self.nb_splits2, self.nb_shared, self.rf, self.normalized_rf
+        )
+    }
+}
+
+/// Robinson-Foulds comparison of the Newick trees `newick1` and `newick2`, which must have the same leaves.
+pub fn compare_trees(newick1: &str, newick2: &str) -> Result<TreeComparison, String> {
+    let tree1 = Tree::from_newick(newick1)?;
+    let tree2 = Tree::from_newick(newick2)?;
+    let labels1 = leaf_labels(&tree1);
+    let mut labels2 = leaf_labels(&tree2);
+    let leaves: HashMap<String, usize> = labels1.iter().enumerate().map(|(k, label)| (label.clone(), k)).collect();
+    if leaves.len() != labels1.len() {
+        return Err("Leaf labels are not unique in the first tree".to_string());
+    }
+    let mut sorted1 = labels1.clone();
+    sorted1.sort();
+    labels2.sort();
+    if sorted1 != labels2 {
+        return Err("The trees do not have the same leaves".to_string());
+    }
+    let splits1 = nontrivial_splits(&tree1, &leaves)?;
+    let splits2 = nontrivial_splits(&tree2, &leaves)?;
+    let nb_shared = splits1.intersection(&splits2).count();
+    let rf = splits1.len() + splits2.len() - 2 * nb_shared;
+    let max_rf = splits1.len() + splits2.len();
+    Ok(TreeComparison {
+        nb_leaves: leaves.len(),
+        nb_splits1: splits1.len(),
+        nb_splits2: splits2.len(),
+        nb_shared,
+        rf,
+        normalized_rf: if max_rf > 0 { rf as f64 / max_rf as f64 } else { 0.0 },
+    })
+}
+
+fn leaf_labels(tree: &Tree) -> Vec<String> {
+    (0..tree.len())
+        .filter(|&i| tree.node(i).children.is_empty())
+        .map(|i| tree.node(i).label.clone().unwrap_or_default())
+        .collect()
+}
+
+// Splits with at least two leaves on each side, those on both sides of a root of degree 2 counting once
+fn nontrivial_splits(tree: &Tree, leaves: &HashMap<String, usize>) -> Result<HashSet<Vec<u64>>, String> {
+    let n = leaves.len();
+    let size = |split: &Vec<u64>| split.iter().map(|w| w.count_ones() as usize).sum::<usize>();
+    Ok(tree
+        .splits(leaves)?
+        .into_iter()
+        .flatten()
+        .filter(|split| (2..=n.saturating_sub(2)).contains(&size(split)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn robinson_foulds() {
+        let tree = "((a:1,b:1):1,(c:1,d:1):1,(e:1,f:1):1);";
+        // one split of three shared
+        let comparison = compare_trees(tree, "((a,c),(b,d),(e,f));").unwrap();
+        assert_eq!((comparison.nb_leaves, comparison.nb_splits1, comparison.nb_splits2), (6, 3, 3));
+        assert_eq!((comparison.nb_shared, comparison.rf), (1, 4));
+        assert!((comparison.normalized_rf - 4.0 / 6.0).abs() < 1e-12);
+        // rooting, a root of degree 2 and branch lengths do not change the splits
+        for same in ["(a,(b,((c,d),(e,f))));", "((a,b),((c,d),(e,f)));", "((e,f),(d,c),(b,a));"] {
+            let comparison = compare_trees(tree, same).unwrap();
+            assert_eq!((comparison.rf, comparison.nb_shared, comparison.nb_splits2), (0, 3, 3), "{}", same);
+        }
+        assert!(compare_trees(tree, "((a,b),(c,d),(e,g));").is_err());
+    }
+}
@@ -0,0 +1,1094 @@
+//! Pairwise distances between sketches.
+
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::checkpoint::{fingerprint, Checkpoint};
+use crate::matrix::{DistMatrix, QueryMatrix};
+use crate::pipeline::PipelineConfig;
+use crate::prescreen::{saturated_distance, Prescreen};
+use crate::simd::{count_equal_f32, hamming_f32};
+use crate::sketch::{Signature, SketchAlgo};
+use crate::weighting::{IdfWeights, Weighting};
+
+/// How an asymmetric matrix is turned into the symmetric one neighbor-joining needs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symmetrization {
+    Min,
+    Max,
+    Mean,
+}
+
+impl FromStr for Symmetrization {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Symmetrization, String> {
+        match s.to_lowercase().as_str() {
+            "min" => Ok(Symmetrization::Min),
+            "max" => Ok(Symmetrization::Max),
+            "mean" => Ok(Symmetrization::Mean),
+            _ => Err(format!("Unknown symmetrization policy: {}", s)),
+        }
+    }
+}
+
+/// Statistic compared between genomes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceKind {
+    /// Jaccard index of the MinHash sketches, transformed by the [DistanceModel]
+    MinHash,
+    /// cosine distance of the canonical k-mer count profiles
+    Cosine,
+    /// d2S distance (Reinert et al. 2009) of the k-mer count profiles, centered by their expectation
+    /// from the GC content
+    D2S,
+}
+
+impl FromStr for DistanceKind {
+    type Err = String;
+    fn from_str(s: &str) -> Result<DistanceKind, String> {
+        match s.to_lowercase().as_str() {
+            "minhash" => Ok(DistanceKind::MinHash),
+            "cosine" => Ok(DistanceKind::Cosine),
+            "d2s" => Ok(DistanceKind::D2S),
+            _ => Err(format!("Unknown distance: {}", s)),
+        }
+    }
+}
+
+/// Transform of the Jaccard index of two sketches into a distance.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DistanceModel {
+    /// `-ln(2J / (1 + J)) / k` from the single precision fraction of differing registers,
+    /// identical sketches getting a tiny positive distance
+    BinDash,
+    /// Mash distance: the same Poisson model in double precision, 0 for identical sketches
+    /// and 1 when no hash is shared
+    Mash,
+    /// `1 - C^(1/k)` from the containment `C` of the row genome in the column genome instead of the
+    /// Jaccard index, see [containment_distance]: directional, the matrix is asymmetric and
+    /// symmetrized for the tree. FracMinHash sketches only.
+    Containment,
+}
+
+impl DistanceModel {
+    /// Whether `d(i, j)` may differ from `d(j, i)`.
+    pub fn is_directional(&self) -> bool {
+        *self == DistanceModel::Containment
+    }
+}
+
+impl FromStr for DistanceModel {
+    type Err = String;
+    fn from_str(s: &str) -> Result<DistanceModel, String> {
+        match s.to_lowercase().as_str() {
+            "bindash" => Ok(DistanceModel::BinDash),
+            "mash" => Ok(DistanceModel::Mash),
+            "containment" => Ok(DistanceModel::Containment),
+            _ => Err(format!("Unknown distance model: {}", s)),
+        }
+    }
+}
+
+/// Distance between two sketches of the same kind under `model`.
+/// The containment model gives NaN for other sketches than FracMinHash ones.
+pub fn model_distance(model: DistanceModel, query: &Signature, reference: &Signature, kmer_size: usize) -> f64 {
+    match (model, query, reference) {
+        (DistanceModel::BinDash, _, _) => sketch_distance(query, reference, kmer_size),
+        (DistanceModel::Mash, _, _) => mash_distance(sketch_jaccard(query, reference).0, kmer_size),
+        // FracMinHash containments need no genome size
+        (DistanceModel::Containment, Signature::Scaled(_), Signature::Scaled(_)) => {
+            containment_distance(sketch_containment(query, reference, 0, 0), kmer_size)
+        }
+        (DistanceModel::Containment, _, _) => f64::NAN,
+    }
+}
+
+/// Jaccard index estimated from two sketches of the same kind, along with the number of
+/// hash comparisons it rests on (registers, or the union of FracMinHash sets).
+/// Sketches of different kinds give NaN, see [check_signatures].
+pub fn sketch_jaccard(query_signature: &Signature, reference_signature: &Signature) -> (f64, usize) {
+    match (query_signature, reference_signature) {
+        (Signature::Registers(query), Signature::Registers(reference)) => {
+            let nb_equal = count_equal_f32(query, reference);
+            (nb_equal as f64 / query.len() as f64, query.len())
+        }
+        (Signature::Scaled(query), Signature::Scaled(reference)) => {
+            let (_, union) = scaled_counts(query, reference);
+            (scaled_jaccard(query, reference), union)
+        }
+        (Signature::Profile { counts: query, .. }, Signature::Profile { counts: reference, .. }) => {
+            let (common, union) = profile_counts(query, reference);
+            (common as f64 / union.max(1) as f64, union)
+        }
+        (
+            Signature::BBit { bits, nb_registers, words: query },
+            Signature::BBit { bits: reference_bits, words: reference, .. },
+        ) if bits == reference_bits => (bbit_jaccard(query, reference, *bits, *nb_registers), *nb_registers),
+        (
+            Signature::SetSketch { b, cardinality, registers: query, .. },
+            Signature::SetSketch { cardinality: reference_cardinality, registers: reference, .. },
+        ) => (setsketch_jaccard(*b, (*cardinality, query), (*reference_cardinality, reference)), query.len()),
+        // sketches are checked by check_signatures before being compared
+        _ => (f64::NAN, 0),
+    }
+}
+
+/// Containment `C(A, B) = |A ∩ B| / |A|` of the k-mers of a query genome A in a reference genome B.
+/// FracMinHash sketches and k-mer profiles give it directly, otherwise it is derived from the Jaccard index and the genome sizes
+/// (numbers of k-mers) as `J (|A| + |B|) / ((1 + J) |A|)`.
+pub fn sketch_containment(
+    query_signature: &Signature,
+    reference_signature: &Signature,
+    query_size: usize,
+    reference_size: usize,
+) -> f64 {
+    if let (Signature::Scaled(query), Signature::Scaled(reference)) = (query_signature, reference_signature) {
+        if query.is_empty() {
+            return 0.0;
+        }
+        let (common, _) = scaled_counts(query, reference);
+        return common as f64 / query.len() as f64;
+    }
+    if let (Signature::Profile { counts: query, .. }, Signature::Profile { counts: reference, .. }) =
+        (query_signature, reference_signature)
+    {
+        let (common, _) = profile_counts(query, reference);
+        return common as f64 / query.len().max(1) as f64;
+    }
+    let (jaccard, _) = sketch_jaccard(query_signature, reference_signature);
+    let containment = jaccard * (query_size + reference_size) as f64 / ((1.0 + jaccard) * query_size as f64);
+    containment.clamp(0.0, 1.0)
+}
+
+/// Estimated fractions of two genomes of `size_a` and `size_b` k-mers covered by their alignment.
+/// As sketches cannot tell divergence from missing regions, the most contained genome is assumed to be
+/// fully aligned, its k-mer loss being due to divergence only, and the containments are corrected by that loss:
+/// 95% ANI over 90% of a genome and 95% ANI over 20% of it then differ even at the same Jaccard index.
+pub fn aligned_fractions(query: &Signature, reference: &Signature, size_a: usize, size_b: usize) -> (f64, f64) {
+    let in_reference = sketch_containment(query, reference, size_a, size_b);
+    let in_query = sketch_containment(reference, query, size_b, size_a);
+    let divergence_loss = in_reference.max(in_query);
+    if divergence_loss == 0.0 {
+        return (0.0, 0.0);
+    }
+    (in_reference / divergence_loss, in_query / divergence_loss)
+}
+
+/// Mash distance `-ln(2J / (1 + J)) / k`, 1 for a null Jaccard index.
+pub fn mash_distance(jaccard: f64, kmer_size: usize) -> f64 {
+    if jaccard <= 0.0 {
+        1.0
+    } else if jaccard >= 1.0 {
+        0.0
+    } else {
+        -(2.0 * jaccard / (1.0 + jaccard)).ln() / kmer_size as f64
+    }
+}
+
+/// Distance of a Jaccard index under `model`, see [model_distance]. The containment model is not
+/// a transform of the Jaccard index and gives NaN.
+pub fn jaccard_to_distance(model: DistanceModel, jaccard: f64, kmer_size: usize) -> f64 {
+    match model {
+        DistanceModel::BinDash => hamming_to_distance((1.0 - jaccard) as f32, kmer_size),
+        DistanceModel::Mash => mash_distance(jaccard, kmer_size),
+        DistanceModel::Containment => f64::NAN,
+    }
+}
+
+/// Containment distance `1 - C^(1/k)`, one minus the average nucleotide identity estimated from a
+/// containment `C` (Hera et al. 2023), 1 for a null containment.
+pub fn containment_distance(containment: f64, kmer_size: usize) -> f64 {
+    if containment <= 0.0 {
+        1.0
+    } else {
+        1.0 - containment.min(1.0).powf(1.0 / kmer_size as f64)
+    }
+}
+
+/// Wilson score interval of a Jaccard index estimated from `shared` equal hashes out of `sketch_size`,
+/// `z` being the standard normal quantile of the confidence level, see [normal_quantile].
+pub fn jaccard_interval(shared: usize, sketch_size: usize, z: f64) -> (f64, f64) {
+    let n = sketch_size as f64;
+    let p = shared as f64 / n;
+    let z2 = z * z;
+    let center = (p + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let half_width = z / (1.0 + z2 / n) * (p * (1.0 - p) / n + z2 / (4.0 * n * n)).sqrt();
+    ((center - half_width).max(0.0), (center + half_width).min(1.0))
+}
+
+/// Quantile `z` of the standard normal distribution such that `P(|Z| <= z) = level`.
+pub fn normal_quantile(level: f64) -> f64 {
+    // bisection on erf(z / sqrt(2)) = level
+    let (mut low, mut high) = (0.0f64, 40.0f64);
+    for _ in 0..100 {
+        let mid = (low + high) / 2.0;
+        if libm::erf(mid / std::f64::consts::SQRT_2) < level {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    (low + high) / 2.0
+}
+
+/// Mash p-value: probability of at least `shared` equal hashes out of `sketch_size` between
+/// two random genomes of `size_a` and `size_b` k-mers, small values meaning the estimate is not chance.
+pub fn mash_pvalue(shared: usize, sketch_size: usize, kmer_size: usize, size_a: usize, size_b: usize) -> f64 {
+    if shared == 0 {
+        return 1.0;
+    }
+    // probability of a random k-mer to belong to a genome, then to both
+    let kmer_space = 4f64.powi(kmer_size as i32);
+    let in_a = 1.0 / (1.0 + kmer_space / size_a as f64);
+    let in_b = 1.0 / (1.0 + kmer_space / size_b as f64);
+    let in_both = 1.0 / (1.0 / in_a + 1.0 / in_b - 1.0);
+    binomial_upper_tail(sketch_size, shared, in_both)
+}
+
+// P(X >= x) for X ~ Binomial(n, p), summed in log space
+fn binomial_upper_tail(n: usize, x: usize, p: f64) -> f64 {
+    let (ln_p, ln_q) = (p.ln(), (-p).ln_1p());
+    let ln_n = libm::lgamma(n as f64 + 1.0);
+    (x..=n)
+        .map(|i| {
+            let ln_choose = ln_n - libm::lgamma(i as f64 + 1.0) - libm::lgamma((n - i) as f64 + 1.0);
+            (ln_choose + i as f64 * ln_p + (n - i) as f64 * ln_q).exp()
+        })
+        .sum::<f64>()
+        .min(1.0)
+}
+
+/// Mash-like distance between two sketches of the same kind, NaN for sketches of different kinds.
+pub fn sketch_distance(query_signature: &Signature, reference_signature: &Signature, kmer_size: usize) -> f64 {
+    match (query_signature, reference_signature) {
+        (Signature::Registers(query), Signature::Registers(reference)) => {
+            hamming_to_distance(hamming_f32(query, reference), kmer_size)
+        }
+        (Signature::Scaled(query), Signature::Scaled(reference)) => {
+            hamming_to_distance(1.0 - scaled_jaccard(query, reference) as f32, kmer_size)
+        }
+        (
+            Signature::BBit { bits, nb_registers, words: query },
+            Signature::BBit { bits: reference_bits, words: reference, .. },
+        ) if bits == reference_bits => {
+            hamming_to_distance(1.0 - bbit_jaccard(query, reference, *bits, *nb_registers) as f32, kmer_size)
+        }
+        (Signature::Profile { .. }, Signature::Profile { .. }) | (Signature::SetSketch { .. }, Signature::SetSketch { .. }) => {
+            hamming_to_distance(1.0 - sketch_jaccard(query_signature, reference_signature).0 as f32, kmer_size)
+        }
+        _ => f64::NAN,
+    }
+}
+
+/// Jaccard index from b-bit registers. Two registers agree by chance with probability `2^-b`,
+/// so the fraction `P` of equal registers is corrected into `(P - 2^-b) / (1 - 2^-b)` (Li & König).
+pub fn bbit_jaccard(query: &[u64], reference: &[u64], bits: u32, nb_registers: usize) -> f64 {
+    // lowest bit of each field, where the OR of the field bits is folded
+    let mut field_lows = 0u64;
+    for i in 0..64 / bits {
+        field_lows |= 1 << (i * bits);
+    }
+    let nb_different: u32 = query
+        .iter()
+        .zip(reference.iter())
+        .map(|(a, b)| {
+            let mut x = a ^ b;
+            let mut shift = 1;
+            while shift < bits {
+                x |= x >> shift;
+                shift *= 2;
+            }
+            (x & field_lows).count_ones()
+        })
+        .sum();
+    let equal = 1.0 - nb_different as f64 / nb_registers as f64;
+    let chance = 0.5_f64.powi(bits as i32);
+    ((equal - chance) / (1.0 - chance)).clamp(0.0, 1.0)
+}
+
+// golden section steps of the SetSketch likelihood maximization, down to 1e-13 of its interval
+const GOLDEN_SECTION_STEPS: usize = 64;
+
+/// Jaccard index of two SetSketches of base `b` by joint maximum likelihood (Ertl 2021, section
+/// 3.2), from the numbers of registers larger, smaller and equal in the first sketch and the
+/// cardinalities of the sets, the likelihood being maximized by golden section search over the
+/// Jaccard indices the cardinalities allow. This is the estimator of probminhash's
+/// `MleJaccard::get_mle`, which logs each of its iterations to the terminal.
+pub fn setsketch_jaccard(b: f64, (query_cardinality, query): (f64, &[u16]), (reference_cardinality, reference): (f64, &[u16])) -> f64 {
+    if !(query_cardinality > 0.0 && reference_cardinality > 0.0) {
+        return 0.0;
+    }
+    let (mut nb_larger, mut nb_smaller) = (0usize, 0usize);
+    for (x, y) in query.iter().zip(reference) {
+        match x.cmp(y) {
+            std::cmp::Ordering::Greater => nb_larger += 1,
+            std::cmp::Ordering::Less => nb_smaller += 1,
+            std::cmp::Ordering::Equal => {}
+        }
+    }
+    let nb_equal = query.len() - nb_larger - nb_smaller;
+    let u = query_cardinality / (query_cardinality + reference_cardinality);
+    let v = reference_cardinality / (query_cardinality + reference_cardinality);
+    // probability of a register larger in a sketch than in the other, -log_b(1 - x (b - 1) / b)
+    let p = |x: f64| -(-x * (b - 1.0) / b).ln_1p() / (b - 1.0).ln_1p();
+    let term = |count: usize, probability: f64| if count == 0 { 0.0 } else { count as f64 * probability.ln() };
+    let log_likelihood = |j: f64| {
+        let (larger, smaller) = (p(u - v * j), p(v - u * j));
+        term(nb_larger, larger) + term(nb_smaller, smaller) + term(nb_equal, 1.0 - larger - smaller)
+    };
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let (mut lo, mut hi) = (0.0, (u / v).min(v / u));
+    let (mut x1, mut x2) = (hi - ratio * (hi - lo), lo + ratio * (hi - lo));
+    let (mut f1, mut f2) = (log_likelihood(x1), log_likelihood(x2));
+    for _ in 0..GOLDEN_SECTION_STEPS {
+        if f1 >= f2 {
+            hi = x2;
+            (x2, f2) = (x1, f1);
+            x1 = hi - ratio * (hi - lo);
+            f1 = log_likelihood(x1);
+        } else {
+            lo = x1;
+            (x1, f1) = (x2, f2);
+            x2 = lo + ratio * (hi - lo);
+            f2 = log_likelihood(x2);
+        }
+    }
+    (lo + hi) / 2.0
+}
+
+/// Jaccard index of two sorted FracMinHash hash sets.
+pub fn scaled_jaccard(query: &[u64], reference: &[u64]) -> f64 {
+    let (common, union) = scaled_counts(query, reference);
+    if union == 0 {
+        return 0.0;
+    }
+    common as f64 / union as f64
+}
+
+// Sizes of the intersection and union of the k-mer sets of two sorted profiles
+fn profile_counts(query: &[(u64, u32)], reference: &[(u64, u32)]) -> (usize, usize) {
+    let (mut i, mut j, mut common) = (0, 0, 0usize);
+    while i < query.len() && j < reference.len() {
+        match query[i].0.cmp(&reference[j].0) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                common += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    (common, query.len() + reference.len() - common)
+}
+
+// Pairs of counts of the k-mers present in either profile, 0 for an absent k-mer
+fn profile_merge<'a>(
+    query: &'a [(u64, u32)],
+    reference: &'a [(u64, u32)],
+) -> impl Iterator<Item = (u64, f64, f64)> + 'a {
+    let (mut i, mut j) = (0, 0);
+    std::iter::from_fn(move || {
+        if j == reference.len() || (i < query.len() && query[i].0 < reference[j].0) {
+            let (kmer, count) = *query.get(i)?;
+            i += 1;
+            Some((kmer, count as f64, 0.0))
+        } else if i == query.len() || reference[j].0 < query[i].0 {
+            let (kmer, count) = reference[j];
+            j += 1;
+            Some((kmer, 0.0, count as f64))
+        } else {
+            let (kmer, count) = query[i];
+            let other = reference[j].1;
+            i += 1;
+            j += 1;
+            Some((kmer, count as f64, other as f64))
+        }
+    })
+}
+
+/// Cosine distance `1 - A.B / (|A| |B|)` of two k-mer count profiles.
+pub fn cosine_distance(query: &[(u64, u32)], reference: &[(u64, u32)]) -> f64 {
+    let (mut dot, mut norm_a, mut norm_b) = (0.0, 0.0, 0.0);
+    for (_, a, b) in profile_merge(query, reference) {
+        dot += a * b;
+        norm_a += a * a;
+        norm_b += b * b;
+    }
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 1.0;
+    }
+    (1.0 - dot / (norm_a.sqrt() * norm_b.sqrt())).max(0.0)
+}
+
+/// d2S distance `(1 - D2S / sqrt(sum Ã²/r * sum B̃²/r)) / 2` with `D2S = sum ÃB̃/r` and `r = sqrt(Ã² + B̃²)`
+/// over all canonical k-mers, `Ã` being the count minus its expectation under an i.i.d. model of the GC content.
+/// Expectations only depend on the number of G and C bases, so k-mers absent from both profiles are summed
+/// by group and any k can be used.
+pub fn d2s_distance(query: (&[(u64, u32)], f64), reference: (&[(u64, u32)], f64), kmer_size: usize) -> f64 {
+    // expected count of a canonical k-mer with g G/C bases, both strands being counted
+    let expectations = |(counts, gc): (&[(u64, u32)], f64)| -> Vec<f64> {
+        let total: f64 = counts.iter().map(|&(_, c)| c as f64).sum();
+        (0..=kmer_size)
+            .map(|g| 2.0 * total * (gc / 2.0).powi(g as i32) * ((1.0 - gc) / 2.0).powi((kmer_size - g) as i32))
+            .collect()
+    };
+    let (expected_a, expected_b) = (expectations(query), expectations(reference));
+    let terms = |a: f64, b: f64| -> [f64; 3] {
+        let r = (a * a + b * b).sqrt();
+        if r == 0.0 {
+            return [0.0; 3];
+        }
+        [a * b / r, a * a / r, b * b / r]
+    };
+    let mut sums = [0.0f64; 3];
+    // every k-mer taken absent first, half of the 4^k k-mers with g G/C bases being canonical
+    let mut nb_kmers = 2f64.powi(kmer_size as i32) / 2.0;
+    for g in 0..=kmer_size {
+        if g > 0 {
+            nb_kmers *= (kmer_size - g + 1) as f64 / g as f64;
+        }
+        let absent = terms(-expected_a[g], -expected_b[g]);
+        for (sum, term) in sums.iter_mut().zip(absent) {
+            *sum += nb_kmers * term;
+        }
+    }
+    // then corrected for the k-mers actually present
+    for (kmer, a, b) in profile_merge(query.0, reference.0) {
+        let g = crate::sketch::gc_count(kmer, kmer_size);
+        let present = terms(a - expected_a[g], b - expected_b[g]);
+        let absent = terms(-expected_a[g], -expected_b[g]);
+        for i in 0..3 {
+            sums[i] += present[i] - absent[i];
+        }
+    }
+    let norm = (sums[1] * sums[2]).sqrt();
+    if norm <= 0.0 {
+        return 0.5;
+    }
+    ((1.0 - sums[0] / norm) / 2.0).clamp(0.0, 1.0)
+}
+
+// Sizes of the intersection and union of two sorted hash sets
+fn scaled_counts(query: &[u64], reference: &[u64]) -> (usize, usize) {
+    let (mut i, mut j, mut common) = (0, 0, 0usize);
+    while i < query.len() && j < reference.len() {
+        match query[i].cmp(&reference[j]) {
+            std::cmp::Ordering::Less => i += 1,
+            std::cmp::Ordering::Greater => j += 1,
+            std::cmp::Ordering::Equal => {
+                common += 1;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    (common, query.len() + reference.len() - common)
+}
+
+/// Mash-like distance from the fraction of differing registers.
+pub fn hamming_to_distance(hamming_distance: f32, kmer_size: usize) -> f64 {
+    let hamming_distance = if hamming_distance == 0.0 {
+        f32::EPSILON // Use a small value close to zero
+    } else {
+        hamming_distance
+    };
+    let jaccard = 1.0 - hamming_distance;
+    let numerator = 2.0 * jaccard;
+    let denominator = 1.0 + jaccard;
+    let fraction = (numerator as f64) / (denominator as f64);
+    -fraction.ln() / (kmer_size as f64)
+}
+
+// share of the L2 cache taken by the sketches of a tile, a block of rows and a block of columns
+const TILE_CACHE_BYTES: usize = 1 << 20;
+// tile side when the size of the compared items is unknown
+pub(crate) const DEFAULT_TILE: usize = 64;
+
+/// Side of the square tiles of [compute_matrix_tiled] for `sketches`, their row and column blocks fitting in L2 cache.
+pub fn sketch_tile(sketches: &HashMap<String, Signature>) -> usize {
+    let sketch_bytes = sketches.values().map(|s| s.size_in_bytes()).max().unwrap_or(0).max(1);
+    (TILE_CACHE_BYTES / (2 * sketch_bytes)).clamp(4, 256)
+}
+
+/// Computes the matrix `d(i, j) = dist_fn(i, j)` over the labelled items.
+/// For a symmetric `dist_fn` only the upper triangle is evaluated.
+/// The diagonal is left at 0 unless `diagonal` is set.
+pub fn compute_matrix<F>(labels: Vec<String>, dist_fn: F, symmetric: bool, diagonal: bool) -> DistMatrix
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    compute_matrix_tiled(labels, dist_fn, symmetric, diagonal, DEFAULT_TILE)
+}
+
+/// [compute_matrix] evaluated tile by tile, each thread comparing a block of `tile` rows
+/// to a block of `tile` columns so that their items stay in cache across the block.
+pub fn compute_matrix_tiled<F>(labels: Vec<String>, dist_fn: F, symmetric: bool, diagonal: bool, tile: usize) -> DistMatrix
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    fill_matrix(DistMatrix::new(labels, symmetric), dist_fn, diagonal, tile)
+}
+
+// tiles computed before their values are stored, bounding the memory of pending values
+const TILES_PER_BATCH: usize = 1024;
+
+/// Fills an empty `matrix`, in memory or mapped, as [compute_matrix_tiled] does.
+/// Tiles are computed in batches of row blocks, so pending values take a few rows, not a second matrix.
+pub fn fill_matrix<F>(matrix: DistMatrix, dist_fn: F, diagonal: bool, tile: usize) -> DistMatrix
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+{
+    let n = matrix.len();
+    fill_matrix_rows(matrix, dist_fn, diagonal, tile, 0..n, |_, _| {})
+}
+
+// [fill_matrix] restricted to `rows`, the other rows being left as they are,
+// calling `on_rows` with the rows completed by each batch
+pub(crate) fn fill_matrix_rows<F, R>(
+    mut matrix: DistMatrix,
+    dist_fn: F,
+    diagonal: bool,
+    tile: usize,
+    rows: Range<usize>,
+    mut on_rows: R,
+) -> DistMatrix
+where
+    F: Fn(usize, usize) -> f64 + Sync,
+    R: FnMut(&DistMatrix, Range<usize>),
+{
+    let n = matrix.len();
+    let symmetric = matrix.is_symmetric();
+    let tile = tile.max(1);
+    let rows = rows.start..rows.end.min(n);
+    let nb_blocks = n.div_ceil(tile);
+    let row_range = |first_block: usize, end_block: usize| {
+        (first_block * tile).max(rows.start)..(end_block * tile).min(rows.end)
+    };
+    // pairs of a tile, in row-major order
+    let pairs = |(bi, bj): (usize, usize)| {
+        let cols = bj * tile..((bj + 1) * tile).min(n);
+        row_range(bi, bi + 1).flat_map(move |i| {
+            cols.clone().filter(move |&j| if symmetric { j > i } else { j != i }).map(move |j| (i, j))
+        })
+    };
+    let dist_fn = &dist_fn;
+    let mut first_block = rows.start / tile;
+    while first_block < nb_blocks && first_block * tile < rows.end {
+        let mut tiles: Vec<(usize, usize)> = Vec::new();
+        let mut bi = first_block;
+        while bi < nb_blocks && bi * tile < rows.end && tiles.len() < TILES_PER_BATCH {
+            let first = if symmetric { bi } else { 0 };
+            tiles.extend((first..nb_blocks).map(|bj| (bi, bj)));
+            bi += 1;
+        }
+        let distances: Vec<Vec<f64>> = tiles
+            .par_iter()
+            .map(|&block| pairs(block).map(|(i, j)| dist_fn(i, j)).collect())
+            .collect();
+        for (&block, values) in tiles.iter().zip(distances.iter()) {
+            for ((i, j), &dist) in pairs(block).zip(values.iter()) {
+                matrix.set(i, j, dist);
+            }
+        }
+        if diagonal {
+            for i in row_range(first_block, bi) {
+                matrix.set(i, i, dist_fn(i, i));
+            }
+        }
+        on_rows(&matrix, row_range(first_block, bi));
+        first_block = bi;
+    }
+    matrix
+}
+
+// Empty matrix of the genomes, asymmetric for a directional distance model, mapped to the matrix
+// file of `config` if any
+fn empty_distance_matrix(config: &PipelineConfig, genomes: &[String]) -> Result<DistMatrix, String> {
+    let labels = genomes.iter().map(|g| genome_label(g)).collect();
+    let symmetric = !config.distance_model.is_directional();
+    match config.matrix_file.as_ref() {
+        Some(path) => DistMatrix::new_mapped(labels, symmetric, path),
+        None => Ok(DistMatrix::new(labels, symmetric)),
+    }
+}
+
+/// Label of a genome in outputs: its file name.
+pub fn genome_label(path: &str) -> String {
+    Path::new(path)
+        .file_name()
+        .and_then(|os_str| os_str.to_str())
+        .unwrap_or(path)
+        .to_string()
+}
+
+/// `genomes` with the files listed more than once kept at their first occurrence only, or an error
+/// when different files share a label (file name), as their rows and tree leaves could not be told
+/// apart.
+pub fn unique_genomes(genomes: &[String]) -> Result<Vec<String>, String> {
+    let mut seen: HashMap<String, &String> = HashMap::new();
+    let mut unique = Vec::with_capacity(genomes.len());
+    for genome in genomes {
+        match seen.get(&genome_label(genome)) {
+            None => {
+                seen.insert(genome_label(genome), genome);
+                unique.push(genome.clone());
+            }
+            Some(&first) if same_file(first, genome) => log::warn!("{} listed again as {}, kept once", first, genome),
+            Some(&first) => {
+                return Err(format!(
+                    "{} and {} are both labelled {}, rename one of them so that genome file names are unique",
+                    first,
+                    genome,
+                    genome_label(genome)
+                ))
+            }
+        }
+    }
+    Ok(unique)
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    a == b || matches!((std::fs::canonicalize(a), std::fs::canonicalize(b)), (Ok(a), Ok(b)) if a == b)
+}
+
+/// Asymmetric matrix of the containments `C(i, j)` of genome `i` in genome `j`, see [sketch_containment].
+/// `sizes` are the numbers of k-mers of the genomes.
+pub fn build_containment_matrix(sketches: &HashMap<String, Signature>, genomes: &[String], sizes: &[usize]) -> DistMatrix {
+    let signatures: Vec<&Signature> = genomes.iter().map(|g| &sketches[g]).collect();
+    let containment_fn = |i: usize, j: usize| sketch_containment(signatures[i], signatures[j], sizes[i], sizes[j]);
+    let labels = genomes.iter().map(|g| genome_label(g)).collect();
+    compute_matrix_tiled(labels, containment_fn, false, true, sketch_tile(sketches))
+}
+
+/// Distance between two sketches under the model of `config`, capped at its maximum distance,
+/// or `None` when they share fewer hashes than its minimum overlap.
+/// With `weights`, the distance comes from the weighted Jaccard index, see [crate::weighting].
+pub fn pair_distance(
+    config: &PipelineConfig,
+    weights: Option<&IdfWeights>,
+    query: &Signature,
+    reference: &Signature,
+) -> Option<f64> {
+    uncapped_distance(config, weights, query, reference).map(|distance| cap_distance(config, distance))
+}
+
+// [pair_distance] before capping, possibly NaN or infinite
+fn uncapped_distance(config: &PipelineConfig, weights: Option<&IdfWeights>, query: &Signature, reference: &Signature) -> Option<f64> {
+    if config.min_shared > 0 {
+        let (jaccard, sketch_size) = sketch_jaccard(query, reference);
+        if ((jaccard * sketch_size as f64).round() as usize) < config.min_shared {
+            return None;
+        }
+    }
+    let distance = match (config.distance_kind, query, reference) {
+        (DistanceKind::Cosine, Signature::Profile { counts: a, .. }, Signature::Profile { counts: b, .. }) => {
+            cosine_distance(a, b)
+        }
+        (DistanceKind::D2S, Signature::Profile { counts: a, gc: gc_a }, Signature::Profile { counts: b, gc: gc_b }) => {
+            d2s_distance((a, *gc_a), (b, *gc_b), config.kmer_size)
+        }
+        _ => match weights {
+            Some(weights) => {
+                jaccard_to_distance(config.distance_model, weights.jaccard(query, reference), config.kmer_size)
+            }
+            None => model_distance(config.distance_model, query, reference, config.kmer_size),
+        },
+    };
+    Some(distance)
+}
+
+// Distance of the pair (i, j) of `query` and `reference`, see [pair_distance], the maximum
+// distance without enough shared hashes, the pair being recorded in `non_finite` when its distance
+// was NaN or infinite
+pub(crate) fn checked_distance(
+    config: &PipelineConfig,
+    weights: Option<&IdfWeights>,
+    (query, reference): (&Signature, &Signature),
+    (i, j): (usize, usize),
+    non_finite: &Mutex<Vec<(usize, usize)>>,
+) -> f64 {
+    match uncapped_distance(config, weights, query, reference) {
+        Some(distance) => {
+            if !distance.is_finite() {
+                non_finite.lock().unwrap().push((i, j));
+            }
+            cap_distance(config, distance)
+        }
+        None => config.max_dist,
+    }
+}
+
+// Warns of the pairs of `rows` and `columns` genomes whose distance was NaN or infinite, each once
+pub(crate) fn report_non_finite(
+    config: &PipelineConfig,
+    rows: &[String],
+    columns: &[String],
+    non_finite: Mutex<Vec<(usize, usize)>>,
+) {
+    let mut pairs = non_finite.into_inner().unwrap();
+    if pairs.is_empty() {
+        return;
+    }
+    pairs.sort_unstable();
+    // a pair may be compared more than once, e.g. while dereplicating
+    pairs.dedup();
+    let shown: Vec<String> =
+        pairs.iter().take(10).map(|&(i, j)| format!("{} - {}", genome_label(&rows[i]), genome_label(&columns[j]))).collect();
+    log::warn!(
+        "{} NaN or infinite distances replaced by {}: {}{}",
+        pairs.len(),
+        non_finite_distance(config),
+        shown.join(", "),
+        if pairs.len() > shown.len() { ", ..." } else { "" }
+    );
+}
+
+/// IDF weights of `genomes` when `config` asks for them, an error when their sketches cannot be weighted.
+pub fn idf_weights(
+    config: &PipelineConfig,
+    sketches: &HashMap<String, Signature>,
+    genomes: &[String],
+) -> Result<Option<IdfWeights>, String> {
+    match config.weighting {
+        Weighting::None => Ok(None),
+        Weighting::Idf => IdfWeights::new(sketches, genomes).map(Some),
+    }
+}
+
+/// Checks that `signatures` are of the kind `config` sketches, so that they can be compared with
+/// each other, and names the flag choosing that kind otherwise.
+pub fn check_signatures(config: &PipelineConfig, signatures: &[&Signature]) -> Result<(), String> {
+    let (expected, flag) = if config.distance_kind != DistanceKind::MinHash {
+        ("composition profile", "--distance")
+    } else if config.scaled > 0 {
+        ("FracMinHash", "--scaled")
+    } else if config.bbits > 0 {
+        ("b-bit", "--bbits")
+    } else if config.algo == SketchAlgo::SetSketch {
+        ("SetSketch", "--algo")
+    } else {
+        ("register", "--algo")
+    };
+    for signature in signatures {
+        let matches = match signature {
+            Signature::BBit { bits, .. } => expected == "b-bit" && *bits == config.bbits,
+            _ => signature.kind() == expected,
+        };
+        if !matches {
+            return Err(format!(
+                "Cannot compare {} sketches with the {} sketches set by {}",
+                signature.kind(),
+                expected,
+                flag
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// `distance` capped at the maximum distance of `config`, if any. A NaN or infinite distance, e.g.
+/// of sketches sharing no hash, is replaced by [non_finite_distance].
+pub fn cap_distance(config: &PipelineConfig, distance: f64) -> f64 {
+    if !distance.is_finite() {
+        non_finite_distance(config)
+    } else if config.max_dist > 0.0 {
+        distance.min(config.max_dist)
+    } else {
+        distance
+    }
+}
+
+/// Distance given to pairs whose distance is NaN or infinite: the maximum distance of `config`, or
+/// without one the largest finite distance of its sketches, see [saturated_distance], or of
+/// composition profiles.
+pub fn non_finite_distance(config: &PipelineConfig) -> f64 {
+    if config.max_dist > 0.0 {
+        config.max_dist
+    } else if config.distance_kind == DistanceKind::MinHash {
+        saturated_distance(config.sketch_size, config.kmer_size)
+    } else {
+        1.0
+    }
+}
+
+/// Computes all pairwise distances and returns them as a matrix in the order of `genomes`, see [pair_distance],
+/// in memory or mapped to the matrix file of `config`.
+/// Pairs without enough shared hashes get the maximum distance.
+/// With a prescreen, pairs whose fingerprints share nothing get the saturated distance, see [crate::prescreen].
+/// NaN or infinite distances are replaced, see [cap_distance], and their pairs reported in a warning.
+pub fn build_distance_matrix(
+    config: &PipelineConfig,
+    sketches: &HashMap<String, Signature>,
+    genomes: &[String],
+) -> Result<DistMatrix, String> {
+    fill_distance_matrix(config, None, sketches, genomes)
+}
+
+/// [build_distance_matrix] of genomes appended to those of `previous`, the matrix of an earlier run:
+/// the distances between genomes labelled in `previous` are copied from it, only those involving a
+/// new genome are computed.
+pub fn update_distance_matrix(
+    config: &PipelineConfig,
+    previous: &DistMatrix,
+    sketches: &HashMap<String, Signature>,
+    genomes: &[String],
+) -> Result<DistMatrix, String> {
+    fill_distance_matrix(config, Some(previous), sketches, genomes)
+}
+
+fn fill_distance_matrix(
+    config: &PipelineConfig,
+    previous: Option<&DistMatrix>,
+    sketches: &HashMap<String, Signature>,
+    genomes: &[String],
+) -> Result<DistMatrix, String> {
+    // positions of the genomes in the previous matrix
+    let known: Vec<Option<usize>> = match previous {
+        Some(previous) => {
+            let positions: HashMap<&str, usize> = previous.labels().iter().enumerate().map(|(i, label)| (label.as_str(), i)).collect();
+            genomes.iter().map(|g| positions.get(genome_label(g).as_str()).copied()).collect()
+        }
+        None => vec![None; genomes.len()],
+    };
+    if previous.is_some() {
+        let nb_known = known.iter().flatten().count();
+        log::info!("{} of {} genomes in the previous matrix, {} new", nb_known, genomes.len(), genomes.len() - nb_known);
+    }
+    // looked up once, not per pair
+    let signatures: Vec<&Signature> = genomes.iter().map(|g| &sketches[g]).collect();
+    check_signatures(config, &signatures)?;
+    let prescreen = Prescreen::new(config, sketches, genomes);
+    let weights = idf_weights(config, sketches, genomes)?;
+    #[cfg(feature = "cuda")]
+    if config.gpu && previous.is_none() {
+        if let Some(matrix) = gpu_distance_matrix(config, &signatures, genomes, &prescreen) {
+            return Ok(matrix);
+        }
+    }
+    let non_finite = Mutex::new(Vec::new());
+    let dist_fn = |i: usize, j: usize| {
+        if let (Some(a), Some(b), Some(previous)) = (known[i], known[j], previous) {
+            return previous.get(a, b);
+        }
+        if i != j {
+            if let Some(saturated) = prescreen.skip(i, j) {
+                return cap_distance(config, saturated);
+            }
+        }
+        checked_distance(config, weights.as_ref(), (signatures[i], signatures[j]), (i, j), &non_finite)
+    };
+    let mut matrix = empty_distance_matrix(config, genomes)?;
+    let tile = sketch_tile(sketches);
+    // a shard only fills its rows
+    let rows = config.shard.map_or(0..genomes.len(), |shard| shard.rows(genomes.len()));
+    let path = match config.checkpoint.as_ref() {
+        Some(path) => path,
+        None => {
+            let matrix = fill_matrix_rows(matrix, dist_fn, config.diagonal, tile, rows, |_, _| {});
+            report_non_finite(config, genomes, genomes, non_finite);
+            return Ok(matrix);
+        }
+    };
+    let mut checkpoint = Checkpoint::open(path, &mut matrix, rows.start, fingerprint(config, genomes), config.resume)?;
+    let rows = checkpoint.rows().end..rows.end;
+    let mut checkpoint_error = None;
+    let matrix = fill_matrix_rows(matrix, dist_fn, config.diagonal, tile, rows, |matrix, rows| {
+        if checkpoint_error.is_some() {
+            return;
+        }
+        match checkpoint.append(matrix, rows.clone()) {
+            Ok(()) => log::info!("checkpoint at row {} of {}", rows.end, matrix.len()),
+            Err(e) => checkpoint_error = Some(format!("Error writing checkpoint {}: {}", path.display(), e)),
+        }
+    });
+    if let Some(e) = checkpoint_error {
+        return Err(e);
+    }
+    report_non_finite(config, genomes, genomes, non_finite);
+    // a finished matrix needs no checkpoint
+    std::fs::remove_file(path).map_err(|e| format!("Cannot remove checkpoint file {}: {}", path.display(), e))?;
+    Ok(matrix)
+}
+
+/// Distance of two register sketches from their number of equal registers out of `nb_registers`,
+/// the same as [pair_distance] without weighting.
+pub fn register_distance(config: &PipelineConfig, nb_equal: usize, nb_registers: usize) -> Option<f64> {
+    uncapped_register_distance(config, nb_equal, nb_registers).map(|distance| cap_distance(config, distance))
+}
+
+fn uncapped_register_distance(config: &PipelineConfig, nb_equal: usize, nb_registers: usize) -> Option<f64> {
+    let jaccard = nb_equal as f64 / nb_registers as f64;
+    if config.min_shared > 0 && ((jaccard * nb_registers as f64).round() as usize) < config.min_shared {
+        return None;
+    }
+    let distance = match config.distance_model {
+        DistanceModel::BinDash => {
+            hamming_to_distance(((nb_registers - nb_equal) as f64 / nb_registers as f64) as f32, config.kmer_size)
+        }
+        DistanceModel::Mash => mash_distance(jaccard, config.kmer_size),
+        DistanceModel::Containment => f64::NAN,
+    };
+    Some(distance)
+}
+
+// The distance matrix from equal register counts computed on GPU, None when no GPU can be used
+#[cfg(feature = "cuda")]
+fn gpu_distance_matrix(
+    config: &PipelineConfig,
+    signatures: &[&Signature],
+    genomes: &[String],
+    prescreen: &Prescreen,
+) -> Option<DistMatrix> {
+    let registers: Vec<&[f32]> = signatures
+        .iter()
+        .map(|s| match s {
+            Signature::Registers(registers) => Some(registers.as_slice()),
+            _ => None,
+        })
+        .collect::<Option<_>>()
+        .or_else(|| {
+            log::warn!("--gpu compares register sketches only, distances are computed on CPU");
+            None
+        })?;
+    let gpu = crate::gpu::GpuRegisters::new(&registers)?;
+    let n = genomes.len();
+    let nb_registers = registers.first().map_or(0, |r| r.len());
+    let mut matrix = empty_distance_matrix(config, genomes).ok()?;
+    let step = gpu.rows_per_launch();
+    let mut non_finite = Vec::new();
+    for first in (0..n).step_by(step) {
+        let rows = first..(first + step).min(n);
+        let counts = match gpu.count_equal(rows.clone()) {
+            Ok(counts) => counts,
+            Err(e) => {
+                log::warn!("GPU comparison failed ({}), distances are computed on CPU", e);
+                return None;
+            }
+        };
+        for i in rows {
+            let start = if config.diagonal { i } else { i + 1 };
+            for j in start..n {
+                let saturated = if i != j { prescreen.skip(i, j) } else { None };
+                let dist = match saturated {
+                    Some(saturated) => cap_distance(config, saturated),
+                    None => match uncapped_register_distance(config, counts[(i - first) * n + j] as usize, nb_registers) {
+                        Some(distance) => {
+                            if !distance.is_finite() {
+                                non_finite.push((i, j));
+                            }
+                            cap_distance(config, distance)
+                        }
+                        None => config.max_dist,
+                    },
+                };
+                matrix.set(i, j, dist);
+            }
+        }
+    }
+    report_non_finite(config, genomes, genomes, Mutex::new(non_finite));
+    Some(matrix)
+}
+
+/// Distances from each query genome to each reference genome, see [pair_distance].
+/// Pairs without enough shared hashes get the maximum distance. IDF weights come from the references.
+/// NaN or infinite distances are replaced and reported as by [build_distance_matrix].
+pub fn build_query_matrix(
+    config: &PipelineConfig,
+    query_sketches: &HashMap<String, Signature>,
+    queries: &[String],
+    reference_sketches: &HashMap<String, Signature>,
+    references: &[String],
+) -> Result<QueryMatrix, String> {
+    let signatures: Vec<&Signature> =
+        queries.iter().map(|q| &query_sketches[q]).chain(references.iter().map(|r| &reference_sketches[r])).collect();
+    check_signatures(config, &signatures)?;
+    let weights = idf_weights(config, reference_sketches, references)?;
+    let non_finite = Mutex::new(Vec::new());
+    let rows: Vec<Vec<f64>> = queries
+        .par_iter()
+        .enumerate()
+        .map(|(i, query)| {
+            references
+                .iter()
+                .enumerate()
+                .map(|(j, reference)| {
+                    let pair = (&query_sketches[query], &reference_sketches[reference]);
+                    checked_distance(config, weights.as_ref(), pair, (i, j), &non_finite)
+                })
+                .collect()
+        })
+        .collect();
+    report_non_finite(config, queries, references, non_finite);
+    let mut matrix = QueryMatrix::new(
+        queries.iter().map(|g| genome_label(g)).collect(),
+        references.iter().map(|g| genome_label(g)).collect(),
+    );
+    for (i, row) in rows.iter().enumerate() {
+        for (j, &dist) in row.iter().enumerate() {
+            matrix.set(i, j, dist);
+        }
+    }
+    Ok(matrix)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sketch::{mix64, to_bbit};
+
+    // Registers of two sketches equal with probability `equal`, as a fraction of 4096
+    fn register_pair(equal: u64, seed: u64) -> (Vec<f32>, Vec<f32>) {
+        (0..4096u64)
+            .map(|k| {
+                let a = (mix64(seed ^ k) >> 40) as f32;
+                let b = if mix64(seed.wrapping_add(k)) % 4096 < equal { a } else { (mix64(!seed ^ k) >> 40) as f32 };
+                (a, b)
+            })
+            .unzip()
+    }
+
+    #[test]
+    fn bbit_matches_full_registers() {
+        for (equal, seed) in [(4096, 1), (3000, 2), (1200, 3), (0, 4)] {
+            let (query, reference) = register_pair(equal, seed);
+            let full = count_equal_f32(&query, &reference) as f64 / query.len() as f64;
+            for bits in [1, 2, 4, 8, 16, 32] {
+                let (Signature::BBit { words: a, .. }, Signature::BBit { words: b, .. }) =
+                    (to_bbit(&query, bits), to_bbit(&reference, bits))
+                else {
+                    unreachable!()
+                };
+                let jaccard = bbit_jaccard(&a, &b, bits, query.len());
+                // chance matches are corrected, leaving the sampling error of fewer informative bits
+                let tolerance = if bits < 8 { 0.05 } else { 0.005 };
+                assert!((jaccard - full).abs() < tolerance, "{} bits: {} instead of {}", bits, jaccard, full);
+            }
+        }
+    }
+
+    #[test]
+    fn mash_pvalue_limits() {
+        // nothing shared is always possible
+        assert_eq!(mash_pvalue(0, 1000, 21, 5_000_000, 5_000_000), 1.0);
+        // genomes holding nearly all the 4^4 k-mers share almost any hash
+        assert!(mash_pvalue(990, 1000, 4, 10_000_000, 10_000_000) > 0.99);
+        // all of 1000 hashes shared by chance between bacterial genomes at k = 21
+        assert_eq!(mash_pvalue(1000, 1000, 21, 5_000_000, 5_000_000), 0.0);
+        let mut previous = 1.0;
+        for shared in 1..=20 {
+            let pvalue = mash_pvalue(shared, 20, 8, 30_000, 30_000);
+            assert!((0.0..=previous).contains(&pvalue));
+            previous = pvalue;
+        }
+        // binomial tail of 2 or more out of 3 for the probability of a k-mer in both genomes
+        let in_a = 1.0 / (1.0 + 4f64.powi(8) / 30_000.0);
+        let in_both = 1.0 / (2.0 / in_a - 1.0);
+        let expected = 3.0 * in_both * in_both * (1.0 - in_both) + in_both.powi(3);
+        assert!((mash_pvalue(2, 3, 8, 30_000, 30_000) - expected).abs() < 1e-12);
+    }
+}
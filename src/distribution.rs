@@ -0,0 +1,206 @@
+//! Distribution of the pairwise distances: summary statistics and histogram, to choose clustering
+//! thresholds and spot saturation (many pairs at the largest distance) before looking at the tree.
+//!
+//! Quantiles are exact without sorting all the pairs: the range of distances holding a rank is
+//! narrowed down to one of its fine bins, by counting the pairs in each, until its pairs are few
+//! enough to be sorted.
+
+use rayon::prelude::*;
+use std::io::Write;
+
+use crate::matrix::DistMatrix;
+
+const QUANTILES: [f64; 5] = [0.05, 0.25, 0.5, 0.75, 0.95];
+const FINE_BINS: usize = 1 << 16;
+// pairs of a range sorted instead of binned
+const SORTED_PAIRS: usize = 1 << 20;
+
+/// Summary of the distances of all the pairs of genomes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DistanceSummary {
+    pub nb_pairs: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+    /// probability and value of the 5, 25, 50, 75 and 95% quantiles, linearly interpolated
+    pub quantiles: Vec<(f64, f64)>,
+    /// pairs at the largest distance
+    pub nb_at_max: usize,
+}
+
+impl std::fmt::Display for DistanceSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Distances over {} pairs: min {}", self.nb_pairs, self.min)?;
+        for &(p, value) in &self.quantiles {
+            if p == 0.5 {
+                write!(f, ", median {}", value)?;
+            } else {
+                write!(f, ", {}% {}", p * 100.0, value)?;
+            }
+        }
+        write!(f, ", max {} (pairs at max: {}), mean {}", self.max, self.nb_at_max, self.mean)
+    }
+}
+
+// distances of the pairs (i, j), i < j, by row
+fn row_pairs(matrix: &DistMatrix, i: usize) -> impl Iterator<Item = f64> + '_ {
+    (i + 1..matrix.len()).map(move |j| matrix.get(i, j))
+}
+
+/// Summary of the distances of the pairs of genomes of the symmetric `matrix`, None without pairs.
+pub fn distance_summary(matrix: &DistMatrix) -> Option<DistanceSummary> {
+    let n = matrix.len();
+    let nb_pairs = n * n.saturating_sub(1) / 2;
+    if nb_pairs == 0 {
+        return None;
+    }
+    let (min, max, sum) = (0..n)
+        .into_par_iter()
+        .map(|i| row_pairs(matrix, i).fold((f64::INFINITY, f64::NEG_INFINITY, 0.0), |(lo, hi, s), d| (lo.min(d), hi.max(d), s + d)))
+        .reduce(|| (f64::INFINITY, f64::NEG_INFINITY, 0.0), |a, b| (a.0.min(b.0), a.1.max(b.1), a.2 + b.2));
+    let nb_at_max = (0..n).into_par_iter().map(|i| row_pairs(matrix, i).filter(|&d| d == max).count()).sum();
+    // ranks of the pairs the quantiles interpolate between
+    let ranks: Vec<usize> = QUANTILES
+        .iter()
+        .flat_map(|&p| {
+            let position = p * (nb_pairs - 1) as f64;
+            [position.floor() as usize, (position.ceil() as usize).min(nb_pairs - 1)]
+        })
+        .collect();
+    let values = select(matrix, &ranks, Range { lo: min, hi: max, nb_pairs, rank: 0 });
+    let quantiles = QUANTILES
+        .iter()
+        .zip(ranks.chunks(2).zip(values.chunks(2)))
+        .map(|(&p, (ranks, values))| (p, values[0] + (p * (nb_pairs - 1) as f64 - ranks[0] as f64) * (values[1] - values[0])))
+        .collect();
+    Some(DistanceSummary { nb_pairs, min, max, mean: sum / nb_pairs as f64, quantiles, nb_at_max })
+}
+
+// Distances in [lo, hi], `nb_pairs` of them, preceded by `rank` others
+#[derive(Debug, Clone, Copy)]
+struct Range {
+    lo: f64,
+    hi: f64,
+    nb_pairs: usize,
+    rank: usize,
+}
+
+// Counts, smallest and largest distances of the fine bins of a range, or its sorted distances
+enum Bins {
+    Counts(Vec<usize>, Vec<f64>, Vec<f64>),
+    Sorted(Vec<f64>),
+}
+
+// Distances of the pairs of the given ranks (in increasing order of distance), all in `all`
+fn select(matrix: &DistMatrix, ranks: &[usize], all: Range) -> Vec<f64> {
+    let n = matrix.len();
+    let mut ranges = vec![all; ranks.len()];
+    let mut values: Vec<Option<f64>> = vec![None; ranks.len()];
+    loop {
+        for (value, range) in values.iter_mut().zip(&ranges) {
+            if value.is_none() && range.lo == range.hi {
+                *value = Some(range.lo);
+            }
+        }
+        let open: Vec<usize> = (0..ranks.len()).filter(|&k| values[k].is_none()).collect();
+        if open.is_empty() {
+            return values.into_iter().map(Option::unwrap).collect();
+        }
+        let empty = || -> Vec<Bins> {
+            open.iter()
+                .map(|&k| {
+                    if ranges[k].nb_pairs <= SORTED_PAIRS {
+                        Bins::Sorted(Vec::new())
+                    } else {
+                        Bins::Counts(vec![0; FINE_BINS], vec![f64::INFINITY; FINE_BINS], vec![f64::NEG_INFINITY; FINE_BINS])
+                    }
+                })
+                .collect()
+        };
+        let bins = (0..n)
+            .into_par_iter()
+            .fold(empty, |mut bins, i| {
+                for d in row_pairs(matrix, i) {
+                    for (b, &k) in bins.iter_mut().zip(&open) {
+                        let Range { lo, hi, .. } = ranges[k];
+                        if d < lo || d > hi {
+                            continue;
+                        }
+                        match b {
+                            Bins::Sorted(v) => v.push(d),
+                            Bins::Counts(counts, mins, maxs) => {
+                                let f = (((d - lo) / (hi - lo) * FINE_BINS as f64) as usize).min(FINE_BINS - 1);
+                                counts[f] += 1;
+                                mins[f] = mins[f].min(d);
+                                maxs[f] = maxs[f].max(d);
+                            }
+                        }
+                    }
+                }
+                bins
+            })
+            .reduce(empty, |a, b| {
+                a.into_iter()
+                    .zip(b)
+                    .map(|pair| match pair {
+                        (Bins::Sorted(mut x), Bins::Sorted(y)) => {
+                            x.extend(y);
+                            Bins::Sorted(x)
+                        }
+                        (Bins::Counts(c1, lo1, hi1), Bins::Counts(c2, lo2, hi2)) => Bins::Counts(
+                            c1.iter().zip(&c2).map(|(x, y)| x + y).collect(),
+                            lo1.iter().zip(&lo2).map(|(x, y)| x.min(*y)).collect(),
+                            hi1.iter().zip(&hi2).map(|(x, y)| x.max(*y)).collect(),
+                        ),
+                        _ => unreachable!(),
+                    })
+                    .collect()
+            });
+        for (b, &k) in bins.into_iter().zip(&open) {
+            let range = ranges[k];
+            match b {
+                Bins::Sorted(mut v) => {
+                    v.sort_by(f64::total_cmp);
+                    values[k] = Some(v[ranks[k] - range.rank]);
+                }
+                // the range narrowed down to the bin of the rank, whose distances are all larger
+                // than those of the bins before it
+                Bins::Counts(counts, mins, maxs) => {
+                    let mut rank = range.rank;
+                    for f in 0..FINE_BINS {
+                        if rank + counts[f] > ranks[k] {
+                            ranges[k] = Range { lo: mins[f], hi: maxs[f], nb_pairs: counts[f], rank };
+                            break;
+                        }
+                        rank += counts[f];
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Writes the histogram of the distances of the pairs of genomes of the symmetric `matrix` in
+/// `bins` bins of equal width from 0 to the largest distance, tab separated bin bounds and count.
+pub fn write_histogram<W: Write>(writer: &mut W, matrix: &DistMatrix, bins: usize) -> std::io::Result<()> {
+    let n = matrix.len();
+    let max = (0..n).into_par_iter().map(|i| row_pairs(matrix, i).fold(0.0, f64::max)).reduce(|| 0.0, f64::max);
+    let width = if max > 0.0 { max / bins as f64 } else { 1.0 };
+    let counts = (0..n)
+        .into_par_iter()
+        .fold(
+            || vec![0usize; bins],
+            |mut counts, i| {
+                for d in row_pairs(matrix, i) {
+                    counts[((d.max(0.0) / width) as usize).min(bins - 1)] += 1;
+                }
+                counts
+            },
+        )
+        .reduce(|| vec![0usize; bins], |a, b| a.iter().zip(&b).map(|(x, y)| x + y).collect());
+    writeln!(writer, "bin_start\tbin_end\tcount")?;
+    for (b, count) in counts.iter().enumerate() {
+        writeln!(writer, "{}\t{}\t{}", b as f64 * width, (b + 1) as f64 * width, count)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,58 @@
+//! Errors ending a command line run, with an exit code per kind so that scripts can tell invalid
+//! parameters from unusable inputs and from failures during the run.
+//!
+//! The library reports its errors as messages; they are given a kind, and the parameter or file
+//! they concern when the message does not name it already, by [Context].
+
+use std::fmt::Display;
+use thiserror::Error;
+
+/// Error of a run, its message naming the offending parameter or file.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// invalid or inconsistent command line parameters
+    #[error("{0}")]
+    Parameter(String),
+    /// missing, unreadable or malformed input files
+    #[error("{0}")]
+    Input(String),
+    /// failures while computing or writing the outputs
+    #[error("{0}")]
+    Runtime(String),
+}
+
+impl Error {
+    /// Exit code of the process: 2 for parameters, as for the usage errors of clap, 3 for inputs
+    /// and 1 for the other errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Runtime(_) => 1,
+            Error::Parameter(_) => 2,
+            Error::Input(_) => 3,
+        }
+    }
+}
+
+/// Gives a kind and a context to the errors of a result.
+pub trait Context<T> {
+    /// Error of the parameter `name`, e.g. `--kmer`.
+    fn parameter(self, name: &str) -> Result<T, Error>;
+    /// Error of the input file `path`.
+    fn input(self, path: &str) -> Result<T, Error>;
+    /// Error of the run, `context` saying what failed.
+    fn runtime(self, context: impl Display) -> Result<T, Error>;
+}
+
+impl<T, E: Display> Context<T> for Result<T, E> {
+    fn parameter(self, name: &str) -> Result<T, Error> {
+        self.map_err(|e| Error::Parameter(format!("{}: {}", name, e)))
+    }
+
+    fn input(self, path: &str) -> Result<T, Error> {
+        self.map_err(|e| Error::Input(format!("{}: {}", path, e)))
+    }
+
+    fn runtime(self, context: impl Display) -> Result<T, Error> {
+        self.map_err(|e| Error::Runtime(format!("{}: {}", context, e)))
+    }
+}
@@ -0,0 +1,105 @@
+//! How well a tree represents the distances it was built from: cophenetic (patristic) distances,
+//! the sums of branch lengths between leaves, against the matrix distances.
+
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::io::Write;
+
+use crate::matrix::DistMatrix;
+use crate::phylo::Tree;
+
+/// Least-squares fit of the cophenetic distances of a tree to a distance matrix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeFit {
+    pub nb_pairs: usize,
+    /// residual sum of squares
+    pub rss: f64,
+    /// Pearson correlation of the matrix and cophenetic distances
+    pub cophenetic_correlation: f64,
+    /// average percent standard deviation of Fitch and Margoliash, over the pairs at a positive distance
+    pub percent_sd: f64,
+}
+
+/// Cophenetic distances between the leaves of the Newick tree `newick`, in the order of `labels`.
+pub fn cophenetic_matrix(newick: &str, labels: &[String]) -> Result<DistMatrix, String> {
+    let tree = Tree::from_newick(newick)?;
+    let positions: HashMap<&str, usize> = labels.iter().enumerate().map(|(i, label)| (label.as_str(), i)).collect();
+    let mut leaf_nodes = vec![usize::MAX; labels.len()];
+    for node in (0..tree.len()).filter(|&node| tree.node(node).children.is_empty()) {
+        let label = tree.node(node).label.as_deref().unwrap_or("");
+        let &i = positions.get(label).ok_or(format!("Unknown leaf in tree: {}", label))?;
+        leaf_nodes[i] = node;
+    }
+    if let Some(i) = leaf_nodes.iter().position(|&node| node == usize::MAX) {
+        return Err(format!("Missing leaf in tree: {}", labels[i]));
+    }
+    // branches as undirected edges, weighted by the length of the child
+    let mut edges: Vec<Vec<(usize, f64)>> = vec![Vec::new(); tree.len()];
+    for parent in 0..tree.len() {
+        for &child in &tree.node(parent).children {
+            let length = tree.node(child).length.unwrap_or(0.0);
+            edges[parent].push((child, length));
+            edges[child].push((parent, length));
+        }
+    }
+    let rows: Vec<Vec<f64>> = leaf_nodes
+        .par_iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let mut depth = vec![f64::NAN; tree.len()];
+            depth[start] = 0.0;
+            let mut stack = vec![start];
+            while let Some(node) = stack.pop() {
+                for &(next, length) in &edges[node] {
+                    if depth[next].is_nan() {
+                        depth[next] = depth[node] + length;
+                        stack.push(next);
+                    }
+                }
+            }
+            leaf_nodes[i + 1..].iter().map(|&node| depth[node]).collect()
+        })
+        .collect();
+    let mut cophenetic = DistMatrix::new(labels.to_vec(), true);
+    for (i, row) in rows.iter().enumerate() {
+        for (k, &d) in row.iter().enumerate() {
+            cophenetic.set(i, i + 1 + k, d);
+        }
+    }
+    Ok(cophenetic)
+}
+
+/// Fit of the `cophenetic` distances of a tree to the symmetric `matrix` it was built from.
+pub fn tree_fit(matrix: &DistMatrix, cophenetic: &DistMatrix) -> TreeFit {
+    let n = matrix.len();
+    let pairs = || (0..n).flat_map(move |i| (i + 1..n).map(move |j| (matrix.get(i, j), cophenetic.get(i, j))));
+    let nb_pairs = n * n.saturating_sub(1) / 2;
+    let rss = pairs().map(|(d, c)| (d - c) * (d - c)).sum();
+    let (sum_d, sum_c) = pairs().fold((0.0, 0.0), |(sd, sc), (d, c)| (sd + d, sc + c));
+    let (mean_d, mean_c) = (sum_d / nb_pairs.max(1) as f64, sum_c / nb_pairs.max(1) as f64);
+    let (mut covariance, mut var_d, mut var_c) = (0.0, 0.0, 0.0);
+    for (d, c) in pairs() {
+        covariance += (d - mean_d) * (c - mean_c);
+        var_d += (d - mean_d) * (d - mean_d);
+        var_c += (c - mean_c) * (c - mean_c);
+    }
+    let cophenetic_correlation = if var_d > 0.0 && var_c > 0.0 { covariance / (var_d * var_c).sqrt() } else { 0.0 };
+    let (sum_relative, nb_positive) =
+        pairs().filter(|&(d, _)| d > 0.0).fold((0.0, 0usize), |(sum, count), (d, c)| (sum + ((d - c) / d).powi(2), count + 1));
+    let percent_sd = if nb_positive > 0 { 100.0 * (sum_relative / nb_positive as f64).sqrt() } else { 0.0 };
+    TreeFit { nb_pairs, rss, cophenetic_correlation, percent_sd }
+}
+
+/// Writes one tab separated line per pair of leaves with its matrix and cophenetic distances and
+/// their difference.
+pub fn write_residuals<W: Write>(writer: &mut W, matrix: &DistMatrix, cophenetic: &DistMatrix) -> std::io::Result<()> {
+    writeln!(writer, "genome1\tgenome2\tdistance\tcophenetic\tresidual")?;
+    let labels = matrix.labels();
+    for i in 0..matrix.len() {
+        for j in i + 1..matrix.len() {
+            let (d, c) = (matrix.get(i, j), cophenetic.get(i, j));
+            writeln!(writer, "{}\t{}\t{}\t{}\t{}", labels[i], labels[j], d, c, d - c)?;
+        }
+    }
+    Ok(())
+}
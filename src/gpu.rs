@@ -0,0 +1,195 @@
+//! All-vs-all register comparison on a CUDA GPU, built with the `cuda` feature.
+//!
+//! The registers of all sketches are uploaded once, then each launch counts the equal registers
+//! of a block of rows against all sketches, one thread per (row, column) pair. The CUDA driver and
+//! NVRTC are loaded at run time, so the binary still runs, on CPU, on machines without them.
+
+use cudarc::driver::{CudaDevice, CudaFunction, CudaSlice, LaunchAsync, LaunchConfig};
+use cudarc::nvrtc::compile_ptx;
+use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+use std::ops::Range;
+use std::sync::Arc;
+
+const MODULE: &str = "bindashtree";
+const KERNEL: &str = "count_equal";
+// registers compared as floats, the same equality as on CPU
+const KERNEL_SOURCE: &str = r#"
+extern "C" __global__ void count_equal(const float* registers, unsigned int n, unsigned int m,
+                                       unsigned int first_row, unsigned int nb_rows, unsigned int* counts) {
+    unsigned int j = blockIdx.x * blockDim.x + threadIdx.x;
+    unsigned int r = blockIdx.y;
+    if (j >= n || r >= nb_rows) {
+        return;
+    }
+    const float* a = registers + (size_t)(first_row + r) * m;
+    const float* b = registers + (size_t)j * m;
+    unsigned int count = 0;
+    for (unsigned int k = 0; k < m; k++) {
+        count += (a[k] == b[k]);
+    }
+    counts[(size_t)r * n + j] = count;
+}
+"#;
+const THREADS_PER_BLOCK: u32 = 256;
+// counts kept on the device per launch
+const COUNTS_PER_LAUNCH: usize = 1 << 26;
+// limit of the second grid dimension
+const MAX_ROWS_PER_LAUNCH: usize = 65535;
+
+// CUDA version of the cudarc bindings (the cuda-12000 feature), in the NVRTC library names
+const CUDA_MAJOR: &str = "12";
+const CUDA_MINOR: &str = "0";
+
+// File names cudarc tries for the CUDA driver library
+fn driver_names() -> Vec<String> {
+    ["cuda", "nvcuda"].iter().map(|name| format!("{}{}{}", DLL_PREFIX, name, DLL_SUFFIX)).collect()
+}
+
+// File names cudarc tries for the NVRTC library
+fn nvrtc_names() -> Vec<String> {
+    let (name, width, major, minor) = ("nvrtc", usize::BITS, CUDA_MAJOR, CUDA_MINOR);
+    vec![
+        format!("{DLL_PREFIX}{name}{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{name}{width}{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{name}{width}_{major}{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{name}{width}_{major}{minor}{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{name}{width}_{major}{minor}_0{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{name}{width}_{major}0_{minor}{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{name}{width}_10{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{name}{width}_{major}0_0{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{name}{width}_9{DLL_SUFFIX}"),
+        format!("{DLL_PREFIX}{name}{DLL_SUFFIX}.{major}"),
+        format!("{DLL_PREFIX}{name}{DLL_SUFFIX}.11"),
+        format!("{DLL_PREFIX}{name}{DLL_SUFFIX}.10"),
+    ]
+}
+
+/// Whether the CUDA driver and NVRTC libraries can be loaded. cudarc panics on its first call when
+/// they cannot, so they are looked up first, under the same names, with its fallible loaders.
+pub fn libraries_available() -> bool {
+    // loading a library runs its initialization code, trusted as for cudarc itself
+    let driver = driver_names().iter().any(|name| unsafe { cudarc::driver::sys::Lib::new(name) }.is_ok());
+    driver && nvrtc_names().iter().any(|name| unsafe { cudarc::nvrtc::sys::Lib::new(name) }.is_ok())
+}
+
+/// Sketch registers uploaded to the first GPU.
+pub struct GpuRegisters {
+    device: Arc<CudaDevice>,
+    kernel: CudaFunction,
+    registers: CudaSlice<f32>,
+    nb_sketches: usize,
+    nb_registers: usize,
+}
+
+impl GpuRegisters {
+    /// Uploads the registers, `None` when no CUDA device, driver or NVRTC library is available.
+    pub fn new(sketches: &[&[f32]]) -> Option<Self> {
+        let nb_registers = sketches.first().map_or(0, |s| s.len());
+        if !libraries_available() {
+            log::warn!("no CUDA driver or NVRTC library, distances are computed on CPU");
+            return None;
+        }
+        let device = match CudaDevice::new(0) {
+            Ok(device) => device,
+            Err(e) => {
+                log::warn!("no usable CUDA device ({}), distances are computed on CPU", e);
+                return None;
+            }
+        };
+        let kernel = compile_ptx(KERNEL_SOURCE)
+            .map_err(|e| e.to_string())
+            .and_then(|ptx| device.load_ptx(ptx, MODULE, &[KERNEL]).map_err(|e| e.to_string()));
+        if let Err(e) = kernel {
+            log::warn!("cannot compile the GPU kernel ({}), distances are computed on CPU", e);
+            return None;
+        }
+        let kernel = device.get_func(MODULE, KERNEL)?;
+        let flat: Vec<f32> = sketches.iter().flat_map(|s| s.iter().copied()).collect();
+        let registers = match device.htod_copy(flat) {
+            Ok(registers) => registers,
+            Err(e) => {
+                log::warn!("cannot upload sketches to the GPU ({}), distances are computed on CPU", e);
+                return None;
+            }
+        };
+        log::info!("comparing {} sketches on GPU", sketches.len());
+        Some(GpuRegisters { device, kernel, registers, nb_sketches: sketches.len(), nb_registers })
+    }
+
+    /// Rows per call of [GpuRegisters::count_equal], keeping the device counts buffer bounded.
+    pub fn rows_per_launch(&self) -> usize {
+        (COUNTS_PER_LAUNCH / self.nb_sketches.max(1)).clamp(1, MAX_ROWS_PER_LAUNCH)
+    }
+
+    /// Numbers of equal registers of the sketches of `rows` with all sketches, row by row.
+    pub fn count_equal(&self, rows: Range<usize>) -> Result<Vec<u32>, String> {
+        let n = self.nb_sketches;
+        let nb_rows = rows.len();
+        let mut counts = self.device.alloc_zeros::<u32>(nb_rows * n).map_err(|e| e.to_string())?;
+        let config = LaunchConfig {
+            grid_dim: ((n as u32).div_ceil(THREADS_PER_BLOCK), nb_rows as u32, 1),
+            block_dim: (THREADS_PER_BLOCK, 1, 1),
+            shared_mem_bytes: 0,
+        };
+        let params = (
+            &self.registers,
+            n as u32,
+            self.nb_registers as u32,
+            rows.start as u32,
+            nb_rows as u32,
+            &mut counts,
+        );
+        unsafe { self.kernel.clone().launch(config, params) }.map_err(|e| e.to_string())?;
+        self.device.dtoh_sync_copy(&counts).map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dist::build_distance_matrix;
+    use crate::pipeline::PipelineBuilder;
+    use crate::sketch::{mix64, Signature};
+    use std::collections::HashMap;
+
+    #[test]
+    fn cpu_fallback() {
+        let nb_registers = 256;
+        let genomes: Vec<String> = (0..6).map(|g| format!("g{}.fa", g)).collect();
+        // registers shared with the other genomes except for a fraction growing with the genome index
+        let sketches: HashMap<String, Signature> = genomes
+            .iter()
+            .enumerate()
+            .map(|(g, genome)| {
+                let registers = (0..nb_registers)
+                    .map(|k| {
+                        let unique = mix64((g * nb_registers + k) as u64) % 8 < g as u64;
+                        (if unique { mix64(g as u64 + 1) ^ k as u64 } else { mix64(k as u64) } >> 40) as f32
+                    })
+                    .collect();
+                (genome.clone(), Signature::Registers(registers))
+            })
+            .collect();
+        if !libraries_available() {
+            let registers: Vec<&[f32]> = genomes
+                .iter()
+                .map(|g| match &sketches[g] {
+                    Signature::Registers(registers) => registers.as_slice(),
+                    _ => unreachable!(),
+                })
+                .collect();
+            assert!(GpuRegisters::new(&registers).is_none());
+        }
+        // on GPU or, without one, on CPU, the distances are the same
+        let cpu = PipelineBuilder::new().kmer(16).sketch_size(nb_registers).build().unwrap();
+        let gpu = PipelineBuilder::new().kmer(16).sketch_size(nb_registers).gpu(true).build().unwrap();
+        let expected = build_distance_matrix(&cpu, &sketches, &genomes).unwrap();
+        let matrix = build_distance_matrix(&gpu, &sketches, &genomes).unwrap();
+        for i in 0..genomes.len() {
+            for j in 0..genomes.len() {
+                assert_eq!(matrix.get(i, j), expected.get(i, j));
+            }
+        }
+        assert!(expected.get(0, 5) > expected.get(0, 1));
+    }
+}
@@ -0,0 +1,205 @@
+//! Approximate nearest neighbor search over the register sketches of many genomes with a
+//! hierarchical navigable small world graph (HNSW, Malkov and Yashunin, 2018, built by hnsw_rs),
+//! each search visiting a small part of the genomes instead of comparing all of them.
+//!
+//! The graph is navigated by the fraction of differing registers, from which the distances follow
+//! under the distance model. An index saved under a prefix is made of `<prefix>.hnsw.graph` and
+//! `<prefix>.hnsw.data`, written by hnsw_rs, and `<prefix>.hnsw.genomes`: a header line
+//! `#index <n> <fingerprint>`, the fingerprint identifying the sketch settings, then the label of
+//! the genome of each point one per line.
+
+use hnsw_rs::prelude::*;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use crate::checkpoint::fingerprint;
+use crate::dist::{cap_distance, genome_label, hamming_to_distance, mash_distance, DistanceModel};
+use crate::pipeline::PipelineConfig;
+use crate::simd::hamming_f32;
+use crate::sketch::Signature;
+use crate::weighting::Weighting;
+
+const INDEX_MARKER: &str = "#index";
+// neighbors of a point in each layer, and candidates explored while building and searching
+const MAX_NB_CONNECTION: usize = 24;
+const MAX_LAYER: usize = 16;
+const EF_CONSTRUCTION: usize = 400;
+const EF_SEARCH: usize = 128;
+// genomes the mean distance of each genome to all of them is estimated from
+const MEAN_SAMPLE: usize = 200;
+
+/// Fraction of differing registers of two sketches, the distance navigating the graph.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RegisterDistance;
+
+impl Distance<f32> for RegisterDistance {
+    fn eval(&self, va: &[f32], vb: &[f32]) -> f32 {
+        hamming_f32(va, vb)
+    }
+}
+
+/// HNSW index of the sketches of a set of genomes.
+pub struct SketchIndex {
+    hnsw: Hnsw<'static, f32, RegisterDistance>,
+    labels: Vec<String>,
+}
+
+fn registers(signature: &Signature) -> Result<&Vec<f32>, String> {
+    match signature {
+        Signature::Registers(registers) => Ok(registers),
+        _ => Err("The HNSW index needs register sketches, not FracMinHash (--scaled), b-bit (--bbits), SetSketch or composition profiles".to_string()),
+    }
+}
+
+fn check_settings(config: &PipelineConfig) -> Result<(), String> {
+    if config.weighting != Weighting::None {
+        return Err("The HNSW index cannot weight hashes (--weighting)".to_string());
+    }
+    Ok(())
+}
+
+impl SketchIndex {
+    /// Index of the sketches of `genomes`, sketched with `config`.
+    pub fn build(config: &PipelineConfig, sketches: &HashMap<String, Signature>, genomes: &[String]) -> Result<SketchIndex, String> {
+        check_settings(config)?;
+        let vectors = genomes.iter().map(|g| registers(&sketches[g])).collect::<Result<Vec<&Vec<f32>>, String>>()?;
+        let mut hnsw = Hnsw::new(MAX_NB_CONNECTION, genomes.len(), MAX_LAYER, EF_CONSTRUCTION, RegisterDistance);
+        let data: Vec<(&Vec<f32>, usize)> = vectors.into_iter().zip(0..).collect();
+        hnsw.parallel_insert(&data);
+        hnsw.set_searching_mode(true);
+        Ok(SketchIndex { hnsw, labels: genomes.iter().map(|g| genome_label(g)).collect() })
+    }
+
+    /// Writes the index to the files of `prefix`, along with the fingerprint of the sketch settings
+    /// of `config`.
+    pub fn save(&self, config: &PipelineConfig, prefix: &str) -> Result<(), String> {
+        let (directory, basename) = split_prefix(prefix)?;
+        let written = self.hnsw.file_dump(directory, basename).map_err(|e| format!("Cannot write index {}: {}", prefix, e))?;
+        if written != basename {
+            return Err(format!("Cannot overwrite index {}, written as {} instead", prefix, written));
+        }
+        let path = format!("{}.hnsw.genomes", prefix);
+        let file = File::create(&path).map_err(|e| format!("Cannot create index file {}: {}", path, e))?;
+        let mut writer = BufWriter::new(file);
+        writeln!(writer, "{}\t{}\t{:016x}", INDEX_MARKER, self.labels.len(), fingerprint(config, &[])).map_err(|e| e.to_string())?;
+        for label in &self.labels {
+            writeln!(writer, "{}", label).map_err(|e| e.to_string())?;
+        }
+        writer.flush().map_err(|e| e.to_string())
+    }
+
+    /// Reads the index saved under `prefix`, checking that it was built with the sketch settings of
+    /// `config`, those the queries are sketched with.
+    pub fn load(config: &PipelineConfig, prefix: &str) -> Result<SketchIndex, String> {
+        check_settings(config)?;
+        let path = format!("{}.hnsw.genomes", prefix);
+        let file = File::open(&path).map_err(|e| format!("Cannot open index file {}: {}", path, e))?;
+        let mut reader = BufReader::new(file);
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(|e| e.to_string())?;
+        let fields: Vec<&str> = header.split_whitespace().collect();
+        if fields.len() != 3 || fields[0] != INDEX_MARKER {
+            return Err(format!("{} is not an index file", path));
+        }
+        let n: usize = fields[1].parse().map_err(|_| format!("Malformed index header in {}", path))?;
+        if fields[2] != format!("{:016x}", fingerprint(config, &[])) {
+            return Err(format!("Index {} was built with other sketch settings", prefix));
+        }
+        let labels = reader.lines().take(n).collect::<Result<Vec<String>, _>>().map_err(|e| e.to_string())?;
+        let (directory, basename) = split_prefix(prefix)?;
+        // the graph borrows from its reader for the rest of the run
+        let io: &'static mut HnswIo = Box::leak(Box::new(HnswIo::new(directory, basename)));
+        let mut hnsw: Hnsw<'static, f32, RegisterDistance> =
+            io.load_hnsw().map_err(|e| format!("Cannot read index {}: {}", prefix, e))?;
+        if labels.len() != n || hnsw.get_nb_point() != n {
+            return Err(format!("Index {} is truncated", prefix));
+        }
+        hnsw.set_searching_mode(true);
+        Ok(SketchIndex { hnsw, labels })
+    }
+
+    /// Labels of the genomes of the index, by point.
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    /// The (about) `k` nearest indexed genomes of each of `signatures` and their distances under
+    /// `config`, closest first.
+    pub fn search(&self, config: &PipelineConfig, signatures: &[&Signature], k: usize) -> Result<Vec<Vec<(usize, f64)>>, String> {
+        let vectors = signatures.iter().map(|s| registers(s)).collect::<Result<Vec<&Vec<f32>>, String>>()?;
+        Ok(vectors.par_iter().map(|v| self.neighbors(config, v, k, None)).collect())
+    }
+
+    /// The (about) `k` nearest other indexed genomes of each indexed genome and their distances
+    /// under `config`, closest first.
+    pub fn nearest_neighbors(&self, config: &PipelineConfig, k: usize) -> Vec<Vec<(usize, f64)>> {
+        self.points().par_iter().enumerate().map(|(id, v)| self.neighbors(config, v, k, Some(id))).collect()
+    }
+
+    /// Mean distance under `config` of each indexed genome to the others, estimated from evenly
+    /// spaced genomes of the index.
+    pub fn mean_distances(&self, config: &PipelineConfig) -> Vec<f64> {
+        let points = self.points();
+        let n = points.len();
+        let sample: Vec<usize> = (0..MEAN_SAMPLE.min(n)).map(|s| s * n / MEAN_SAMPLE.min(n)).collect();
+        points
+            .par_iter()
+            .enumerate()
+            .map(|(id, v)| {
+                let others: Vec<f64> = sample
+                    .iter()
+                    .filter(|&&s| s != id)
+                    .map(|&s| register_distance(config, hamming_f32(v, &points[s]), v.len()))
+                    .collect();
+                others.iter().sum::<f64>() / others.len().max(1) as f64
+            })
+            .collect()
+    }
+
+    // Registers of the indexed genomes, by point
+    fn points(&self) -> Vec<Vec<f32>> {
+        let mut points: Vec<(usize, Vec<f32>)> =
+            self.hnsw.get_point_indexation().into_iter().map(|point| (point.get_origin_id(), point.get_v().to_vec())).collect();
+        points.sort_by_key(|&(id, _)| id);
+        points.into_iter().map(|(_, v)| v).collect()
+    }
+
+    fn neighbors(&self, config: &PipelineConfig, registers: &[f32], k: usize, exclude: Option<usize>) -> Vec<(usize, f64)> {
+        let wanted = k + usize::from(exclude.is_some());
+        let mut found: Vec<(usize, f32)> = self
+            .hnsw
+            .search(registers, wanted, EF_SEARCH.max(wanted))
+            .into_iter()
+            .map(|neighbour| (neighbour.d_id, neighbour.distance))
+            .filter(|&(id, _)| Some(id) != exclude)
+            .collect();
+        found.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)));
+        found.truncate(k);
+        found.into_iter().map(|(id, hamming)| (id, register_distance(config, hamming, registers.len()))).collect()
+    }
+}
+
+// Distance of two sketches with a fraction `hamming` of their `nb_registers` registers differing,
+// as by [crate::dist::pair_distance]
+fn register_distance(config: &PipelineConfig, hamming: f32, nb_registers: usize) -> f64 {
+    let jaccard = 1.0 - hamming as f64;
+    if config.min_shared > 0 && ((jaccard * nb_registers as f64).round() as usize) < config.min_shared {
+        return config.max_dist;
+    }
+    let distance = match config.distance_model {
+        DistanceModel::BinDash => hamming_to_distance(hamming, config.kmer_size),
+        DistanceModel::Mash => mash_distance(jaccard, config.kmer_size),
+        DistanceModel::Containment => f64::NAN,
+    };
+    cap_distance(config, distance)
+}
+
+fn split_prefix(prefix: &str) -> Result<(&Path, &str), String> {
+    let path = Path::new(prefix);
+    let basename = path.file_name().and_then(|name| name.to_str()).ok_or(format!("Invalid index prefix: {}", prefix))?;
+    let directory = path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    Ok((directory, basename))
+}
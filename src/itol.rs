@@ -0,0 +1,58 @@
+//! Annotation files for the iTOL tree viewer (https://itol.embl.de), to be dropped onto the tree
+//! uploaded there.
+
+use std::collections::HashMap;
+
+use crate::matrix::DistMatrix;
+
+// distinct colors, cycled when there are more clusters
+const PALETTE: [&str; 12] = [
+    "#1f77b4", "#ff7f0e", "#2ca02c", "#d62728", "#9467bd", "#8c564b", "#e377c2", "#7f7f7f", "#bcbd22", "#17becf", "#393b79",
+    "#637939",
+];
+
+/// TREE_COLORS file coloring the range of each leaf by its cluster, `clusters` being the cluster
+/// index of each label of `matrix`. Singletons are left uncolored.
+pub fn cluster_colors(matrix: &DistMatrix, clusters: &[usize]) -> String {
+    let mut sizes: HashMap<usize, usize> = HashMap::new();
+    for &cluster in clusters {
+        *sizes.entry(cluster).or_default() += 1;
+    }
+    let mut text = String::from("TREE_COLORS\nSEPARATOR TAB\nDATA\n");
+    // colors are taken by clusters of several leaves only, in order of first appearance
+    let mut colors: HashMap<usize, &str> = HashMap::new();
+    for (label, &cluster) in matrix.labels().iter().zip(clusters) {
+        if sizes[&cluster] < 2 {
+            continue;
+        }
+        let next = colors.len() % PALETTE.len();
+        let color = *colors.entry(cluster).or_insert(PALETTE[next]);
+        text.push_str(&format!("{}\trange\t{}\tcluster {}\n", label, color, cluster));
+    }
+    text
+}
+
+/// DATASET_HEATMAP file of the distance of each leaf to its nearest neighbor in `matrix`.
+pub fn nearest_neighbor_heatmap(matrix: &DistMatrix) -> String {
+    let n = matrix.len();
+    let mut text = String::from("DATASET_HEATMAP\nSEPARATOR TAB\nDATASET_LABEL\tnearest neighbor distance\nCOLOR\t#ff0000\n");
+    text.push_str("FIELD_LABELS\tnearest_neighbor\nCOLOR_MIN\t#ffffff\nCOLOR_MAX\t#ff0000\nDATA\n");
+    for (i, label) in matrix.labels().iter().enumerate() {
+        let nearest = (0..n).filter(|&j| j != i).map(|j| matrix.get(i, j)).fold(f64::INFINITY, f64::min);
+        if nearest.is_finite() {
+            text.push_str(&format!("{}\t{}\n", label, nearest));
+        }
+    }
+    text
+}
+
+/// LABELS file renaming the leaves of `matrix` found in `label_map` (leaf label to display name).
+pub fn leaf_labels(matrix: &DistMatrix, label_map: &HashMap<String, String>) -> String {
+    let mut text = String::from("LABELS\nSEPARATOR TAB\nDATA\n");
+    for label in matrix.labels() {
+        if let Some(name) = label_map.get(label) {
+            text.push_str(&format!("{}\t{}\n", label, name));
+        }
+    }
+    text
+}
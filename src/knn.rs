@@ -0,0 +1,127 @@
+//! k-nearest-neighbor graph of the genomes, an edge list for network visualization and community
+//! detection tools.
+
+use rayon::prelude::*;
+use std::io::Write;
+use std::str::FromStr;
+
+use crate::matrix::{DistMatrix, OutputValues};
+use crate::phylo::xml_escape;
+
+/// File format of the k-nearest-neighbor graph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// tab separated edge list
+    Tsv,
+    /// GraphML, directed edges with their rank and value
+    Graphml,
+}
+
+impl FromStr for GraphFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<GraphFormat, String> {
+        match s.to_lowercase().as_str() {
+            "tsv" => Ok(GraphFormat::Tsv),
+            "graphml" => Ok(GraphFormat::Graphml),
+            _ => Err(format!("Unknown graph format: {}", s)),
+        }
+    }
+}
+
+/// The `k` closest other genomes of each genome of `matrix` and their distances, closest first,
+/// ties going to the first genome. Distances of the matrix are taken from the row of each genome.
+pub fn nearest_neighbors(matrix: &DistMatrix, k: usize) -> Vec<Vec<(usize, f64)>> {
+    let n = matrix.len();
+    (0..n)
+        .into_par_iter()
+        .map(|i| {
+            let mut row: Vec<(usize, f64)> = (0..n).filter(|&j| j != i).map(|j| (j, matrix.get(i, j))).collect();
+            let by_distance = |a: &(usize, f64), b: &(usize, f64)| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0));
+            if row.len() > k {
+                row.select_nth_unstable_by(k, by_distance);
+                row.truncate(k);
+            }
+            row.sort_by(by_distance);
+            row
+        })
+        .collect()
+}
+
+fn value_column(values: OutputValues) -> &'static str {
+    match values {
+        OutputValues::Distance => "distance",
+        OutputValues::Ani => "ani",
+    }
+}
+
+// Tab separated edges from each source to its neighbors among the targets, with their rank
+fn write_edges<W: Write>(
+    writer: &mut W,
+    header: &str,
+    sources: &[String],
+    targets: &[String],
+    neighbors: &[Vec<(usize, f64)>],
+    values: OutputValues,
+) -> std::io::Result<()> {
+    writeln!(writer, "{}\trank\t{}", header, value_column(values))?;
+    for (source, row) in sources.iter().zip(neighbors) {
+        for (rank, &(j, distance)) in row.iter().enumerate() {
+            writeln!(writer, "{}\t{}\t{}\t{}", source, targets[j], rank + 1, values.transform(distance))?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the edges from each genome of `labels` to its `neighbors`, with their rank (1 for the
+/// closest) and distance or ANI as given by `values`.
+pub fn write_knn<W: Write>(
+    writer: &mut W,
+    labels: &[String],
+    neighbors: &[Vec<(usize, f64)>],
+    format: GraphFormat,
+    values: OutputValues,
+) -> std::io::Result<()> {
+    let column = value_column(values);
+    match format {
+        GraphFormat::Tsv => write_edges(writer, "genome\tneighbor", labels, labels, neighbors, values)?,
+        GraphFormat::Graphml => {
+            writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+            writeln!(writer, "<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">")?;
+            writeln!(writer, "  <key id=\"label\" for=\"node\" attr.name=\"label\" attr.type=\"string\"/>")?;
+            writeln!(writer, "  <key id=\"rank\" for=\"edge\" attr.name=\"rank\" attr.type=\"int\"/>")?;
+            writeln!(writer, "  <key id=\"{0}\" for=\"edge\" attr.name=\"{0}\" attr.type=\"double\"/>", column)?;
+            writeln!(writer, "  <graph id=\"knn\" edgedefault=\"directed\">")?;
+            for (i, label) in labels.iter().enumerate() {
+                writeln!(writer, "    <node id=\"n{}\"><data key=\"label\">{}</data></node>", i, xml_escape(label))?;
+            }
+            for (i, row) in neighbors.iter().enumerate() {
+                for (rank, &(j, distance)) in row.iter().enumerate() {
+                    writeln!(
+                        writer,
+                        "    <edge source=\"n{}\" target=\"n{}\"><data key=\"rank\">{}</data><data key=\"{}\">{}</data></edge>",
+                        i,
+                        j,
+                        rank + 1,
+                        column,
+                        values.transform(distance)
+                    )?;
+                }
+            }
+            writeln!(writer, "  </graph>")?;
+            writeln!(writer, "</graphml>")?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes the edges from each query to its `neighbors` among the `references`, as a tab separated
+/// table like [write_knn].
+pub fn write_query_neighbors<W: Write>(
+    writer: &mut W,
+    queries: &[String],
+    references: &[String],
+    neighbors: &[Vec<(usize, f64)>],
+    values: OutputValues,
+) -> std::io::Result<()> {
+    write_edges(writer, "query\treference", queries, references, neighbors, values)
+}
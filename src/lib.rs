@@ -0,0 +1,59 @@
+//! MinHash based phylogenomics via neighbor joining.
+//!
+//! Genomes are sketched with Binwise Densified MinHash (kmerutils/probminhash), pairwise
+//! distances are derived from the sketches and a tree is built with rapid neighbor-joining (speedytree).
+//!
+//! The whole pipeline is configured through [pipeline::PipelineBuilder]:
+//!
+//! ```no_run
+//! use bindashtree::pipeline::PipelineBuilder;
+//! use bindashtree::sketch::Densification;
+//! use bindashtree::tree::TreeAlgo;
+//!
+//! let config = PipelineBuilder::new()
+//!     .kmer(16)
+//!     .sketch_size(10240)
+//!     .densification(Densification::ReverseOptimal)
+//!     .tree(TreeAlgo::RapidNJ)
+//!     .build()
+//!     .unwrap();
+//! let genomes = vec!["a.fna.gz".to_string(), "b.fna.gz".to_string(), "c.fna.gz".to_string()];
+//! let newick = config.run(&genomes).unwrap();
+//! ```
+
+pub mod bme;
+pub mod budget;
+pub mod checkpoint;
+pub mod cluster;
+pub mod compare;
+#[cfg(feature = "arrow")]
+pub mod columnar;
+pub mod dist;
+pub mod distribution;
+pub mod error;
+pub mod fit;
+#[cfg(feature = "cuda")]
+pub mod gpu;
+pub mod index;
+pub mod itol;
+pub mod knn;
+pub mod mad;
+pub mod matrix;
+pub mod nni;
+pub mod ordination;
+pub mod pairs;
+pub mod phylo;
+pub mod place;
+pub mod pipeline;
+pub mod plot;
+pub mod preprocess;
+pub mod prescreen;
+pub mod qc;
+pub mod resampling;
+pub mod selftest;
+pub mod shard;
+pub mod simd;
+pub mod sketch;
+pub mod sparse;
+pub mod tree;
+pub mod weighting;
@@ -0,0 +1,200 @@
+//! Rooting by minimal ancestor deviation (MAD, Tria, Landan and Dagan, 2017).
+//!
+//! A root makes the ancestor of two leaves `b` and `c` a point `a` on the path between them, which a
+//! molecular clock would put at its middle. The relative deviation `2 d(b, a) / d(b, c) - 1` measures
+//! how far from the middle it is, and the tree is rooted where the mean squared deviation over all
+//! pairs of leaves is the smallest, which is more robust than the midpoint to rate variation.
+//! With the root on the branch above node `u`, the ancestor of a pair across the branch is the root
+//! itself, its deviation being linear in the root position, while the ancestor of any other pair is
+//! a node fixed by the branch. The sums of the latter come from one pass over the pairs and a walk
+//! down the tree, the former from the pairs across each branch. Pairs at distance zero are left out.
+
+use rayon::prelude::*;
+
+use crate::phylo::Tree;
+
+/// Same tree rooted at the point of minimal ancestor deviation, see [Tree::reroot].
+pub fn mad_root(tree: &Tree) -> Tree {
+    let m = tree.len();
+    let order = tree.post_order();
+    let length = |node: usize| tree.node(node).length.unwrap_or(0.0).max(0.0);
+    let children = |node: usize| &tree.node(node).children;
+    let mut depth = vec![0.0; m];
+    for &node in order.iter().rev() {
+        for &child in children(node) {
+            depth[child] = depth[node] + length(child);
+        }
+    }
+    // leaves numbered so that those below each node are a range
+    let mut leaves = Vec::new();
+    let mut range = vec![(0, 0); m];
+    for &node in &order {
+        range[node] = if children(node).is_empty() {
+            leaves.push(node);
+            (leaves.len() - 1, leaves.len())
+        } else {
+            let lo = children(node).iter().map(|&child| range[child].0).min().unwrap();
+            let hi = children(node).iter().map(|&child| range[child].1).max().unwrap();
+            (lo, hi)
+        };
+    }
+    let n = leaves.len();
+    if n < 3 {
+        return tree.clone();
+    }
+    let leaf_depth: Vec<f64> = leaves.iter().map(|&leaf| depth[leaf]).collect();
+    // squared deviation of a pair at distance d whose ancestor is at x from one of its leaves
+    let deviation = |x: f64, d: f64| if d > 0.0 { (2.0 * x / d - 1.0).powi(2) } else { 0.0 };
+
+    // distances between leaves and the deviations of the pairs whose ancestor is their most recent
+    // common ancestor as rooted now: by ancestor, and by the child of the ancestor above either leaf
+    let mut dist = vec![0.0; n * n];
+    let mut at_node = vec![0.0; m];
+    let mut across_child = vec![0.0; m];
+    let mut nb_pairs = 0usize;
+    for &node in order.iter().filter(|&&node| children(node).len() > 1) {
+        let kids = children(node);
+        for (k, &s1) in kids.iter().enumerate() {
+            for &s2 in &kids[k + 1..] {
+                let mut sum = 0.0;
+                for b in range[s1].0..range[s1].1 {
+                    for c in range[s2].0..range[s2].1 {
+                        let d = leaf_depth[b] + leaf_depth[c] - 2.0 * depth[node];
+                        dist[b * n + c] = d;
+                        dist[c * n + b] = d;
+                        nb_pairs += usize::from(d > 0.0);
+                        sum += deviation(leaf_depth[b] - depth[node], d);
+                    }
+                }
+                at_node[node] += sum;
+                across_child[s1] += sum;
+                across_child[s2] += sum;
+            }
+        }
+    }
+    if nb_pairs == 0 {
+        return tree.clone();
+    }
+    // pairs below each node
+    let mut inside = at_node;
+    for &node in &order {
+        for &child in children(node) {
+            inside[node] += inside[child];
+        }
+    }
+    let parents = tree.parents();
+    let outside_range = |(lo, hi): (usize, usize)| (0..lo).chain(hi..n);
+    // for the branch above each node u below p: the sums over the pairs across it of the squared
+    // deviation, as a polynomial of the root position, and over the pairs with a leaf below p but not
+    // below u and the other not below p, whose ancestor is p
+    let sums: Vec<(usize, [f64; 3], f64)> = (0..m)
+        .into_par_iter()
+        .filter_map(|u| parents[u].map(|p| (u, p)))
+        .map(|(u, p)| {
+            let mut across = [0.0; 3];
+            for b in range[u].0..range[u].1 {
+                let x = leaf_depth[b] - depth[u];
+                for c in outside_range(range[u]) {
+                    let d = dist[b * n + c];
+                    if d > 0.0 {
+                        let (alpha, beta) = (2.0 * x / d - 1.0, 2.0 / d);
+                        across[0] += alpha * alpha;
+                        across[1] += alpha * beta;
+                        across[2] += beta * beta;
+                    }
+                }
+            }
+            let mut side = 0.0;
+            for b in (range[p].0..range[p].1).filter(|&b| b < range[u].0 || b >= range[u].1) {
+                for c in outside_range(range[p]) {
+                    side += deviation(leaf_depth[b] - depth[p], dist[b * n + c]);
+                }
+            }
+            (u, across, side)
+        })
+        .collect();
+    let mut side = vec![0.0; m];
+    for &(u, _, s) in &sums {
+        side[u] = s;
+    }
+    // pairs outside each node, their ancestor being where their path meets the path to the node
+    let mut outside = vec![0.0; m];
+    for &node in order.iter().rev() {
+        for &child in children(node) {
+            outside[child] = outside[node] + inside[node] - inside[child] - across_child[child] + side[child];
+        }
+    }
+    let mut best = (f64::INFINITY, tree.root(), 0.0);
+    for &(u, [a, b, c], _) in &sums {
+        let rho = if c > 0.0 { (-b / c).clamp(0.0, length(u)) } else { 0.0 };
+        let total = inside[u] + outside[u] + a + 2.0 * b * rho + c * rho * rho;
+        if total < best.0 {
+            best = (total, u, rho);
+        }
+    }
+    let (total, node, offset) = best;
+    log::info!("minimal ancestor deviation root: deviation {}", (total.max(0.0) / nb_pairs as f64).sqrt());
+    tree.reroot(node, offset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sum of the squared ancestor deviations of the pairs of leaves of a rooted tree, from scratch
+    fn total_deviation(tree: &Tree) -> f64 {
+        let parents = tree.parents();
+        let path = |mut node: usize| {
+            let mut path = vec![(node, 0.0)];
+            let mut depth = 0.0;
+            while let Some(parent) = parents[node] {
+                depth += tree.node(node).length.unwrap_or(0.0).max(0.0);
+                path.push((parent, depth));
+                node = parent;
+            }
+            path
+        };
+        let leaves: Vec<usize> = (0..tree.len()).filter(|&node| tree.node(node).children.is_empty()).collect();
+        let mut total = 0.0;
+        for (k, &b) in leaves.iter().enumerate() {
+            let up_b = path(b);
+            for &c in &leaves[k + 1..] {
+                let up_c = path(c);
+                // heights of the common ancestor above b and c
+                let (x, y) = up_b
+                    .iter()
+                    .find_map(|&(node, x)| up_c.iter().find(|&&(other, _)| other == node).map(|&(_, y)| (x, y)))
+                    .unwrap();
+                if x + y > 0.0 {
+                    total += (2.0 * x / (x + y) - 1.0).powi(2);
+                }
+            }
+        }
+        total
+    }
+
+    #[test]
+    fn mad_root_is_minimal() {
+        let trees = [
+            "((a:1,b:2):0.5,(c:3,d:1):1,e:4);",
+            "(((a:0.1,b:0.3):0.2,c:0.9):0.1,(d:0.4,(e:0.2,f:0.25):0.6):0.3);",
+            // zero length internal and leaf branches
+            "((a:1,b:0):0,(c:2,(d:0.5,e:1.5):0.7):1.2,f:3);",
+        ];
+        for newick in trees {
+            let tree = Tree::from_newick(newick).unwrap();
+            let parents = tree.parents();
+            // every point of every branch, in steps of a hundredth of the branch
+            let brute = (0..tree.len())
+                .filter(|&node| parents[node].is_some())
+                .flat_map(|node| {
+                    let length = tree.node(node).length.unwrap_or(0.0).max(0.0);
+                    (0..=100).map(move |k| (node, length * k as f64 / 100.0))
+                })
+                .map(|(node, offset)| total_deviation(&tree.reroot(node, offset)))
+                .fold(f64::INFINITY, f64::min);
+            let mad = total_deviation(&mad_root(&tree));
+            assert!(mad <= brute + 1e-9, "{}: {} above {}", newick, mad, brute);
+        }
+    }
+}
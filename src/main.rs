@@ -1,260 +1,235 @@
-use clap::{Arg, ArgAction, Command};
+use clap::{Arg, ArgAction, ArgGroup, ArgMatches, Command};
 use rayon::prelude::*;
 use rayon::ThreadPoolBuilder;
-use std::fs::File;
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, BufWriter, Write};
-use needletail::{parse_fastx_file, Sequence};
-use std::collections::HashMap;
-use kmerutils::sketcharg::{SeqSketcherParams, SketchAlgo, DataType};
-use kmerutils::base::{
-    CompressedKmerT, KmerBuilder,
-    kmergenerator::{KmerGenerator, KmerGenerationPattern},
-    alphabet::Alphabet2b,
-    sequence::Sequence as SequenceStruct,
-    kmer::{Kmer32bit, Kmer16b32bit, Kmer64bit}
-};
-use kmerutils::sketching::setsketchert::*; // Contains SeqSketcherT, OptDensHashSketch, RevOptDensHashSketch
-use anndists::dist::{Distance, DistHamming};
-use num;
-use std::path::Path;
-use speedytree::DistanceMatrix;
-use speedytree::{Canonical, Hybrid, NeighborJoiningSolver, RapidBtrees};
-use std::str::FromStr;
-use std::fmt::Debug;
-use serde::Serialize;
-use rand_distr::uniform::SampleUniform;
-
-// Introduce SeqSketcherFactory trait to provide `new` method.
-trait SeqSketcherFactory<Kmer>: SeqSketcherT<Kmer>
-where
-    Kmer: CompressedKmerT + KmerBuilder<Kmer>,
-    KmerGenerator<Kmer>: KmerGenerationPattern<Kmer>,
-{
-    fn new(params: &SeqSketcherParams) -> Self;
-}
-
-// Implement SeqSketcherFactory for OptDensHashSketch
-impl<Kmer, S> SeqSketcherFactory<Kmer> for OptDensHashSketch<Kmer, S>
-where
-    Kmer: CompressedKmerT + KmerBuilder<Kmer> + Send + Sync,
-    Kmer::Val: num::PrimInt + Send + Sync + Debug,
-    KmerGenerator<Kmer>: KmerGenerationPattern<Kmer>,
-    S: num::Float + SampleUniform + Send + Sync + Debug + Serialize,
-{
-    fn new(params: &SeqSketcherParams) -> Self {
-        // Call the existing public new method from OptDensHashSketch
-        OptDensHashSketch::<Kmer, S>::new(params)
-    }
-}
-
-// Implement SeqSketcherFactory for RevOptDensHashSketch
-impl<Kmer, S> SeqSketcherFactory<Kmer> for RevOptDensHashSketch<Kmer, S>
-where
-    Kmer: CompressedKmerT + KmerBuilder<Kmer> + Send + Sync,
-    Kmer::Val: num::PrimInt + Send + Sync + Debug,
-    KmerGenerator<Kmer>: KmerGenerationPattern<Kmer>,
-    S: num::Float + SampleUniform + Send + Sync + Debug + Serialize,
-{
-    fn new(params: &SeqSketcherParams) -> Self {
-        // Call the existing public new method from RevOptDensHashSketch
-        RevOptDensHashSketch::<Kmer, S>::new(params)
-    }
-}
-
-#[derive(Debug, Clone)]
-enum TreeAlgo {
-    Naive,
-    RapidNJ,
-    Hybrid,
-}
-
-impl FromStr for TreeAlgo {
-    type Err = String;
-    fn from_str(s: &str) -> Result<TreeAlgo, String> {
-        match s.to_lowercase().as_str() {
-            "naive" => Ok(TreeAlgo::Naive),
-            "rapidnj" => Ok(TreeAlgo::RapidNJ),
-            "hybrid" => Ok(TreeAlgo::Hybrid),
-            _ => Err(format!("Unknown tree method: {}", s)),
-        }
-    }
-}
-
-fn ascii_to_seq(bases: &[u8]) -> Result<SequenceStruct, ()> {
-    let alphabet = Alphabet2b::new();
-    let mut seq = SequenceStruct::with_capacity(2, bases.len());
-    seq.encode_and_add(bases, &alphabet);
-    Ok(seq)
-}
-
-fn read_sequences(path: &str) -> Vec<SequenceStruct> {
-    let mut sequences = Vec::new();
-    let mut reader = parse_fastx_file(path).expect("Invalid FASTA/Q file");
-    while let Some(record) = reader.next() {
-        let seq_record = record.expect("Error reading sequence record");
-        let seq_seq = seq_record.normalize(false).into_owned();
-        let seq = ascii_to_seq(&seq_seq).unwrap();
-        sequences.push(seq);
-    }
-    sequences
-}
-
-fn sketch_with<Kmer, Sketcher>(
-    sketch_args: &SeqSketcherParams,
-    genomes: &Vec<String>,
-    kmer_size: usize,
-    dens: usize
-) -> HashMap<String, Vec<f32>>
-where
-    Kmer: CompressedKmerT + KmerBuilder<Kmer> + Send + Sync,
-    <Kmer as CompressedKmerT>::Val: num::PrimInt + Send + Sync + Debug,
-    KmerGenerator<Kmer>: KmerGenerationPattern<Kmer>,
-    Sketcher: SeqSketcherFactory<Kmer, Sig = f32> + Send + Sync + 'static,
-{
-    let nb_alphabet_bits = 2;
-    let sketcher = Sketcher::new(sketch_args);
-    let hash_fn = move |kmer: &Kmer| -> <Kmer as CompressedKmerT>::Val {
-        let mask: <Kmer as CompressedKmerT>::Val =
-            num::NumCast::from::<u64>((1u64 << (nb_alphabet_bits * kmer.get_nb_base())) - 1).unwrap();
-        let canonical = kmer.reverse_complement().min(*kmer);
-        canonical.get_compressed_value() & mask
-    };
-
-    genomes
-        .par_iter()
-        .map(|path| {
-            let sequences = read_sequences(path);
-            let sequences_ref: Vec<&SequenceStruct> = sequences.iter().collect();
-            let signature = sketcher.sketch_compressedkmer_seqs(&sequences_ref, &hash_fn);
-            (path.clone(), signature[0].clone())
-        })
-        .collect()
-}
-
-fn sketch_genomes(
-    kmer_size: usize,
-    dens: usize,
-    sketch_args: &SeqSketcherParams,
-    genomes: &Vec<String>
-) -> HashMap<String, Vec<f32>> {
-    if kmer_size <= 14 {
-        if dens == 0 {
-            sketch_with::<Kmer32bit, OptDensHashSketch<Kmer32bit, f32>>(sketch_args, genomes, kmer_size, dens)
-        } else {
-            sketch_with::<Kmer32bit, RevOptDensHashSketch<Kmer32bit, f32>>(sketch_args, genomes, kmer_size, dens)
-        }
-    } else if kmer_size == 16 {
-        if dens == 0 {
-            sketch_with::<Kmer16b32bit, OptDensHashSketch<Kmer16b32bit, f32>>(sketch_args, genomes, kmer_size, dens)
-        } else {
-            sketch_with::<Kmer16b32bit, RevOptDensHashSketch<Kmer16b32bit, f32>>(sketch_args, genomes, kmer_size, dens)
-        }
-    } else if kmer_size <= 32 {
-        if dens == 0 {
-            sketch_with::<Kmer64bit, OptDensHashSketch<Kmer64bit, f32>>(sketch_args, genomes, kmer_size, dens)
-        } else {
-            sketch_with::<Kmer64bit, RevOptDensHashSketch<Kmer64bit, f32>>(sketch_args, genomes, kmer_size, dens)
-        }
-    } else {
-        panic!("kmers cannot be 15 or greater than 32");
-    }
-}
-
-fn build_distance_matrix(
-    sketches: &HashMap<String, Vec<f32>>,
-    kmer_size: usize,
-    genomes: &Vec<String>,
-) -> Vec<u8> {
-    let dist_hamming = DistHamming;
-    let n = genomes.len();
-    let distances: Vec<(usize, usize, f64)> = (0..n)
-        .into_par_iter()
-        .flat_map(|i| {
-            (i + 1..n)
-                .into_par_iter()
-                .map(move |j| {
-                    let query_signature = &sketches[&genomes[i]];
-                    let reference_signature = &sketches[&genomes[j]];
-                    let hamming_distance = dist_hamming.eval(query_signature, reference_signature);
-                    let hamming_distance = if hamming_distance == 0.0 {
-                        std::f32::EPSILON // Use a small value close to zero
-                    } else {
-                        hamming_distance
-                    };
-                    let jaccard = 1.0 - hamming_distance;
-                    let numerator = 2.0 * jaccard;
-                    let denominator = 1.0 + jaccard;
-                    let fraction = (numerator as f64) / (denominator as f64);
-                    let distance = -fraction.ln() / (kmer_size as f64);
-                    (i, j, distance)
-                })
-        })
-        .collect();
-
-    let mut matrix = vec![vec![0.0_f64; n]; n];
-    for &(i, j, dist) in &distances {
-        matrix[i][j] = dist;
-        matrix[j][i] = dist;
-    }
-
-    let mut phylip_data = Vec::new();
-    writeln!(phylip_data, "{}", n).unwrap();
-    for i in 0..n {
-        let name = Path::new(&genomes[i])
-            .file_name()
-            .and_then(|os_str| os_str.to_str())
-            .unwrap_or(&genomes[i])
-            .to_string();
-        write!(phylip_data, "{:10}", name).unwrap();
-        for j in 0..n {
-            write!(phylip_data, " {:8.6}", matrix[i][j]).unwrap();
-        }
-        writeln!(phylip_data).unwrap();
-    }
-
-    phylip_data
-}
-
-fn build_tree(
-    tree_algo: &TreeAlgo,
-    chunk_size: usize,
-    naive_percentage: usize,
-    phylip_data: &[u8]
-) -> String {
-    let distance_matrix =
-        DistanceMatrix::read_from_phylip(&phylip_data[..]).expect("Error reading phylip matrix");
-
-    let graph = match tree_algo {
-        TreeAlgo::Naive => {
-            NeighborJoiningSolver::<Canonical>::default(distance_matrix).solve()
-        }
-        TreeAlgo::RapidNJ => {
-            NeighborJoiningSolver::<RapidBtrees>::build(distance_matrix, chunk_size).solve()
-        }
-        TreeAlgo::Hybrid => {
-            let naive_steps = distance_matrix.size() * naive_percentage / 100;
-            NeighborJoiningSolver::<Hybrid>::build(distance_matrix, chunk_size, naive_steps).solve()
-        }
-    }
-    .expect("Error constructing tree");
-
-    speedytree::to_newick(&graph)
-}
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use bindashtree::cluster::{clique_partition, connected_components, Linkage, Representative};
+use bindashtree::compare::compare_trees;
+use bindashtree::fit::write_residuals;
+use bindashtree::dist::{genome_label, unique_genomes, DistanceKind, DistanceModel, Symmetrization};
+use bindashtree::error::{Context, Error};
+use bindashtree::index::SketchIndex;
+use bindashtree::knn::{nearest_neighbors, write_knn, write_query_neighbors, GraphFormat};
+use bindashtree::matrix::{DistMatrix, InputValues, MatrixFormat, OutputValues, PhylipNames, Precision};
+use bindashtree::phylo::Tree;
+use bindashtree::pipeline::{kmer_path, PipelineBuilder, PipelineConfig};
+use bindashtree::nni::Nni;
+use bindashtree::ordination::{umap as umap_embedding, write_embedding, write_pcoa};
+use bindashtree::pairs::{sort_pairs, PairsFormat};
+use bindashtree::preprocess::CommandPreprocessor;
+use bindashtree::qc::write_stats;
+use bindashtree::selftest::selftest;
+use bindashtree::shard::merge_shards;
+use bindashtree::sketch::{check_genome, AmbigPolicy, Densification, HashFunction, SeqStats, Signature, SketchAlgo};
+use bindashtree::plot::tree_svg;
+use bindashtree::tree::{format_tree, tree_stats, BranchLengths, InternalLabels, Ladderize, NegativeBranches, Rooting, TreeAlgo, TreeFormat};
+use bindashtree::weighting::Weighting;
 
 fn main() {
     // Initialize logger
     println!("\n ************** initializing logger *****************\n");
-    let _ = env_logger::Builder::from_default_env().init();
+    env_logger::Builder::from_default_env().init();
     let matches = Command::new("BinDashtree")
         .version("0.1.1")
         .about("Binwise Densified MinHash and Rapid Neighbor-joining Tree Construction")
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("selftest")
+                .about("Run the whole pipeline on embedded miniature genomes and check distances and topology against reference values"),
+        )
+        .subcommand(
+            Command::new("compare_trees")
+                .about("Robinson-Foulds distance, normalized by its maximum, and shared splits of two Newick trees of the same leaves")
+                .arg(Arg::new("tree1").value_name("NEWICK_FILE_1").required(true))
+                .arg(Arg::new("tree2").value_name("NEWICK_FILE_2").required(true)),
+        )
+        .subcommand(
+            Command::new("derep")
+                .about("Greedy dereplication of the -i genomes at an ANI threshold, sketching with the main options: by decreasing genome size, a genome becomes the representative of a new cluster unless it is within the threshold of an earlier representative")
+                .arg(
+                    Arg::new("ani")
+                        .long("ani")
+                        .value_name("ANI")
+                        .help("ANI threshold in percent of the clusters, e.g. 95 for species or 99 for strains")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("95"),
+                )
+                .arg(
+                    Arg::new("representative")
+                        .long("representative")
+                        .value_name("CHOICE")
+                        .help("Representative of each cluster, the genomes being taken by decreasing size, or in input order for first, a medoid replacing the first representative of its cluster: size (largest genome), medoid (lowest mean distance to the other members) or first (first in -i)")
+                        .default_value("size"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("REPRESENTATIVES_FILE")
+                        .help("Write the representative genome files, one per line, to this file instead of the standard output"),
+                )
+                .arg(
+                    Arg::new("clusters")
+                        .long("clusters")
+                        .value_name("CLUSTER_FILE")
+                        .help("Write the cluster membership, tab separated representative, genome and ANI to it, to this file"),
+                ),
+        )
+        .subcommand(
+            Command::new("components")
+                .about("Connected components of the graph of the -i genomes joining the pairs at an ANI threshold or more, sketching with the main options; only the pairs within the threshold are kept in memory")
+                .arg(
+                    Arg::new("ani")
+                        .long("ani")
+                        .value_name("ANI")
+                        .help("ANI threshold in percent of the edges of the graph, e.g. 95 for species")
+                        .value_parser(clap::value_parser!(f64))
+                        .default_value("95"),
+                )
+                .arg(
+                    Arg::new("representative")
+                        .long("representative")
+                        .value_name("CHOICE")
+                        .help("Representative of each component: size (largest genome), medoid (lowest mean distance to the other members) or first (first in -i)")
+                        .default_value("size"),
+                )
+                .arg(
+                    Arg::new("cliques")
+                        .long("cliques")
+                        .help("Also split the components into cliques, genomes all within the threshold of each other, by a greedy partition")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("COMPONENT_FILE")
+                        .help("Write the tab separated genome, component (and clique) number and representative of the component to this file instead of the standard output"),
+                )
+                .arg(
+                    Arg::new("representatives")
+                        .long("representatives")
+                        .value_name("REPRESENTATIVES_FILE")
+                        .help("Write the representative genome files of the components, one per line, to this file"),
+                )
+                .arg(
+                    Arg::new("edges")
+                        .long("edges")
+                        .value_name("EDGE_FILE")
+                        .help("Write the edges of the graph, tab separated genomes and ANI, to this file"),
+                ),
+        )
+        .subcommand(
+            Command::new("extract_clade")
+                .about("Subtree of a Newick tree rooted at the most recent common ancestor of two or more taxa")
+                .arg(Arg::new("tree").value_name("NEWICK_FILE").required(true))
+                .arg(Arg::new("taxa").value_name("TAXON").num_args(2..).required(true).help("Labels or genome files of the taxa"))
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_TREE_FILE")
+                        .help("Write the Newick tree to this file instead of the standard output"),
+                ),
+        )
+        .subcommand(
+            Command::new("place")
+                .about("Graft query genomes onto an existing tree of the -i reference genomes at their least-squares placement, sketching with the main options")
+                .arg(Arg::new("tree").value_name("NEWICK_FILE").required(true))
+                .arg(Arg::new("queries").value_name("QUERY_LIST_FILE").required(true).help("Query genome list file (one FASTA/FNA file per line)"))
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_TREE_FILE")
+                        .help("Write the Newick tree to this file instead of the standard output"),
+                ),
+        )
+        .subcommand(
+            Command::new("prune")
+                .about("Subtree of a Newick tree induced by a list of taxa, branch lengths preserved")
+                .arg(Arg::new("tree").value_name("NEWICK_FILE").required(true))
+                .arg(Arg::new("taxa").value_name("TAXON_LIST_FILE").required(true).help("Taxa to keep, one label or genome file per line"))
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("OUTPUT_TREE_FILE")
+                        .help("Write the Newick tree to this file instead of the standard output"),
+                ),
+        )
         .arg(
             Arg::new("input_list")
                 .short('i')
                 .long("input")
                 .value_name("INPUT_LIST_FILE")
                 .help("Genome list file (one FASTA/FNA file per line), .gz supported")
-                .required(true)
+                .required_unless_present_any(["input_matrix", "merge_shards", "load_index"])
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("input_matrix")
+                .long("input_matrix")
+                .value_name("MATRIX_FILE")
+                .help("Build the tree from an existing matrix, see --input_matrix_format, instead of sketching genomes")
+                .conflicts_with("input_list")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("input_matrix_format")
+                .long("input_matrix_format")
+                .value_name("FORMAT")
+                .help("Format of --input_matrix: phylip (square or lower-triangular), tsv or csv (labelled square matrices)")
+                .default_value("phylip")
+                .requires("input_matrix")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("merge_shards")
+                .long("merge_shards")
+                .value_name("SHARD_FILE")
+                .help("Merge the --output_shard files of all shards into the distance matrix, then write --output_matrix and build the tree, instead of sketching genomes")
+                .num_args(1..)
+                .conflicts_with_all(["input_list", "input_matrix"])
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("query_list")
+                .long("query")
+                .value_name("QUERY_LIST_FILE")
+                .help("Query genome list file: compute the distances of these genomes to the -i reference genomes only, written to --output_query, instead of all pairs and a tree")
+                .requires("references")
+                .requires("output_query")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("input_values")
+                .long("input_values")
+                .value_name("KIND")
+                .help("Values of --input_matrix: distance, ani (1-ANI/100, or 1-ANI for fractions) or similarity (1-S)")
+                .default_value("distance")
+                .requires("input_matrix")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("previous_matrix")
+                .long("previous_matrix")
+                .value_name("MATRIX_FILE")
+                .help("PHYLIP distance matrix of an earlier run on part of the -i genomes, e.g. before more were appended to the list: only the distances involving the other genomes are computed, with the same sketch parameters")
+                .requires("input_list")
+                .conflicts_with_all(["query_list", "gpu"])
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("previous_tree")
+                .long("previous_tree")
+                .value_name("NEWICK_FILE")
+                .help("Tree of the earlier run of --previous_matrix: the new genomes are placed on it and refined by local NNI (see --nni, bme when none) instead of building the tree again")
+                .requires("previous_matrix")
+                .conflicts_with_all(["bootstrap", "jackknife"])
                 .action(ArgAction::Set),
         )
         .arg(
@@ -262,8 +237,9 @@ fn main() {
                 .short('k')
                 .long("kmer_size")
                 .value_name("KMER_SIZE")
-                .help("K-mer size")
+                .help("K-mer size, or comma separated sizes (e.g. 16,21,31) sketched in a single pass over the genomes, each output file then being written once per k with .k<K> before its extension")
                 .default_value("16")
+                .value_delimiter(',')
                 .value_parser(clap::value_parser!(usize))
                 .action(ArgAction::Set),
         )
@@ -277,14 +253,112 @@ fn main() {
                 .value_parser(clap::value_parser!(usize))
                 .action(ArgAction::Set),
         )
+        .arg(
+            Arg::new("algo")
+                .long("algo")
+                .value_name("ALGO")
+                .help("Sketching algorithm: optdens (densified one permutation hashing, see -d), superminhash (slower, lower variance), probminhash (weighted by k-mer abundance), setsketch (HyperLogLog like, compact, compared by joint maximum likelihood Jaccard estimation)")
+                .default_value("optdens")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("scaled")
+                .long("scaled")
+                .value_name("SCALED")
+                .help("FracMinHash sketches keeping 1/SCALED of all k-mers, sketch size grows with genome size (overrides --algo and -s, 0 disables)")
+                .default_value("0")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("bbits")
+                .long("bbits")
+                .value_name("BITS")
+                .help("Keep only the lowest BITS bits of each register (1, 2, 4, 8, 16 or 32), with the Jaccard estimate corrected for chance matches; smaller sketches, faster comparisons (0 keeps full registers)")
+                .default_value("0")
+                .value_parser(clap::value_parser!(u32))
+                .action(ArgAction::Set),
+        )
         .arg(
             Arg::new("dens_opt")
                 .short('d')
                 .long("densification")
                 .value_name("DENS_OPT")
-                .help("Densification strategy: 0=Optimal Densification, 1=Reverse Optimal Densification/faster Densification")
+                .help("Densification of empty bins: optimal (or 0, most accurate), reverse (or 1, reverse optimal, faster for large sketches, same accuracy), rotation (single linear pass, fastest, higher variance for genomes much smaller than the sketch), auto (optimal, or reverse when a genome leaves more than half of the bins empty)")
+                .default_value("optimal")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("seed")
+                .long("seed")
+                .value_name("SEED")
+                .help("Seed of the k-mer hash function (--hash); runs with the same seed are identical, different seeds give independent sketches to assess estimator variance (0 with the permutation sketches canonical k-mer values directly)")
                 .default_value("0")
-                .value_parser(clap::value_parser!(usize))
+                .value_parser(clap::value_parser!(u64))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("hash")
+                .long("hash")
+                .value_name("HASH")
+                .help("Hash function of canonical k-mer values, seeded by --seed: permutation (collision free, the identity with seed 0), xxh3, wyhash (truncated to 32 bits for k <= 16)")
+                .default_value("permutation")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("ambig")
+                .long("ambig")
+                .value_name("AMBIG_POLICY")
+                .help("K-mers with ambiguous bases (N/IUPAC): skip, random (replace by a random base), fail")
+                .default_value("skip")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("skip_softmasked")
+                .long("skip_softmasked")
+                .help("Exclude lowercase (soft-masked, e.g. RepeatMasker) regions from k-mer generation")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("min_entropy")
+                .long("min_entropy")
+                .value_name("BITS")
+                .help("Drop low complexity k-mers whose base composition entropy is below this value (0 to 2 bits, 0 disables)")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("skip_empty")
+                .long("skip_empty")
+                .help("Skip genomes yielding no k-mer (empty files, all bases ambiguous, all sequences shorter than k, or no hash kept by --scaled) with a warning instead of failing")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("skip_bad_files")
+                .long("skip_bad_files")
+                .help("Skip genome files that cannot be read or sketched (missing, truncated or corrupt) with a warning instead of failing, listing them in --failed_inputs")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("failed_inputs")
+                .long("failed_inputs")
+                .value_name("FAILED_INPUTS_FILE")
+                .help("Tab separated list of the genomes skipped by --skip_bad_files and of their error")
+                .default_value("failed_inputs.txt")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("dry_run")
+                .long("dry_run")
+                .help("Check the parameters, the genome lists and the first record of every genome, print the estimated memory and time of the run and exit without sketching")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("preprocess_cmd")
+                .long("preprocess_cmd")
+                .value_name("COMMAND")
+                .help("Shell command each genome file is piped through before sketching, its FASTA/FASTQ output (gzip allowed) is sketched, e.g. \"seqkit seq -m 1000\"")
                 .action(ArgAction::Set),
         )
         .arg(
@@ -297,14 +371,96 @@ fn main() {
                 .value_parser(clap::value_parser!(usize))
                 .action(ArgAction::Set),
         )
+        .arg(
+            Arg::new("tree_threads")
+                .long("tree_threads")
+                .value_name("THREADS")
+                .help("Number of threads of the tree construction (RapidNJ/Hybrid search, bionj, bme and NNI), which can differ from the sketching and distance threads [default: --threads]")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
         .arg(
             Arg::new("tree_method")
                 .long("tree")
                 .value_name("TREE_METHOD")
-                .help("Tree construction method: naive, rapidnj, hybrid")
+                .help("Tree construction method: naive, rapidnj, hybrid (neighbor-joining), bionj (variance weighted neighbor-joining), bme (balanced minimum evolution with SPR moves from the bionj tree) or upgma (rooted ultrametric tree)")
                 .default_value("rapidnj")
                 .action(ArgAction::Set),
         )
+        .arg(
+            Arg::new("root")
+                .long("root")
+                .value_name("ROOTING")
+                .help("Rooting of the output tree: none (as built), midpoint (midpoint of the longest path between two leaves) or mad (minimal ancestor deviation, more robust to rate variation)")
+                .default_value("none")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("outgroup")
+                .long("outgroup")
+                .value_name("LABEL,...")
+                .help("Root the output tree on the branch separating these taxa (tree labels, comma separated) from the others, failing if they are not monophyletic in the tree")
+                .conflicts_with("root")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("nni")
+                .long("nni")
+                .value_name("CRITERION")
+                .help("Refine the tree by nearest neighbor interchanges shortening its length: none, bme (balanced minimum evolution) or ols (ordinary least squares)")
+                .default_value("none")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("branch_lengths")
+                .long("branch_lengths")
+                .value_name("ESTIMATION")
+                .help("Branch lengths of the tree: tree (as estimated by the tree method, or the --nni criterion) or ols (refitted by ordinary least squares against all the distances on the final topology)")
+                .default_value("tree")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("collapse_below")
+                .long("collapse_below")
+                .value_name("LENGTH")
+                .help("Collapse the internal branches shorter than this into polytomies, their children joining the node above, instead of showing an arbitrary resolution; 0 for none")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("collapse_support")
+                .long("collapse_support")
+                .value_name("PERCENT")
+                .help("Collapse the internal branches with a --bootstrap or --jackknife support below this percentage into polytomies; 0 for none")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("negative_branches")
+                .long("negative_branches")
+                .value_name("POLICY")
+                .help("Negative branch lengths of neighbor-joining: keep, zero (set to 0) or redistribute (set to 0, subtracting the negative length from the sibling branch); adjusted branches are counted in a warning")
+                .default_value("keep")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("internal_labels")
+                .long("internal_labels")
+                .value_name("LABELS")
+                .help("Labels of the internal nodes of the tree: support (bootstrap or jackknife support, when computed), ids (N1, N2... in pre-order from the root) or none")
+                .default_value("support")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("ladderize")
+                .long("ladderize")
+                .value_name("ORDER")
+                .help("Rotate the internal nodes of the tree by clade size: none, asc (smallest clades first) or desc (largest first)")
+                .default_value("none")
+                .action(ArgAction::Set),
+        )
         .arg(
             Arg::new("chunk_size")
                 .long("chunk_size")
@@ -321,69 +477,1326 @@ fn main() {
                 .value_parser(clap::value_parser!(usize))
                 .action(ArgAction::Set),
         )
+        .arg(
+            Arg::new("gpu")
+                .long("gpu")
+                .help("Compute the distance matrix on a CUDA GPU, falling back to CPU when none is available (needs the cuda build feature)")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("matrix_file")
+                .long("matrix_file")
+                .value_name("FILE")
+                .help("Keep the distance matrix in this memory-mapped file (upper triangle of native endian f64) instead of memory, for very large numbers of genomes; --tree upgma and bionj keep their working copy in a file next to it and bme reads it in place, while the other neighbor-joining methods copy it into memory")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("checkpoint")
+                .long("checkpoint")
+                .value_name("FILE")
+                .help("Write completed rows of the distance matrix to this checkpoint file as they are computed, removed once the matrix is done")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("resume")
+                .long("resume")
+                .help("Continue an interrupted run from its --checkpoint file instead of starting over")
+                .requires("checkpoint")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("shard")
+                .long("shard")
+                .value_name("i/N")
+                .help("Compute only shard i (from 0) of N of the distance matrix, e.g. as one job of a job array, written to --output_shard; no other output or tree is produced, see --merge_shards")
+                .requires("output_shard")
+                .conflicts_with_all(["query_list", "output_matrix", "output_npy", "output_containment", "output_pairs"])
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_shard")
+                .long("output_shard")
+                .value_name("SHARD_FILE")
+                .help("Output the rows of the --shard of the distance matrix to a file")
+                .requires("shard")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("exact_tree")
+                .long("exact_tree")
+                .help("Build the tree from full precision distances instead of distances rounded to 6 decimals as in the PHYLIP output")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("diagonal")
+                .long("diagonal")
+                .help("Write self comparison values on the matrix diagonal instead of 0")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("prescreen")
+                .long("prescreen")
+                .value_name("NB_REGISTERS")
+                .help("Skip the full comparison of pairs whose fingerprints over this many registers share nothing, they get the saturated distance (0 disables, 64 is a good start for broad collections)")
+                .default_value("0")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("lsh_bands")
+                .long("lsh_bands")
+                .value_name("NB_BANDS")
+                .help("Compare only pairs agreeing on all registers of at least one of this many bands, plus a background sample, the others getting the saturated distance; a pair with Jaccard J is compared with probability 1-(1-J^R)^B (0 disables)")
+                .default_value("0")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("lsh_rows")
+                .long("lsh_rows")
+                .value_name("R")
+                .help("Registers per LSH band, fewer finding more distant pairs")
+                .default_value("4")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("lsh_background")
+                .long("lsh_background")
+                .value_name("FRACTION")
+                .help("Fraction of the pairs left out by LSH banding that are compared all the same")
+                .default_value("0.01")
+                .value_parser(clap::value_parser!(f64))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("distance_kind")
+                .long("distance")
+                .value_name("DISTANCE")
+                .help("Statistic compared: minhash (sketch Jaccard index), cosine or d2s (alignment free distances of full k-mer count profiles, for small genomes like viruses or plasmids, memory grows with genome size)")
+                .default_value("minhash")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("distance_model")
+                .long("distance_model")
+                .value_name("MODEL")
+                .help("Transform of Jaccard indices into distances: bindash (single precision, never exactly 0), mash (exact Mash distance, 0 for identical sketches, 1 when nothing is shared), containment (1 - C^(1/k) from the containment C of the row genome in the column genome, an asymmetric matrix symmetrized for the tree by --symmetrize; needs --scaled)")
+                .default_value("bindash")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("weighting")
+                .long("weighting")
+                .value_name("WEIGHTING")
+                .help("Weighting of shared hashes: none, idf (hashes found in many input genomes, e.g. from mobile elements, weigh less, computed from the sketches; not with --bbits)")
+                .default_value("none")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("max_dist")
+                .long("max_dist")
+                .value_name("DISTANCE")
+                .help("Cap all distances at this value, avoiding the unstable huge distances of genomes sharing almost no hashes (0 disables). NaN or infinite distances, e.g. of genomes sharing no hash, get this value, or without it the largest finite distance of the sketches")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("min_shared")
+                .long("min_shared")
+                .value_name("NB_HASHES")
+                .help("Pairs sharing fewer hashes get NA in --output_pairs and --max_dist in the matrix (0 disables, needs --max_dist)")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("0")
+                .requires("max_dist")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("max_report_dist")
+                .long("max_report_dist")
+                .value_name("DISTANCE")
+                .help("Write only pairs at this distance or closer to --output_pairs, a sparse edge list instead of all pairs (0 disables)")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0")
+                .requires("output_pairs")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("refine_sketch_size")
+                .long("refine_sketch_size")
+                .value_name("SKETCH_SIZE")
+                .help("Re-sketch genomes of coarse clusters (see --refine_threshold) at this larger size and recompute within cluster distances, between cluster distances keep the -s resolution (0 disables)")
+                .default_value("0")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("refine_threshold")
+                .long("refine_threshold")
+                .value_name("DISTANCE")
+                .help("Single linkage distance defining the coarse clusters refined by --refine_sketch_size")
+                .default_value("0.05")
+                .value_parser(clap::value_parser!(f64))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("refine_pairs")
+                .long("refine_pairs")
+                .help("Refine only the pairs closer than --refine_threshold instead of whole single linkage clusters, avoiding the quadratic cost of large chained clusters")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("symmetrize")
+                .long("symmetrize")
+                .value_name("POLICY")
+                .help("How d(i,j) and d(j,i) of asymmetric distances are combined for tree construction: min, max, mean")
+                .default_value("mean")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_values")
+                .long("output")
+                .value_name("VALUES")
+                .help("Values of --output_matrix, --output_pairs and --output_knn: distance, ani (100*(1-d), in percent); the tree is always built from distances")
+                .default_value("distance")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("matrix_format")
+                .long("matrix_format")
+                .value_name("FORMAT")
+                .help("Format of --output_matrix and --output_containment: phylip, phylip_lower (lower-triangular), tsv, csv (labelled square matrices readable by pandas or R)")
+                .default_value("phylip")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("phylip_names")
+                .long("phylip_names")
+                .value_name("NAMES")
+                .help("Row names of PHYLIP matrices: relaxed (full names, whitespace replaced by _) or strict (truncated to 10 characters, made unique)")
+                .default_value("relaxed")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("precision")
+                .long("precision")
+                .value_name("DIGITS")
+                .help("Digits after the decimal point of values in PHYLIP matrices")
+                .value_parser(clap::value_parser!(usize))
+                .default_value("6")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("scientific")
+                .long("scientific")
+                .help("Write values of PHYLIP matrices in scientific notation, so that tiny distances between near-identical strains are not rounded to zero")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("output_matrix")
                 .long("output_matrix")
                 .value_name("OUTPUT_MATRIX_FILE")
-                .help("Output the phylip distance matrix to a file")
+                .help("Output the distance matrix to a file, see --matrix_format")
+                .required(false)
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_query")
+                .long("output_query")
+                .value_name("OUTPUT_QUERY_FILE")
+                .help("Output the tab separated matrix of --query genomes (rows) against reference genomes (columns), or with --hnsw their --knn nearest references, tab separated query, reference, rank and distance")
+                .requires("query_list")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_npy")
+                .long("output_npy")
+                .value_name("NPY_FILE")
+                .help("Output the distance matrix as a float64 NumPy .npy file, labels written one per line to a .labels.txt file next to it")
+                .required(false)
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_containment")
+                .long("output_containment")
+                .value_name("OUTPUT_CONTAINMENT_FILE")
+                .help("Output the asymmetric matrix of containments C(row, column), the fraction of the row genome k-mers found in the column genome, to spot genomes (e.g. MAGs, plasmids) included in others")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_pairs")
+                .long("output_pairs")
+                .value_name("OUTPUT_PAIRS_FILE")
+                .help("Stream pairwise distances to a long format table (genome_a, genome_b, distance, see --pair_details for more columns), one line per pair, with resumable offset markers")
                 .required(false)
                 .action(ArgAction::Set),
         )
+        .arg(
+            Arg::new("pairs_format")
+                .long("pairs_format")
+                .value_name("FORMAT")
+                .help("Format of --output_pairs: tsv, jsonl (streamed as pairs are computed), parquet or arrow (IPC file); only tsv can be resumed or sorted, parquet and arrow need the arrow build feature")
+                .default_value("tsv")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("resume_pairs")
+                .long("resume_pairs")
+                .help("Continue an interrupted --output_pairs file after its last offset marker")
+                .requires("output_pairs")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("pair_details")
+                .long("pair_details")
+                .help("Add the Jaccard index and the number of shared hashes after the distance in --output_pairs")
+                .requires("output_pairs")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("pvalues")
+                .long("pvalues")
+                .help("Add a column of Mash p-values to --output_pairs, the probability of sharing as many hashes by chance given the genome sizes")
+                .requires("output_pairs")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("aligned_fraction")
+                .long("aligned_fraction")
+                .help("Add the estimated aligned fractions of both genomes to --output_pairs (af_a, af_b), telling apart a high identity over most of the genomes from the same identity over a small part")
+                .requires("output_pairs")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("confidence")
+                .long("confidence")
+                .value_name("LEVEL")
+                .help("Add the bounds of a confidence interval at this level (e.g. 0.95) to --output_pairs, from the number of shared hashes out of the sketch size (0 disables)")
+                .value_parser(clap::value_parser!(f64))
+                .default_value("0")
+                .requires("output_pairs")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("sort_pairs")
+                .long("sort_pairs")
+                .help("Sort --output_pairs by increasing distance, with sorted chunks spilled to --tmp_dir and merged, so that huge outputs need little memory")
+                .requires("output_pairs")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("tmp_dir")
+                .long("tmp_dir")
+                .value_name("DIR")
+                .help("Directory for temporary files of --sort_pairs [default: system temporary directory]")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_stats")
+                .long("output_stats")
+                .value_name("OUTPUT_STATS_FILE")
+                .help("Write a per genome QC table (sequences, bases, k-mers, file size, sketch time)")
+                .required(false)
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("bootstrap")
+                .long("bootstrap")
+                .value_name("N")
+                .help("Annotate the tree with the support of its splits (percent) in N bootstrap replicates, whose distances and trees are computed from resampled sketch registers, without sketching again")
+                .default_value("0")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with_all(["input_matrix", "merge_shards"])
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("jackknife")
+                .long("jackknife")
+                .value_name("N")
+                .help("Same as --bootstrap, with N jackknife replicates each dropping a fraction of the sketch registers")
+                .default_value("0")
+                .value_parser(clap::value_parser!(usize))
+                .conflicts_with_all(["input_matrix", "merge_shards", "bootstrap"])
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("jackknife_drop")
+                .long("jackknife_drop")
+                .value_name("FRACTION")
+                .help("Fraction of the sketch registers dropped by each jackknife replicate")
+                .default_value("0.5")
+                .value_parser(clap::value_parser!(f64))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_replicates")
+                .long("output_replicates")
+                .value_name("OUTPUT_REPLICATES_FILE")
+                .help("Write the trees of the --bootstrap or --jackknife replicates, one Newick tree per line")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_consensus")
+                .long("output_consensus")
+                .value_name("OUTPUT_CONSENSUS_FILE")
+                .help("Write the majority-rule consensus of the --bootstrap or --jackknife replicate trees in Newick format, labelled with the support of its splits")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("tree_format")
+                .long("tree_format")
+                .value_name("FORMAT")
+                .help("Format of --output_tree and --output_consensus: newick, nexus (TREES block with a translate table) or phyloxml (leaves annotated with their file, genome size and cluster at --cluster_threshold)")
+                .default_value("newick")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("cluster_threshold")
+                .long("cluster_threshold")
+                .value_name("DIST")
+                .help("Distance threshold of the clusters of genomes (see --linkage) written to --output_clusters and annotating the leaves in phyloxml and iTOL output")
+                .default_value("0.05")
+                .value_parser(clap::value_parser!(f64))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("linkage")
+                .long("linkage")
+                .value_name("LINKAGE")
+                .help("Agglomerative clustering of the genomes cut at --cluster_threshold: single, average or complete linkage")
+                .default_value("single")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_clusters")
+                .long("output_clusters")
+                .value_name("CLUSTER_FILE")
+                .help("Output the flat clusters of the genomes at --cluster_threshold, tab separated genome and cluster number, to a file")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_histogram")
+                .long("output_histogram")
+                .value_name("HISTOGRAM_FILE")
+                .help("Output the histogram of the distances of all pairs of genomes, tab separated bin bounds and count, to a file, e.g. to choose clustering thresholds; a summary of the distances (quantiles, pairs at the largest distance) is printed by every run")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("histogram_bins")
+                .long("histogram_bins")
+                .value_name("BINS")
+                .help("Number of bins of equal width from 0 to the largest distance in --output_histogram")
+                .default_value("50")
+                .value_parser(clap::value_parser!(usize))
+                .requires("output_histogram")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("pcoa")
+                .long("pcoa")
+                .value_name("PCOA_FILE")
+                .help("Output the principal coordinates (PCoA) of the genomes on the first --pcoa_axes axes, tab separated after a comment line with the fraction of the variance explained by each axis, to a file")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("pcoa_axes")
+                .long("pcoa_axes")
+                .value_name("AXES")
+                .help("Number of principal coordinates in --pcoa, between 2 and 10")
+                .default_value("10")
+                .value_parser(clap::value_parser!(usize))
+                .requires("pcoa")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("umap")
+                .long("umap")
+                .value_name("UMAP_FILE")
+                .help("Output a UMAP plane embedding of the genomes from the graph of their --knn nearest neighbors (of the HNSW index with --hnsw), tab separated genome and coordinates, to a file, to visualize collections too large for a readable tree")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_knn")
+                .long("output_knn")
+                .value_name("KNN_FILE")
+                .help("Output the graph of the --knn nearest neighbors of each genome, an edge list of genome, neighbor, rank and distance (or ANI, see --output), to a file")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("knn")
+                .long("knn")
+                .value_name("K")
+                .help("Number of nearest neighbors of each genome in --output_knn and the --hnsw tree, and of each --query genome with --hnsw")
+                .default_value("10")
+                .value_parser(clap::value_parser!(usize))
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("knn_format")
+                .long("knn_format")
+                .value_name("FORMAT")
+                .help("Format of --output_knn: tsv or graphml (directed edges)")
+                .default_value("tsv")
+                .requires("output_knn")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("hnsw")
+                .long("hnsw")
+                .help("Search an HNSW graph of the register sketches of the -i genomes for --output_knn, --umap and --query instead of comparing all pairs, approximate but scaling to millions of genomes; no matrix is computed, --output_tree being built by neighbor-joining on the graph of the --knn nearest neighbors only")
+                .conflicts_with_all(["bootstrap", "jackknife", "output_matrix", "output_npy", "output_pairs", "output_clusters", "output_histogram", "pcoa", "input_matrix", "merge_shards", "shard", "gpu"])
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("save_index")
+                .long("save_index")
+                .value_name("INDEX_PREFIX")
+                .help("Save the HNSW index to the files <INDEX_PREFIX>.hnsw.graph, .hnsw.data and .hnsw.genomes")
+                .requires("hnsw")
+                .conflicts_with("load_index")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("load_index")
+                .long("load_index")
+                .value_name("INDEX_PREFIX")
+                .help("Load an HNSW index saved by --save_index instead of sketching -i genomes, the sketch options being those it was built with")
+                .requires("hnsw")
+                .conflicts_with("input_list")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_cophenetic")
+                .long("output_cophenetic")
+                .value_name("OUTPUT_COPHENETIC_FILE")
+                .help("Write the cophenetic (patristic) distances of the tree, see --matrix_format, and print their least-squares fit to the distance matrix")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_fit")
+                .long("output_fit")
+                .value_name("OUTPUT_FIT_FILE")
+                .help("Write the distance, cophenetic distance and residual of each pair of genomes, and print the least-squares fit of the tree to the distance matrix")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("output_tree_stats")
+                .long("output_tree_stats")
+                .value_name("OUTPUT_TREE_STATS_FILE")
+                .help("Write the total length, maximum tip depth, near-zero and negative branch counts and branch length quantiles of the tree")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("plot")
+                .long("plot")
+                .value_name("SVG_FILE")
+                .help("Draw the tree as a rectangular phylogram with tip labels and branch lengths, in SVG")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("itol")
+                .long("itol")
+                .value_name("PREFIX")
+                .help("Write iTOL annotation files PREFIX.itol_clusters.txt (leaves colored by cluster at --cluster_threshold), PREFIX.itol_nearest.txt (heat strip of the nearest neighbor distance) and, with --label_map, PREFIX.itol_labels.txt")
+                .action(ArgAction::Set),
+        )
+        .arg(
+            Arg::new("label_map")
+                .long("label_map")
+                .value_name("LABEL_MAP_FILE")
+                .help("Tab separated genome file names (or paths) and display names, for the iTOL leaf labels")
+                .requires("itol")
+                .action(ArgAction::Set),
+        )
         .arg(
             Arg::new("output_tree")
                 .long("output_tree")
                 .value_name("OUTPUT_TREE_FILE")
-                .help("Output the resulting tree in Newick format to a file")
-                .required(true)
+                .help("Output the resulting tree to a file, see --tree_format; runs writing --output_matrix, --output_npy, --output_clusters, --output_knn, --output_histogram, --pcoa or --umap may leave it out to skip the tree")
+                .required_unless_present_any(["query_list", "shard", "output_matrix", "output_npy", "output_clusters", "output_knn", "output_histogram", "pcoa", "umap", "hnsw"])
                 .action(ArgAction::Set),
         )
+        .group(ArgGroup::new("references").args(["input_list", "load_index"]))
         .get_matches();
 
-    let input_list = matches.get_one::<String>("input_list").unwrap().to_string();
-    let kmer_size = *matches.get_one::<usize>("kmer_size").unwrap();
+    if let Err(e) = run(&matches) {
+        eprintln!("Error: {}", e);
+        std::process::exit(e.exit_code());
+    }
+}
+
+fn run(matches: &ArgMatches) -> Result<(), Error> {
+    if matches.subcommand_matches("selftest").is_some() {
+        selftest().map_err(|errors| Error::Runtime(format!("selftest failed:\n{}", errors)))?;
+        println!("selftest passed");
+        return Ok(());
+    }
+    if let Some(compare) = matches.subcommand_matches("compare_trees") {
+        let (tree1, tree2) = (compare.get_one::<String>("tree1").unwrap(), compare.get_one::<String>("tree2").unwrap());
+        let comparison = compare_trees(&read_file(tree1)?, &read_file(tree2)?).input(&format!("{} or {}", tree1, tree2))?;
+        print!("{}", comparison.to_tsv());
+        return Ok(());
+    }
+    if let Some(extract) = matches.subcommand_matches("extract_clade") {
+        let path = extract.get_one::<String>("tree").unwrap();
+        let newick = read_file(path)?;
+        let taxa: Vec<String> = extract.get_many::<String>("taxa").unwrap().map(|taxon| genome_label(taxon)).collect();
+        let tree = Tree::from_newick(&newick).and_then(|tree| tree.clade(&taxa)).input(path)?;
+        write_newick(extract.get_one::<String>("output"), &tree.to_newick())?;
+        return Ok(());
+    }
+    if let Some(prune) = matches.subcommand_matches("prune") {
+        let path = prune.get_one::<String>("tree").unwrap();
+        let newick = read_file(path)?;
+        let taxa = read_taxa(prune.get_one::<String>("taxa").unwrap())?;
+        let tree = Tree::from_newick(&newick).and_then(|tree| tree.prune(&taxa)).input(path)?;
+        write_newick(prune.get_one::<String>("output"), &tree.to_newick())?;
+        return Ok(());
+    }
+
+    let input_list = matches.get_one::<String>("input_list").cloned();
+    let input_matrix = matches.get_one::<String>("input_matrix").cloned();
+    let shard_files: Option<Vec<String>> = matches.get_many::<String>("merge_shards").map(|v| v.cloned().collect());
+    let query_list = matches.get_one::<String>("query_list").cloned();
+    let input_values = matches.get_one::<String>("input_values").unwrap();
+    let input_matrix_format = matches.get_one::<String>("input_matrix_format").unwrap();
+    let previous_matrix = matches.get_one::<String>("previous_matrix").cloned();
+    let previous_tree = matches.get_one::<String>("previous_tree").map(|path| read_file(path)).transpose()?;
+    let kmer_sizes: Vec<usize> = matches.get_many::<usize>("kmer_size").unwrap().copied().collect();
     let sketch_size = *matches.get_one::<usize>("sketch_size").unwrap();
-    let dens = *matches.get_one::<usize>("dens_opt").unwrap();
+    let algo = matches.get_one::<String>("algo").unwrap();
+    let scaled = *matches.get_one::<usize>("scaled").unwrap();
+    let bbits = *matches.get_one::<u32>("bbits").unwrap();
+    let dens = matches.get_one::<String>("dens_opt").unwrap();
+    let seed = *matches.get_one::<u64>("seed").unwrap();
+    let hash = matches.get_one::<String>("hash").unwrap();
+    let ambig_policy = matches.get_one::<String>("ambig").unwrap();
+    let skip_softmasked = matches.get_flag("skip_softmasked");
+    let min_entropy = *matches.get_one::<f64>("min_entropy").unwrap();
+    let skip_empty = matches.get_flag("skip_empty");
+    let skip_bad_files = matches.get_flag("skip_bad_files");
+    let failed_inputs = skip_bad_files.then(|| matches.get_one::<String>("failed_inputs").unwrap().clone());
+    let dry_run = matches.get_flag("dry_run");
+    let preprocess_cmd = matches.get_one::<String>("preprocess_cmd").cloned();
     let threads = *matches.get_one::<usize>("threads").unwrap();
+    let tree_threads = matches.get_one::<usize>("tree_threads").copied().unwrap_or(0);
     let tree_method = matches.get_one::<String>("tree_method").unwrap();
+    let root = matches.get_one::<String>("root").unwrap();
+    let outgroup = matches.get_one::<String>("outgroup").cloned();
+    let nni = matches.get_one::<String>("nni").unwrap();
+    let branch_lengths = matches.get_one::<String>("branch_lengths").unwrap();
+    let negative_branches = matches.get_one::<String>("negative_branches").unwrap();
+    let collapse_below = *matches.get_one::<f64>("collapse_below").unwrap();
+    let collapse_support = *matches.get_one::<f64>("collapse_support").unwrap();
+    let internal_labels = matches.get_one::<String>("internal_labels").unwrap();
+    let ladderize = matches.get_one::<String>("ladderize").unwrap();
     let chunk_size = *matches.get_one::<usize>("chunk_size").unwrap();
     let naive_percentage = *matches.get_one::<usize>("naive_percentage").unwrap();
+    let exact_tree = matches.get_flag("exact_tree");
+    let bootstrap = *matches.get_one::<usize>("bootstrap").unwrap();
+    let jackknife = *matches.get_one::<usize>("jackknife").unwrap();
+    let jackknife_drop = *matches.get_one::<f64>("jackknife_drop").unwrap();
+    let diagonal = matches.get_flag("diagonal");
+    let prescreen = *matches.get_one::<usize>("prescreen").unwrap();
+    let lsh_bands = *matches.get_one::<usize>("lsh_bands").unwrap();
+    let lsh_rows = *matches.get_one::<usize>("lsh_rows").unwrap();
+    let lsh_background = *matches.get_one::<f64>("lsh_background").unwrap();
+    let gpu = matches.get_flag("gpu");
+    let matrix_file = matches.get_one::<String>("matrix_file").cloned();
+    let checkpoint = matches.get_one::<String>("checkpoint").cloned();
+    let resume = matches.get_flag("resume");
+    let shard = matches.get_one::<String>("shard").cloned();
+    let output_shard = matches.get_one::<String>("output_shard").cloned();
+    let distance_kind = matches.get_one::<String>("distance_kind").unwrap();
+    let distance_model = matches.get_one::<String>("distance_model").unwrap();
+    let weighting = matches.get_one::<String>("weighting").unwrap();
+    let max_dist = *matches.get_one::<f64>("max_dist").unwrap();
+    let min_shared = *matches.get_one::<usize>("min_shared").unwrap();
+    let max_report_dist = *matches.get_one::<f64>("max_report_dist").unwrap();
+    let refine_sketch_size = *matches.get_one::<usize>("refine_sketch_size").unwrap();
+    let refine_threshold = *matches.get_one::<f64>("refine_threshold").unwrap();
+    let refine_pairs = matches.get_flag("refine_pairs");
+    let symmetrize = matches.get_one::<String>("symmetrize").unwrap();
+    let output_values = matches.get_one::<String>("output_values").unwrap();
+    let matrix_format = matches.get_one::<String>("matrix_format").unwrap();
+    let phylip_names = matches.get_one::<String>("phylip_names").unwrap();
+    let precision = Precision {
+        digits: *matches.get_one::<usize>("precision").unwrap(),
+        scientific: matches.get_flag("scientific"),
+    };
     let output_matrix = matches.get_one::<String>("output_matrix").cloned();
+    let output_query = matches.get_one::<String>("output_query").cloned();
+    let output_npy = matches.get_one::<String>("output_npy").cloned();
+    let output_containment = matches.get_one::<String>("output_containment").cloned();
+    let output_pairs = matches.get_one::<String>("output_pairs").cloned();
+    let pairs_format = matches.get_one::<String>("pairs_format").unwrap();
+    let resume_pairs = matches.get_flag("resume_pairs");
+    let pair_details = matches.get_flag("pair_details");
+    let pvalues = matches.get_flag("pvalues");
+    let aligned_fraction = matches.get_flag("aligned_fraction");
+    let confidence = *matches.get_one::<f64>("confidence").unwrap();
+    let sort = matches.get_flag("sort_pairs");
+    let tmp_dir = matches.get_one::<String>("tmp_dir").map(PathBuf::from).unwrap_or_else(std::env::temp_dir);
+    let output_stats = matches.get_one::<String>("output_stats").cloned();
+    let output_replicates = matches.get_one::<String>("output_replicates").cloned();
+    let output_consensus = matches.get_one::<String>("output_consensus").cloned();
+    let tree_format = matches.get_one::<String>("tree_format").unwrap();
+    let cluster_threshold = *matches.get_one::<f64>("cluster_threshold").unwrap();
+    let linkage = matches.get_one::<String>("linkage").unwrap();
+    let output_clusters = matches.get_one::<String>("output_clusters").cloned();
+    let output_histogram = matches.get_one::<String>("output_histogram").cloned();
+    let histogram_bins = *matches.get_one::<usize>("histogram_bins").unwrap();
+    let pcoa = matches.get_one::<String>("pcoa").cloned();
+    let pcoa_axes = *matches.get_one::<usize>("pcoa_axes").unwrap();
+    let umap = matches.get_one::<String>("umap").cloned();
+    let output_knn = matches.get_one::<String>("output_knn").cloned();
+    let knn = *matches.get_one::<usize>("knn").unwrap();
+    let hnsw = matches.get_flag("hnsw");
+    let save_index = matches.get_one::<String>("save_index").cloned();
+    let load_index = matches.get_one::<String>("load_index").cloned();
+    let knn_format: GraphFormat = matches.get_one::<String>("knn_format").unwrap().parse().parameter("--knn_format")?;
+    let output_cophenetic = matches.get_one::<String>("output_cophenetic").cloned();
+    let output_fit = matches.get_one::<String>("output_fit").cloned();
+    let output_tree_stats = matches.get_one::<String>("output_tree_stats").cloned();
+    let plot = matches.get_one::<String>("plot").cloned();
+    let itol = matches.get_one::<String>("itol").cloned();
+    let label_map = matches.get_one::<String>("label_map").map(|path| read_label_map(path)).transpose()?.unwrap_or_default();
     let output_tree = matches.get_one::<String>("output_tree").cloned();
+    // matrix-only runs skip the tree
+    let tree_wanted = output_tree.is_some()
+        || output_tree_stats.is_some()
+        || output_cophenetic.is_some()
+        || output_fit.is_some()
+        || plot.is_some()
+        || itol.is_some()
+        || output_replicates.is_some()
+        || output_consensus.is_some();
 
-    let tree_algo: TreeAlgo = tree_method.parse().expect("Invalid tree method");
+    let tree_algo: TreeAlgo = tree_method.parse().parameter("--tree")?;
+    let nni: Nni = nni.parse().parameter("--nni")?;
+    let branch_lengths: BranchLengths = branch_lengths.parse().parameter("--branch_lengths")?;
+    let negative_branches: NegativeBranches = negative_branches.parse().parameter("--negative_branches")?;
+    let internal_labels: InternalLabels = internal_labels.parse().parameter("--internal_labels")?;
+    let ladderize: Ladderize = ladderize.parse().parameter("--ladderize")?;
+    let linkage: Linkage = linkage.parse().parameter("--linkage")?;
+    let rooting = match outgroup {
+        Some(outgroup) => Rooting::Outgroup(outgroup.split(',').map(|label| label.trim().to_string()).collect()),
+        None => root.parse().parameter("--root")?,
+    };
+    let ambig: AmbigPolicy = ambig_policy.parse().parameter("--ambig")?;
+    let symmetrization: Symmetrization = symmetrize.parse().parameter("--symmetrize")?;
+    let algo: SketchAlgo = algo.parse().parameter("--algo")?;
+    let input_values: InputValues = input_values.parse().parameter("--input_values")?;
+    let input_matrix_format: MatrixFormat = input_matrix_format.parse().parameter("--input_matrix_format")?;
+    let densification: Densification = dens.parse().parameter("--densification")?;
+    let hash: HashFunction = hash.parse().parameter("--hash")?;
+    let distance_kind: DistanceKind = distance_kind.parse().parameter("--distance")?;
+    let distance_model: DistanceModel = distance_model.parse().parameter("--distance_model")?;
+    let matrix_format: MatrixFormat = matrix_format.parse().parameter("--matrix_format")?;
+    let phylip_names: PhylipNames = phylip_names.parse().parameter("--phylip_names")?;
+    let tree_format: TreeFormat = tree_format.parse().parameter("--tree_format")?;
+    let pairs_format: PairsFormat = pairs_format.parse().parameter("--pairs_format")?;
+    if pairs_format != PairsFormat::Tsv && (resume_pairs || sort) {
+        return Err(Error::Parameter("--resume_pairs and --sort_pairs need --pairs_format tsv".to_string()));
+    }
+    if (output_replicates.is_some() || output_consensus.is_some()) && bootstrap == 0 && jackknife == 0 {
+        return Err(Error::Parameter("--output_replicates and --output_consensus need --bootstrap or --jackknife".to_string()));
+    }
+    if previous_matrix.is_some() && kmer_sizes.len() > 1 {
+        return Err(Error::Parameter("--previous_matrix holds the distances of a single k-mer size".to_string()));
+    }
+    if histogram_bins == 0 {
+        return Err(Error::Parameter("--histogram_bins must be positive".to_string()));
+    }
+    if !(2..=10).contains(&pcoa_axes) {
+        return Err(Error::Parameter(format!("--pcoa_axes must be between 2 and 10, got {}", pcoa_axes)));
+    }
+    let previous_matrix = previous_matrix
+        .map(|path| File::open(&path).map_err(|e| e.to_string()).and_then(|file| DistMatrix::from_phylip(BufReader::new(file))).input(&path))
+        .transpose()?;
+    let weighting: Weighting = weighting.parse().parameter("--weighting")?;
+    let output_values: OutputValues = output_values.parse().parameter("--output")?;
+
+    let mut builder = PipelineBuilder::new()
+        .kmers(&kmer_sizes)
+        .sketch_size(sketch_size)
+        .algo(algo)
+        .scaled(scaled)
+        .bbits(bbits)
+        .densification(densification)
+        .seed(seed)
+        .hash(hash)
+        .ambig(ambig)
+        .skip_softmasked(skip_softmasked)
+        .min_entropy(min_entropy)
+        .skip_empty(skip_empty)
+        .skip_bad_files(skip_bad_files)
+        .diagonal(diagonal)
+        .prescreen(prescreen)
+        .lsh_bands(lsh_bands)
+        .lsh_rows(lsh_rows)
+        .lsh_background(lsh_background)
+        .gpu(gpu)
+        .distance_kind(distance_kind)
+        .distance_model(distance_model)
+        .weighting(weighting)
+        .max_dist(max_dist)
+        .min_shared(min_shared)
+        .max_report_dist(max_report_dist)
+        .pair_details(pair_details)
+        .pvalues(pvalues)
+        .aligned_fraction(aligned_fraction)
+        .confidence(confidence)
+        .output_values(output_values)
+        .refine_sketch_size(refine_sketch_size)
+        .refine_threshold(refine_threshold)
+        .refine_pairs(refine_pairs)
+        .symmetrization(symmetrization)
+        .tree(tree_algo)
+        .root(rooting)
+        .nni(nni)
+        .branch_lengths(branch_lengths)
+        .negative_branches(negative_branches)
+        .collapse_below(collapse_below)
+        .collapse_support(collapse_support)
+        .internal_labels(internal_labels)
+        .ladderize(ladderize)
+        .chunk_size(chunk_size)
+        .naive_percentage(naive_percentage)
+        .tree_threads(tree_threads)
+        .cluster_threshold(cluster_threshold)
+        .linkage(linkage)
+        .exact_tree(exact_tree)
+        .bootstrap(bootstrap)
+        .jackknife(jackknife)
+        .jackknife_drop(jackknife_drop)
+        .resume(resume);
+    if let Some(path) = matrix_file {
+        builder = builder.matrix_file(path);
+    }
+    if let Some(path) = checkpoint {
+        builder = builder.checkpoint(path);
+    }
+    if let Some(shard) = shard {
+        builder = builder.shard(shard.parse().parameter("--shard")?);
+    }
+    if let Some(command) = preprocess_cmd {
+        builder = builder.preprocessor(Arc::new(CommandPreprocessor::new(&command)));
+    }
+    let config = builder.build().parameter("Invalid parameters")?;
 
     ThreadPoolBuilder::new()
         .num_threads(threads)
         .build_global()
-        .unwrap();
+        .runtime("Cannot create the thread pool")?;
+    if dry_run {
+        let place_queries = matches.subcommand_matches("place").and_then(|place| place.get_one::<String>("queries"));
+        let lists: Vec<&String> = [input_list.as_ref(), query_list.as_ref(), place_queries].into_iter().flatten().collect();
+        return check_run(&config, &lists, threads, tree_wanted);
+    }
+    // filled by each sketching stage, empty when all the genomes could be sketched
+    if let Some(path) = failed_inputs.as_ref() {
+        write_file(path, |f| writeln!(f, "genome\terror"))?;
+    }
 
-    let file = File::open(&input_list).expect("Cannot open input genome list file");
-    let reader = BufReader::new(file);
-    let genomes: Vec<String> = reader
-        .lines()
-        .map(|line| line.expect("Error reading genome list"))
-        .collect();
+    if let Some(place) = matches.subcommand_matches("place") {
+        let input_list = input_list.ok_or(Error::Parameter("place needs the reference genomes of the tree, given with -i".to_string()))?;
+        let genomes = read_genome_list(&input_list)?;
+        let queries = read_genome_list(place.get_one::<String>("queries").unwrap())?;
+        let tree = place.get_one::<String>("tree").unwrap();
+        let newick = read_file(tree)?;
+        println!("Sketching all genomes...");
+        let (sketches, stats) = config.sketch(&genomes).map_err(Error::Input)?;
+        record_failures(failed_inputs.as_ref(), &stats)?;
+        println!("Sketching query genomes...");
+        let (query_sketches, stats) = config.sketch(&queries).map_err(Error::Input)?;
+        record_failures(failed_inputs.as_ref(), &stats)?;
+        let genomes: Vec<String> = genomes.iter().filter(|g| sketches.contains_key(*g)).cloned().collect();
+        let queries: Vec<String> = queries.iter().filter(|q| query_sketches.contains_key(*q)).cloned().collect();
+        println!("Placing query genomes...");
+        let newick = config.place(&newick, &query_sketches, &queries, &sketches, &genomes).input(tree)?;
+        write_newick(place.get_one::<String>("output"), &newick)?;
+        return Ok(());
+    }
 
-    let sketch_args = SeqSketcherParams::new(kmer_size, sketch_size, SketchAlgo::OPTDENS, DataType::DNA);
+    if let Some(derep) = matches.subcommand_matches("derep") {
+        let input_list = input_list.ok_or(Error::Parameter("derep needs the genomes to dereplicate, given with -i".to_string()))?;
+        let genomes = read_genome_list(&input_list)?;
+        let ani = *derep.get_one::<f64>("ani").unwrap();
+        let representative: Representative = derep.get_one::<String>("representative").unwrap().parse().parameter("--representative")?;
+        if !(0.0..=100.0).contains(&ani) {
+            return Err(Error::Parameter(format!("--ani must be a percentage between 0 and 100, got {}", ani)));
+        }
+        println!("Sketching all genomes...");
+        let (sketches, stats) = config.sketch(&genomes).map_err(Error::Input)?;
+        record_failures(failed_inputs.as_ref(), &stats)?;
+        let genomes: Vec<String> = genomes.iter().filter(|g| sketches.contains_key(*g)).cloned().collect();
+        println!("Dereplicating genomes...");
+        let clusters = config.derep(&sketches, &stats, &genomes, ani, representative).map_err(Error::Runtime)?;
+        let representatives: Vec<&String> = (0..genomes.len()).filter(|&i| clusters[i].0 == i).map(|i| &genomes[i]).collect();
+        println!("{} genomes in {} clusters at {}% ANI", genomes.len(), representatives.len(), ani);
+        let mut text = String::new();
+        for representative in representatives {
+            text.push_str(&format!("{}\n", representative));
+        }
+        match derep.get_one::<String>("output") {
+            Some(path) => write_file(path, |f| f.write_all(text.as_bytes()))?,
+            None => print!("{}", text),
+        }
+        if let Some(path) = derep.get_one::<String>("clusters") {
+            write_file(path, |f| {
+                writeln!(f, "representative\tgenome\tani")?;
+                for (genome, &(representative, distance)) in genomes.iter().zip(&clusters) {
+                    writeln!(f, "{}\t{}\t{}", genomes[representative], genome, OutputValues::Ani.transform(distance))?;
+                }
+                Ok(())
+            })?;
+        }
+        return Ok(());
+    }
+
+    if let Some(components) = matches.subcommand_matches("components") {
+        let input_list = input_list.ok_or(Error::Parameter("components needs the genomes of the graph, given with -i".to_string()))?;
+        let genomes = read_genome_list(&input_list)?;
+        let ani = *components.get_one::<f64>("ani").unwrap();
+        let representative: Representative = components.get_one::<String>("representative").unwrap().parse().parameter("--representative")?;
+        if !(0.0..=100.0).contains(&ani) {
+            return Err(Error::Parameter(format!("--ani must be a percentage between 0 and 100, got {}", ani)));
+        }
+        println!("Sketching all genomes...");
+        let (sketches, stats) = config.sketch(&genomes).map_err(Error::Input)?;
+        record_failures(failed_inputs.as_ref(), &stats)?;
+        let genomes: Vec<String> = genomes.iter().filter(|g| sketches.contains_key(*g)).cloned().collect();
+        println!("Building the graph of genomes...");
+        let edges = config.threshold_graph(&sketches, &genomes, ani).map_err(Error::Runtime)?;
+        let component = connected_components(genomes.len(), &edges);
+        let nb_components = component.iter().max().map_or(0, |&c| c + 1);
+        println!("{} genomes and {} edges in {} connected components at {}% ANI", genomes.len(), edges.len(), nb_components, ani);
+        let clique = components.get_flag("cliques").then(|| clique_partition(genomes.len(), &edges));
+        if let Some(clique) = clique.as_ref() {
+            println!("{} cliques", clique.iter().max().map_or(0, |&c| c + 1));
+        }
+        let representatives = config.representatives(&sketches, &stats, &genomes, &component, representative).map_err(Error::Runtime)?;
+        let mut text = String::from(if clique.is_some() { "genome\tcomponent\tclique\trepresentative\n" } else { "genome\tcomponent\trepresentative\n" });
+        for (i, genome) in genomes.iter().enumerate() {
+            let representative = &genomes[representatives[component[i]]];
+            match clique.as_ref() {
+                Some(clique) => text.push_str(&format!("{}\t{}\t{}\t{}\n", genome, component[i], clique[i], representative)),
+                None => text.push_str(&format!("{}\t{}\t{}\n", genome, component[i], representative)),
+            }
+        }
+        match components.get_one::<String>("output") {
+            Some(path) => write_file(path, |f| f.write_all(text.as_bytes()))?,
+            None => print!("{}", text),
+        }
+        if let Some(path) = components.get_one::<String>("representatives") {
+            write_file(path, |f| representatives.iter().try_for_each(|&r| writeln!(f, "{}", genomes[r])))?;
+        }
+        if let Some(path) = components.get_one::<String>("edges") {
+            write_file(path, |f| {
+                writeln!(f, "genome1\tgenome2\tani")?;
+                for &(i, j, distance) in &edges {
+                    writeln!(f, "{}\t{}\t{}", genomes[i], genomes[j], OutputValues::Ani.transform(distance))?;
+                }
+                Ok(())
+            })?;
+        }
+        return Ok(());
+    }
+
+    if hnsw {
+        if kmer_sizes.len() > 1 {
+            return Err(Error::Parameter("--hnsw takes a single k-mer size".to_string()));
+        }
+        if output_knn.is_none() && output_tree.is_none() && umap.is_none() && query_list.is_none() {
+            return Err(Error::Parameter("--hnsw needs --output_knn, --output_tree, --umap or --query".to_string()));
+        }
+        let index = match load_index.as_ref() {
+            Some(prefix) => {
+                println!("Loading the index...");
+                SketchIndex::load(&config, prefix).map_err(Error::Input)?
+            }
+            None => {
+                let genomes = read_genome_list(&input_list.unwrap())?;
+                println!("Sketching all genomes...");
+                let (sketches, stats) = config.sketch(&genomes).map_err(Error::Input)?;
+                record_failures(failed_inputs.as_ref(), &stats)?;
+                if let Some(filename) = output_stats.as_ref() {
+                    write_stats(&stats, filename).map_err(Error::Runtime)?;
+                }
+                let genomes: Vec<String> = genomes.iter().filter(|g| sketches.contains_key(*g)).cloned().collect();
+                println!("Building the index...");
+                SketchIndex::build(&config, &sketches, &genomes).parameter("--hnsw")?
+            }
+        };
+        if let Some(prefix) = save_index.as_ref() {
+            index.save(&config, prefix).map_err(Error::Runtime)?;
+        }
+        let neighbors = (output_knn.is_some() || output_tree.is_some() || umap.is_some()).then(|| {
+            println!("Searching nearest neighbors...");
+            index.nearest_neighbors(&config, knn)
+        });
+        if let Some(filename) = output_knn.as_ref() {
+            write_file(filename, |f| write_knn(f, index.labels(), neighbors.as_ref().unwrap(), knn_format, output_values))?;
+        }
+        if let Some(filename) = output_tree.as_ref() {
+            println!("Building sparse neighbor-joining tree...");
+            let means = index.mean_distances(&config);
+            let newick = config.sparse_tree(index.labels(), neighbors.as_ref().unwrap(), &means).runtime("Cannot build the tree")?;
+            let tree = format_tree(tree_format, &newick, &HashMap::new()).runtime("Cannot format the tree")?;
+            write_file(filename, |f| f.write_all(tree.as_bytes()))?;
+        }
+        if let Some(filename) = umap.as_ref() {
+            println!("Computing UMAP embedding...");
+            let embedding = umap_embedding(neighbors.as_ref().unwrap(), None);
+            write_file(filename, |f| write_embedding(f, index.labels(), &embedding))?;
+        }
+        if let Some(filename) = query_list.as_ref() {
+            let queries = read_genome_list(filename)?;
+            println!("Sketching query genomes...");
+            let (query_sketches, stats) = config.sketch(&queries).map_err(Error::Input)?;
+            record_failures(failed_inputs.as_ref(), &stats)?;
+            let queries: Vec<String> = queries.iter().filter(|q| query_sketches.contains_key(*q)).cloned().collect();
+            let signatures: Vec<&Signature> = queries.iter().map(|q| &query_sketches[q]).collect();
+            println!("Searching query genomes...");
+            let neighbors = index.search(&config, &signatures, knn).parameter("--query")?;
+            let labels: Vec<String> = queries.iter().map(|q| genome_label(q)).collect();
+            write_file(output_query.as_ref().unwrap(), |f| write_query_neighbors(f, &labels, index.labels(), &neighbors, output_values))?;
+        }
+        return Ok(());
+    }
+
+    // several k-mer sizes give one matrix, and one set of output files, per k
+    let multi_k = kmer_sizes.len() > 1;
+    let matrices: Vec<TreeInput> = if let Some(filename) = input_matrix {
+        println!("Reading distance matrix...");
+        let file = File::open(&filename).input(&filename)?;
+        let matrix = DistMatrix::read_format(BufReader::new(file), input_matrix_format).input(&filename)?;
+        let matrix = matrix.to_distances(input_values).input(&filename)?;
+        vec![TreeInput { config, kmer_size: None, matrix, replicates: Vec::new(), stats: Vec::new() }]
+    } else if let Some(filenames) = shard_files {
+        println!("Merging distance matrix shards...");
+        let matrix = merge_shards(&filenames).map_err(Error::Input)?;
+        if let Some(filename) = output_matrix.as_ref() {
+            write_file(filename, |f| matrix.to_values(output_values).write_format(f, matrix_format, phylip_names, precision))?;
+        }
+        vec![TreeInput { config, kmer_size: None, matrix, replicates: Vec::new(), stats: Vec::new() }]
+    } else {
+        let genomes = read_genome_list(&input_list.unwrap())?;
 
-    println!("Sketching all genomes...");
-    let sketches = sketch_genomes(kmer_size, dens, &sketch_args, &genomes);
+        println!("Sketching all genomes...");
+        let sketched = config.sketch_multi_k(&genomes).map_err(Error::Input)?;
+        record_failures(failed_inputs.as_ref(), sketched.iter().flat_map(|(_, (_, stats))| stats))?;
+        let mut sketched_queries = match query_list {
+            Some(filename) => {
+                let queries = read_genome_list(&filename)?;
+                println!("Sketching query genomes...");
+                let sketched = config.sketch_multi_k(&queries).map_err(Error::Input)?;
+                record_failures(failed_inputs.as_ref(), sketched.iter().flat_map(|(_, (_, stats))| stats))?;
+                Some((queries, sketched.into_iter()))
+            }
+            None => None,
+        };
+        let mut matrices = Vec::new();
+        for (config, (sketches, stats)) in sketched {
+            let kmer_size = if multi_k { Some(config.kmer_size()) } else { None };
+            if let Some(filename) = output_stats.as_ref() {
+                write_stats(&stats, &kmer_path(filename, kmer_size)).map_err(Error::Runtime)?;
+            }
+            let genomes: Vec<String> = genomes.iter().filter(|g| sketches.contains_key(*g)).cloned().collect();
 
-    println!("Building PHYLIP distance matrix...");
-    let phylip_data = build_distance_matrix(&sketches, kmer_size, &genomes);
+            if let Some((queries, sketched)) = sketched_queries.as_mut() {
+                let (_, (query_sketches, _)) = sketched.next().unwrap();
+                let queries: Vec<String> = queries.iter().filter(|q| query_sketches.contains_key(*q)).cloned().collect();
+                println!("Computing query distances...");
+                let matrix = config.query_matrix(&query_sketches, &queries, &sketches, &genomes).map_err(Error::Runtime)?;
+                write_file(&kmer_path(output_query.as_ref().unwrap(), kmer_size), |f| matrix.to_values(output_values).write_tsv(f))?;
+                continue;
+            }
 
-    if let Some(filename) = output_matrix.as_ref() {
-        let mut f = BufWriter::new(File::create(filename).expect("Cannot create matrix file"));
-        f.write_all(&phylip_data).expect("Error writing matrix");
+            println!("Building PHYLIP distance matrix...");
+            let matrix = match previous_matrix.as_ref() {
+                Some(previous) => config.update_distance_matrix(previous, &sketches, &genomes),
+                None => config.distance_matrix(&sketches, &genomes),
+            }
+            .map_err(Error::Runtime)?;
+            if let Some(filename) = output_shard.as_ref() {
+                config.write_shard(&matrix, &genomes, &kmer_path(filename, kmer_size)).map_err(Error::Runtime)?;
+                continue;
+            }
+            let matrix = config.refine(matrix, &genomes).map_err(Error::Runtime)?;
+
+            if let Some(filename) = output_pairs.as_ref() {
+                let filename = kmer_path(filename, kmer_size);
+                println!("Writing pairwise distances...");
+                if sort {
+                    // the unsorted file keeps its offset markers so that it can still be resumed
+                    let unsorted = format!("{}.unsorted", filename);
+                    config.pairs(&matrix, &sketches, &stats, &genomes, (&unsorted, pairs_format), resume_pairs).map_err(Error::Runtime)?;
+                    println!("Sorting pairwise distances...");
+                    sort_pairs(&unsorted, &filename, &tmp_dir).map_err(Error::Runtime)?;
+                    std::fs::remove_file(&unsorted).runtime(format!("Cannot remove {}", unsorted))?;
+                } else {
+                    config.pairs(&matrix, &sketches, &stats, &genomes, (&filename, pairs_format), resume_pairs).map_err(Error::Runtime)?;
+                }
+            }
+
+            // distances are written as they are, other values from a transformed copy
+            let values = (output_values != OutputValues::Distance && (output_matrix.is_some() || output_npy.is_some()))
+                .then(|| matrix.to_values(output_values));
+            let values = values.as_ref().unwrap_or(&matrix);
+            if let Some(filename) = output_matrix.as_ref() {
+                write_file(&kmer_path(filename, kmer_size), |f| values.write_format(f, matrix_format, phylip_names, precision))?;
+            }
+            if let Some(filename) = output_npy.as_ref() {
+                let filename = kmer_path(filename, kmer_size);
+                write_file(&filename, |f| values.write_npy(f))?;
+                let labels_path = Path::new(&filename).with_extension("labels.txt");
+                write_file(&labels_path.to_string_lossy(), |f| matrix.labels().iter().try_for_each(|label| writeln!(f, "{}", label)))?;
+            }
+            if let Some(filename) = output_containment.as_ref() {
+                let containment = config.containment_matrix(&sketches, &stats, &genomes).map_err(Error::Runtime)?;
+                write_file(&kmer_path(filename, kmer_size), |f| containment.write_format(f, matrix_format, phylip_names, precision))?;
+            }
+            let replicates = if tree_wanted && config.nb_replicates() > 0 {
+                println!("Computing resampled replicates...");
+                config.replicate_trees(&sketches, &genomes).map_err(Error::Runtime)?
+            } else {
+                Vec::new()
+            };
+            matrices.push(TreeInput { config, kmer_size, matrix, replicates, stats });
+        }
+        matrices
+    };
+    for TreeInput { config, kmer_size, matrix, .. } in &matrices {
+        if let Some(summary) = config.distance_summary(matrix) {
+            match kmer_size {
+                Some(k) => println!("k = {}: {}", k, summary),
+                None => println!("{}", summary),
+            }
+        }
+        if let Some(filename) = output_histogram.as_ref() {
+            write_file(&kmer_path(filename, *kmer_size), |f| config.write_histogram(f, matrix, histogram_bins))?;
+        }
+    }
+    if !tree_wanted && output_clusters.is_none() && output_knn.is_none() && pcoa.is_none() && umap.is_none() {
+        return Ok(());
+    }
+
+    for TreeInput { config, kmer_size, matrix, replicates, stats } in matrices {
+        if let Some(filename) = output_clusters.as_ref() {
+            let clusters = config.clusters(&matrix);
+            write_file(&kmer_path(filename, kmer_size), |f| {
+                writeln!(f, "genome\tcluster")?;
+                for (label, cluster) in matrix.labels().iter().zip(clusters) {
+                    writeln!(f, "{}\t{}", label, cluster)?;
+                }
+                Ok(())
+            })?;
+        }
+        if let Some(filename) = output_knn.as_ref() {
+            let neighbors = nearest_neighbors(&matrix, knn);
+            write_file(&kmer_path(filename, kmer_size), |f| write_knn(f, matrix.labels(), &neighbors, knn_format, output_values))?;
+        }
+        if let Some(filename) = pcoa.as_ref() {
+            println!("Computing principal coordinates...");
+            let coordinates = config.pcoa(&matrix, pcoa_axes);
+            write_file(&kmer_path(filename, kmer_size), |f| write_pcoa(f, matrix.labels(), &coordinates))?;
+        }
+        if let Some(filename) = umap.as_ref() {
+            println!("Computing UMAP embedding...");
+            let embedding = config.umap(&matrix, knn);
+            write_file(&kmer_path(filename, kmer_size), |f| write_embedding(f, matrix.labels(), &embedding))?;
+        }
+        if !tree_wanted {
+            continue;
+        }
+        let mut newick = match previous_tree.as_ref() {
+            Some(previous) => {
+                println!("Updating the tree...");
+                config.update_tree(previous, &matrix).input(matches.get_one::<String>("previous_tree").unwrap())?
+            }
+            None => {
+                println!("Constructing the tree...");
+                config.tree(&matrix).runtime("Cannot build the tree")?
+            }
+        };
+        if !replicates.is_empty() {
+            newick = config.support(&newick, &replicates).runtime("Cannot compute split support")?;
+        }
+        let newick = config.collapse(newick).runtime("Cannot collapse branches")?;
+        let newick = config.label_internal_nodes(newick).runtime("Cannot label internal nodes")?;
+        let properties = if tree_format == TreeFormat::PhyloXml {
+            config.tip_properties(&matrix, &stats)
+        } else {
+            HashMap::new()
+        };
+        if let Some(filename) = output_replicates.as_ref() {
+            write_file(&kmer_path(filename, kmer_size), |f| replicates.iter().try_for_each(|replicate| writeln!(f, "{}", replicate)))?;
+        }
+        if let Some(filename) = output_consensus.as_ref() {
+            let consensus = config.consensus(&replicates).runtime("Cannot build the consensus tree")?;
+            let consensus = format_tree(tree_format, &consensus, &properties).runtime("Cannot format the consensus tree")?;
+            write_file(&kmer_path(filename, kmer_size), |f| f.write_all(consensus.as_bytes()))?;
+        }
+
+        if output_cophenetic.is_some() || output_fit.is_some() {
+            let (cophenetic, fit) = config.tree_fit(&matrix, &newick).runtime("Cannot compute cophenetic distances")?;
+            println!(
+                "Tree fit over {} pairs: residual sum of squares {}, cophenetic correlation {:.4}, percent standard deviation {:.2}",
+                fit.nb_pairs, fit.rss, fit.cophenetic_correlation, fit.percent_sd
+            );
+            if let Some(filename) = output_cophenetic.as_ref() {
+                write_file(&kmer_path(filename, kmer_size), |f| cophenetic.write_format(f, matrix_format, phylip_names, precision))?;
+            }
+            if let Some(filename) = output_fit.as_ref() {
+                write_file(&kmer_path(filename, kmer_size), |f| write_residuals(f, &matrix, &cophenetic))?;
+            }
+        }
+        if let Some(filename) = output_tree_stats.as_ref() {
+            let tree_stats = tree_stats(&newick).runtime("Cannot compute tree statistics")?;
+            write_file(&kmer_path(filename, kmer_size), |f| f.write_all(tree_stats.to_tsv().as_bytes()))?;
+        }
+        if let Some(prefix) = itol.as_ref() {
+            for (suffix, text) in config.itol_annotations(&matrix, &label_map) {
+                write_file(&kmer_path(&format!("{}{}", prefix, suffix), kmer_size), |f| f.write_all(text.as_bytes()))?;
+            }
+        }
+        if let Some(filename) = plot.as_ref() {
+            let svg = tree_svg(&newick).runtime("Cannot plot the tree")?;
+            write_file(&kmer_path(filename, kmer_size), |f| f.write_all(svg.as_bytes()))?;
+        }
+
+        let tree = format_tree(tree_format, &newick, &properties).runtime("Cannot format the tree")?;
+        if let Some(filename) = output_tree.as_ref() {
+            write_file(&kmer_path(filename, kmer_size), |f| f.write_all(tree.as_bytes()))?;
+        } else {
+            print!("{}", tree);
+        }
+    }
+    Ok(())
+}
+
+// A distance matrix to build a tree from, with its k-mer size when several are used, the trees of its
+// resampled replicates, and the QC stats of its genomes when sketched
+struct TreeInput {
+    config: PipelineConfig,
+    kmer_size: Option<usize>,
+    matrix: DistMatrix,
+    replicates: Vec<String>,
+    stats: Vec<(String, SeqStats)>,
+}
+
+// Tree labels, one per line, genome files standing for their label
+fn read_taxa(path: &str) -> Result<Vec<String>, Error> {
+    Ok(read_lines(path)?.iter().map(|line| line.trim()).filter(|line| !line.is_empty()).map(genome_label).collect())
+}
+
+// Newick tree to a file, or the standard output
+fn write_newick(path: Option<&String>, newick: &str) -> Result<(), Error> {
+    match path {
+        Some(path) => write_file(path, |f| writeln!(f, "{}", newick)),
+        None => {
+            println!("{}", newick);
+            Ok(())
+        }
     }
+}
 
-    println!("Constructing the tree...");
-    let newick = build_tree(&tree_algo, chunk_size, naive_percentage, &phylip_data);
+// Display names of genomes, by label
+fn read_label_map(path: &str) -> Result<HashMap<String, String>, Error> {
+    read_lines(path)?
+        .into_iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let (genome, name) = line.split_once('\t').ok_or(format!("expected two tab separated columns: {}", line)).input(path)?;
+            Ok((genome_label(genome.trim()), name.trim().to_string()))
+        })
+        .collect()
+}
 
-    if let Some(filename) = output_tree {
-        let mut f = BufWriter::new(File::create(filename).expect("Cannot create tree file"));
-        writeln!(f, "{}", newick).expect("Error writing tree");
+// Appends the genomes skipped as bad files, with their error, to the failed input list `path`,
+// once per genome whatever the number of k-mer sizes
+fn record_failures<'a>(path: Option<&String>, stats: impl IntoIterator<Item = &'a (String, SeqStats)>) -> Result<(), Error> {
+    let Some(path) = path else {
+        return Ok(());
+    };
+    let mut seen = HashSet::new();
+    let failed: Vec<(&String, &String)> = stats
+        .into_iter()
+        .filter_map(|(genome, s)| s.error.as_ref().map(|e| (genome, e)))
+        .filter(|(genome, _)| seen.insert(*genome))
+        .collect();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    println!("{} genomes could not be read or sketched and were skipped, see {}", failed.len(), path);
+    let file = OpenOptions::new().append(true).open(path).runtime(format!("Cannot open {}", path))?;
+    let mut f = BufWriter::new(file);
+    failed
+        .iter()
+        .try_for_each(|(genome, e)| writeln!(f, "{}\t{}", genome, e.strip_prefix(&format!("{}: ", genome)).unwrap_or(e)))
+        .and_then(|_| f.flush())
+        .runtime(format!("Error writing {}", path))
+}
+
+// Dry run: checks the genomes of the `lists` without sketching them and prints the budget of the
+// run, the unreadable genomes being reported all at once or skipped with --skip_bad_files
+fn check_run(config: &PipelineConfig, lists: &[&String], threads: usize, tree: bool) -> Result<(), Error> {
+    let mut genomes = Vec::new();
+    for list in lists {
+        genomes.extend(read_genome_list(list)?);
+    }
+    println!("Checking {} genomes...", genomes.len());
+    let checked: Vec<(String, Result<u64, String>)> =
+        genomes.into_par_iter().map(|genome| (genome.clone(), check_genome(&genome, config))).collect();
+    let mut sizes = Vec::new();
+    let mut errors = Vec::new();
+    for (genome, checked) in checked {
+        match checked {
+            Ok(size) => sizes.push((genome, size)),
+            Err(e) => errors.push(e),
+        }
+    }
+    for e in &errors {
+        eprintln!("{}", e);
+    }
+    if !errors.is_empty() && !config.skip_bad_files() {
+        return Err(Error::Input(format!("{} genomes cannot be read, use --skip_bad_files to skip them", errors.len())));
+    }
+    if !errors.is_empty() {
+        println!("{} genomes cannot be read and would be skipped", errors.len());
+    }
+    if lists.is_empty() {
+        println!("Parameters are valid, no genome list to check");
     } else {
-        println!("{}", newick);
+        println!("Parameters and genomes are valid, estimated budget:");
+        println!("{}", config.budget(&sizes, threads, tree));
+    }
+    Ok(())
+}
+
+fn read_genome_list(path: &str) -> Result<Vec<String>, Error> {
+    let genomes: Vec<String> = read_lines(path)?.into_iter().filter(|line| !line.trim().is_empty()).collect();
+    let unique = unique_genomes(&genomes).input(path)?;
+    if unique.len() < genomes.len() {
+        println!("{} genomes listed more than once in {}, kept once", genomes.len() - unique.len(), path);
     }
+    Ok(unique)
+}
+
+fn read_lines(path: &str) -> Result<Vec<String>, Error> {
+    let file = File::open(path).input(path)?;
+    BufReader::new(file).lines().collect::<Result<_, _>>().input(path)
+}
+
+fn read_file(path: &str) -> Result<String, Error> {
+    std::fs::read_to_string(path).input(path)
+}
+
+// Writes the output file `path` with `write`, errors naming the file
+fn write_file(path: &str, write: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>) -> Result<(), Error> {
+    let mut f = BufWriter::new(File::create(path).runtime(format!("Cannot create {}", path))?);
+    write(&mut f).and_then(|_| f.flush()).runtime(format!("Error writing {}", path))
 }
@@ -0,0 +1,803 @@
+//! Labelled distance matrix shared by the distance, output and tree stages.
+
+use ndarray::Array2;
+use serde::{Deserialize, Serialize};
+use memmap2::MmapMut;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::ops::{Deref, DerefMut, Range};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::dist::Symmetrization;
+
+/// Kind of values held by an imported matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputValues {
+    Distance,
+    /// average nucleotide identity, in percent or as a fraction
+    Ani,
+    /// similarity in [0, 1], e.g. a Jaccard index
+    Similarity,
+}
+
+impl FromStr for InputValues {
+    type Err = String;
+    fn from_str(s: &str) -> Result<InputValues, String> {
+        match s.to_lowercase().as_str() {
+            "distance" => Ok(InputValues::Distance),
+            "ani" => Ok(InputValues::Ani),
+            "similarity" => Ok(InputValues::Similarity),
+            _ => Err(format!("Unknown matrix value kind: {}", s)),
+        }
+    }
+}
+
+/// Kind of values written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputValues {
+    Distance,
+    /// average nucleotide identity in percent, `100 (1 - d)`
+    Ani,
+}
+
+impl FromStr for OutputValues {
+    type Err = String;
+    fn from_str(s: &str) -> Result<OutputValues, String> {
+        match s.to_lowercase().as_str() {
+            "distance" => Ok(OutputValues::Distance),
+            "ani" => Ok(OutputValues::Ani),
+            _ => Err(format!("Unknown output value kind: {}", s)),
+        }
+    }
+}
+
+impl OutputValues {
+    pub fn transform(self, distance: f64) -> f64 {
+        match self {
+            OutputValues::Distance => distance,
+            OutputValues::Ani => 100.0 * (1.0 - distance),
+        }
+    }
+}
+
+/// Text format of a written square matrix.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MatrixFormat {
+    Phylip,
+    /// lower-triangular PHYLIP, row i holding its distances to rows 0 to i - 1
+    PhylipLower,
+    /// labelled tab separated matrix
+    Tsv,
+    /// labelled comma separated matrix
+    Csv,
+}
+
+impl FromStr for MatrixFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<MatrixFormat, String> {
+        match s.to_lowercase().as_str() {
+            "phylip" => Ok(MatrixFormat::Phylip),
+            "phylip_lower" => Ok(MatrixFormat::PhylipLower),
+            "tsv" => Ok(MatrixFormat::Tsv),
+            "csv" => Ok(MatrixFormat::Csv),
+            _ => Err(format!("Unknown matrix format: {}", s)),
+        }
+    }
+}
+
+/// Naming of PHYLIP rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PhylipNames {
+    /// names of any length followed by a space, whitespace and Newick punctuation in names replaced by `_`
+    Relaxed,
+    /// names truncated to the 10 characters of the original PHYLIP format, then made unique
+    Strict,
+}
+
+impl FromStr for PhylipNames {
+    type Err = String;
+    fn from_str(s: &str) -> Result<PhylipNames, String> {
+        match s.to_lowercase().as_str() {
+            "relaxed" => Ok(PhylipNames::Relaxed),
+            "strict" => Ok(PhylipNames::Strict),
+            _ => Err(format!("Unknown PHYLIP naming: {}", s)),
+        }
+    }
+}
+
+/// Number formatting of PHYLIP matrices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Precision {
+    /// digits after the decimal point
+    pub digits: usize,
+    /// scientific notation, so that tiny distances between near-identical genomes are not rounded to zero
+    pub scientific: bool,
+}
+
+impl Default for Precision {
+    fn default() -> Self {
+        Precision { digits: 6, scientific: false }
+    }
+}
+
+impl Precision {
+    fn format(&self, value: f64) -> String {
+        if self.scientific {
+            format!("{:.*e}", self.digits, value)
+        } else {
+            format!("{:width$.digits$}", value, width = self.digits + 2, digits = self.digits)
+        }
+    }
+}
+
+const STRICT_NAME_LENGTH: usize = 10;
+
+// PHYLIP row names, a word each so that the matrix can be read back, without the Newick punctuation
+// that would break trees built from it by other programs
+fn phylip_names(labels: &[String], names: PhylipNames) -> Vec<String> {
+    let words = labels.iter().map(|l| {
+        l.split_whitespace().collect::<Vec<_>>().join("_").replace(|c: char| "()[]':;,".contains(c), "_")
+    });
+    match names {
+        PhylipNames::Relaxed => words.collect(),
+        PhylipNames::Strict => {
+            let mut seen = HashSet::new();
+            let mut truncated = 0;
+            let names = words
+                .map(|word| {
+                    let mut name: String = word.chars().take(STRICT_NAME_LENGTH).collect();
+                    truncated += (name.len() < word.len()) as usize;
+                    // a duplicate ends with a counter instead of its last characters
+                    let mut counter = 1;
+                    while !seen.insert(name.clone()) {
+                        let suffix = counter.to_string();
+                        name = word.chars().take(STRICT_NAME_LENGTH.saturating_sub(suffix.len())).collect();
+                        name.push_str(&suffix);
+                        counter += 1;
+                    }
+                    name
+                })
+                .collect();
+            if truncated > 0 {
+                log::warn!("{} names truncated to {} characters in strict PHYLIP output", truncated, STRICT_NAME_LENGTH);
+            }
+            names
+        }
+    }
+}
+
+// Quotes a CSV field holding a separator or a quote
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+// Fields of a CSV line, quoted fields having their quotes doubled
+fn csv_fields(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                fields.last_mut().unwrap().push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            _ => fields.last_mut().unwrap().push(c),
+        }
+    }
+    fields
+}
+
+// Values of a matrix, in memory or in a memory-mapped file of native endian f64, removed along with
+// the values when it is a scratch file
+enum Values {
+    Memory(Vec<f64>),
+    Mapped { map: MmapMut, path: PathBuf, scratch: bool },
+}
+
+impl Drop for Values {
+    fn drop(&mut self) {
+        if let Values::Mapped { path, scratch: true, .. } = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// number of scratch files created so far, keeping their names apart
+static SCRATCH_FILES: AtomicUsize = AtomicUsize::new(0);
+
+impl Deref for Values {
+    type Target = [f64];
+    fn deref(&self) -> &[f64] {
+        match self {
+            Values::Memory(values) => values,
+            // a mapping is page aligned and its length a multiple of 8
+            Values::Mapped { map, .. } => unsafe { std::slice::from_raw_parts(map.as_ptr() as *const f64, map.len() / 8) },
+        }
+    }
+}
+
+impl DerefMut for Values {
+    fn deref_mut(&mut self) -> &mut [f64] {
+        match self {
+            Values::Memory(values) => values,
+            Values::Mapped { map, .. } => unsafe { std::slice::from_raw_parts_mut(map.as_mut_ptr() as *mut f64, map.len() / 8) },
+        }
+    }
+}
+
+impl std::fmt::Debug for Values {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+// a copy is always in memory
+impl Clone for Values {
+    fn clone(&self) -> Self {
+        Values::Memory(self.to_vec())
+    }
+}
+
+impl PartialEq for Values {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Serialize for Values {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (**self).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Values {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Vec::deserialize(deserializer).map(Values::Memory)
+    }
+}
+
+/// Square distance matrix with one label per row.
+///
+/// Symmetric matrices store only the upper triangle row by row, the diagonal included as ANI or
+/// similarity values are not zero there; asymmetric ones store the full square in row-major order.
+/// Values are in memory, or for very large matrices in a memory-mapped file (see [DistMatrix::new_mapped]).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DistMatrix {
+    labels: Vec<String>,
+    symmetric: bool,
+    values: Values,
+}
+
+fn nb_values(n: usize, symmetric: bool) -> usize {
+    if symmetric {
+        n * (n + 1) / 2
+    } else {
+        n * n
+    }
+}
+
+impl DistMatrix {
+    /// All zero matrix.
+    pub fn new(labels: Vec<String>, symmetric: bool) -> Self {
+        let size = nb_values(labels.len(), symmetric);
+        DistMatrix { labels, symmetric, values: Values::Memory(vec![0.0; size]) }
+    }
+
+    /// All zero matrix whose values live in the file at `path`, created or truncated, and are paged
+    /// in and out by the operating system, so that matrices larger than memory can be built and written.
+    /// The file holds the values as native endian f64 in storage order. Copies of the matrix are in memory.
+    pub fn new_mapped(labels: Vec<String>, symmetric: bool, path: &Path) -> Result<Self, String> {
+        DistMatrix::mapped(labels, symmetric, path, false)
+    }
+
+    fn mapped(labels: Vec<String>, symmetric: bool, path: &Path, scratch: bool) -> Result<Self, String> {
+        let size = nb_values(labels.len(), symmetric);
+        if size == 0 {
+            return Ok(DistMatrix::new(labels, symmetric));
+        }
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .map_err(|e| format!("Cannot create matrix file {}: {}", path.display(), e))?;
+        file.set_len((size * std::mem::size_of::<f64>()) as u64)
+            .map_err(|e| format!("Cannot allocate matrix file {}: {}", path.display(), e))?;
+        let map = unsafe { MmapMut::map_mut(&file) }.map_err(|e| format!("Cannot map matrix file {}: {}", path.display(), e))?;
+        Ok(DistMatrix { labels, symmetric, values: Values::Mapped { map, path: path.to_path_buf(), scratch } })
+    }
+
+    /// All zero matrix of the same labels stored like this one: in memory, or for a mapped matrix in a
+    /// scratch file next to its file, removed when the matrix is dropped, so that working copies of a
+    /// matrix larger than memory stay out of memory as well.
+    pub(crate) fn scratch(&self, symmetric: bool) -> Result<DistMatrix, String> {
+        match &self.values {
+            Values::Mapped { path, .. } => {
+                let mut name = path.clone().into_os_string();
+                name.push(format!(".{}.{}.scratch", std::process::id(), SCRATCH_FILES.fetch_add(1, Ordering::Relaxed)));
+                DistMatrix::mapped(self.labels.clone(), symmetric, Path::new(&name), true)
+            }
+            Values::Memory(_) => Ok(DistMatrix::new(self.labels.clone(), symmetric)),
+        }
+    }
+
+    /// Matrix from full rows, stored as symmetric when it is.
+    pub fn from_rows(labels: Vec<String>, rows: &[Vec<f64>]) -> Result<Self, String> {
+        let n = labels.len();
+        if rows.len() != n || rows.iter().any(|r| r.len() != n) {
+            return Err(format!("Expected a {} x {} matrix", n, n));
+        }
+        let symmetric = (0..n).all(|i| (i + 1..n).all(|j| rows[i][j] == rows[j][i]));
+        let mut matrix = DistMatrix::new(labels, symmetric);
+        for (i, row) in rows.iter().enumerate() {
+            let first = if symmetric { i } else { 0 };
+            for (j, &value) in row.iter().enumerate().skip(first) {
+                matrix.set(i, j, value);
+            }
+        }
+        Ok(matrix)
+    }
+
+    fn index(&self, i: usize, j: usize) -> usize {
+        let n = self.labels.len();
+        if !self.symmetric {
+            return i * n + j;
+        }
+        let (i, j) = if i <= j { (i, j) } else { (j, i) };
+        // rows before i hold n, n - 1, ... n - i + 1 values
+        i * (2 * n - i + 1) / 2 + (j - i)
+    }
+
+    // position of the first stored value of `row`, the number of values for `row == n`
+    pub(crate) fn row_start(&self, row: usize) -> usize {
+        let n = self.labels.len();
+        if self.symmetric {
+            row * (2 * n - row + 1) / 2
+        } else {
+            row * n
+        }
+    }
+
+    // stored values of consecutive rows
+    pub(crate) fn rows_values(&self, rows: Range<usize>) -> &[f64] {
+        &self.values[self.row_start(rows.start)..self.row_start(rows.end)]
+    }
+
+    pub(crate) fn rows_values_mut(&mut self, rows: Range<usize>) -> &mut [f64] {
+        let (start, end) = (self.row_start(rows.start), self.row_start(rows.end));
+        &mut self.values[start..end]
+    }
+
+    pub fn get(&self, i: usize, j: usize) -> f64 {
+        self.values[self.index(i, j)]
+    }
+
+    /// Sets `d(i, j)`, and `d(j, i)` as well for a symmetric matrix.
+    pub fn set(&mut self, i: usize, j: usize, value: f64) {
+        let idx = self.index(i, j);
+        self.values[idx] = value;
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    pub fn labels(&self) -> &[String] {
+        &self.labels
+    }
+
+    pub fn is_symmetric(&self) -> bool {
+        self.symmetric
+    }
+
+    /// Full rows of the matrix.
+    pub fn rows(&self) -> Vec<Vec<f64>> {
+        let n = self.len();
+        (0..n).map(|i| (0..n).map(|j| self.get(i, j)).collect()).collect()
+    }
+
+    /// Symmetric matrix with a zero diagonal, as expected by tree construction: the matrix itself when
+    /// it already is one, so that a mapped matrix is not copied into memory, a copy otherwise.
+    pub fn symmetrize(&self, policy: Symmetrization) -> Cow<'_, DistMatrix> {
+        let n = self.len();
+        if self.symmetric && (0..n).all(|i| self.get(i, i) == 0.0) {
+            return Cow::Borrowed(self);
+        }
+        let mut sym = DistMatrix::new(self.labels.clone(), true);
+        for i in 0..n {
+            for j in (i + 1)..n {
+                let (a, b) = (self.get(i, j), self.get(j, i));
+                let d = match policy {
+                    Symmetrization::Min => a.min(b),
+                    Symmetrization::Max => a.max(b),
+                    Symmetrization::Mean => (a + b) / 2.0,
+                };
+                sym.set(i, j, d);
+            }
+        }
+        Cow::Owned(sym)
+    }
+
+    /// Writes the PHYLIP square format row by row, see [PhylipNames] for row names.
+    pub fn write_phylip<W: Write>(&self, writer: &mut W, names: PhylipNames, precision: Precision) -> std::io::Result<()> {
+        let n = self.len();
+        let names = phylip_names(&self.labels, names);
+        writeln!(writer, "{}", n)?;
+        for (i, name) in names.iter().enumerate() {
+            write!(writer, "{:10}", name)?;
+            for j in 0..n {
+                write!(writer, " {}", precision.format(self.get(i, j)))?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the lower-triangular PHYLIP format, half the size of the square one.
+    /// Only `d(i, j)` with `j < i` is written, an asymmetric matrix loses its upper triangle.
+    pub fn write_phylip_lower<W: Write>(&self, writer: &mut W, names: PhylipNames, precision: Precision) -> std::io::Result<()> {
+        let n = self.len();
+        let names = phylip_names(&self.labels, names);
+        writeln!(writer, "{}", n)?;
+        for (i, name) in names.iter().enumerate() {
+            write!(writer, "{:10}", name)?;
+            for j in 0..i {
+                write!(writer, " {}", precision.format(self.get(i, j)))?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a PHYLIP square or lower-triangular (with or without diagonal) matrix,
+    /// names being the first word of each row.
+    pub fn from_phylip<R: BufRead>(reader: R) -> Result<Self, String> {
+        let mut lines = reader.lines();
+        let first = lines
+            .next()
+            .ok_or("Empty PHYLIP matrix")?
+            .map_err(|e| e.to_string())?;
+        let n: usize = first
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid PHYLIP matrix size: {}", first.trim()))?;
+        let mut labels = Vec::with_capacity(n);
+        let mut rows = Vec::with_capacity(n);
+        for line in lines.take(n) {
+            let line = line.map_err(|e| e.to_string())?;
+            let mut words = line.split_whitespace();
+            labels.push(words.next().ok_or("Empty PHYLIP row")?.to_string());
+            let row: Result<Vec<f64>, String> = words
+                .map(|w| w.parse::<f64>().map_err(|_| format!("Invalid distance in PHYLIP matrix: {}", w)))
+                .collect();
+            rows.push(row?);
+        }
+        // lower-triangular rows hold i, or i + 1 values with the diagonal
+        let lower = (0..rows.len()).all(|i| rows[i].len() == i) || (0..rows.len()).all(|i| rows[i].len() == i + 1);
+        if lower && n > 1 {
+            if labels.len() != n {
+                return Err(format!("Expected a {} x {} matrix", n, n));
+            }
+            // filled straight into the triangular storage, without a dense copy
+            let mut matrix = DistMatrix::new(labels, true);
+            for (i, row) in rows.iter().enumerate() {
+                for (j, &value) in row.iter().enumerate() {
+                    matrix.set(i, j, value);
+                }
+            }
+            return Ok(matrix);
+        }
+        DistMatrix::from_rows(labels, &rows)
+    }
+
+    /// Writes a tab separated square matrix with a header row of labels.
+    pub fn write_tsv<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for label in &self.labels {
+            write!(writer, "\t{}", label)?;
+        }
+        writeln!(writer)?;
+        for i in 0..self.len() {
+            write!(writer, "{}", self.labels[i])?;
+            for j in 0..self.len() {
+                write!(writer, "\t{}", self.get(i, j))?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a comma separated square matrix with a header row of labels, labels quoted when needed.
+    pub fn write_csv<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for label in &self.labels {
+            write!(writer, ",{}", csv_field(label))?;
+        }
+        writeln!(writer)?;
+        for i in 0..self.len() {
+            write!(writer, "{}", csv_field(&self.labels[i]))?;
+            for j in 0..self.len() {
+                write!(writer, ",{}", self.get(i, j))?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the square matrix as a little-endian float64 NumPy `.npy` array, row by row
+    /// so that large matrices are not copied; row and column order is that of [DistMatrix::labels].
+    pub fn write_npy<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        let n = self.len();
+        let mut header = format!("{{'descr': '<f8', 'fortran_order': False, 'shape': ({}, {}), }}", n, n);
+        // magic, version and header length take 10 bytes, the whole header is padded to 64 bytes
+        let padding = 63 - (10 + header.len()) % 64;
+        header.push_str(&" ".repeat(padding));
+        header.push('\n');
+        writer.write_all(b"\x93NUMPY\x01\x00")?;
+        writer.write_all(&(header.len() as u16).to_le_bytes())?;
+        writer.write_all(header.as_bytes())?;
+        let mut row = Vec::with_capacity(8 * n);
+        for i in 0..n {
+            row.clear();
+            for j in 0..n {
+                row.extend_from_slice(&self.get(i, j).to_le_bytes());
+            }
+            writer.write_all(&row)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the matrix in `format` row by row, `names` and `precision` applying to PHYLIP formats,
+    /// tab and comma separated ones holding values at full precision.
+    pub fn write_format<W: Write>(&self, writer: &mut W, format: MatrixFormat, names: PhylipNames, precision: Precision) -> std::io::Result<()> {
+        match format {
+            MatrixFormat::Phylip => self.write_phylip(writer, names, precision),
+            MatrixFormat::PhylipLower => self.write_phylip_lower(writer, names, precision),
+            MatrixFormat::Tsv => self.write_tsv(writer),
+            MatrixFormat::Csv => self.write_csv(writer),
+        }
+    }
+
+    /// Matrix written in `format` to memory, see [DistMatrix::write_format].
+    pub fn to_format(&self, format: MatrixFormat, names: PhylipNames, precision: Precision) -> Vec<u8> {
+        let mut data = Vec::new();
+        self.write_format(&mut data, format, names, precision).unwrap();
+        data
+    }
+
+    /// Reads a matrix written by [DistMatrix::write_tsv].
+    pub fn from_tsv<R: BufRead>(reader: R) -> Result<Self, String> {
+        let mut labels = Vec::new();
+        let mut rows = Vec::new();
+        for line in reader.lines().skip(1) {
+            let line = line.map_err(|e| e.to_string())?;
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = line.split('\t');
+            labels.push(fields.next().unwrap().to_string());
+            let row: Result<Vec<f64>, String> = fields
+                .map(|f| f.parse::<f64>().map_err(|_| format!("Invalid distance in TSV matrix: {}", f)))
+                .collect();
+            rows.push(row?);
+        }
+        DistMatrix::from_rows(labels, &rows)
+    }
+
+    /// Reads a matrix written by [DistMatrix::write_csv], or any labelled square CSV matrix.
+    pub fn from_csv<R: BufRead>(reader: R) -> Result<Self, String> {
+        let mut labels = Vec::new();
+        let mut rows = Vec::new();
+        for line in reader.lines().skip(1) {
+            let line = line.map_err(|e| e.to_string())?;
+            let line = line.trim_end_matches('\r');
+            if line.is_empty() {
+                continue;
+            }
+            let mut fields = csv_fields(line).into_iter();
+            labels.push(fields.next().unwrap());
+            let row: Result<Vec<f64>, String> = fields
+                .map(|f| f.trim().parse::<f64>().map_err(|_| format!("Invalid distance in CSV matrix: {}", f)))
+                .collect();
+            rows.push(row?);
+        }
+        DistMatrix::from_rows(labels, &rows)
+    }
+
+    /// Reads a matrix in `format`, PHYLIP ones being square or lower-triangular.
+    pub fn read_format<R: BufRead>(reader: R, format: MatrixFormat) -> Result<Self, String> {
+        match format {
+            MatrixFormat::Phylip | MatrixFormat::PhylipLower => DistMatrix::from_phylip(reader),
+            MatrixFormat::Tsv => DistMatrix::from_tsv(reader),
+            MatrixFormat::Csv => DistMatrix::from_csv(reader),
+        }
+    }
+
+    pub fn to_ndarray(&self) -> Array2<f64> {
+        let n = self.len();
+        Array2::from_shape_fn((n, n), |(i, j)| self.get(i, j))
+    }
+
+    pub fn from_ndarray(labels: Vec<String>, array: &Array2<f64>) -> Result<Self, String> {
+        let rows: Vec<Vec<f64>> = array.outer_iter().map(|r| r.to_vec()).collect();
+        DistMatrix::from_rows(labels, &rows)
+    }
+
+    /// Matrix of `values` computed from these distances, e.g. for output.
+    pub fn to_values(&self, values: OutputValues) -> DistMatrix {
+        let mut transformed = self.clone();
+        for v in transformed.values.iter_mut() {
+            *v = values.transform(*v);
+        }
+        transformed
+    }
+
+    /// Turns a matrix of `values` into distances: `1 - ANI/100` (or `1 - ANI` when all values are at most 1)
+    /// and `1 - similarity`. Distances are checked to be finite and non negative.
+    pub fn to_distances(&self, values: InputValues) -> Result<DistMatrix, String> {
+        let max = self.values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let transform: Box<dyn Fn(f64) -> f64> = match values {
+            InputValues::Distance => {
+                if (0..self.len()).any(|i| self.get(i, i) != 0.0) {
+                    log::warn!("non zero diagonal in a distance matrix, are these similarities or ANI values?");
+                }
+                Box::new(|d| d)
+            }
+            InputValues::Ani if max > 1.0 => Box::new(|ani| 1.0 - ani / 100.0),
+            InputValues::Ani | InputValues::Similarity => Box::new(|s| 1.0 - s),
+        };
+        let mut distances = self.clone();
+        for v in distances.values.iter_mut() {
+            *v = transform(*v);
+            if !v.is_finite() || *v < 0.0 {
+                return Err(format!("Negative or invalid distance {} after transforming {:?} values", v, values));
+            }
+        }
+        Ok(distances)
+    }
+}
+
+/// Rectangular matrix of distances from query genomes (rows) to reference genomes (columns).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct QueryMatrix {
+    queries: Vec<String>,
+    references: Vec<String>,
+    values: Vec<f64>,
+}
+
+impl QueryMatrix {
+    /// All zero matrix.
+    pub fn new(queries: Vec<String>, references: Vec<String>) -> Self {
+        let values = vec![0.0; queries.len() * references.len()];
+        QueryMatrix { queries, references, values }
+    }
+
+    pub fn get(&self, query: usize, reference: usize) -> f64 {
+        self.values[query * self.references.len() + reference]
+    }
+
+    pub fn set(&mut self, query: usize, reference: usize, value: f64) {
+        let idx = query * self.references.len() + reference;
+        self.values[idx] = value;
+    }
+
+    pub fn queries(&self) -> &[String] {
+        &self.queries
+    }
+
+    pub fn references(&self) -> &[String] {
+        &self.references
+    }
+
+    /// Matrix of `values` computed from these distances, see [DistMatrix::to_values].
+    pub fn to_values(&self, values: OutputValues) -> QueryMatrix {
+        let mut transformed = self.clone();
+        for v in transformed.values.iter_mut() {
+            *v = values.transform(*v);
+        }
+        transformed
+    }
+
+    /// Writes a tab separated matrix with a header row of reference labels, row by row.
+    pub fn write_tsv<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        write!(writer, "query")?;
+        for label in &self.references {
+            write!(writer, "\t{}", label)?;
+        }
+        writeln!(writer)?;
+        for i in 0..self.queries.len() {
+            write!(writer, "{}", self.queries[i])?;
+            for j in 0..self.references.len() {
+                write!(writer, "\t{}", self.get(i, j))?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pipeline::PipelineBuilder;
+    use crate::tree::TreeAlgo;
+
+    #[test]
+    fn symmetrization_policies() {
+        let labels: Vec<String> = ["a", "b", "c", "d"].iter().map(|l| l.to_string()).collect();
+        // containments of small genomes in large ones are higher than the reverse
+        let rows = vec![
+            vec![0.0, 0.10, 0.30, 0.40],
+            vec![0.20, 0.0, 0.25, 0.45],
+            vec![0.34, 0.15, 0.0, 0.20],
+            vec![0.50, 0.35, 0.10, 0.0],
+        ];
+        let matrix = DistMatrix::from_rows(labels.clone(), &rows).unwrap();
+        assert!(!matrix.is_symmetric());
+        let mut trees = Vec::new();
+        for policy in [Symmetrization::Min, Symmetrization::Max, Symmetrization::Mean] {
+            let sym = matrix.symmetrize(policy);
+            assert!(matches!(sym, Cow::Owned(_)) && sym.is_symmetric());
+            for (i, row) in rows.iter().enumerate() {
+                for (j, &a) in row.iter().enumerate() {
+                    let b = rows[j][i];
+                    let expected = match policy {
+                        Symmetrization::Min => a.min(b),
+                        Symmetrization::Max => a.max(b),
+                        Symmetrization::Mean => (a + b) / 2.0,
+                    };
+                    assert!((sym.get(i, j) - expected).abs() < 1e-12);
+                }
+            }
+            let config = PipelineBuilder::new().tree(TreeAlgo::BioNJ).symmetrization(policy).build().unwrap();
+            trees.push(config.tree(&matrix).unwrap());
+        }
+        // the policies give different distances, hence different branch lengths
+        assert!(trees[0] != trees[1] && trees[1] != trees[2] && trees[0] != trees[2]);
+
+        let sym = DistMatrix::from_rows(labels[..3].to_vec(), &[vec![0.0, 0.1, 0.2], vec![0.1, 0.0, 0.3], vec![0.2, 0.3, 0.0]]);
+        assert!(matches!(sym.unwrap().symmetrize(Symmetrization::Min), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn phylip_lower_round_trip() {
+        let labels: Vec<String> = (0..6).map(|i| format!("genome {}.fa", i)).collect();
+        let mut matrix = DistMatrix::new(labels, true);
+        for i in 0..6 {
+            for j in i + 1..6 {
+                matrix.set(i, j, (crate::sketch::mix64((i * 6 + j) as u64) >> 11) as f64 / (1u64 << 53) as f64);
+            }
+        }
+        let lower = matrix.to_format(MatrixFormat::PhylipLower, PhylipNames::Relaxed, Precision::default());
+        let square = matrix.to_format(MatrixFormat::Phylip, PhylipNames::Relaxed, Precision::default());
+        let read = DistMatrix::from_phylip(lower.as_slice()).unwrap();
+        let read_square = DistMatrix::from_phylip(square.as_slice()).unwrap();
+        assert!(read.is_symmetric());
+        assert_eq!(read.labels(), read_square.labels());
+        assert_eq!(read.labels()[2], "genome_2.fa");
+        for i in 0..6 {
+            for j in 0..6 {
+                // values come back at the written precision, as from the square format
+                assert!((read.get(i, j) - matrix.get(i, j)).abs() <= 5e-7);
+                assert_eq!(read.get(i, j), read_square.get(i, j));
+            }
+        }
+        // the diagonal may be written too
+        let with_diagonal = DistMatrix::from_phylip("3\na 0\nb 0.1 0\nc 0.2 0.3 0\n".as_bytes()).unwrap();
+        assert_eq!((with_diagonal.get(2, 1), with_diagonal.get(1, 2), with_diagonal.get(0, 2)), (0.3, 0.3, 0.2));
+    }
+}
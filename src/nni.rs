@@ -0,0 +1,204 @@
+//! Nearest neighbor interchange (NNI) refinement of a tree against a minimum evolution criterion.
+//!
+//! Each round computes the averages between all pairs of disjoint subtrees of the current topology,
+//! scores the two interchanges across every internal edge and applies the one shortening most the tree
+//! length, balanced (Pauplin, 2000) or ordinary least squares (Desper and Gascuel, 2002), until no
+//! interchange shortens it. Edge lengths are the estimates of the criterion on the final topology.
+
+use rayon::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::str::FromStr;
+
+use crate::bme::{replace, to_tree, Averages};
+use crate::matrix::DistMatrix;
+use crate::phylo::Tree;
+use crate::tree::leaf_distance;
+
+// length changes below this are rounding noise
+const MIN_GAIN: f64 = 1e-10;
+// interchanges around given leaves are tried on the edges between nodes this many branches away at most
+const LOCAL_RADIUS: usize = 3;
+
+/// Tree length minimized by the interchanges.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Nni {
+    None,
+    /// balanced minimum evolution
+    Bme,
+    /// ordinary least squares minimum evolution
+    Ols,
+}
+
+impl FromStr for Nni {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Nni, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Nni::None),
+            "bme" => Ok(Nni::Bme),
+            "ols" => Ok(Nni::Ols),
+            _ => Err(format!("Unknown NNI criterion: {}", s)),
+        }
+    }
+}
+
+/// Refines `tree`, a binary tree of the labels of `matrix` (rooted or not), by nearest neighbor
+/// interchanges. Distances are rounded as in [crate::tree::build_tree] unless `exact`.
+pub fn nni(tree: &Tree, matrix: &DistMatrix, exact: bool, criterion: Nni) -> Result<Tree, String> {
+    interchanges(tree, matrix, exact, criterion, None)
+}
+
+/// [nni] restricted to the edges within a few branches of the `around` leaves, e.g. just added to
+/// the tree, edge lengths being estimated again over the whole tree.
+pub fn nni_around(tree: &Tree, matrix: &DistMatrix, exact: bool, criterion: Nni, around: &[String]) -> Result<Tree, String> {
+    let positions: HashMap<&str, usize> = matrix.labels().iter().enumerate().map(|(i, label)| (label.as_str(), i)).collect();
+    let around = around
+        .iter()
+        .map(|label| positions.get(label.as_str()).copied().ok_or(format!("Unknown leaf: {}", label)))
+        .collect::<Result<Vec<usize>, String>>()?;
+    interchanges(tree, matrix, exact, criterion, Some(&around))
+}
+
+/// Edge lengths of `tree`, a binary tree of the labels of `matrix`, estimated again on its topology
+/// by ordinary least squares against all the distances. The tree comes back rooted as by [nni].
+pub fn ols_lengths(tree: &Tree, matrix: &DistMatrix, exact: bool) -> Result<Tree, String> {
+    let n = matrix.len();
+    // three leaves have a single solution, already that of the tree
+    if n < 4 {
+        return Ok(tree.clone());
+    }
+    let neighbors = unrooted_topology(tree, matrix.labels())?;
+    let averages = Averages::new(&neighbors, &|i: usize, j: usize| leaf_distance(matrix, exact, i, j), true);
+    Ok(to_tree(&neighbors, &averages, matrix.labels()))
+}
+
+fn interchanges(tree: &Tree, matrix: &DistMatrix, exact: bool, criterion: Nni, around: Option<&[usize]>) -> Result<Tree, String> {
+    let n = matrix.len();
+    if criterion == Nni::None || n < 4 {
+        return Ok(tree.clone());
+    }
+    let mut neighbors = unrooted_topology(tree, matrix.labels())?;
+    let leaf_dist = |i: usize, j: usize| leaf_distance(matrix, exact, i, j);
+    let ols = criterion == Nni::Ols;
+    let mut nb_moves = 0;
+    let averages = loop {
+        let averages = Averages::new(&neighbors, &leaf_dist, ols);
+        let local = around.map(|leaves| near(&neighbors, leaves));
+        let best = (n..neighbors.len())
+            .into_par_iter()
+            .flat_map_iter(|u| neighbors[u].iter().filter(move |&&w| w > u).map(move |&w| (u, w)))
+            .filter(|&(u, w)| local.as_ref().is_none_or(|near| near[u] && near[w]))
+            .filter_map(|(u, w)| averages.best_swap(&neighbors, u, w).map(|(gain, b, c)| (gain, u, w, b, c)))
+            .min_by(|a, b| a.partial_cmp(b).unwrap());
+        match best {
+            Some((gain, u, w, b, c)) if gain < -MIN_GAIN => {
+                // b moves from u to w, c from w to u
+                replace(&mut neighbors[u], b, c);
+                replace(&mut neighbors[w], c, b);
+                replace(&mut neighbors[b], u, w);
+                replace(&mut neighbors[c], w, u);
+                nb_moves += 1;
+            }
+            _ => break averages,
+        }
+    };
+    log::info!("{:?} nearest neighbor interchange: {} moves", criterion, nb_moves);
+    Ok(to_tree(&neighbors, &averages, matrix.labels()))
+}
+
+// Nodes at most LOCAL_RADIUS branches away from one of the `leaves`
+fn near(neighbors: &[Vec<usize>], leaves: &[usize]) -> Vec<bool> {
+    let mut depth = vec![usize::MAX; neighbors.len()];
+    let mut queue: VecDeque<usize> = leaves.iter().copied().collect();
+    for &leaf in leaves {
+        depth[leaf] = 0;
+    }
+    while let Some(node) = queue.pop_front() {
+        if depth[node] == LOCAL_RADIUS {
+            continue;
+        }
+        for &next in &neighbors[node] {
+            if depth[next] == usize::MAX {
+                depth[next] = depth[node] + 1;
+                queue.push_back(next);
+            }
+        }
+    }
+    depth.iter().map(|&d| d != usize::MAX).collect()
+}
+
+// Neighbors of the nodes of `tree` as an unrooted binary tree, its leaves first in the order of
+// `labels`, a root of degree 2 being removed
+fn unrooted_topology(tree: &Tree, labels: &[String]) -> Result<Vec<Vec<usize>>, String> {
+    let n = labels.len();
+    let positions: HashMap<&str, usize> = labels.iter().enumerate().map(|(i, label)| (label.as_str(), i)).collect();
+    let root = tree.root();
+    let suppressed = (tree.node(root).children.len() == 2).then_some(root);
+    let mut index = vec![usize::MAX; tree.len()];
+    let mut seen = vec![false; n];
+    let mut nb_internal = 0;
+    for (node, index) in index.iter_mut().enumerate() {
+        if tree.node(node).children.is_empty() {
+            let label = tree.node(node).label.as_deref().unwrap_or("");
+            let leaf = *positions.get(label).ok_or(format!("Unknown leaf in tree: {}", label))?;
+            if seen[leaf] {
+                return Err(format!("Leaf {} found twice in tree", label));
+            }
+            seen[leaf] = true;
+            *index = leaf;
+        } else if Some(node) != suppressed {
+            *index = n + nb_internal;
+            nb_internal += 1;
+        }
+    }
+    let mut neighbors = vec![Vec::new(); n + nb_internal];
+    for parent in 0..tree.len() {
+        if Some(parent) == suppressed {
+            continue;
+        }
+        for &child in &tree.node(parent).children {
+            neighbors[index[parent]].push(index[child]);
+            neighbors[index[child]].push(index[parent]);
+        }
+    }
+    if let Some(root) = suppressed {
+        let (a, b) = (index[tree.node(root).children[0]], index[tree.node(root).children[1]]);
+        neighbors[a].push(b);
+        neighbors[b].push(a);
+    }
+    if neighbors[..n].iter().any(|o| o.len() != 1) {
+        return Err("NNI needs a tree with every label of the matrix as a leaf".to_string());
+    }
+    if neighbors[n..].iter().any(|o| o.len() != 3) {
+        return Err("NNI needs a binary tree".to_string());
+    }
+    Ok(neighbors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fit::cophenetic_matrix;
+    use std::collections::HashSet;
+
+    const NEWICK: &str = "((((a:0.1,b:0.2):0.05,c:0.3):0.1,d:0.15):0.02,((e:0.1,f:0.05):0.2,(g:0.3,h:0.1):0.08):0.03);";
+    // b and d swapped, and e and g
+    const START: &str = "((((a:0.1,d:0.2):0.05,c:0.3):0.1,b:0.15):0.02,((g:0.1,f:0.05):0.2,(e:0.3,h:0.1):0.08):0.03);";
+
+    fn splits(tree: &Tree, labels: &[String]) -> HashSet<Vec<u64>> {
+        let leaves: HashMap<String, usize> = labels.iter().enumerate().map(|(i, label)| (label.clone(), i)).collect();
+        tree.splits(&leaves).unwrap().into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn recovers_additive_tree() {
+        let labels: Vec<String> = "abcdefgh".chars().map(|c| c.to_string()).collect();
+        let matrix = cophenetic_matrix(NEWICK, &labels).unwrap();
+        let expected = splits(&Tree::from_newick(NEWICK).unwrap(), &labels);
+        let start = Tree::from_newick(START).unwrap();
+        assert_ne!(splits(&start, &labels), expected);
+        for criterion in [Nni::Bme, Nni::Ols] {
+            let tree = nni(&start, &matrix, true, criterion).unwrap();
+            assert_eq!(splits(&tree, &labels), expected, "{:?}", criterion);
+        }
+    }
+}
@@ -0,0 +1,373 @@
+//! Low dimensional embeddings of the genomes: principal coordinates of a distance matrix, and UMAP
+//! of a nearest neighbor graph.
+//!
+//! Principal coordinates analysis (PCoA, classical multidimensional scaling) places the genomes along
+//! the eigenvectors of the doubly centered matrix of squared distances `B = -J D² J / 2`, scaled by
+//! the square root of their eigenvalue, so that Euclidean distances between the coordinates
+//! approximate the distances. Only the largest eigenvalues are needed: they are found by subspace
+//! iteration with Rayleigh-Ritz projection, each iteration multiplying a few vectors by `B` straight
+//! from the matrix, without storing `B`.
+//!
+//! UMAP (McInnes, Healy and Melville, 2018) needs the nearest neighbors of each genome only, so it
+//! scales to collections whose tree or matrix would be unreadable or too large. The neighbor
+//! distances of each genome are turned into edge weights relative to its nearest neighbor, and the
+//! plane layout is optimized by stochastic gradient descent, pulling the ends of the edges together
+//! and pushing random pairs apart, with the default settings of umap-learn (minimum distance 0.1).
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+use crate::matrix::DistMatrix;
+
+// vectors iterated beyond the wanted axes, speeding up convergence
+const OVERSAMPLING: usize = 10;
+const MAX_ITERATIONS: usize = 1000;
+const TOLERANCE: f64 = 1e-8;
+// UMAP curve 1 / (1 + a d^2b) for a minimum distance of 0.1 and a spread of 1
+const UMAP_A: f64 = 1.577;
+const UMAP_B: f64 = 0.8951;
+const NEGATIVE_SAMPLES: usize = 5;
+
+/// Principal coordinates of the genomes of a matrix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pcoa {
+    /// coordinates of each genome on each axis
+    pub coordinates: Vec<Vec<f64>>,
+    /// eigenvalue of each axis, largest first
+    pub eigenvalues: Vec<f64>,
+    /// fraction of the total variance (the trace of `B`) explained by each axis
+    pub explained: Vec<f64>,
+}
+
+/// Principal coordinates of the genomes of the symmetric `matrix` on (at most) its `axes` first
+/// axes, those of positive eigenvalues only.
+pub fn pcoa(matrix: &DistMatrix, axes: usize) -> Pcoa {
+    let n = matrix.len();
+    let p = (axes + OVERSAMPLING).min(n);
+    let total: f64 = (0..n).into_par_iter().map(|i| (0..n).map(|j| matrix.get(i, j).powi(2)).sum::<f64>()).sum::<f64>() / (2.0 * n.max(1) as f64);
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut q: Vec<f64> = (0..n * p).map(|_| rng.gen_range(-1.0..1.0)).collect();
+    orthonormalize(&mut q, n, p);
+    let (mut values, mut vectors) = (Vec::new(), Vec::new());
+    for iteration in 1..=MAX_ITERATIONS {
+        let z = multiply(matrix, &q, p);
+        // Rayleigh-Ritz: eigenpairs of B restricted to the span of q
+        let mut t = vec![0.0; p * p];
+        for i in 0..n {
+            for a in 0..p {
+                for b in 0..p {
+                    t[a * p + b] += q[i * p + a] * z[i * p + b];
+                }
+            }
+        }
+        let (theta, s) = symmetric_eigen(&t, p);
+        let rotate = |m: &[f64]| -> Vec<f64> {
+            let mut r = vec![0.0; n * p];
+            for i in 0..n {
+                for a in 0..p {
+                    r[i * p + a] = (0..p).map(|b| m[i * p + b] * s[b * p + a]).sum();
+                }
+            }
+            r
+        };
+        let (v, bv) = (rotate(&q), rotate(&z));
+        let scale = theta.iter().fold(0.0, |max: f64, l| max.max(l.abs())).max(f64::MIN_POSITIVE);
+        let wanted: Vec<usize> = (0..p).filter(|&a| theta[a] > TOLERANCE * scale).take(axes).collect();
+        let converged = wanted.iter().all(|&a| {
+            let residual: f64 = (0..n).map(|i| (bv[i * p + a] - theta[a] * v[i * p + a]).powi(2)).sum();
+            residual.sqrt() <= TOLERANCE * scale
+        });
+        values = wanted.iter().map(|&a| theta[a]).collect();
+        vectors = wanted.iter().map(|&a| (0..n).map(|i| v[i * p + a]).collect::<Vec<f64>>()).collect();
+        if converged {
+            break;
+        }
+        if iteration == MAX_ITERATIONS {
+            log::warn!("principal coordinates not converged after {} iterations", MAX_ITERATIONS);
+        }
+        q = bv;
+        orthonormalize(&mut q, n, p);
+    }
+    let mut coordinates = vec![Vec::with_capacity(values.len()); n];
+    for (value, mut vector) in values.iter().zip(vectors) {
+        // signs fixed by the largest coordinate, positive
+        let largest = vector.iter().fold(0.0, |largest: f64, &x| if x.abs() > largest.abs() { x } else { largest });
+        if largest < 0.0 {
+            vector.iter_mut().for_each(|x| *x = -*x);
+        }
+        for (row, x) in coordinates.iter_mut().zip(vector) {
+            row.push(x * value.sqrt());
+        }
+    }
+    let explained = values.iter().map(|value| if total > 0.0 { value / total } else { 0.0 }).collect();
+    Pcoa { coordinates, eigenvalues: values, explained }
+}
+
+// B times the p columns of the n by p row-major q, with B = -J A J / 2 for the squared distances A
+fn multiply(matrix: &DistMatrix, q: &[f64], p: usize) -> Vec<f64> {
+    let n = matrix.len();
+    let centered = center(q, n, p);
+    let mut z: Vec<f64> = (0..n)
+        .into_par_iter()
+        .flat_map_iter(|i| {
+            let mut row = vec![0.0; p];
+            for j in 0..n {
+                let a = -0.5 * matrix.get(i, j).powi(2);
+                for (r, x) in row.iter_mut().zip(&centered[j * p..(j + 1) * p]) {
+                    *r += a * x;
+                }
+            }
+            row
+        })
+        .collect();
+    z = center(&z, n, p);
+    z
+}
+
+// columns of the n by p row-major m minus their means
+fn center(m: &[f64], n: usize, p: usize) -> Vec<f64> {
+    let mut means = vec![0.0; p];
+    for i in 0..n {
+        for a in 0..p {
+            means[a] += m[i * p + a] / n as f64;
+        }
+    }
+    m.iter().enumerate().map(|(k, x)| x - means[k % p]).collect()
+}
+
+// Gram-Schmidt on the columns of the n by p row-major q, columns dependent on the previous ones set to zero
+fn orthonormalize(q: &mut [f64], n: usize, p: usize) {
+    for a in 0..p {
+        let before: f64 = (0..n).map(|i| q[i * p + a].powi(2)).sum::<f64>().sqrt();
+        for b in 0..a {
+            let dot: f64 = (0..n).map(|i| q[i * p + a] * q[i * p + b]).sum();
+            for i in 0..n {
+                q[i * p + a] -= dot * q[i * p + b];
+            }
+        }
+        let norm: f64 = (0..n).map(|i| q[i * p + a].powi(2)).sum::<f64>().sqrt();
+        let scale = if norm > 1e-10 * before { 1.0 / norm } else { 0.0 };
+        for i in 0..n {
+            q[i * p + a] *= scale;
+        }
+    }
+}
+
+// Eigenvalues, largest first, and eigenvectors (as columns, p by p row-major) of the symmetric
+// p by p row-major t, by cyclic Jacobi rotations
+fn symmetric_eigen(t: &[f64], p: usize) -> (Vec<f64>, Vec<f64>) {
+    let mut a = t.to_vec();
+    for i in 0..p {
+        for j in 0..i {
+            let mean = (a[i * p + j] + a[j * p + i]) / 2.0;
+            a[i * p + j] = mean;
+            a[j * p + i] = mean;
+        }
+    }
+    let mut v = vec![0.0; p * p];
+    for i in 0..p {
+        v[i * p + i] = 1.0;
+    }
+    let norm: f64 = a.iter().map(|x| x * x).sum::<f64>().sqrt();
+    for _ in 0..100 {
+        let off: f64 = (0..p).flat_map(|i| (0..p).filter(move |&j| j != i).map(move |j| (i, j))).map(|(i, j)| a[i * p + j].powi(2)).sum();
+        if off.sqrt() <= 1e-15 * norm {
+            break;
+        }
+        for k in 0..p {
+            for l in k + 1..p {
+                let akl = a[k * p + l];
+                if akl == 0.0 {
+                    continue;
+                }
+                let theta = (a[l * p + l] - a[k * p + k]) / (2.0 * akl);
+                let tan = theta.signum() / (theta.abs() + (theta * theta + 1.0).sqrt());
+                let cos = 1.0 / (tan * tan + 1.0).sqrt();
+                let sin = tan * cos;
+                for i in 0..p {
+                    let (aik, ail) = (a[i * p + k], a[i * p + l]);
+                    a[i * p + k] = cos * aik - sin * ail;
+                    a[i * p + l] = sin * aik + cos * ail;
+                }
+                for i in 0..p {
+                    let (aki, ali) = (a[k * p + i], a[l * p + i]);
+                    a[k * p + i] = cos * aki - sin * ali;
+                    a[l * p + i] = sin * aki + cos * ali;
+                }
+                for i in 0..p {
+                    let (vik, vil) = (v[i * p + k], v[i * p + l]);
+                    v[i * p + k] = cos * vik - sin * vil;
+                    v[i * p + l] = sin * vik + cos * vil;
+                }
+            }
+        }
+    }
+    let mut order: Vec<usize> = (0..p).collect();
+    order.sort_by(|&x, &y| a[y * p + y].total_cmp(&a[x * p + x]));
+    let values = order.iter().map(|&k| a[k * p + k]).collect();
+    let mut vectors = vec![0.0; p * p];
+    for (column, &k) in order.iter().enumerate() {
+        for i in 0..p {
+            vectors[i * p + column] = v[i * p + k];
+        }
+    }
+    (values, vectors)
+}
+
+/// UMAP plane coordinates of the genomes from their `neighbors` (adjacency lists of distances),
+/// starting from `init` (e.g. the first two principal coordinates) or from random positions.
+pub fn umap(neighbors: &[Vec<(usize, f64)>], init: Option<&[Vec<f64>]>) -> Vec<[f64; 2]> {
+    let n = neighbors.len();
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut embedding: Vec<[f64; 2]> = match init {
+        Some(init) => init.iter().map(|row| [row.first().copied().unwrap_or(0.0), row.get(1).copied().unwrap_or(0.0)]).collect(),
+        None => (0..n).map(|_| [rng.gen_range(-10.0..10.0), rng.gen_range(-10.0..10.0)]).collect(),
+    };
+    let largest = embedding.iter().flatten().fold(0.0, |largest: f64, x| largest.max(x.abs()));
+    let expansion = if largest > 0.0 { 10.0 / largest } else { 1.0 };
+    for point in embedding.iter_mut() {
+        for x in point.iter_mut() {
+            *x = *x * expansion + rng.gen_range(-1e-4..1e-4);
+        }
+    }
+    let edges = fuzzy_edges(neighbors);
+    let Some(max_weight) = edges.iter().map(|&(_, _, w)| w).reduce(f64::max) else {
+        return embedding;
+    };
+    let epochs = if n <= 10000 { 500 } else { 200 };
+    // each edge sampled in proportion to its weight, every `period` epochs
+    let edges: Vec<(usize, usize, f64)> =
+        edges.into_iter().filter(|&(_, _, w)| w >= max_weight / epochs as f64).map(|(i, j, w)| (i, j, max_weight / w)).collect();
+    let mut next: Vec<f64> = edges.iter().map(|&(_, _, period)| period).collect();
+    let mut next_negative: Vec<f64> = edges.iter().map(|&(_, _, period)| period / NEGATIVE_SAMPLES as f64).collect();
+    let clip = |x: f64| x.clamp(-4.0, 4.0);
+    for epoch in 0..epochs {
+        let alpha = 1.0 - epoch as f64 / epochs as f64;
+        for (e, &(i, j, period)) in edges.iter().enumerate() {
+            if next[e] > epoch as f64 {
+                continue;
+            }
+            let d2 = squared_distance(&embedding[i], &embedding[j]);
+            if d2 > 0.0 {
+                let coefficient = -2.0 * UMAP_A * UMAP_B * d2.powf(UMAP_B - 1.0) / (1.0 + UMAP_A * d2.powf(UMAP_B));
+                let (a, b) = (embedding[i], embedding[j]);
+                for c in 0..2 {
+                    let step = clip(coefficient * (a[c] - b[c])) * alpha;
+                    embedding[i][c] += step;
+                    embedding[j][c] -= step;
+                }
+            }
+            next[e] += period;
+            let negatives = ((epoch as f64 - next_negative[e]) / (period / NEGATIVE_SAMPLES as f64)).max(0.0) as usize;
+            for _ in 0..negatives {
+                let k = rng.gen_range(0..n);
+                if k == i {
+                    continue;
+                }
+                let d2 = squared_distance(&embedding[i], &embedding[k]);
+                let coefficient = if d2 > 0.0 { 2.0 * UMAP_B / ((0.001 + d2) * (1.0 + UMAP_A * d2.powf(UMAP_B))) } else { 0.0 };
+                let (a, b) = (embedding[i], embedding[k]);
+                for (c, x) in embedding[i].iter_mut().enumerate() {
+                    let step = if coefficient > 0.0 { clip(coefficient * (a[c] - b[c])) } else { 4.0 };
+                    *x += step * alpha;
+                }
+            }
+            next_negative[e] += negatives as f64 * period / NEGATIVE_SAMPLES as f64;
+        }
+    }
+    embedding
+}
+
+fn squared_distance(a: &[f64; 2], b: &[f64; 2]) -> f64 {
+    (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2)
+}
+
+// Weighted edges, both ways, of the union of the fuzzy neighborhoods of the genomes: the weight of
+// a neighbor at distance d being exp(-(d - rho) / sigma), with rho the distance to the nearest
+// neighbor and sigma such that the weights sum to log2(k)
+fn fuzzy_edges(neighbors: &[Vec<(usize, f64)>]) -> Vec<(usize, usize, f64)> {
+    let directed: Vec<Vec<(usize, f64)>> = neighbors
+        .par_iter()
+        .enumerate()
+        .map(|(i, row)| {
+            let row: Vec<(usize, f64)> = row.iter().copied().filter(|&(j, _)| j != i).collect();
+            if row.is_empty() {
+                return row;
+            }
+            let rho = row.iter().map(|&(_, d)| d).filter(|&d| d > 0.0).reduce(f64::min).unwrap_or(0.0);
+            let target = (row.len() as f64).log2();
+            let weight = |d: f64, sigma: f64| if d > rho { (-(d - rho) / sigma).exp() } else { 1.0 };
+            let (mut lo, mut hi, mut sigma) = (0.0, f64::INFINITY, 1.0);
+            for _ in 0..64 {
+                let total: f64 = row.iter().map(|&(_, d)| weight(d, sigma)).sum();
+                if (total - target).abs() < 1e-5 {
+                    break;
+                }
+                if total > target {
+                    hi = sigma;
+                    sigma = (lo + hi) / 2.0;
+                } else {
+                    lo = sigma;
+                    sigma = if hi == f64::INFINITY { sigma * 2.0 } else { (lo + hi) / 2.0 };
+                }
+            }
+            let mean = row.iter().map(|&(_, d)| d).sum::<f64>() / row.len() as f64;
+            let sigma = sigma.max(1e-3 * mean).max(f64::MIN_POSITIVE);
+            row.into_iter().map(|(j, d)| (j, weight(d, sigma))).collect()
+        })
+        .collect();
+    let mut weights: BTreeMap<(usize, usize), (f64, f64)> = BTreeMap::new();
+    for (i, row) in directed.iter().enumerate() {
+        for &(j, w) in row {
+            let entry = weights.entry((i.min(j), i.max(j))).or_default();
+            if i < j {
+                entry.0 = w;
+            } else {
+                entry.1 = w;
+            }
+        }
+    }
+    // fuzzy union of the two directions
+    weights
+        .into_iter()
+        .map(|((i, j), (a, b))| (i, j, a + b - a * b))
+        .filter(|&(_, _, w)| w > 0.0)
+        .flat_map(|(i, j, w)| [(i, j, w), (j, i, w)])
+        .collect()
+}
+
+/// Writes the UMAP coordinates of the genomes of `labels`, tab separated.
+pub fn write_embedding<W: Write>(writer: &mut W, labels: &[String], embedding: &[[f64; 2]]) -> std::io::Result<()> {
+    writeln!(writer, "genome\tUMAP1\tUMAP2")?;
+    for (label, [x, y]) in labels.iter().zip(embedding) {
+        writeln!(writer, "{}\t{}\t{}", label, x, y)?;
+    }
+    Ok(())
+}
+
+/// Writes the coordinates of the genomes of `labels` on each axis, tab separated, after a comment
+/// line with the fraction of the variance explained by each axis.
+pub fn write_pcoa<W: Write>(writer: &mut W, labels: &[String], pcoa: &Pcoa) -> std::io::Result<()> {
+    let axes = pcoa.eigenvalues.len();
+    write!(writer, "#explained_variance")?;
+    for explained in &pcoa.explained {
+        write!(writer, "\t{}", explained)?;
+    }
+    writeln!(writer)?;
+    write!(writer, "genome")?;
+    for axis in 1..=axes {
+        write!(writer, "\tPC{}", axis)?;
+    }
+    writeln!(writer)?;
+    for (label, row) in labels.iter().zip(&pcoa.coordinates) {
+        write!(writer, "{}", label)?;
+        for x in row {
+            write!(writer, "\t{}", x)?;
+        }
+        writeln!(writer)?;
+    }
+    Ok(())
+}
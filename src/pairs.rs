@@ -0,0 +1,501 @@
+//! Long-format pairwise output, one line per unordered pair.
+//!
+//! Pairs are written in deterministic (i, j), i < j order. After each completed block of rows
+//! an offset marker `#offset <next_row>` is written and the file flushed, so an interrupted run
+//! can be resumed: the file is truncated after the last marker and computation restarts at that row.
+//! A finished file can then be sorted by distance with an external merge sort, see [sort_pairs].
+
+use rayon::prelude::*;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::dist::{
+    aligned_fractions, genome_label, jaccard_interval, jaccard_to_distance, mash_pvalue, normal_quantile, sketch_jaccard,
+};
+use crate::matrix::{DistMatrix, OutputValues};
+use crate::pipeline::PipelineConfig;
+use crate::sketch::Signature;
+
+const OFFSET_MARKER: &str = "#offset";
+const GENOMES_MARKER: &str = "#genomes";
+// distance of pairs sharing too few hashes
+const NOT_AVAILABLE: &str = "NA";
+// number of pairs computed between two offset markers
+const PAIRS_PER_BLOCK: usize = 1_000_000;
+
+// Returns the row to restart from and the byte length of the file up to the last offset marker
+fn find_resume_point(path: &str, nb_genomes: usize) -> Result<(usize, u64), String> {
+    let file = File::open(path).map_err(|e| format!("Cannot open pairwise file {} to resume: {}", path, e))?;
+    let mut reader = BufReader::new(file);
+    let mut line = String::new();
+    let mut position = 0u64;
+    let mut resume = (0usize, 0u64);
+    loop {
+        line.clear();
+        let nb_read = reader.read_line(&mut line).map_err(|e| format!("Error reading pairwise file {}: {}", path, e))?;
+        if nb_read == 0 {
+            break;
+        }
+        position += nb_read as u64;
+        // a line without newline was cut by the interruption, it cannot hold a complete marker
+        if !line.ends_with('\n') {
+            break;
+        }
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            Some(GENOMES_MARKER) => {
+                let n: usize = fields.next().and_then(|f| f.parse().ok()).unwrap_or(0);
+                if n != nb_genomes {
+                    return Err(format!("Cannot resume {}: it was written for {} genomes, not {}", path, n, nb_genomes));
+                }
+            }
+            Some(OFFSET_MARKER) => {
+                let row: usize = fields
+                    .next()
+                    .and_then(|f| f.parse().ok())
+                    .ok_or(format!("Malformed offset marker in {}", path))?;
+                resume = (row, position);
+            }
+            _ => {}
+        }
+    }
+    Ok(resume)
+}
+
+/// File format of the pairwise table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PairsFormat {
+    /// tab separated text, resumable and sortable
+    Tsv,
+    /// one JSON object per pair, flushed block by block while the computation runs
+    Jsonl,
+    /// zstd compressed Parquet, needs the `arrow` feature
+    Parquet,
+    /// Arrow IPC file, needs the `arrow` feature
+    Arrow,
+}
+
+impl FromStr for PairsFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<PairsFormat, String> {
+        let format = match s.to_lowercase().as_str() {
+            "tsv" => PairsFormat::Tsv,
+            "jsonl" | "json" => PairsFormat::Jsonl,
+            "parquet" => PairsFormat::Parquet,
+            "arrow" | "ipc" => PairsFormat::Arrow,
+            _ => return Err(format!("Unknown pairwise format: {}", s)),
+        };
+        if matches!(format, PairsFormat::Parquet | PairsFormat::Arrow) && !cfg!(feature = "arrow") {
+            return Err(format!("{} output needs bindashtree built with the arrow feature", s));
+        }
+        Ok(format)
+    }
+}
+
+// Values of one pair, the optional columns being computed only when requested
+pub(crate) struct PairRecord {
+    pub i: usize,
+    pub j: usize,
+    // distance or ANI, None when too few hashes are shared
+    pub value: Option<f64>,
+    pub details: Option<(f64, usize)>,
+    pub pvalue: Option<f64>,
+    pub fractions: Option<(f64, f64)>,
+    pub interval: Option<(f64, f64)>,
+}
+
+// Name of the value column
+pub(crate) fn value_column(config: &PipelineConfig) -> &'static str {
+    match config.output_values {
+        OutputValues::Distance => "distance",
+        OutputValues::Ani => "ani",
+    }
+}
+
+fn header(config: &PipelineConfig) -> String {
+    let column = value_column(config);
+    let mut header = format!("genome_a\tgenome_b\t{}", column);
+    if config.pair_details {
+        header.push_str("\tjaccard\tshared_hashes");
+    }
+    if config.pvalues {
+        header.push_str("\tp_value");
+    }
+    if config.aligned_fraction {
+        header.push_str("\taf_a\taf_b");
+    }
+    if config.confidence > 0.0 {
+        header.push_str(&format!("\t{}_low\t{}_high", column, column));
+    }
+    header
+}
+
+fn tsv_line(record: &PairRecord, labels: &[String]) -> String {
+    let mut line = match record.value {
+        Some(value) => format!("{}\t{}\t{}", labels[record.i], labels[record.j], value),
+        None => format!("{}\t{}\t{}", labels[record.i], labels[record.j], NOT_AVAILABLE),
+    };
+    if let Some((jaccard, shared)) = record.details {
+        line.push_str(&format!("\t{}\t{}", jaccard, shared));
+    }
+    if let Some(pvalue) = record.pvalue {
+        line.push_str(&format!("\t{:e}", pvalue));
+    }
+    if let Some((af_a, af_b)) = record.fractions {
+        line.push_str(&format!("\t{}\t{}", af_a, af_b));
+    }
+    if let Some((low, high)) = record.interval {
+        line.push_str(&format!("\t{}\t{}", low, high));
+    }
+    line
+}
+
+// JSON string with quotes, backslashes and control characters escaped
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+// JSON number, null for values JSON cannot hold
+fn json_number(value: f64) -> String {
+    if value.is_finite() {
+        value.to_string()
+    } else {
+        "null".to_string()
+    }
+}
+
+fn json_line(config: &PipelineConfig, record: &PairRecord, labels: &[String]) -> String {
+    let column = value_column(config);
+    let mut line = format!(
+        "{{\"genome_a\":{},\"genome_b\":{},\"{}\":{}",
+        json_string(&labels[record.i]),
+        json_string(&labels[record.j]),
+        column,
+        record.value.map_or("null".to_string(), json_number)
+    );
+    if let Some((jaccard, shared)) = record.details {
+        line.push_str(&format!(",\"jaccard\":{},\"shared_hashes\":{}", json_number(jaccard), shared));
+    }
+    if let Some(pvalue) = record.pvalue {
+        let pvalue = if pvalue.is_finite() { format!("{:e}", pvalue) } else { json_number(pvalue) };
+        line.push_str(&format!(",\"p_value\":{}", pvalue));
+    }
+    if let Some((af_a, af_b)) = record.fractions {
+        line.push_str(&format!(",\"af_a\":{},\"af_b\":{}", json_number(af_a), json_number(af_b)));
+    }
+    if let Some((low, high)) = record.interval {
+        line.push_str(&format!(
+            ",\"{}_low\":{},\"{}_high\":{}",
+            column,
+            json_number(low),
+            column,
+            json_number(high)
+        ));
+    }
+    line.push('}');
+    line
+}
+
+/// Gathers the pairs of rows `first_row..` in blocks of about `PAIRS_PER_BLOCK` pairs, in (i, j), i < j order,
+/// or all (i, j), i != j for an asymmetric matrix, handing each block and the row following it to `write_block`.
+/// Distances are those of `matrix`, the distance matrix of the genomes, so that the pairs agree with
+/// it, pairs skipped by the prescreen or LSH banding included; only the optional columns are computed.
+pub(crate) fn pair_blocks(
+    config: &PipelineConfig,
+    matrix: &DistMatrix,
+    sketches: &HashMap<String, Signature>,
+    genomes: &[String],
+    genome_sizes: Option<&[usize]>,
+    first_row: usize,
+    mut write_block: impl FnMut(Vec<Vec<PairRecord>>, usize) -> Result<(), String>,
+) -> Result<(), String> {
+    if genome_sizes.is_none() && (config.pvalues || config.aligned_fraction) {
+        let flag = if config.pvalues { "--pvalues" } else { "--aligned_fraction" };
+        return Err(format!("{} needs the genome sizes", flag));
+    }
+    let kmer_size = config.kmer_size;
+    let n = genomes.len();
+    let symmetric = matrix.is_symmetric();
+    let z = (config.confidence > 0.0).then(|| normal_quantile(config.confidence));
+    let mut row = first_row;
+    while row < n {
+        // gather enough rows to keep all threads busy between two markers
+        let mut last = row;
+        let mut nb_pairs = 0;
+        while last < n && nb_pairs < PAIRS_PER_BLOCK {
+            nb_pairs += if symmetric { n - last - 1 } else { n - 1 };
+            last += 1;
+        }
+        let block: Vec<Vec<PairRecord>> = (row..last)
+            .into_par_iter()
+            .map(|i| {
+                (if symmetric { i + 1 } else { 0 }..n)
+                    .filter(|&j| j != i)
+                    .filter_map(|j| {
+                        let (query, reference) = (&sketches[&genomes[i]], &sketches[&genomes[j]]);
+                        let dist = matrix.get(i, j);
+                        // pairs sharing too few hashes hold the maximum distance in the matrix
+                        let dist = (config.min_shared == 0
+                            || dist < config.max_dist
+                            || shared_hashes(query, reference) >= config.min_shared)
+                            .then_some(dist);
+                        // sparse output, pairs without a distance are not reported either
+                        if config.max_report_dist > 0.0 && !dist.is_some_and(|d| d <= config.max_report_dist) {
+                            return None;
+                        }
+                        let mut record = PairRecord {
+                            i,
+                            j,
+                            value: dist.map(|dist| config.output_values.transform(dist)),
+                            details: None,
+                            pvalue: None,
+                            fractions: None,
+                            interval: None,
+                        };
+                        if !config.pair_details && !config.pvalues && !config.aligned_fraction && z.is_none() {
+                            return Some(record);
+                        }
+                        let (jaccard, sketch_size) = sketch_jaccard(query, reference);
+                        let shared = (jaccard * sketch_size as f64).round() as usize;
+                        if config.pair_details {
+                            record.details = Some((jaccard, shared));
+                        }
+                        if let (true, Some(sizes)) = (config.pvalues, genome_sizes) {
+                            record.pvalue = Some(mash_pvalue(shared, sketch_size, kmer_size, sizes[i], sizes[j]));
+                        }
+                        if let (true, Some(sizes)) = (config.aligned_fraction, genome_sizes) {
+                            record.fractions = Some(aligned_fractions(query, reference, sizes[i], sizes[j]));
+                        }
+                        if let Some(z) = z {
+                            let (low, high) = jaccard_interval(shared, sketch_size, z);
+                            let [a, b] = [low, high].map(|jaccard| {
+                                config
+                                    .output_values
+                                    .transform(jaccard_to_distance(config.distance_model, jaccard, kmer_size))
+                            });
+                            record.interval = Some((a.min(b), a.max(b)));
+                        }
+                        Some(record)
+                    })
+                    .collect()
+            })
+            .collect();
+        write_block(block, last)?;
+        row = last;
+    }
+    Ok(())
+}
+
+// Number of hashes shared by two sketches
+fn shared_hashes(query: &Signature, reference: &Signature) -> usize {
+    let (jaccard, sketch_size) = sketch_jaccard(query, reference);
+    (jaccard * sketch_size as f64).round() as usize
+}
+
+/// Streams all pairwise distances (or ANI values) of `matrix`, the distance matrix of the genomes, to `path` as `genome_a genome_b distance` lines,
+/// or only those up to the maximum reported distance of `config`.
+/// With `resume` an existing file is continued after its last offset marker.
+/// Depending on `config`, columns of Jaccard index and number of shared hashes, of Mash p-values (see [mash_pvalue]) and aligned fractions
+/// (see [aligned_fractions]), both needing the k-mer counts of the genomes, and the bounds of
+/// the distance confidence interval (see [jaccard_interval]) are added.
+/// JSON lines, Parquet and Arrow formats hold the same columns, NA being null, and cannot be resumed.
+pub fn write_pairs(
+    config: &PipelineConfig,
+    matrix: &DistMatrix,
+    sketches: &HashMap<String, Signature>,
+    genomes: &[String],
+    genome_sizes: Option<&[usize]>,
+    (path, format): (&str, PairsFormat),
+    resume: bool,
+) -> Result<(), String> {
+    let write_error = |e: std::io::Error| format!("Error writing pairwise file {}: {}", path, e);
+    if format == PairsFormat::Jsonl {
+        let file = File::create(path).map_err(|e| format!("Cannot create pairwise file {}: {}", path, e))?;
+        let mut writer = BufWriter::new(file);
+        let labels: Vec<String> = genomes.iter().map(|g| genome_label(g)).collect();
+        return pair_blocks(config, matrix, sketches, genomes, genome_sizes, 0, |block, _| {
+            for record in block.iter().flatten() {
+                writeln!(writer, "{}", json_line(config, record, &labels)).map_err(write_error)?;
+            }
+            // consumers reading the file can start on every completed block
+            writer.flush().map_err(write_error)
+        });
+    }
+    if format != PairsFormat::Tsv {
+        #[cfg(not(feature = "arrow"))]
+        return Err(format!("{:?} output needs bindashtree built with the arrow feature", format));
+        #[cfg(feature = "arrow")]
+        return crate::columnar::write_pairs_columnar(config, matrix, sketches, genomes, genome_sizes, path, format);
+    }
+    let n = genomes.len();
+    let (first_row, mut writer) = if resume && std::path::Path::new(path).exists() {
+        let (row, length) = find_resume_point(path, n)?;
+        let open_error = |e: std::io::Error| format!("Cannot open pairwise file {}: {}", path, e);
+        let file = OpenOptions::new().write(true).open(path).map_err(open_error)?;
+        file.set_len(length).map_err(write_error)?;
+        let file = OpenOptions::new().append(true).open(path).map_err(open_error)?;
+        log::info!("resuming pairwise output {} at row {}", path, row);
+        (row, BufWriter::new(file))
+    } else {
+        let file = File::create(path).map_err(|e| format!("Cannot create pairwise file {}: {}", path, e))?;
+        (0, BufWriter::new(file))
+    };
+    if first_row == 0 {
+        // an empty or headerless file is simply rewritten from the start
+        writer.get_ref().set_len(0).map_err(write_error)?;
+        writeln!(writer, "{}\t{}", GENOMES_MARKER, n).map_err(write_error)?;
+        writeln!(writer, "{}", header(config)).map_err(write_error)?;
+        writeln!(writer, "{}\t0", OFFSET_MARKER).map_err(write_error)?;
+    }
+
+    let labels: Vec<String> = genomes.iter().map(|g| genome_label(g)).collect();
+    pair_blocks(config, matrix, sketches, genomes, genome_sizes, first_row, |block, last| {
+        for records in block.iter() {
+            for record in records {
+                writeln!(writer, "{}", tsv_line(record, &labels)).map_err(write_error)?;
+            }
+        }
+        writeln!(writer, "{}\t{}", OFFSET_MARKER, last).map_err(write_error)?;
+        writer.flush().map_err(write_error)
+    })
+}
+
+// number of pairs sorted in memory before being spilled to a temporary file
+const PAIRS_PER_CHUNK: usize = 10_000_000;
+
+// Line of a sorted chunk waiting in the merge heap, smallest distance first then earliest chunk
+struct MergeItem {
+    distance: f64,
+    chunk: usize,
+    line: String,
+}
+
+impl PartialEq for MergeItem {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeItem {}
+
+impl PartialOrd for MergeItem {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MergeItem {
+    // reversed, BinaryHeap being a max heap
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance).then(other.chunk.cmp(&self.chunk))
+    }
+}
+
+// Sort key of a line, ANI values being negated so that the closest pairs still come first, NA last
+fn line_key(line: &str, ani: bool) -> Result<f64, String> {
+    let field = line.split('\t').nth(2).ok_or(format!("malformed line: {}", line))?;
+    if field == NOT_AVAILABLE {
+        return Ok(f64::INFINITY);
+    }
+    let value: f64 = field.parse().map_err(|_| format!("malformed line: {}", line))?;
+    Ok(if ani { -value } else { value })
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<Option<String>, String> {
+    let mut line = String::new();
+    match reader.read_line(&mut line).map_err(|e| e.to_string())? {
+        0 => Ok(None),
+        _ => Ok(Some(line.trim_end_matches('\n').to_string())),
+    }
+}
+
+/// Sorts a pairwise file written by [write_pairs] by increasing distance (decreasing ANI) into `output`,
+/// ties kept in pair order.
+/// Sorted chunks are spilled to `tmp_dir` and k-way merged, so the sort runs in bounded memory.
+/// Offset markers are dropped, the sorted file cannot be resumed.
+pub fn sort_pairs(input: &str, output: &str, tmp_dir: &Path) -> Result<(), String> {
+    let file = File::open(input).map_err(|e| format!("Cannot open pairwise file {}: {}", input, e))?;
+    let mut reader = BufReader::new(file);
+    let file = File::create(output).map_err(|e| format!("Cannot create sorted pairwise file {}: {}", output, e))?;
+    let mut writer = BufWriter::new(file);
+    let mut chunk_paths = Vec::new();
+    let sorted = sort_lines(&mut reader, &mut writer, tmp_dir, &mut chunk_paths)
+        .and_then(|_| writer.flush().map_err(|e| e.to_string()))
+        .map_err(|e| format!("Cannot sort pairwise file {} into {}: {}", input, output, e));
+    for path in chunk_paths {
+        let _ = std::fs::remove_file(path);
+    }
+    sorted
+}
+
+// Sorts the lines of `reader` into `writer`, through the chunks spilled to `chunk_paths`
+fn sort_lines(
+    reader: &mut impl BufRead,
+    writer: &mut impl Write,
+    tmp_dir: &Path,
+    chunk_paths: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let mut chunk: Vec<(f64, String)> = Vec::with_capacity(PAIRS_PER_CHUNK);
+    let mut ani = false;
+    let spill = |chunk: &mut Vec<(f64, String)>, chunk_paths: &mut Vec<PathBuf>| -> std::io::Result<()> {
+        // stable, ties keep their pair order
+        chunk.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let path = tmp_dir.join(format!("bindashtree_pairs_{}_{}.tsv", std::process::id(), chunk_paths.len()));
+        let mut f = BufWriter::new(File::create(&path)?);
+        chunk_paths.push(path);
+        for (_, line) in chunk.drain(..) {
+            writeln!(f, "{}", line)?;
+        }
+        f.flush()
+    };
+    while let Some(line) = read_line(reader)? {
+        if line.starts_with(GENOMES_MARKER) || line.starts_with("genome_a\t") {
+            ani |= line.split('\t').nth(2) == Some("ani");
+            writeln!(writer, "{}", line).map_err(|e| e.to_string())?;
+            continue;
+        }
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        chunk.push((line_key(&line, ani)?, line));
+        if chunk.len() == PAIRS_PER_CHUNK {
+            spill(&mut chunk, chunk_paths).map_err(|e| format!("temporary file: {}", e))?;
+        }
+    }
+    if !chunk.is_empty() {
+        spill(&mut chunk, chunk_paths).map_err(|e| format!("temporary file: {}", e))?;
+    }
+
+    let mut readers: Vec<BufReader<File>> = chunk_paths
+        .iter()
+        .map(|p| File::open(p).map(BufReader::new).map_err(|e| format!("temporary file: {}", e)))
+        .collect::<Result<_, String>>()?;
+    let mut heap = BinaryHeap::with_capacity(readers.len());
+    for (chunk, reader) in readers.iter_mut().enumerate() {
+        if let Some(line) = read_line(reader)? {
+            heap.push(MergeItem { distance: line_key(&line, ani)?, chunk, line });
+        }
+    }
+    while let Some(item) = heap.pop() {
+        writeln!(writer, "{}", item.line).map_err(|e| e.to_string())?;
+        if let Some(line) = read_line(&mut readers[item.chunk])? {
+            heap.push(MergeItem { distance: line_key(&line, ani)?, chunk: item.chunk, line });
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,742 @@
+//! Rooted tree built by the clustering methods, read and written in Newick format.
+
+use std::collections::{HashMap, HashSet};
+
+/// Node of a [Tree], its branch leading to its parent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node {
+    pub label: Option<String>,
+    pub length: Option<f64>,
+    pub children: Vec<usize>,
+}
+
+/// Annotation of a leaf in phyloXML output, e.g. the genome size.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Property {
+    pub name: String,
+    /// XML schema type of the value, e.g. `xsd:integer`
+    pub datatype: &'static str,
+    pub value: String,
+}
+
+/// Rooted tree whose nodes are stored in a vector, children referring to their index.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Tree {
+    nodes: Vec<Node>,
+    root: usize,
+}
+
+impl Tree {
+    pub fn new() -> Self {
+        Tree::default()
+    }
+
+    /// Adds a leaf and returns its index.
+    pub fn add_leaf(&mut self, label: &str) -> usize {
+        self.nodes.push(Node { label: Some(label.to_string()), length: None, children: Vec::new() });
+        self.nodes.len() - 1
+    }
+
+    /// Adds an internal node above `children`, which become attached by branches of the given lengths,
+    /// and returns its index. The last node added is the root.
+    pub fn join(&mut self, children: &[(usize, f64)]) -> usize {
+        for &(child, length) in children {
+            self.nodes[child].length = Some(length);
+        }
+        let children = children.iter().map(|&(child, _)| child).collect();
+        self.nodes.push(Node { label: None, length: None, children });
+        self.root = self.nodes.len() - 1;
+        self.root
+    }
+
+    /// Adds an internal node above `children`, without branch lengths, and returns its index.
+    /// The last node added is the root.
+    pub fn join_unweighted(&mut self, children: &[usize]) -> usize {
+        self.nodes.push(Node { label: None, length: None, children: children.to_vec() });
+        self.root = self.nodes.len() - 1;
+        self.root
+    }
+
+    /// Reads a Newick tree, e.g. written by speedytree or [Tree::to_newick].
+    /// Labels may be quoted with `'`, comments in square brackets are skipped.
+    pub fn from_newick(newick: &str) -> Result<Self, String> {
+        let text: Vec<char> = newick.trim().chars().collect();
+        let mut tree = Tree::new();
+        // children of the nodes being read, innermost last
+        let mut open: Vec<Vec<usize>> = Vec::new();
+        let mut pos = 0;
+        loop {
+            skip_comments(&text, &mut pos);
+            let node = if text.get(pos) == Some(&'(') {
+                pos += 1;
+                open.push(Vec::new());
+                continue;
+            } else if text.get(pos) == Some(&')') {
+                pos += 1;
+                let children = open.pop().ok_or("Unbalanced parentheses in Newick tree")?;
+                tree.nodes.push(Node { label: None, length: None, children });
+                tree.nodes.len() - 1
+            } else {
+                tree.nodes.push(Node { label: None, length: None, children: Vec::new() });
+                tree.nodes.len() - 1
+            };
+            let label = read_label(&text, &mut pos)?;
+            tree.nodes[node].label = (!label.is_empty()).then_some(label);
+            skip_comments(&text, &mut pos);
+            if text.get(pos) == Some(&':') {
+                pos += 1;
+                let start = pos;
+                while pos < text.len() && !",);[".contains(text[pos]) {
+                    pos += 1;
+                }
+                let length: String = text[start..pos].iter().collect();
+                let length = length.trim().parse().map_err(|_| format!("Invalid branch length in Newick tree: {}", length))?;
+                tree.nodes[node].length = Some(length);
+            }
+            skip_comments(&text, &mut pos);
+            match (text.get(pos), open.last_mut()) {
+                (Some(','), Some(children)) => {
+                    children.push(node);
+                    pos += 1;
+                }
+                (Some(')'), Some(children)) => children.push(node),
+                (Some(';') | None, None) => {
+                    tree.root = node;
+                    return Ok(tree);
+                }
+                _ => return Err(format!("Malformed Newick tree at character {}", pos)),
+            }
+        }
+    }
+
+    pub fn root(&self) -> usize {
+        self.root
+    }
+
+    pub fn set_label(&mut self, index: usize, label: Option<String>) {
+        self.nodes[index].label = label;
+    }
+
+    /// Indices of the nodes, each after its children.
+    pub fn post_order(&self) -> Vec<usize> {
+        let mut order = Vec::with_capacity(self.nodes.len());
+        if self.nodes.is_empty() {
+            return order;
+        }
+        let mut stack = vec![self.root];
+        while let Some(node) = stack.pop() {
+            order.push(node);
+            stack.extend(self.nodes[node].children.iter().copied());
+        }
+        order.reverse();
+        order
+    }
+
+    /// Leaf sets below each node, as bit sets over `leaves` (labels to positions).
+    pub fn clusters(&self, leaves: &HashMap<String, usize>) -> Result<Vec<Vec<u64>>, String> {
+        let words = leaves.len().div_ceil(64);
+        let mut below: Vec<Vec<u64>> = vec![Vec::new(); self.nodes.len()];
+        for node in self.post_order() {
+            let mut set = vec![0u64; words];
+            match self.nodes[node].children.as_slice() {
+                [] => {
+                    let label = self.nodes[node].label.as_deref().unwrap_or("");
+                    let leaf = *leaves.get(label).ok_or(format!("Unknown leaf in tree: {}", label))?;
+                    set[leaf / 64] |= 1 << (leaf % 64);
+                }
+                children => {
+                    for &child in children {
+                        for (w, c) in set.iter_mut().zip(below[child].iter()) {
+                            *w |= c;
+                        }
+                    }
+                }
+            }
+            below[node] = set;
+        }
+        Ok(below)
+    }
+
+    /// Splits of the branches above each node, None for the root, as bit sets over `leaves`.
+    /// A split is given by the side without the first leaf, so that it does not depend on where the
+    /// tree is rooted.
+    pub fn splits(&self, leaves: &HashMap<String, usize>) -> Result<Vec<Option<Vec<u64>>>, String> {
+        let below = self.clusters(leaves)?;
+        Ok(below
+            .into_iter()
+            .enumerate()
+            .map(|(node, set)| {
+                if node == self.root {
+                    None
+                } else if set[0] & 1 == 0 {
+                    Some(set)
+                } else {
+                    Some(complement(&set, leaves.len()))
+                }
+            })
+            .collect())
+    }
+
+    /// Sets negative branch lengths to zero and returns how many there were. With `redistribute`,
+    /// the negative length is taken from the longest sibling branch instead, keeping the distance
+    /// between the two subtrees, as long as the sibling branch does not become negative.
+    pub fn clamp_negative_branches(&mut self, redistribute: bool) -> usize {
+        let mut nb_negative = 0;
+        for parent in 0..self.nodes.len() {
+            let children = self.nodes[parent].children.clone();
+            for &child in &children {
+                let length = match self.nodes[child].length {
+                    Some(length) if length < 0.0 => length,
+                    _ => continue,
+                };
+                nb_negative += 1;
+                self.nodes[child].length = Some(0.0);
+                let sibling = children
+                    .iter()
+                    .copied()
+                    .filter(|&s| s != child)
+                    .max_by(|&a, &b| self.branch_length(a).total_cmp(&self.branch_length(b)));
+                if let Some(sibling) = sibling.filter(|_| redistribute) {
+                    self.nodes[sibling].length = Some((self.branch_length(sibling) + length).max(0.0));
+                }
+            }
+        }
+        nb_negative
+    }
+
+    /// Orders the children of each node by their number of leaves, the smallest clades first if
+    /// `ascending`, ties keeping their order.
+    pub fn ladderize(&mut self, ascending: bool) {
+        let mut nb_leaves = vec![0usize; self.nodes.len()];
+        for node in self.post_order() {
+            let children = &self.nodes[node].children;
+            nb_leaves[node] = if children.is_empty() { 1 } else { children.iter().map(|&c| nb_leaves[c]).sum() };
+        }
+        for node in self.nodes.iter_mut() {
+            if ascending {
+                node.children.sort_by_key(|&c| nb_leaves[c]);
+            } else {
+                node.children.sort_by_key(|&c| std::cmp::Reverse(nb_leaves[c]));
+            }
+        }
+    }
+
+    /// Parent of each node, None for the root.
+    pub fn parents(&self) -> Vec<Option<usize>> {
+        let mut parents = vec![None; self.nodes.len()];
+        for (parent, node) in self.nodes.iter().enumerate() {
+            for &child in &node.children {
+                parents[child] = Some(parent);
+            }
+        }
+        parents
+    }
+
+    // Neighbors of each node as (neighbor, branch), the branch being given by the node below it
+    fn neighbors(&self) -> Vec<Vec<(usize, usize)>> {
+        let mut neighbors = vec![Vec::new(); self.nodes.len()];
+        for (parent, node) in self.nodes.iter().enumerate() {
+            for &child in &node.children {
+                neighbors[parent].push((child, child));
+                neighbors[child].push((parent, child));
+            }
+        }
+        neighbors
+    }
+
+    fn branch_length(&self, branch: usize) -> f64 {
+        self.nodes[branch].length.unwrap_or(0.0)
+    }
+
+    /// Same tree rooted on the branch above `node`, at `offset` from `node` along the branch.
+    /// Labels of internal nodes are taken as branch support values and stay with their branch;
+    /// nodes left with a single child, like the former root, are removed.
+    pub fn reroot(&self, node: usize, offset: f64) -> Tree {
+        let parent = match self.parents()[node] {
+            Some(parent) => parent,
+            None => return self.clone(),
+        };
+        let neighbors = self.neighbors();
+        let mut tree = Tree::new();
+        // each side of the root branch, copied away from the other side
+        let below = self.copy_away(&neighbors, node, parent, &mut tree);
+        let above = self.copy_away(&neighbors, parent, node, &mut tree);
+        let length = self.branch_length(node);
+        let offset = offset.clamp(0.0, length);
+        let support = (!self.nodes[node].children.is_empty()).then(|| self.nodes[node].label.clone()).flatten();
+        // a side whose start was skipped, like a former root of degree 2, already has the branch beyond it
+        let beyond = |side: usize| tree.nodes[side].length.unwrap_or(0.0);
+        let (below_length, above_length) = (beyond(below) + offset, beyond(above) + length - offset);
+        tree.join(&[(below, below_length), (above, above_length)]);
+        for side in [below, above] {
+            if !tree.nodes[side].children.is_empty() {
+                tree.nodes[side].label = support.clone();
+            }
+        }
+        tree
+    }
+
+    // Copies into `tree` the subtree of `start` away from its neighbor `from`, returning its new index
+    fn copy_away(&self, neighbors: &[Vec<(usize, usize)>], start: usize, from: usize, tree: &mut Tree) -> usize {
+        let mut index = vec![usize::MAX; self.nodes.len()];
+        let mut stack = vec![(start, from, false)];
+        while let Some((node, from, done)) = stack.pop() {
+            let below: Vec<(usize, usize)> = neighbors[node].iter().copied().filter(|&(o, _)| o != from).collect();
+            if below.is_empty() {
+                index[node] = tree.add_leaf(self.nodes[node].label.as_deref().unwrap_or(""));
+            } else if !done {
+                stack.push((node, from, true));
+                stack.extend(below.iter().rev().map(|&(child, _)| (child, node, false)));
+            } else if let [(child, branch)] = below[..] {
+                // a node of degree 2 is skipped, its two branches joined
+                let copy = index[child];
+                let length = tree.nodes[copy].length.unwrap_or(0.0) + self.branch_length(branch);
+                tree.nodes[copy].length = Some(length);
+                self.copy_support(tree, copy, branch);
+                index[node] = copy;
+            } else {
+                let children: Vec<(usize, f64)> =
+                    below.iter().map(|&(child, branch)| (index[child], self.branch_length(branch))).collect();
+                for &(child, branch) in &below {
+                    self.copy_support(tree, index[child], branch);
+                }
+                index[node] = tree.join(&children);
+            }
+        }
+        index[start]
+    }
+
+    // Support of `branch` given to the copied internal node `copy`, unless it already has one
+    fn copy_support(&self, tree: &mut Tree, copy: usize, branch: usize) {
+        if !tree.nodes[copy].children.is_empty() && tree.nodes[copy].label.is_none() {
+            tree.nodes[copy].label = self.nodes[branch].label.clone();
+        }
+    }
+
+    /// Subtree induced by the `keep` leaves, branch lengths preserved: nodes without any of them are
+    /// removed and nodes left with a single child are skipped, their branch added to the child's.
+    pub fn prune(&self, keep: &[String]) -> Result<Tree, String> {
+        let labels: HashSet<&str> = (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].children.is_empty())
+            .filter_map(|i| self.nodes[i].label.as_deref())
+            .collect();
+        if keep.is_empty() {
+            return Err("No taxon to keep".to_string());
+        }
+        if let Some(label) = keep.iter().find(|label| !labels.contains(label.as_str())) {
+            return Err(format!("Taxon not in the tree: {}", label));
+        }
+        let keep: HashSet<&str> = keep.iter().map(|label| label.as_str()).collect();
+        let mut tree = Tree::new();
+        let mut index: Vec<Option<usize>> = vec![None; self.nodes.len()];
+        for node in self.post_order() {
+            let original = &self.nodes[node];
+            let children: Vec<usize> = original.children.iter().filter_map(|&child| index[child]).collect();
+            index[node] = match children[..] {
+                [] if original.children.is_empty() && keep.contains(original.label.as_deref().unwrap_or("")) => {
+                    Some(tree.add_leaf(original.label.as_deref().unwrap_or("")))
+                }
+                [] => None,
+                [child] => Some(child),
+                _ => {
+                    let copy = tree.join_unweighted(&children);
+                    tree.nodes[copy].label = original.label.clone();
+                    Some(copy)
+                }
+            };
+            // the branch above a skipped node is joined to its child's
+            if let Some(copy) = index[node] {
+                tree.nodes[copy].length = match (tree.nodes[copy].length.filter(|_| children.len() == 1), original.length) {
+                    (Some(below), Some(above)) => Some(below + above),
+                    (below, above) => above.or(below),
+                };
+            }
+        }
+        if let Some(root) = index[self.root] {
+            tree.root = root;
+            tree.nodes[root].length = None;
+        }
+        Ok(tree)
+    }
+
+    /// Same tree with the branches above the internal nodes for which `collapsed` holds removed, their
+    /// children joining the node above with their own lengths, giving polytomies. The root is kept.
+    /// Returns the tree and the number of branches removed.
+    pub fn collapse(&self, collapsed: impl Fn(usize) -> bool) -> (Tree, usize) {
+        if self.nodes.is_empty() {
+            return (self.clone(), 0);
+        }
+        let mut tree = Tree::new();
+        // nodes of the new tree standing for each node: its copy, or the children of a removed one
+        let mut copies: Vec<Vec<usize>> = vec![Vec::new(); self.nodes.len()];
+        let mut nb_collapsed = 0;
+        for node in self.post_order() {
+            let original = &self.nodes[node];
+            if original.children.is_empty() {
+                let leaf = tree.add_leaf(original.label.as_deref().unwrap_or(""));
+                tree.nodes[leaf].length = original.length;
+                copies[node] = vec![leaf];
+                continue;
+            }
+            let children: Vec<usize> = original.children.iter().flat_map(|&child| std::mem::take(&mut copies[child])).collect();
+            if node != self.root && collapsed(node) {
+                nb_collapsed += 1;
+                copies[node] = children;
+                continue;
+            }
+            let copy = tree.join_unweighted(&children);
+            tree.nodes[copy].label = original.label.clone();
+            tree.nodes[copy].length = original.length;
+            copies[node] = vec![copy];
+        }
+        (tree, nb_collapsed)
+    }
+
+    /// Same tree with new leaves attached to its branches, each given as (node below the branch,
+    /// length from that node to the attachment point, leaf label, pendant branch length).
+    pub fn graft(&self, leaves: &[(usize, f64, String, f64)]) -> Tree {
+        let mut tree = self.clone();
+        let parents = self.parents();
+        let mut by_branch: HashMap<usize, Vec<&(usize, f64, String, f64)>> = HashMap::new();
+        for leaf in leaves {
+            by_branch.entry(leaf.0).or_default().push(leaf);
+        }
+        let mut branches: Vec<usize> = by_branch.keys().copied().collect();
+        branches.sort_unstable();
+        for node in branches {
+            let Some(parent) = parents[node] else {
+                continue;
+            };
+            let mut attached = by_branch.remove(&node).unwrap();
+            attached.sort_by(|a, b| a.1.total_cmp(&b.1));
+            let length = self.branch_length(node);
+            // each attachment point becomes a node above the previous one
+            let (mut lower, mut height) = (node, 0.0);
+            for (_, distal, label, pendant) in attached {
+                let distal = distal.clamp(height, length.max(height));
+                tree.nodes[lower].length = Some(distal - height);
+                let leaf = tree.add_leaf(label);
+                tree.nodes[leaf].length = Some(*pendant);
+                tree.nodes.push(Node { label: None, length: None, children: vec![lower, leaf] });
+                lower = tree.nodes.len() - 1;
+                height = distal;
+            }
+            tree.nodes[lower].length = Some(length - height);
+            for child in tree.nodes[parent].children.iter_mut() {
+                if *child == node {
+                    *child = lower;
+                }
+            }
+        }
+        tree
+    }
+
+    /// Subtree rooted at the most recent common ancestor of the `taxa` leaves, in the tree as rooted.
+    pub fn clade(&self, taxa: &[String]) -> Result<Tree, String> {
+        let targets: HashSet<&str> = taxa.iter().map(|label| label.as_str()).collect();
+        if targets.len() < 2 {
+            return Err("A clade needs at least two taxa".to_string());
+        }
+        // number of target leaves below each node, the first node holding them all being their ancestor
+        let mut below = vec![0usize; self.nodes.len()];
+        let mrca = self.post_order().into_iter().find(|&node| {
+            let children = &self.nodes[node].children;
+            below[node] = if children.is_empty() {
+                targets.contains(self.nodes[node].label.as_deref().unwrap_or("")) as usize
+            } else {
+                children.iter().map(|&child| below[child]).sum()
+            };
+            below[node] == targets.len()
+        });
+        let Some(mrca) = mrca else {
+            let found: HashSet<&str> = (0..self.nodes.len())
+                .filter(|&i| self.nodes[i].children.is_empty())
+                .filter_map(|i| self.nodes[i].label.as_deref())
+                .collect();
+            let missing = taxa.iter().find(|label| !found.contains(label.as_str())).cloned().unwrap_or_default();
+            return Err(format!("Taxon not in the tree: {}", missing));
+        };
+        let mut leaves = Vec::new();
+        let mut stack = vec![mrca];
+        while let Some(node) = stack.pop() {
+            let node = &self.nodes[node];
+            if node.children.is_empty() {
+                leaves.push(node.label.clone().unwrap_or_default());
+            }
+            stack.extend(node.children.iter().copied());
+        }
+        self.prune(&leaves)
+    }
+
+    /// Same tree rooted on the branch separating the `outgroup` leaves from the others, at its middle.
+    /// Fails when the outgroup is not one side of a branch, i.e. not monophyletic in the unrooted tree.
+    pub fn outgroup_root(&self, outgroup: &[String]) -> Result<Tree, String> {
+        let leaves: HashMap<String, usize> = (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].children.is_empty())
+            .enumerate()
+            .map(|(k, i)| (self.nodes[i].label.clone().unwrap_or_default(), k))
+            .collect();
+        let mut target = vec![0u64; leaves.len().div_ceil(64)];
+        for label in outgroup {
+            let leaf = *leaves.get(label).ok_or(format!("Outgroup taxon not in the tree: {}", label))?;
+            target[leaf / 64] |= 1 << (leaf % 64);
+        }
+        let other_side = complement(&target, leaves.len());
+        let clusters = self.clusters(&leaves)?;
+        (0..self.nodes.len())
+            .find(|&node| node != self.root && (clusters[node] == target || clusters[node] == other_side))
+            .map(|node| self.reroot(node, self.branch_length(node) / 2.0))
+            .ok_or(format!("Outgroup {} is not monophyletic in the tree", outgroup.join(",")))
+    }
+
+    /// Same tree rooted at the midpoint of the longest path between two leaves.
+    pub fn midpoint_root(&self) -> Tree {
+        let neighbors = self.neighbors();
+        let leaves: Vec<usize> = (0..self.nodes.len()).filter(|&i| neighbors[i].len() == 1).collect();
+        if leaves.len() < 2 {
+            return self.clone();
+        }
+        // farthest node from `start`, its distance, and the previous node and branch on the path to each node
+        let farthest = |start: usize| {
+            let mut dist = vec![f64::NAN; self.nodes.len()];
+            let mut previous = vec![(usize::MAX, usize::MAX); self.nodes.len()];
+            dist[start] = 0.0;
+            let mut stack = vec![start];
+            while let Some(node) = stack.pop() {
+                for &(next, branch) in &neighbors[node] {
+                    if dist[next].is_nan() {
+                        dist[next] = dist[node] + self.branch_length(branch);
+                        previous[next] = (node, branch);
+                        stack.push(next);
+                    }
+                }
+            }
+            let end = leaves.iter().copied().max_by(|&a, &b| dist[a].total_cmp(&dist[b])).unwrap();
+            (end, dist, previous)
+        };
+        let (a, _, _) = farthest(leaves[0]);
+        let (b, dist, previous) = farthest(a);
+        let half = dist[b] / 2.0;
+        // walking back from b, the branch reaching past the midpoint holds the root
+        let mut node = b;
+        while node != a {
+            let (next, branch) = previous[node];
+            if dist[next] <= half {
+                // distance from the lower end of the branch
+                let offset = if branch == node { dist[node] - half } else { half - dist[next] };
+                return self.reroot(branch, offset);
+            }
+            node = next;
+        }
+        self.clone()
+    }
+
+    pub fn node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Newick string of the tree, ending with `;`.
+    pub fn to_newick(&self) -> String {
+        let mut newick = String::new();
+        if !self.nodes.is_empty() {
+            self.write_newick(self.root, &mut newick);
+        }
+        newick.push(';');
+        newick
+    }
+
+    /// NEXUS file holding the tree in a TREES block, leaves being numbered by a translate table.
+    /// The tree is marked rooted when its root has two children.
+    pub fn to_nexus(&self) -> String {
+        let leaves: Vec<usize> = (0..self.nodes.len()).filter(|&i| self.nodes[i].children.is_empty()).collect();
+        let mut numbered = self.clone();
+        let mut nexus = String::from("#NEXUS\nBEGIN TREES;\n\tTRANSLATE\n");
+        for (k, &leaf) in leaves.iter().enumerate() {
+            let label = self.nodes[leaf].label.as_deref().unwrap_or("");
+            let separator = if k + 1 < leaves.len() { "," } else { "" };
+            nexus.push_str(&format!("\t\t{} {}{}\n", k + 1, nexus_token(label), separator));
+            numbered.nodes[leaf].label = Some((k + 1).to_string());
+        }
+        let rooted = self.nodes.get(self.root).is_some_and(|root| root.children.len() == 2);
+        nexus.push_str("\t;\n");
+        nexus.push_str(&format!("\tTREE tree_1 = [&{}] {}\n", if rooted { "R" } else { "U" }, numbered.to_newick()));
+        nexus.push_str("END;\n");
+        nexus
+    }
+
+    /// phyloXML document of the tree, leaves carrying their `properties` (by label). Numeric labels
+    /// of internal nodes are written as support values, other ones as names.
+    pub fn to_phyloxml(&self, properties: &HashMap<String, Vec<Property>>) -> String {
+        let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str("<phyloxml xmlns=\"http://www.phyloxml.org\">\n");
+        let rooted = self.nodes.get(self.root).is_some_and(|root| root.children.len() == 2);
+        xml.push_str(&format!("  <phylogeny rooted=\"{}\">\n", rooted));
+        if !self.nodes.is_empty() {
+            // iterative, as for Newick
+            let mut stack = vec![(self.root, 2, false)];
+            while let Some((i, depth, done)) = stack.pop() {
+                let indent = "  ".repeat(depth);
+                if done {
+                    xml.push_str(&format!("{}</clade>\n", indent));
+                    continue;
+                }
+                let node = &self.nodes[i];
+                xml.push_str(&format!("{}<clade>\n", indent));
+                if let Some(label) = node.label.as_ref() {
+                    match label.parse::<f64>() {
+                        Ok(_) if !node.children.is_empty() => {
+                            xml.push_str(&format!("{}  <confidence type=\"support\">{}</confidence>\n", indent, label))
+                        }
+                        _ => xml.push_str(&format!("{}  <name>{}</name>\n", indent, xml_escape(label))),
+                    }
+                }
+                if let Some(length) = node.length {
+                    xml.push_str(&format!("{}  <branch_length>{}</branch_length>\n", indent, length));
+                }
+                let leaf_properties = node.label.as_ref().filter(|_| node.children.is_empty()).and_then(|l| properties.get(l));
+                for property in leaf_properties.into_iter().flatten() {
+                    xml.push_str(&format!(
+                        "{}  <property ref=\"bindashtree:{}\" datatype=\"{}\" applies_to=\"clade\">{}</property>\n",
+                        indent,
+                        property.name,
+                        property.datatype,
+                        xml_escape(&property.value)
+                    ));
+                }
+                stack.push((i, depth, true));
+                stack.extend(node.children.iter().rev().map(|&child| (child, depth + 1, false)));
+            }
+        }
+        xml.push_str("  </phylogeny>\n</phyloxml>\n");
+        xml
+    }
+
+    fn write_newick(&self, index: usize, newick: &mut String) {
+        // iterative, so that deep unbalanced trees cannot overflow the stack
+        enum Step {
+            Open(usize),
+            Comma,
+            Close(usize),
+        }
+        let mut stack = vec![Step::Open(index)];
+        while let Some(step) = stack.pop() {
+            match step {
+                Step::Open(i) => {
+                    let node = &self.nodes[i];
+                    if node.children.is_empty() {
+                        self.write_node(i, newick);
+                        continue;
+                    }
+                    newick.push('(');
+                    stack.push(Step::Close(i));
+                    for (k, &child) in node.children.iter().enumerate().rev() {
+                        stack.push(Step::Open(child));
+                        if k > 0 {
+                            stack.push(Step::Comma);
+                        }
+                    }
+                }
+                Step::Comma => newick.push(','),
+                Step::Close(i) => {
+                    newick.push(')');
+                    self.write_node(i, newick);
+                }
+            }
+        }
+    }
+
+    fn write_node(&self, index: usize, newick: &mut String) {
+        let node = &self.nodes[index];
+        if let Some(label) = node.label.as_ref() {
+            newick.push_str(&newick_label(label));
+        }
+        if let Some(length) = node.length {
+            newick.push_str(&format!(":{}", length));
+        }
+    }
+}
+
+fn skip_comments(text: &[char], pos: &mut usize) {
+    loop {
+        while *pos < text.len() && text[*pos].is_whitespace() {
+            *pos += 1;
+        }
+        if text.get(*pos) != Some(&'[') {
+            return;
+        }
+        while *pos < text.len() && text[*pos] != ']' {
+            *pos += 1;
+        }
+        *pos += 1;
+    }
+}
+
+// Plain or quoted label, quotes inside a quoted label being doubled
+fn read_label(text: &[char], pos: &mut usize) -> Result<String, String> {
+    let mut label = String::new();
+    if text.get(*pos) == Some(&'\'') {
+        *pos += 1;
+        loop {
+            match text.get(*pos) {
+                Some('\'') if text.get(*pos + 1) == Some(&'\'') => {
+                    label.push('\'');
+                    *pos += 2;
+                }
+                Some('\'') => {
+                    *pos += 1;
+                    return Ok(label);
+                }
+                Some(&c) => {
+                    label.push(c);
+                    *pos += 1;
+                }
+                None => return Err("Unterminated quoted label in Newick tree".to_string()),
+            }
+        }
+    }
+    while *pos < text.len() && !":,);[".contains(text[*pos]) {
+        label.push(text[*pos]);
+        *pos += 1;
+    }
+    Ok(label.trim().to_string())
+}
+
+// Leaves of `nb_leaves` not in `set`
+fn complement(set: &[u64], nb_leaves: usize) -> Vec<u64> {
+    let mut complement: Vec<u64> = set.iter().map(|w| !w).collect();
+    // bits past the last leaf stay clear
+    if !nb_leaves.is_multiple_of(64) {
+        *complement.last_mut().unwrap() &= (1u64 << (nb_leaves % 64)) - 1;
+    }
+    complement
+}
+
+/// Newick label, quoted with `'` when it holds blanks or punctuation that would break the tree.
+pub fn newick_label(label: &str) -> String {
+    if label.chars().any(|c| c.is_whitespace() || "()[]':;,".contains(c)) {
+        format!("'{}'", label.replace('\'', "''"))
+    } else {
+        label.to_string()
+    }
+}
+
+// NEXUS word, quoted with `'` when it holds blanks or punctuation
+fn nexus_token(word: &str) -> String {
+    if !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric() || "_.-|".contains(c)) {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', "''"))
+    }
+}
+
+pub(crate) fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,1345 @@
+//! Validated configuration of the whole sketch → distance → tree pipeline.
+
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::budget::{estimate_budget, Budget};
+use crate::checkpoint::fingerprint;
+use crate::cluster::{greedy_clusters, linkage_clusters, representatives, single_linkage, threshold_graph, Linkage, Representative};
+
+use crate::dist::{
+    build_containment_matrix, build_distance_matrix, build_query_matrix, check_signatures, checked_distance, genome_label, idf_weights, report_non_finite, unique_genomes, update_distance_matrix,
+    DistanceKind,
+    DistanceModel,
+    Symmetrization,
+};
+use crate::distribution::{distance_summary, write_histogram, DistanceSummary};
+use crate::fit::{cophenetic_matrix, tree_fit, TreeFit};
+use crate::itol::{cluster_colors, leaf_labels, nearest_neighbor_heatmap};
+use crate::knn::nearest_neighbors;
+use crate::matrix::{DistMatrix, OutputValues, QueryMatrix};
+use crate::pairs::{write_pairs, PairsFormat};
+use crate::phylo::{Property, Tree};
+use crate::place::{add_leaves, place_queries};
+use crate::preprocess::Preprocessor;
+use crate::resampling::{consensus, resample_positions, resample_sketches, support, Resampling};
+use crate::shard::{write_shard, Shard};
+use crate::sketch::{sketch_genomes, sketch_genomes_multi_k, AmbigPolicy, Densification, HashFunction, SeqStats, Signature, SketchAlgo, SketchResult};
+use crate::sparse::sparse_nj;
+use crate::nni::{nni_around, Nni};
+use crate::ordination::{pcoa, umap, Pcoa};
+use crate::tree::{
+    build_tree, collapse_branches, fix_negative_branches, label_internal_nodes, ladderize_tree, refine_tree, refit_branch_lengths, root_tree,
+    BranchLengths, InternalLabels, Ladderize, NegativeBranches, Rooting, TreeAlgo,
+};
+use crate::weighting::Weighting;
+
+/// Builder for [PipelineConfig]. Defaults match the command line defaults.
+#[derive(Debug, Clone)]
+pub struct PipelineBuilder {
+    kmer_sizes: Vec<usize>,
+    sketch_size: usize,
+    algo: SketchAlgo,
+    scaled: usize,
+    bbits: u32,
+    densification: Densification,
+    seed: u64,
+    hash: HashFunction,
+    ambig: AmbigPolicy,
+    skip_softmasked: bool,
+    min_entropy: f64,
+    skip_empty: bool,
+    skip_bad_files: bool,
+    preprocessor: Option<Arc<dyn Preprocessor>>,
+    diagonal: bool,
+    prescreen: usize,
+    lsh_bands: usize,
+    lsh_rows: usize,
+    lsh_background: f64,
+    gpu: bool,
+    matrix_file: Option<PathBuf>,
+    checkpoint: Option<PathBuf>,
+    resume: bool,
+    shard: Option<Shard>,
+    distance_kind: DistanceKind,
+    distance_model: DistanceModel,
+    weighting: Weighting,
+    pair_details: bool,
+    pvalues: bool,
+    aligned_fraction: bool,
+    confidence: f64,
+    max_dist: f64,
+    min_shared: usize,
+    max_report_dist: f64,
+    output_values: OutputValues,
+    refine_sketch_size: usize,
+    refine_threshold: f64,
+    refine_pairs: bool,
+    symmetrization: Symmetrization,
+    tree_algo: TreeAlgo,
+    nni: Nni,
+    rooting: Rooting,
+    negative_branches: NegativeBranches,
+    internal_labels: InternalLabels,
+    ladderize: Ladderize,
+    branch_lengths: BranchLengths,
+    collapse_below: f64,
+    collapse_support: f64,
+    chunk_size: usize,
+    naive_percentage: usize,
+    tree_threads: usize,
+    cluster_threshold: f64,
+    linkage: Linkage,
+    exact_tree: bool,
+    bootstrap: usize,
+    jackknife: usize,
+    jackknife_drop: f64,
+}
+
+impl Default for PipelineBuilder {
+    fn default() -> Self {
+        PipelineBuilder {
+            kmer_sizes: vec![16],
+            sketch_size: 10240,
+            algo: SketchAlgo::OptDens,
+            scaled: 0,
+            bbits: 0,
+            densification: Densification::Optimal,
+            seed: 0,
+            hash: HashFunction::Permutation,
+            ambig: AmbigPolicy::Skip,
+            skip_softmasked: false,
+            min_entropy: 0.0,
+            skip_empty: false,
+            skip_bad_files: false,
+            preprocessor: None,
+            diagonal: false,
+            prescreen: 0,
+            lsh_bands: 0,
+            lsh_rows: 4,
+            lsh_background: 0.01,
+            gpu: false,
+            matrix_file: None,
+            checkpoint: None,
+            resume: false,
+            shard: None,
+            distance_kind: DistanceKind::MinHash,
+            distance_model: DistanceModel::BinDash,
+            weighting: Weighting::None,
+            pair_details: false,
+            pvalues: false,
+            aligned_fraction: false,
+            confidence: 0.0,
+            max_dist: 0.0,
+            min_shared: 0,
+            max_report_dist: 0.0,
+            output_values: OutputValues::Distance,
+            refine_sketch_size: 0,
+            refine_threshold: 0.05,
+            refine_pairs: false,
+            symmetrization: Symmetrization::Mean,
+            tree_algo: TreeAlgo::RapidNJ,
+            nni: Nni::None,
+            rooting: Rooting::None,
+            negative_branches: NegativeBranches::Keep,
+            internal_labels: InternalLabels::Support,
+            ladderize: Ladderize::None,
+            branch_lengths: BranchLengths::Tree,
+            collapse_below: 0.0,
+            collapse_support: 0.0,
+            chunk_size: 30,
+            naive_percentage: 90,
+            tree_threads: 0,
+            cluster_threshold: 0.05,
+            linkage: Linkage::Single,
+            exact_tree: false,
+            bootstrap: 0,
+            jackknife: 0,
+            jackknife_drop: 0.5,
+        }
+    }
+}
+
+impl PipelineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// k-mer size, at most 32
+    pub fn kmer(mut self, kmer_size: usize) -> Self {
+        self.kmer_sizes = vec![kmer_size];
+        self
+    }
+
+    /// several k-mer sizes sketched in a single pass over the genomes, see [PipelineConfig::sketch_multi_k].
+    /// The first one is used by the single k stages.
+    pub fn kmers(mut self, kmer_sizes: &[usize]) -> Self {
+        self.kmer_sizes = kmer_sizes.to_vec();
+        self
+    }
+
+    /// number of MinHash registers per genome
+    pub fn sketch_size(mut self, sketch_size: usize) -> Self {
+        self.sketch_size = sketch_size;
+        self
+    }
+
+    pub fn algo(mut self, algo: SketchAlgo) -> Self {
+        self.algo = algo;
+        self
+    }
+
+    /// FracMinHash scale factor: keep the k-mers hashing into the lowest 1/scaled of the hash space,
+    /// so that sketches grow with genome size. 0 keeps fixed size sketches built by `algo`.
+    pub fn scaled(mut self, scaled: usize) -> Self {
+        self.scaled = scaled;
+        self
+    }
+
+    /// keep only the lowest bits of each register (1, 2, 4, 8, 16 or 32), 0 keeps full registers
+    pub fn bbits(mut self, bbits: u32) -> Self {
+        self.bbits = bbits;
+        self
+    }
+
+    /// densification used by [SketchAlgo::OptDens], ignored otherwise
+    pub fn densification(mut self, densification: Densification) -> Self {
+        self.densification = densification;
+        self
+    }
+
+    /// seed of the k-mer hashing, 0 with the permutation sketches the canonical k-mer values directly.
+    /// Different seeds give independent sketches, e.g. to assess the estimator variance.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// hash function of canonical k-mer values, seeded by [PipelineBuilder::seed]
+    pub fn hash(mut self, hash: HashFunction) -> Self {
+        self.hash = hash;
+        self
+    }
+
+    /// handling of k-mers overlapping ambiguous bases
+    pub fn ambig(mut self, ambig: AmbigPolicy) -> Self {
+        self.ambig = ambig;
+        self
+    }
+
+    /// exclude lowercase (soft-masked) regions from k-mer generation
+    pub fn skip_softmasked(mut self, skip_softmasked: bool) -> Self {
+        self.skip_softmasked = skip_softmasked;
+        self
+    }
+
+    /// drop k-mers whose base composition entropy (in bits, 0 to 2) is below this value, 0 disables
+    pub fn min_entropy(mut self, min_entropy: f64) -> Self {
+        self.min_entropy = min_entropy;
+        self
+    }
+
+    /// drop genomes yielding no k-mer (or no hash of a FracMinHash sketch) instead of failing
+    pub fn skip_empty(mut self, skip_empty: bool) -> Self {
+        self.skip_empty = skip_empty;
+        self
+    }
+
+    /// drop genomes that cannot be read or sketched (missing, truncated or corrupt files) instead
+    /// of failing, their error being kept in their [SeqStats]
+    pub fn skip_bad_files(mut self, skip_bad_files: bool) -> Self {
+        self.skip_bad_files = skip_bad_files;
+        self
+    }
+
+    /// transformation of each genome file applied before sketching, see [crate::preprocess]
+    pub fn preprocessor(mut self, preprocessor: Arc<dyn Preprocessor>) -> Self {
+        self.preprocessor = Some(preprocessor);
+        self
+    }
+
+    /// compute self comparisons instead of leaving the diagonal at 0
+    pub fn diagonal(mut self, diagonal: bool) -> Self {
+        self.diagonal = diagonal;
+        self
+    }
+
+    /// number of registers in the fingerprints used to skip unrelated pairs, 0 disables
+    pub fn prescreen(mut self, prescreen: usize) -> Self {
+        self.prescreen = prescreen;
+        self
+    }
+
+    /// number of LSH bands, only pairs agreeing on a whole band being compared, 0 disables, see [crate::prescreen]
+    pub fn lsh_bands(mut self, lsh_bands: usize) -> Self {
+        self.lsh_bands = lsh_bands;
+        self
+    }
+
+    /// registers per LSH band
+    pub fn lsh_rows(mut self, lsh_rows: usize) -> Self {
+        self.lsh_rows = lsh_rows;
+        self
+    }
+
+    /// fraction of the pairs left out by LSH banding compared all the same
+    pub fn lsh_background(mut self, lsh_background: f64) -> Self {
+        self.lsh_background = lsh_background;
+        self
+    }
+
+    /// computes the distance matrix on a CUDA GPU when one is available, see [crate::gpu]
+    pub fn gpu(mut self, gpu: bool) -> Self {
+        self.gpu = gpu;
+        self
+    }
+
+    /// keeps the distance matrix in a memory-mapped file instead of memory, see [DistMatrix::new_mapped];
+    /// with several k-mer sizes each gets its own file, see [kmer_path]
+    pub fn matrix_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.matrix_file = Some(path.into());
+        self
+    }
+
+    /// writes completed rows of the distance matrix to a checkpoint file, removed once the matrix is done,
+    /// see [crate::checkpoint]; with several k-mer sizes each gets its own file, see [kmer_path]
+    pub fn checkpoint(mut self, path: impl Into<PathBuf>) -> Self {
+        self.checkpoint = Some(path.into());
+        self
+    }
+
+    /// continues from the rows of an existing checkpoint file instead of starting over
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    /// computes only the rows of the distance matrix of this shard, see [crate::shard]
+    pub fn shard(mut self, shard: Shard) -> Self {
+        self.shard = Some(shard);
+        self
+    }
+
+    /// statistic compared between genomes, composition distances replacing sketches by full k-mer profiles
+    pub fn distance_kind(mut self, distance_kind: DistanceKind) -> Self {
+        self.distance_kind = distance_kind;
+        self
+    }
+
+    /// transform of Jaccard indices into distances
+    pub fn distance_model(mut self, distance_model: DistanceModel) -> Self {
+        self.distance_model = distance_model;
+        self
+    }
+
+    /// weighting of shared hashes by their frequency among the genomes compared
+    pub fn weighting(mut self, weighting: Weighting) -> Self {
+        self.weighting = weighting;
+        self
+    }
+
+    /// add the Jaccard index and the number of shared hashes to the pairwise output
+    pub fn pair_details(mut self, pair_details: bool) -> Self {
+        self.pair_details = pair_details;
+        self
+    }
+
+    /// add Mash p-values to the pairwise output
+    pub fn pvalues(mut self, pvalues: bool) -> Self {
+        self.pvalues = pvalues;
+        self
+    }
+
+    /// add the estimated aligned fractions of both genomes to the pairwise output
+    pub fn aligned_fraction(mut self, aligned_fraction: bool) -> Self {
+        self.aligned_fraction = aligned_fraction;
+        self
+    }
+
+    /// level of the distance confidence intervals added to the pairwise output, 0 disables
+    pub fn confidence(mut self, confidence: f64) -> Self {
+        self.confidence = confidence;
+        self
+    }
+
+    /// cap of all distances, 0 disables
+    pub fn max_dist(mut self, max_dist: f64) -> Self {
+        self.max_dist = max_dist;
+        self
+    }
+
+    /// minimum number of shared hashes for a distance to be estimated, pairs below get
+    /// the maximum distance in the matrix and NA in the pairwise output. 0 disables
+    pub fn min_shared(mut self, min_shared: usize) -> Self {
+        self.min_shared = min_shared;
+        self
+    }
+
+    /// only pairs at this distance or closer are written to the pairwise output, 0 disables
+    pub fn max_report_dist(mut self, max_report_dist: f64) -> Self {
+        self.max_report_dist = max_report_dist;
+        self
+    }
+
+    /// values of the pairwise output, distances or ANI
+    pub fn output_values(mut self, output_values: OutputValues) -> Self {
+        self.output_values = output_values;
+        self
+    }
+
+    /// sketch size used to recompute distances within coarse clusters, 0 disables the refinement
+    pub fn refine_sketch_size(mut self, refine_sketch_size: usize) -> Self {
+        self.refine_sketch_size = refine_sketch_size;
+        self
+    }
+
+    /// single linkage distance defining the coarse clusters to refine
+    pub fn refine_threshold(mut self, refine_threshold: f64) -> Self {
+        self.refine_threshold = refine_threshold;
+        self
+    }
+
+    /// refine only the pairs closer than the refine threshold instead of whole clusters
+    pub fn refine_pairs(mut self, refine_pairs: bool) -> Self {
+        self.refine_pairs = refine_pairs;
+        self
+    }
+
+    /// how asymmetric distances are combined before tree construction
+    pub fn symmetrization(mut self, symmetrization: Symmetrization) -> Self {
+        self.symmetrization = symmetrization;
+        self
+    }
+
+    pub fn tree(mut self, tree_algo: TreeAlgo) -> Self {
+        self.tree_algo = tree_algo;
+        self
+    }
+
+    /// criterion of the nearest neighbor interchanges refining the tree
+    pub fn nni(mut self, nni: Nni) -> Self {
+        self.nni = nni;
+        self
+    }
+
+    /// where the tree is rooted
+    pub fn root(mut self, rooting: Rooting) -> Self {
+        self.rooting = rooting;
+        self
+    }
+
+    /// what is done with negative branch lengths
+    pub fn negative_branches(mut self, negative_branches: NegativeBranches) -> Self {
+        self.negative_branches = negative_branches;
+        self
+    }
+
+    /// labels of the internal nodes of the tree
+    pub fn internal_labels(mut self, internal_labels: InternalLabels) -> Self {
+        self.internal_labels = internal_labels;
+        self
+    }
+
+    /// order of the children in the tree, by clade size
+    pub fn ladderize(mut self, ladderize: Ladderize) -> Self {
+        self.ladderize = ladderize;
+        self
+    }
+
+    /// internal branches shorter than this are collapsed into polytomies, 0 for none
+    pub fn collapse_below(mut self, collapse_below: f64) -> Self {
+        self.collapse_below = collapse_below;
+        self
+    }
+
+    /// internal branches with a support below this percentage are collapsed into polytomies, 0 for none
+    pub fn collapse_support(mut self, collapse_support: f64) -> Self {
+        self.collapse_support = collapse_support;
+        self
+    }
+
+    /// chunk size for RapidNJ/Hybrid methods
+    pub fn chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// estimation of the branch lengths of the tree
+    pub fn branch_lengths(mut self, branch_lengths: BranchLengths) -> Self {
+        self.branch_lengths = branch_lengths;
+        self
+    }
+
+    /// percentage of naive steps for the hybrid method
+    pub fn naive_percentage(mut self, naive_percentage: usize) -> Self {
+        self.naive_percentage = naive_percentage;
+        self
+    }
+
+    /// threads of the tree construction, 0 for the threads of the calling rayon pool
+    pub fn tree_threads(mut self, tree_threads: usize) -> Self {
+        self.tree_threads = tree_threads;
+        self
+    }
+
+    /// distance threshold of the clusters of the genomes, annotating the leaves of the tree
+    pub fn cluster_threshold(mut self, cluster_threshold: f64) -> Self {
+        self.cluster_threshold = cluster_threshold;
+        self
+    }
+
+    /// linkage of the clusters at the cluster threshold
+    pub fn linkage(mut self, linkage: Linkage) -> Self {
+        self.linkage = linkage;
+        self
+    }
+
+    /// builds the tree from full precision distances instead of distances rounded
+    /// as in the default PHYLIP output
+    pub fn exact_tree(mut self, exact_tree: bool) -> Self {
+        self.exact_tree = exact_tree;
+        self
+    }
+
+    /// number of bootstrap replicates drawn from the sketch registers, 0 for none
+    pub fn bootstrap(mut self, bootstrap: usize) -> Self {
+        self.bootstrap = bootstrap;
+        self
+    }
+
+    /// number of jackknife replicates, each dropping a fraction of the sketch registers, 0 for none
+    pub fn jackknife(mut self, jackknife: usize) -> Self {
+        self.jackknife = jackknife;
+        self
+    }
+
+    /// fraction of the registers dropped by each jackknife replicate
+    pub fn jackknife_drop(mut self, jackknife_drop: f64) -> Self {
+        self.jackknife_drop = jackknife_drop;
+        self
+    }
+
+    /// Checks parameter ranges and returns the frozen configuration.
+    pub fn build(self) -> Result<PipelineConfig, String> {
+        if self.kmer_sizes.is_empty() {
+            return Err("at least one k-mer size is needed".to_string());
+        }
+        for (i, &kmer_size) in self.kmer_sizes.iter().enumerate() {
+            if kmer_size == 0 || kmer_size > 32 {
+                return Err(format!("k-mer size must be between 1 and 32, got {}", kmer_size));
+            }
+            if self.kmer_sizes[..i].contains(&kmer_size) {
+                return Err(format!("k-mer size {} given twice", kmer_size));
+            }
+        }
+        if self.sketch_size == 0 {
+            return Err("sketch size must be positive".to_string());
+        }
+        if !(0.0..=2.0).contains(&self.min_entropy) {
+            return Err(format!("minimum k-mer entropy must be between 0 and 2 bits, got {}", self.min_entropy));
+        }
+        if self.bbits > 0 && (self.bbits > 32 || 64 % self.bbits != 0) {
+            return Err(format!("b-bit registers must be 1, 2, 4, 8, 16 or 32 bits, got {}", self.bbits));
+        }
+        if self.bbits > 0 && self.scaled > 0 {
+            return Err("b-bit registers apply to fixed size sketches, not to FracMinHash".to_string());
+        }
+        if !(0.0..1.0).contains(&self.confidence) {
+            return Err(format!("confidence level must be between 0 and 1, got {}", self.confidence));
+        }
+        if self.distance_kind != DistanceKind::MinHash
+            && (self.scaled > 0 || self.bbits > 0 || self.seed != 0 || self.hash != HashFunction::Permutation)
+        {
+            return Err("composition distances count k-mers themselves, without --scaled, --bbits, --seed or --hash".to_string());
+        }
+        if self.distance_kind != DistanceKind::MinHash && self.weighting == Weighting::Idf {
+            return Err("IDF weighting applies to MinHash sketches, not to composition distances".to_string());
+        }
+        if self.weighting == Weighting::Idf && self.bbits > 0 {
+            return Err("IDF weighting needs full registers, it cannot be combined with b-bit sketches".to_string());
+        }
+        if self.algo == SketchAlgo::SetSketch
+            && self.distance_kind == DistanceKind::MinHash
+            && self.scaled == 0
+            && (self.bbits > 0 || self.gpu || self.weighting != Weighting::None || self.prescreen > 0 || self.lsh_bands > 0)
+        {
+            return Err("SetSketch registers are compared by joint estimation, not by equality: no --bbits, --gpu, --weighting, --prescreen or --lsh_bands".to_string());
+        }
+        if self.distance_model == DistanceModel::Containment && (self.scaled == 0 || self.distance_kind != DistanceKind::MinHash) {
+            return Err("the containment distance model needs FracMinHash sketches (--scaled)".to_string());
+        }
+        if self.distance_model == DistanceModel::Containment
+            && (self.weighting != Weighting::None || self.confidence > 0.0 || self.shard.is_some())
+        {
+            return Err("containment distances are directional: no --weighting, --confidence or --shard".to_string());
+        }
+        if self.gpu && !cfg!(feature = "cuda") {
+            return Err("GPU distances need bindashtree built with the cuda feature".to_string());
+        }
+        if self.gpu
+            && (self.scaled > 0 || self.bbits > 0 || self.distance_kind != DistanceKind::MinHash || self.weighting != Weighting::None)
+        {
+            return Err("GPU distances compare full registers, without --scaled, --bbits, composition distances or weighting".to_string());
+        }
+        if self.gpu && self.checkpoint.is_some() {
+            return Err("GPU distances are not checkpointed".to_string());
+        }
+        if self.shard.is_some() && (self.gpu || self.refine_sketch_size > 0) {
+            return Err("shards are computed on CPU, without cluster refinement".to_string());
+        }
+        if self.resume && self.checkpoint.is_none() {
+            return Err("resuming needs a checkpoint file".to_string());
+        }
+        if self.max_dist < 0.0 {
+            return Err(format!("maximum distance cannot be negative, got {}", self.max_dist));
+        }
+        if self.min_shared > 0 && self.max_dist == 0.0 {
+            return Err("a minimum number of shared hashes needs a maximum distance for the pairs below it".to_string());
+        }
+        if self.max_report_dist < 0.0 {
+            return Err(format!("maximum reported distance cannot be negative, got {}", self.max_report_dist));
+        }
+        if self.prescreen > self.sketch_size {
+            return Err(format!("prescreen registers ({}) cannot exceed the sketch size ({})", self.prescreen, self.sketch_size));
+        }
+        if self.lsh_bands > 0 && (self.lsh_rows == 0 || self.lsh_bands * self.lsh_rows > self.sketch_size) {
+            return Err(format!(
+                "LSH bands ({} x {} registers) must fit in the sketch size ({})",
+                self.lsh_bands, self.lsh_rows, self.sketch_size
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.lsh_background) {
+            return Err(format!("LSH background fraction must be between 0 and 1, got {}", self.lsh_background));
+        }
+        if self.refine_sketch_size > 0 && self.refine_sketch_size <= self.sketch_size {
+            return Err(format!(
+                "refine sketch size ({}) must exceed the sketch size ({})",
+                self.refine_sketch_size, self.sketch_size
+            ));
+        }
+        if self.refine_sketch_size > 0 && self.scaled > 0 {
+            return Err("cluster refinement needs fixed size sketches, not FracMinHash".to_string());
+        }
+        if self.bootstrap > 0 && self.jackknife > 0 {
+            return Err("replicates are drawn either by bootstrap or by jackknife, not both".to_string());
+        }
+        let replicates = self.bootstrap + self.jackknife;
+        if replicates > 0 && (self.scaled > 0 || self.bbits > 0 || self.distance_kind != DistanceKind::MinHash) {
+            return Err("replicates resample full registers, without --scaled, --bbits or composition distances".to_string());
+        }
+        if replicates > 0 && (self.refine_sketch_size > 0 || self.shard.is_some()) {
+            return Err("replicates are computed on the whole matrix, without cluster refinement or shards".to_string());
+        }
+        if !(0.0..1.0).contains(&self.jackknife_drop) {
+            return Err(format!("jackknife drop fraction must be at least 0 and below 1, got {}", self.jackknife_drop));
+        }
+        if let Rooting::Outgroup(outgroup) = &self.rooting {
+            if outgroup.is_empty() || outgroup.iter().any(|label| label.is_empty()) {
+                return Err("outgroup taxa cannot be empty".to_string());
+            }
+        }
+        if self.nni != Nni::None && matches!(self.tree_algo, TreeAlgo::Upgma) {
+            return Err("NNI refines unrooted trees, not the ultrametric UPGMA tree".to_string());
+        }
+        if self.branch_lengths == BranchLengths::Ols && matches!(self.tree_algo, TreeAlgo::Upgma) {
+            return Err("OLS branch lengths apply to unrooted trees, not the ultrametric UPGMA tree".to_string());
+        }
+        if self.collapse_below < 0.0 {
+            return Err(format!("collapse length cannot be negative, got {}", self.collapse_below));
+        }
+        if !(0.0..=100.0).contains(&self.collapse_support) {
+            return Err(format!("collapse support must be a percentage between 0 and 100, got {}", self.collapse_support));
+        }
+        if self.collapse_support > 0.0 && replicates == 0 {
+            return Err("collapsing by support needs bootstrap or jackknife replicates".to_string());
+        }
+        if self.cluster_threshold < 0.0 {
+            return Err(format!("cluster threshold cannot be negative, got {}", self.cluster_threshold));
+        }
+        if self.chunk_size == 0 {
+            return Err("chunk size must be positive".to_string());
+        }
+        if self.naive_percentage > 100 {
+            return Err(format!("naive percentage must be at most 100, got {}", self.naive_percentage));
+        }
+        Ok(PipelineConfig {
+            kmer_size: self.kmer_sizes[0],
+            kmer_sizes: self.kmer_sizes,
+            sketch_size: self.sketch_size,
+            algo: self.algo,
+            scaled: self.scaled,
+            bbits: self.bbits,
+            densification: self.densification,
+            seed: self.seed,
+            hash: self.hash,
+            ambig: self.ambig,
+            skip_softmasked: self.skip_softmasked,
+            min_entropy: self.min_entropy,
+            skip_empty: self.skip_empty,
+            skip_bad_files: self.skip_bad_files,
+            preprocessor: self.preprocessor,
+            diagonal: self.diagonal,
+            prescreen: self.prescreen,
+            lsh_bands: self.lsh_bands,
+            lsh_rows: self.lsh_rows,
+            lsh_background: self.lsh_background,
+            gpu: self.gpu,
+            matrix_file: self.matrix_file,
+            checkpoint: self.checkpoint,
+            resume: self.resume,
+            shard: self.shard,
+            distance_kind: self.distance_kind,
+            distance_model: self.distance_model,
+            weighting: self.weighting,
+            pair_details: self.pair_details,
+            pvalues: self.pvalues,
+            aligned_fraction: self.aligned_fraction,
+            confidence: self.confidence,
+            max_dist: self.max_dist,
+            min_shared: self.min_shared,
+            max_report_dist: self.max_report_dist,
+            output_values: self.output_values,
+            refine_sketch_size: self.refine_sketch_size,
+            refine_threshold: self.refine_threshold,
+            refine_pairs: self.refine_pairs,
+            symmetrization: self.symmetrization,
+            tree_algo: self.tree_algo,
+            nni: self.nni,
+            rooting: self.rooting,
+            negative_branches: self.negative_branches,
+            internal_labels: self.internal_labels,
+            ladderize: self.ladderize,
+            branch_lengths: self.branch_lengths,
+            collapse_below: self.collapse_below,
+            collapse_support: self.collapse_support,
+            chunk_size: self.chunk_size,
+            naive_percentage: self.naive_percentage,
+            tree_threads: self.tree_threads,
+            cluster_threshold: self.cluster_threshold,
+            linkage: self.linkage,
+            exact_tree: self.exact_tree,
+            bootstrap: self.bootstrap,
+            jackknife: self.jackknife,
+            jackknife_drop: self.jackknife_drop,
+        })
+    }
+}
+
+/// A validated pipeline configuration, obtained from [PipelineBuilder::build].
+#[derive(Debug, Clone)]
+pub struct PipelineConfig {
+    pub(crate) kmer_size: usize,
+    pub(crate) kmer_sizes: Vec<usize>,
+    pub(crate) sketch_size: usize,
+    pub(crate) algo: SketchAlgo,
+    pub(crate) scaled: usize,
+    pub(crate) bbits: u32,
+    pub(crate) densification: Densification,
+    pub(crate) seed: u64,
+    pub(crate) hash: HashFunction,
+    pub(crate) ambig: AmbigPolicy,
+    pub(crate) skip_softmasked: bool,
+    pub(crate) min_entropy: f64,
+    pub(crate) skip_empty: bool,
+    pub(crate) skip_bad_files: bool,
+    pub(crate) preprocessor: Option<Arc<dyn Preprocessor>>,
+    pub(crate) diagonal: bool,
+    pub(crate) prescreen: usize,
+    pub(crate) lsh_bands: usize,
+    pub(crate) lsh_rows: usize,
+    pub(crate) lsh_background: f64,
+    pub(crate) gpu: bool,
+    pub(crate) matrix_file: Option<PathBuf>,
+    pub(crate) checkpoint: Option<PathBuf>,
+    pub(crate) resume: bool,
+    pub(crate) shard: Option<Shard>,
+    pub(crate) distance_kind: DistanceKind,
+    pub(crate) distance_model: DistanceModel,
+    pub(crate) weighting: Weighting,
+    pub(crate) pair_details: bool,
+    pub(crate) pvalues: bool,
+    pub(crate) aligned_fraction: bool,
+    pub(crate) confidence: f64,
+    pub(crate) max_dist: f64,
+    pub(crate) min_shared: usize,
+    pub(crate) max_report_dist: f64,
+    pub(crate) output_values: OutputValues,
+    pub(crate) refine_sketch_size: usize,
+    pub(crate) refine_threshold: f64,
+    pub(crate) refine_pairs: bool,
+    pub(crate) symmetrization: Symmetrization,
+    pub(crate) tree_algo: TreeAlgo,
+    pub(crate) nni: Nni,
+    pub(crate) rooting: Rooting,
+    pub(crate) negative_branches: NegativeBranches,
+    pub(crate) internal_labels: InternalLabels,
+    pub(crate) ladderize: Ladderize,
+    pub(crate) branch_lengths: BranchLengths,
+    pub(crate) collapse_below: f64,
+    pub(crate) collapse_support: f64,
+    pub(crate) chunk_size: usize,
+    pub(crate) naive_percentage: usize,
+    pub(crate) tree_threads: usize,
+    pub(crate) cluster_threshold: f64,
+    pub(crate) linkage: Linkage,
+    pub(crate) exact_tree: bool,
+    pub(crate) bootstrap: usize,
+    pub(crate) jackknife: usize,
+    pub(crate) jackknife_drop: f64,
+}
+
+impl PipelineConfig {
+    pub fn kmer_size(&self) -> usize {
+        self.kmer_size
+    }
+
+    pub fn kmer_sizes(&self) -> &[usize] {
+        &self.kmer_sizes
+    }
+
+    pub fn sketch_size(&self) -> usize {
+        self.sketch_size
+    }
+
+    pub fn algo(&self) -> SketchAlgo {
+        self.algo
+    }
+
+    pub fn scaled(&self) -> usize {
+        self.scaled
+    }
+
+    pub fn densification(&self) -> Densification {
+        self.densification
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn hash(&self) -> HashFunction {
+        self.hash
+    }
+
+    pub fn gpu(&self) -> bool {
+        self.gpu
+    }
+
+    pub fn skip_bad_files(&self) -> bool {
+        self.skip_bad_files
+    }
+
+    pub fn tree_algo(&self) -> &TreeAlgo {
+        &self.tree_algo
+    }
+
+    /// Estimated memory and time of a run on the `genomes` (paths and file sizes), see [estimate_budget].
+    pub fn budget(&self, genomes: &[(String, u64)], threads: usize, tree: bool) -> Budget {
+        estimate_budget(self, genomes, threads, tree)
+    }
+
+    /// Sketches the genomes, see [sketch_genomes].
+    pub fn sketch(&self, genomes: &[String]) -> Result<SketchResult, String> {
+        sketch_genomes(self, genomes)
+    }
+
+    /// Sketches the genomes at each k-mer size, reading every file once, see [sketch_genomes_multi_k].
+    /// Each result comes with the configuration of its k-mer size, for the downstream stages.
+    pub fn sketch_multi_k(&self, genomes: &[String]) -> Result<Vec<(PipelineConfig, SketchResult)>, String> {
+        let configs: Vec<PipelineConfig> = self
+            .kmer_sizes
+            .iter()
+            .map(|&kmer_size| {
+                let multi_k = (self.kmer_sizes.len() > 1).then_some(kmer_size);
+                let per_k = |path: &PathBuf| PathBuf::from(kmer_path(&path.to_string_lossy(), multi_k));
+                let matrix_file = self.matrix_file.as_ref().map(per_k);
+                let checkpoint = self.checkpoint.as_ref().map(per_k);
+                PipelineConfig { kmer_size, kmer_sizes: vec![kmer_size], matrix_file, checkpoint, ..self.clone() }
+            })
+            .collect();
+        let results = sketch_genomes_multi_k(&configs, genomes)?;
+        Ok(configs.into_iter().zip(results).collect())
+    }
+
+    /// Full (possibly asymmetric) distance matrix of the genomes, in the order given.
+    pub fn distance_matrix(&self, sketches: &HashMap<String, Signature>, genomes: &[String]) -> Result<DistMatrix, String> {
+        build_distance_matrix(self, sketches, genomes)
+    }
+
+    /// Distance matrix of the genomes, reusing the distances between those of `previous`, the matrix
+    /// of an earlier run on part of them, see [update_distance_matrix].
+    pub fn update_distance_matrix(
+        &self,
+        previous: &DistMatrix,
+        sketches: &HashMap<String, Signature>,
+        genomes: &[String],
+    ) -> Result<DistMatrix, String> {
+        update_distance_matrix(self, previous, sketches, genomes)
+    }
+
+    /// Writes the rows of the shard of `matrix`, computed by [PipelineConfig::distance_matrix], to a shard file.
+    pub fn write_shard(&self, matrix: &DistMatrix, genomes: &[String], path: &str) -> Result<(), String> {
+        let shard = self.shard.ok_or("no shard to write")?;
+        write_shard(path, matrix, shard, fingerprint(self, genomes))
+    }
+
+    /// Recomputes the distances within single linkage clusters of `matrix` with sketches of
+    /// the refine sketch size, leaving between cluster distances at the coarse resolution.
+    /// With refine pairs, only the pairs closer than the refine threshold are recomputed.
+    /// Returns `matrix` unchanged when refinement is disabled. NaN or infinite distances are replaced
+    /// and reported as by [build_distance_matrix].
+    pub fn refine(&self, mut matrix: DistMatrix, genomes: &[String]) -> Result<DistMatrix, String> {
+        if self.refine_sketch_size == 0 {
+            return Ok(matrix);
+        }
+        let n = genomes.len();
+        let pairs: Vec<(usize, usize)> = if self.refine_pairs {
+            (0..n)
+                .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+                .filter(|&(i, j)| matrix.get(i, j) < self.refine_threshold)
+                .collect()
+        } else {
+            let clusters = single_linkage(&matrix, self.refine_threshold);
+            let mut members: Vec<Vec<usize>> = Vec::new();
+            for (i, &c) in clusters.iter().enumerate() {
+                if c == members.len() {
+                    members.push(Vec::new());
+                }
+                members[c].push(i);
+            }
+            members
+                .iter()
+                .flat_map(|m| m.iter().enumerate().flat_map(move |(a, &i)| m[a + 1..].iter().map(move |&j| (i, j))))
+                .collect()
+        };
+        let mut refined = vec![false; n];
+        for &(i, j) in &pairs {
+            refined[i] = true;
+            refined[j] = true;
+        }
+        let to_refine: Vec<String> = (0..n).filter(|&i| refined[i]).map(|i| genomes[i].clone()).collect();
+        log::info!(
+            "refining {} pairs ({} genomes) with sketch size {}",
+            pairs.len(),
+            to_refine.len(),
+            self.refine_sketch_size
+        );
+        if to_refine.is_empty() {
+            return Ok(matrix);
+        }
+        let fine_config = PipelineConfig { sketch_size: self.refine_sketch_size, ..self.clone() };
+        let (fine_sketches, _) = fine_config.sketch(&to_refine)?;
+        let weights = idf_weights(self, &fine_sketches, &to_refine)?;
+        let non_finite = Mutex::new(Vec::new());
+        let distances: Vec<f64> = pairs
+            .par_iter()
+            .map(|&(i, j)| {
+                let pair = (&fine_sketches[&genomes[i]], &fine_sketches[&genomes[j]]);
+                checked_distance(self, weights.as_ref(), pair, (i, j), &non_finite)
+            })
+            .collect();
+        report_non_finite(self, genomes, genomes, non_finite);
+        for (&(i, j), &d) in pairs.iter().zip(distances.iter()) {
+            matrix.set(i, j, d);
+            matrix.set(j, i, d);
+        }
+        Ok(matrix)
+    }
+
+    /// Greedy dereplication of the genomes at `ani` percent identity: by decreasing genome size, or in
+    /// input order for [Representative::First], a genome farther than `1 - ani / 100` from all the
+    /// representatives chosen before it becomes one, any other joins the nearest. For
+    /// [Representative::Medoid] the medoid of each cluster then replaces its representative, which
+    /// may leave members farther than the threshold from it. `stats` give the genome sizes, in
+    /// bases. Returns the representative of each genome, as its position in `genomes`, and the
+    /// distance to it. NaN or infinite distances are replaced and reported as by [build_distance_matrix].
+    pub fn derep(
+        &self,
+        sketches: &HashMap<String, Signature>,
+        stats: &[(String, SeqStats)],
+        genomes: &[String],
+        ani: f64,
+        representative: Representative,
+    ) -> Result<Vec<(usize, f64)>, String> {
+        let sizes = genome_bases(stats, genomes);
+        let mut priority: Vec<usize> = (0..genomes.len()).collect();
+        if representative != Representative::First {
+            priority.sort_by_key(|&i| std::cmp::Reverse(sizes[i]));
+        }
+        let signatures: Vec<&Signature> = genomes.iter().map(|g| &sketches[g]).collect();
+        check_signatures(self, &signatures)?;
+        let weights = idf_weights(self, sketches, genomes)?;
+        let non_finite = Mutex::new(Vec::new());
+        let distance = |i: usize, j: usize| {
+            checked_distance(self, weights.as_ref(), (signatures[i], signatures[j]), (i, j), &non_finite)
+        };
+        let clusters = greedy_clusters(&priority, 1.0 - ani / 100.0, distance);
+        if representative != Representative::Medoid {
+            report_non_finite(self, genomes, genomes, non_finite);
+            return Ok(clusters);
+        }
+        let mut ids = vec![usize::MAX; genomes.len()];
+        let mut nb_clusters = 0;
+        let cluster_ids: Vec<usize> = clusters
+            .iter()
+            .map(|&(r, _)| {
+                if ids[r] == usize::MAX {
+                    ids[r] = nb_clusters;
+                    nb_clusters += 1;
+                }
+                ids[r]
+            })
+            .collect();
+        let medoids = representatives(&cluster_ids, representative, &sizes, distance);
+        let clusters = cluster_ids
+            .par_iter()
+            .enumerate()
+            .map(|(i, &c)| {
+                let medoid = medoids[c];
+                (medoid, if medoid == i { 0.0 } else { distance(i, medoid) })
+            })
+            .collect();
+        report_non_finite(self, genomes, genomes, non_finite);
+        Ok(clusters)
+    }
+
+    /// Representative of each cluster of the genomes, `clusters` giving the cluster index of each of
+    /// them, see [representatives]. `stats` give the genome sizes, in bases. NaN or infinite
+    /// distances are replaced and reported as by [build_distance_matrix].
+    pub fn representatives(
+        &self,
+        sketches: &HashMap<String, Signature>,
+        stats: &[(String, SeqStats)],
+        genomes: &[String],
+        clusters: &[usize],
+        representative: Representative,
+    ) -> Result<Vec<usize>, String> {
+        let sizes = genome_bases(stats, genomes);
+        let signatures: Vec<&Signature> = genomes.iter().map(|g| &sketches[g]).collect();
+        let weights = match representative {
+            Representative::Medoid => {
+                check_signatures(self, &signatures)?;
+                idf_weights(self, sketches, genomes)?
+            }
+            _ => None,
+        };
+        let non_finite = Mutex::new(Vec::new());
+        let representatives = representatives(clusters, representative, &sizes, |i, j| {
+            checked_distance(self, weights.as_ref(), (signatures[i], signatures[j]), (i, j), &non_finite)
+        });
+        report_non_finite(self, genomes, genomes, non_finite);
+        Ok(representatives)
+    }
+
+    /// Graph of the genomes joining the pairs at `ani` percent identity or more, as the positions of
+    /// the two genomes in `genomes` and their distance, see [threshold_graph]. NaN or infinite
+    /// distances are replaced and reported as by [build_distance_matrix].
+    pub fn threshold_graph(
+        &self,
+        sketches: &HashMap<String, Signature>,
+        genomes: &[String],
+        ani: f64,
+    ) -> Result<Vec<(usize, usize, f64)>, String> {
+        let signatures: Vec<&Signature> = genomes.iter().map(|g| &sketches[g]).collect();
+        check_signatures(self, &signatures)?;
+        let weights = idf_weights(self, sketches, genomes)?;
+        let non_finite = Mutex::new(Vec::new());
+        let edges = threshold_graph(genomes.len(), 1.0 - ani / 100.0, |i, j| {
+            checked_distance(self, weights.as_ref(), (signatures[i], signatures[j]), (i, j), &non_finite)
+        });
+        report_non_finite(self, genomes, genomes, non_finite);
+        Ok(edges)
+    }
+
+    /// Distances from query genomes to reference genomes, sketched with this configuration, see [build_query_matrix].
+    pub fn query_matrix(
+        &self,
+        query_sketches: &HashMap<String, Signature>,
+        queries: &[String],
+        sketches: &HashMap<String, Signature>,
+        genomes: &[String],
+    ) -> Result<QueryMatrix, String> {
+        build_query_matrix(self, query_sketches, queries, sketches, genomes)
+    }
+
+    /// Newick tree `newick` of the `genomes` with the `queries` grafted at their least-squares
+    /// placement from their distances to the genomes, see [crate::place].
+    pub fn place(
+        &self,
+        newick: &str,
+        query_sketches: &HashMap<String, Signature>,
+        queries: &[String],
+        sketches: &HashMap<String, Signature>,
+        genomes: &[String],
+    ) -> Result<String, String> {
+        let tree = Tree::from_newick(newick)?;
+        let distances = self.query_matrix(query_sketches, queries, sketches, genomes)?;
+        let placements = place_queries(&tree, &distances)?;
+        for placement in &placements {
+            log::info!(
+                "{} placed with a pendant branch of {}, residual sum of squares {}",
+                placement.query,
+                placement.pendant,
+                placement.rss
+            );
+        }
+        let leaves: Vec<(usize, f64, String, f64)> = placements.into_iter().map(|p| (p.node, p.distal, p.query, p.pendant)).collect();
+        Ok(tree.graft(&leaves).to_newick())
+    }
+
+    /// Containment of each genome in every other, rows being the contained genomes, see [build_containment_matrix].
+    /// `stats` give the genome sizes.
+    pub fn containment_matrix(
+        &self,
+        sketches: &HashMap<String, Signature>,
+        stats: &[(String, SeqStats)],
+        genomes: &[String],
+    ) -> Result<DistMatrix, String> {
+        let signatures: Vec<&Signature> = genomes.iter().map(|g| &sketches[g]).collect();
+        check_signatures(self, &signatures)?;
+        Ok(build_containment_matrix(sketches, genomes, &genome_sizes(stats, genomes)))
+    }
+
+    /// Streams the pairwise distances of `matrix`, computed by [PipelineConfig::distance_matrix],
+    /// in long format to `path`, see [write_pairs].
+    /// `stats` give the genome sizes of the p-values and aligned fractions.
+    pub fn pairs(
+        &self,
+        matrix: &DistMatrix,
+        sketches: &HashMap<String, Signature>,
+        stats: &[(String, SeqStats)],
+        genomes: &[String],
+        (path, format): (&str, PairsFormat),
+        resume: bool,
+    ) -> Result<(), String> {
+        let sizes = (self.pvalues || self.aligned_fraction).then(|| genome_sizes(stats, genomes));
+        write_pairs(self, matrix, sketches, genomes, sizes.as_deref(), (path, format), resume)
+    }
+
+    /// Newick tree built from a distance matrix, symmetrized first, then refined by nearest neighbor
+    /// interchanges, its branch lengths refitted, with negative branches handled, rooted and ladderized.
+    /// With tree threads, this runs in a thread pool of its own.
+    pub fn tree(&self, matrix: &DistMatrix) -> Result<String, String> {
+        if self.tree_threads == 0 {
+            return self.build_tree(matrix);
+        }
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(self.tree_threads)
+            .build()
+            .map_err(|e| format!("Cannot create the tree thread pool: {}", e))?;
+        pool.install(|| self.build_tree(matrix))
+    }
+
+    fn build_tree(&self, matrix: &DistMatrix) -> Result<String, String> {
+        check_tree_size(matrix.len())?;
+        let matrix = matrix.symmetrize(self.symmetrization);
+        let newick = build_tree(&self.tree_algo, self.chunk_size, self.naive_percentage, self.exact_tree, &matrix)?;
+        let newick = refine_tree(self.nni, &matrix, self.exact_tree, newick)?;
+        let newick = refit_branch_lengths(self.branch_lengths, &matrix, self.exact_tree, newick)?;
+        let newick = fix_negative_branches(self.negative_branches, newick)?;
+        let newick = root_tree(&self.rooting, newick).map_err(|e| format!("Cannot root tree: {}", e))?;
+        ladderize_tree(self.ladderize, newick)
+    }
+
+    /// Newick tree of the genomes of `labels` built from their distances to their `neighbors` and
+    /// their estimated `means` distances to all the genomes only, e.g. found in an HNSW index, see
+    /// [sparse_nj], with negative branches handled, rooted and ladderized as by [PipelineConfig::tree].
+    pub fn sparse_tree(&self, labels: &[String], neighbors: &[Vec<(usize, f64)>], means: &[f64]) -> Result<String, String> {
+        check_tree_size(labels.len())?;
+        let newick = sparse_nj(labels, neighbors, means).to_newick();
+        let newick = fix_negative_branches(self.negative_branches, newick)?;
+        let newick = root_tree(&self.rooting, newick).map_err(|e| format!("Cannot root tree: {}", e))?;
+        ladderize_tree(self.ladderize, newick)
+    }
+
+    /// Newick tree of `matrix` updated from the Newick tree `newick` of part of its labels, e.g. built
+    /// by an earlier run before genomes were appended, instead of built again: the missing labels are
+    /// placed one by one, see [add_leaves], then the tree is refined by nearest neighbor interchanges
+    /// around them (balanced minimum evolution without NNI criterion) before negative branches are
+    /// handled, rooted and ladderized as by [PipelineConfig::tree].
+    pub fn update_tree(&self, newick: &str, matrix: &DistMatrix) -> Result<String, String> {
+        let matrix = matrix.symmetrize(self.symmetrization);
+        let (tree, added) = add_leaves(&Tree::from_newick(newick)?, &matrix)?;
+        log::info!("{} genomes added to the tree", added.len());
+        let criterion = if self.nni == Nni::None { Nni::Bme } else { self.nni };
+        let newick = nni_around(&tree, &matrix, self.exact_tree, criterion, &added)?.to_newick();
+        let newick = refit_branch_lengths(self.branch_lengths, &matrix, self.exact_tree, newick)?;
+        let newick = fix_negative_branches(self.negative_branches, newick)?;
+        let newick = root_tree(&self.rooting, newick).map_err(|e| format!("Cannot root tree: {}", e))?;
+        ladderize_tree(self.ladderize, newick)
+    }
+
+    /// Cophenetic distances of the Newick tree `newick` built from `matrix`, and their least-squares
+    /// fit to the matrix, symmetrized as for the tree.
+    pub fn tree_fit(&self, matrix: &DistMatrix, newick: &str) -> Result<(DistMatrix, TreeFit), String> {
+        let matrix = matrix.symmetrize(self.symmetrization);
+        let cophenetic = cophenetic_matrix(newick, matrix.labels())?;
+        let fit = tree_fit(&matrix, &cophenetic);
+        Ok((cophenetic, fit))
+    }
+
+    /// Flat clusters of the genomes of `matrix` at the cluster threshold, with the linkage of this
+    /// configuration, symmetrized as for the tree, see [linkage_clusters]: the cluster index of each genome.
+    pub fn clusters(&self, matrix: &DistMatrix) -> Vec<usize> {
+        linkage_clusters(&matrix.symmetrize(self.symmetrization), self.linkage, self.cluster_threshold)
+    }
+
+    /// Summary of the distances of all the pairs of genomes of `matrix`, symmetrized as for the tree,
+    /// see [distance_summary].
+    pub fn distance_summary(&self, matrix: &DistMatrix) -> Option<DistanceSummary> {
+        distance_summary(&matrix.symmetrize(self.symmetrization))
+    }
+
+    /// Writes the histogram of the distances of all the pairs of genomes of `matrix`, symmetrized as
+    /// for the tree, in `bins` bins, see [write_histogram].
+    pub fn write_histogram<W: Write>(&self, writer: &mut W, matrix: &DistMatrix, bins: usize) -> std::io::Result<()> {
+        write_histogram(writer, &matrix.symmetrize(self.symmetrization), bins)
+    }
+
+    /// Principal coordinates of the genomes of `matrix`, symmetrized as for the tree, on its first
+    /// `axes` axes, see [pcoa].
+    pub fn pcoa(&self, matrix: &DistMatrix, axes: usize) -> Pcoa {
+        pcoa(&matrix.symmetrize(self.symmetrization), axes)
+    }
+
+    /// UMAP plane coordinates of the genomes of `matrix`, symmetrized as for the tree, from the graph
+    /// of their `k` nearest neighbors, starting from their first two principal coordinates, see [umap].
+    pub fn umap(&self, matrix: &DistMatrix, k: usize) -> Vec<[f64; 2]> {
+        let matrix = matrix.symmetrize(self.symmetrization);
+        umap(&nearest_neighbors(&matrix, k), Some(&pcoa(&matrix, 2).coordinates))
+    }
+
+    /// Annotations of the leaves of the tree of `matrix`, by label: the genome file and its size in
+    /// bases when sketched (`stats` being empty otherwise), and its cluster, see [PipelineConfig::clusters].
+    pub fn tip_properties(&self, matrix: &DistMatrix, stats: &[(String, SeqStats)]) -> HashMap<String, Vec<Property>> {
+        let mut properties: HashMap<String, Vec<Property>> = HashMap::new();
+        for (genome, stats) in stats {
+            properties.entry(genome_label(genome)).or_default().extend([
+                Property { name: "path".to_string(), datatype: "xsd:string", value: genome.clone() },
+                Property { name: "genome_size".to_string(), datatype: "xsd:integer", value: stats.nb_bases.to_string() },
+            ]);
+        }
+        let clusters = self.clusters(matrix);
+        for (label, cluster) in matrix.labels().iter().zip(clusters) {
+            properties.entry(label.clone()).or_default().push(Property {
+                name: "cluster".to_string(),
+                datatype: "xsd:integer",
+                value: cluster.to_string(),
+            });
+        }
+        properties
+    }
+
+    /// iTOL annotation files of the tree built from `matrix`, as (file name suffix, content): leaves
+    /// colored by cluster, their nearest neighbor distance and, when `label_map` is not
+    /// empty, their display names.
+    pub fn itol_annotations(&self, matrix: &DistMatrix, label_map: &HashMap<String, String>) -> Vec<(&'static str, String)> {
+        let clusters = self.clusters(matrix);
+        let mut files = vec![
+            (".itol_clusters.txt", cluster_colors(matrix, &clusters)),
+            (".itol_nearest.txt", nearest_neighbor_heatmap(matrix)),
+        ];
+        if !label_map.is_empty() {
+            files.push((".itol_labels.txt", leaf_labels(matrix, label_map)));
+        }
+        files
+    }
+
+    /// Number of bootstrap or jackknife replicates, 0 for none.
+    pub fn nb_replicates(&self) -> usize {
+        self.bootstrap + self.jackknife
+    }
+
+    /// Newick trees of the bootstrap or jackknife replicates, built from resampled registers of `sketches`,
+    /// see [crate::resampling].
+    pub fn replicate_trees(&self, sketches: &HashMap<String, Signature>, genomes: &[String]) -> Result<Vec<String>, String> {
+        let resampling = if self.jackknife > 0 { Resampling::Jackknife { drop: self.jackknife_drop } } else { Resampling::Bootstrap };
+        // replicates are small and short lived: in memory, without checkpoint, and left unrooted as
+        // an outgroup may not hold in all of them
+        let replicate_config =
+            PipelineConfig { matrix_file: None, checkpoint: None, resume: false, rooting: Rooting::None, ..self.clone() };
+        (0..self.nb_replicates())
+            .map(|replicate| {
+                log::info!("replicate {} of {}", replicate + 1, self.nb_replicates());
+                let positions = resample_positions(self.sketch_size, resampling, self.seed, replicate);
+                let resampled = resample_sketches(sketches, genomes, &positions)?;
+                replicate_config.tree(&replicate_config.distance_matrix(&resampled, genomes)?)
+            })
+            .collect()
+    }
+
+    /// Annotates the Newick tree `newick` with the support of its splits in the `replicates`.
+    pub fn support(&self, newick: &str, replicates: &[String]) -> Result<String, String> {
+        support(newick, replicates)
+    }
+
+    /// Collapses the short or poorly supported internal branches of the Newick tree `newick` into
+    /// polytomies, see [collapse_branches].
+    pub fn collapse(&self, newick: String) -> Result<String, String> {
+        collapse_branches(self.collapse_below, self.collapse_support, newick)
+    }
+
+    /// Labels the internal nodes of the Newick tree `newick`, see [InternalLabels].
+    pub fn label_internal_nodes(&self, newick: String) -> Result<String, String> {
+        label_internal_nodes(self.internal_labels, newick)
+    }
+
+    /// Majority-rule consensus tree of the `replicates`, in Newick format.
+    pub fn consensus(&self, replicates: &[String]) -> Result<String, String> {
+        consensus(replicates)
+    }
+
+    /// Runs the whole pipeline on a list of genome files and returns the Newick tree,
+    /// with bootstrap or jackknife support when asked for.
+    pub fn run(&self, genomes: &[String]) -> Result<String, String> {
+        let genomes = unique_genomes(genomes)?;
+        let (sketches, _) = self.sketch(&genomes)?;
+        let genomes: Vec<String> = genomes.iter().filter(|g| sketches.contains_key(*g)).cloned().collect();
+        let matrix = self.refine(self.distance_matrix(&sketches, &genomes)?, &genomes)?;
+        let mut newick = self.tree(&matrix)?;
+        if self.nb_replicates() > 0 {
+            let replicates = self.replicate_trees(&sketches, &genomes)?;
+            newick = self.support(&newick, &replicates)?;
+        }
+        let newick = self.collapse(newick)?;
+        self.label_internal_nodes(newick)
+    }
+}
+
+// An unrooted tree needs three leaves, fewer are left when most genomes are skipped
+fn check_tree_size(nb_genomes: usize) -> Result<(), String> {
+    if nb_genomes < 3 {
+        return Err(format!("a tree needs at least 3 genomes, {} left", nb_genomes));
+    }
+    Ok(())
+}
+
+// Numbers of k-mers of the genomes, in the order of `genomes`
+fn genome_sizes(stats: &[(String, SeqStats)], genomes: &[String]) -> Vec<usize> {
+    let nb_kmers: HashMap<&String, usize> = stats.iter().map(|(g, s)| (g, s.nb_kmers)).collect();
+    genomes.iter().map(|g| nb_kmers.get(g).copied().unwrap_or(0)).collect()
+}
+
+// Numbers of bases of the genomes, in the order of `genomes`
+fn genome_bases(stats: &[(String, SeqStats)], genomes: &[String]) -> Vec<usize> {
+    let nb_bases: HashMap<&String, usize> = stats.iter().map(|(g, s)| (g, s.nb_bases)).collect();
+    genomes.iter().map(|g| nb_bases.get(g).copied().unwrap_or(0)).collect()
+}
+
+/// Output file of one k-mer size when several are computed, try.nwk becoming try.k21.nwk.
+pub fn kmer_path(path: &str, kmer_size: Option<usize>) -> String {
+    let Some(kmer_size) = kmer_size else {
+        return path.to_string();
+    };
+    let path = Path::new(path);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(extension) => format!("{}.k{}.{}", stem, kmer_size, extension.to_string_lossy()),
+        None => format!("{}.k{}", stem, kmer_size),
+    };
+    path.with_file_name(name).to_string_lossy().into_owned()
+}
@@ -0,0 +1,159 @@
+//! Distance-based placement of new genomes on an existing tree.
+//!
+//! A query is attached to the branch, at the position along it and with the pendant branch length,
+//! minimizing the squared differences between its distances to the leaves and the path lengths
+//! through the attachment point (least-squares placement, as in APPLES, Balaban et al., 2020).
+//! Above node `u`, with `e_i = D_i - d(i, u)`, a leaf below `u` is at `e_i - x - l` of its target
+//! and any other one at `e_i + x - l`, for an attachment at `x` above `u` and a pendant branch of
+//! length `l`: a linear least-squares problem in `(x, l)`. The sums it needs for every branch
+//! come from two passes over the tree, so that a query is placed in time linear in the tree size.
+
+use std::collections::HashMap;
+
+use crate::matrix::{DistMatrix, QueryMatrix};
+use crate::phylo::Tree;
+
+/// Best attachment of a query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    pub query: String,
+    /// node below the branch the query is attached to
+    pub node: usize,
+    /// length from `node` to the attachment point
+    pub distal: f64,
+    pub pendant: f64,
+    /// residual sum of squares of the distances
+    pub rss: f64,
+}
+
+/// Places the queries of `distances` (queries by reference genomes, every leaf of `tree` being a
+/// reference) on `tree`, independently of each other.
+pub fn place_queries(tree: &Tree, distances: &QueryMatrix) -> Result<Vec<Placement>, String> {
+    let references: HashMap<&str, usize> = distances.references().iter().enumerate().map(|(r, label)| (label.as_str(), r)).collect();
+    let mut leaf_reference = vec![usize::MAX; tree.len()];
+    for node in (0..tree.len()).filter(|&node| tree.node(node).children.is_empty()) {
+        let label = tree.node(node).label.as_deref().unwrap_or("");
+        leaf_reference[node] = *references.get(label).ok_or(format!("Leaf of the tree not among the reference genomes: {}", label))?;
+    }
+    if tree.len() < 2 {
+        return Err("Cannot place queries on a tree of a single leaf".to_string());
+    }
+    Ok(distances
+        .queries()
+        .iter()
+        .enumerate()
+        .map(|(q, query)| {
+            let target = |node: usize| distances.get(q, leaf_reference[node]);
+            let (node, distal, pendant, rss) = best_branch(tree, &target);
+            Placement { query: query.clone(), node, distal, pendant, rss }
+        })
+        .collect())
+}
+
+/// Adds the labels of the symmetric `matrix` missing from `tree`, whose leaves it must all hold,
+/// one at a time in the order of the matrix, each at its least-squares placement on the tree
+/// holding those added before it. Returns the new tree and the labels added.
+pub fn add_leaves(tree: &Tree, matrix: &DistMatrix) -> Result<(Tree, Vec<String>), String> {
+    let positions: HashMap<&str, usize> = matrix.labels().iter().enumerate().map(|(i, label)| (label.as_str(), i)).collect();
+    let leaf_positions = |tree: &Tree| -> Result<Vec<usize>, String> {
+        let mut leaf_position = vec![usize::MAX; tree.len()];
+        for node in (0..tree.len()).filter(|&node| tree.node(node).children.is_empty()) {
+            let label = tree.node(node).label.as_deref().unwrap_or("");
+            leaf_position[node] = *positions.get(label).ok_or(format!("Leaf of the tree not in the matrix: {}", label))?;
+        }
+        Ok(leaf_position)
+    };
+    let mut in_tree = vec![false; matrix.len()];
+    for position in leaf_positions(tree)?.into_iter().filter(|&position| position != usize::MAX) {
+        in_tree[position] = true;
+    }
+    if tree.len() < 2 {
+        return Err("Cannot add leaves to a tree of a single leaf".to_string());
+    }
+    let mut tree = tree.clone();
+    let mut added = Vec::new();
+    for i in (0..matrix.len()).filter(|&i| !in_tree[i]) {
+        let leaf_position = leaf_positions(&tree)?;
+        let (node, distal, pendant, _) = best_branch(&tree, &|node| matrix.get(i, leaf_position[node]));
+        let label = matrix.labels()[i].clone();
+        tree = tree.graft(&[(node, distal, label.clone(), pendant)]);
+        added.push(label);
+    }
+    Ok((tree, added))
+}
+
+// Moments of the leaves below a node, `d` being the path length from the node to a leaf and `t` its target
+#[derive(Clone, Copy, Default)]
+struct Moments {
+    count: f64,
+    sum_d: f64,
+    sum_d2: f64,
+    sum_td: f64,
+    sum_t: f64,
+}
+
+// Branch (by its lower node), distal and pendant lengths and residual sum of squares of the best attachment
+fn best_branch(tree: &Tree, target: &dyn Fn(usize) -> f64) -> (usize, f64, f64, f64) {
+    let order = tree.post_order();
+    let length = |node: usize| tree.node(node).length.unwrap_or(0.0);
+    let mut below = vec![Moments::default(); tree.len()];
+    for &node in &order {
+        let children = &tree.node(node).children;
+        if children.is_empty() {
+            let t = target(node);
+            below[node] = Moments { count: 1.0, sum_d: 0.0, sum_d2: 0.0, sum_td: 0.0, sum_t: t };
+            continue;
+        }
+        let mut m = Moments::default();
+        for &child in children {
+            let (c, l) = (below[child], length(child));
+            m.count += c.count;
+            m.sum_d += c.sum_d + c.count * l;
+            m.sum_d2 += c.sum_d2 + 2.0 * l * c.sum_d + c.count * l * l;
+            m.sum_td += c.sum_td + l * c.sum_t;
+            m.sum_t += c.sum_t;
+        }
+        below[node] = m;
+    }
+    // the same sums over all the leaves, from the root down
+    let root = tree.root();
+    let all = below[root];
+    let sum_t2: f64 = order.iter().filter(|&&node| tree.node(node).children.is_empty()).map(|&node| target(node).powi(2)).sum();
+    let mut total = vec![Moments::default(); tree.len()];
+    total[root] = all;
+    for &node in order.iter().rev() {
+        for &child in &tree.node(node).children {
+            let (c, l, up) = (below[child], length(child), total[node]);
+            // leaves below the child get closer by l, the others farther
+            let sum_d_below = c.sum_d + c.count * l;
+            total[child] = Moments {
+                count: all.count,
+                sum_d: up.sum_d + l * (all.count - 2.0 * c.count),
+                sum_d2: up.sum_d2 + all.count * l * l + 2.0 * l * (up.sum_d - 2.0 * sum_d_below),
+                sum_td: up.sum_td + l * (all.sum_t - 2.0 * c.sum_t),
+                sum_t: all.sum_t,
+            };
+        }
+    }
+    let n = all.count;
+    let mut best = (root, 0.0, 0.0, f64::INFINITY);
+    for &node in order.iter().filter(|&&node| node != root) {
+        let (b, t) = (below[node], total[node]);
+        let sum_below = b.sum_t - b.sum_d;
+        let sum_above = (t.sum_t - b.sum_t) - (t.sum_d - b.sum_d);
+        let q = sum_t2 - 2.0 * t.sum_td + t.sum_d2;
+        let (e, f, m) = (sum_below + sum_above, sum_below - sum_above, 2.0 * b.count - n);
+        let branch = length(node).max(0.0);
+        let det = n * n - m * m;
+        let mut x = if det > 0.0 { (n * f - m * e) / det } else { 0.0 };
+        x = x.clamp(0.0, branch);
+        let mut l = ((e - m * x) / n).max(0.0);
+        x = ((f - m * l) / n).clamp(0.0, branch);
+        l = ((e - m * x) / n).max(0.0);
+        let rss = q - 2.0 * x * f - 2.0 * l * e + n * x * x + 2.0 * m * x * l + n * l * l;
+        if rss < best.3 {
+            best = (node, x, l, rss);
+        }
+    }
+    best
+}
@@ -0,0 +1,175 @@
+//! Rendering of a tree as an SVG rectangular phylogram.
+//!
+//! Leaves are drawn one per row in the order of the tree, each node at the horizontal position of
+//! its depth (sum of branch lengths from the root, negative lengths drawn as zero). Branches carry
+//! their length above them, internal nodes their label (e.g. support) below, and a scale bar sits
+//! under the tree.
+
+use crate::phylo::{xml_escape, Tree};
+
+const ROW_HEIGHT: f64 = 16.0;
+const MARGIN: f64 = 20.0;
+const TREE_WIDTH: f64 = 600.0;
+const FONT_SIZE: f64 = 12.0;
+const SMALL_FONT_SIZE: f64 = 9.0;
+// average glyph width relative to the font size, to make room for the tip labels
+const CHAR_WIDTH: f64 = 0.6;
+
+/// SVG document of the Newick tree `newick`.
+pub fn tree_svg(newick: &str) -> Result<String, String> {
+    let tree = Tree::from_newick(newick)?;
+    if tree.is_empty() {
+        return Err("Cannot plot an empty tree".to_string());
+    }
+    // depths in pre-order, then leaf rows and node heights in post-order
+    let mut depth = vec![0.0f64; tree.len()];
+    let mut stack = vec![tree.root()];
+    while let Some(node) = stack.pop() {
+        for &child in &tree.node(node).children {
+            depth[child] = depth[node] + tree.node(child).length.unwrap_or(0.0).max(0.0);
+            stack.push(child);
+        }
+    }
+    let mut y = vec![0.0f64; tree.len()];
+    let mut nb_leaves = 0;
+    for node in post_order_left_to_right(&tree) {
+        let children = &tree.node(node).children;
+        y[node] = match (children.first(), children.last()) {
+            (Some(&first), Some(&last)) => (y[first] + y[last]) / 2.0,
+            _ => {
+                nb_leaves += 1;
+                MARGIN + (nb_leaves - 1) as f64 * ROW_HEIGHT
+            }
+        };
+    }
+    let max_depth = depth.iter().cloned().fold(0.0, f64::max);
+    let scale = if max_depth > 0.0 { TREE_WIDTH / max_depth } else { 0.0 };
+    let x = |node: usize| MARGIN + depth[node] * scale;
+    let label_width = (0..tree.len())
+        .filter(|&node| tree.node(node).children.is_empty())
+        .map(|node| tree.node(node).label.as_deref().unwrap_or("").chars().count())
+        .max()
+        .unwrap_or(0) as f64
+        * FONT_SIZE
+        * CHAR_WIDTH;
+    let width = 2.0 * MARGIN + TREE_WIDTH + FONT_SIZE / 2.0 + label_width;
+    let height = 2.0 * MARGIN + nb_leaves as f64 * ROW_HEIGHT + 2.0 * ROW_HEIGHT;
+
+    let mut svg = format!(
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{:.0}\" height=\"{:.0}\" font-family=\"sans-serif\">\n",
+        width, height
+    );
+    svg.push_str("<g stroke=\"black\" stroke-width=\"1\" fill=\"none\">\n");
+    for node in 0..tree.len() {
+        let children = &tree.node(node).children;
+        if let (Some(&first), Some(&last)) = (children.first(), children.last()) {
+            svg.push_str(&format!(
+                "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\"/>\n",
+                x(node),
+                y[first],
+                x(node),
+                y[last]
+            ));
+        }
+        for &child in children {
+            svg.push_str(&format!(
+                "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\"/>\n",
+                x(node),
+                y[child],
+                x(child),
+                y[child]
+            ));
+        }
+    }
+    svg.push_str("</g>\n");
+
+    svg.push_str(&format!("<g font-size=\"{}\" dominant-baseline=\"middle\">\n", FONT_SIZE));
+    for node in (0..tree.len()).filter(|&node| tree.node(node).children.is_empty()) {
+        let label = tree.node(node).label.as_deref().unwrap_or("");
+        svg.push_str(&format!(
+            "<text x=\"{:.1}\" y=\"{:.1}\">{}</text>\n",
+            x(node) + FONT_SIZE / 2.0,
+            y[node],
+            xml_escape(label)
+        ));
+    }
+    svg.push_str("</g>\n");
+
+    svg.push_str(&format!("<g font-size=\"{}\" fill=\"dimgray\" text-anchor=\"middle\">\n", SMALL_FONT_SIZE));
+    for node in 0..tree.len() {
+        for &child in &tree.node(node).children {
+            if let Some(length) = tree.node(child).length {
+                svg.push_str(&format!(
+                    "<text x=\"{:.1}\" y=\"{:.1}\">{}</text>\n",
+                    (x(node) + x(child)) / 2.0,
+                    y[child] - 2.0,
+                    short_number(length)
+                ));
+            }
+        }
+    }
+    for node in (0..tree.len()).filter(|&node| !tree.node(node).children.is_empty()) {
+        if let Some(label) = tree.node(node).label.as_ref() {
+            svg.push_str(&format!(
+                "<text x=\"{:.1}\" y=\"{:.1}\" text-anchor=\"end\">{}</text>\n",
+                x(node) - 2.0,
+                y[node] + SMALL_FONT_SIZE + 1.0,
+                xml_escape(label)
+            ));
+        }
+    }
+    svg.push_str("</g>\n");
+
+    if scale > 0.0 {
+        let bar = scale_bar_length(max_depth);
+        let bar_y = MARGIN + nb_leaves as f64 * ROW_HEIGHT + ROW_HEIGHT / 2.0;
+        svg.push_str(&format!(
+            "<line x1=\"{:.1}\" y1=\"{:.1}\" x2=\"{:.1}\" y2=\"{:.1}\" stroke=\"black\" stroke-width=\"1\"/>\n",
+            MARGIN,
+            bar_y,
+            MARGIN + bar * scale,
+            bar_y
+        ));
+        svg.push_str(&format!(
+            "<text x=\"{:.1}\" y=\"{:.1}\" font-size=\"{}\" text-anchor=\"middle\" dominant-baseline=\"hanging\">{:.*}</text>\n",
+            MARGIN + bar * scale / 2.0,
+            bar_y + 3.0,
+            SMALL_FONT_SIZE,
+            (-bar.log10().floor()).max(0.0) as usize,
+            bar
+        ));
+    }
+    svg.push_str("</svg>\n");
+    Ok(svg)
+}
+
+// Nodes after their children, children from the first to the last, so that leaves come in tree order
+fn post_order_left_to_right(tree: &Tree) -> Vec<usize> {
+    let mut order = Vec::with_capacity(tree.len());
+    let mut stack = vec![(tree.root(), false)];
+    while let Some((node, done)) = stack.pop() {
+        if done {
+            order.push(node);
+            continue;
+        }
+        stack.push((node, true));
+        stack.extend(tree.node(node).children.iter().rev().map(|&child| (child, false)));
+    }
+    order
+}
+
+// Round length of about a fifth of the tree depth: 1, 2 or 5 times a power of 10
+fn scale_bar_length(max_depth: f64) -> f64 {
+    let target = max_depth / 5.0;
+    let power = 10f64.powf(target.log10().floor());
+    [5.0, 2.0, 1.0].iter().map(|m| m * power).find(|&l| l <= target).unwrap_or(power)
+}
+
+// Three significant digits
+fn short_number(value: f64) -> String {
+    if value == 0.0 || !value.is_finite() {
+        return format!("{}", value);
+    }
+    let digits = (2 - value.abs().log10().floor() as i32).max(0) as usize;
+    format!("{:.*}", digits, value)
+}
@@ -0,0 +1,66 @@
+//! Per genome preprocessing applied before sketching.
+//!
+//! A [Preprocessor] turns a genome file into the FASTA/FASTQ stream actually sketched, so that
+//! site specific cleaning (adapter removal, contig filtering...) can be plugged in without forking the crate.
+//! The stream may be gzip compressed, it is decompressed on the fly.
+
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{self, Read};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+/// Source of the sequences of a genome.
+pub trait Preprocessor: Send + Sync + Debug {
+    fn open(&self, path: &str) -> Result<Box<dyn Read + Send>, String>;
+}
+
+/// Pipes each genome file, as stored on disk, through a shell command and sketches its standard output,
+/// e.g. `seqkit seq -m 1000` to drop contigs shorter than 1 kb.
+#[derive(Debug, Clone)]
+pub struct CommandPreprocessor {
+    command: String,
+}
+
+impl CommandPreprocessor {
+    pub fn new(command: &str) -> Self {
+        CommandPreprocessor { command: command.to_string() }
+    }
+}
+
+// Output of the command, reporting its failure once the output is exhausted
+struct CommandOutput {
+    command: String,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Read for CommandOutput {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let nb_read = self.stdout.read(buf)?;
+        if nb_read == 0 && !buf.is_empty() {
+            let status = self.child.wait()?;
+            if !status.success() {
+                return Err(io::Error::other(format!(
+                    "preprocessing command `{}` failed: {}",
+                    self.command, status
+                )));
+            }
+        }
+        Ok(nb_read)
+    }
+}
+
+impl Preprocessor for CommandPreprocessor {
+    fn open(&self, path: &str) -> Result<Box<dyn Read + Send>, String> {
+        let input = File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(&self.command)
+            .stdin(input)
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("Cannot run preprocessing command `{}`: {}", self.command, e))?;
+        let stdout = child.stdout.take().unwrap();
+        Ok(Box::new(CommandOutput { command: self.command.clone(), child, stdout }))
+    }
+}
@@ -0,0 +1,126 @@
+//! Cheap prescreen of unrelated pairs.
+//!
+//! Each genome gets a tiny fingerprint: a 16 bit hash of each of its first registers. Two genomes whose
+//! fingerprints agree at no position are considered unrelated and their full register comparison is skipped.
+//! A pair with Jaccard `J` is wrongly skipped with probability `(1 - J)^F` for `F` fingerprint registers,
+//! negligible for pairs within a genus with the usual `F` of 64 or more.
+//!
+//! For very large collections, LSH banding avoids comparing all pairs: the registers are cut into
+//! bands of `R` registers and only genomes agreeing on a whole band, candidates, are compared, along
+//! with a sparse random background of the other pairs. A pair with Jaccard `J` is a candidate with
+//! probability `1 - (1 - J^R)^B` for `B` bands.
+//! Only full register sketches are prescreened, FracMinHash and b-bit sketches are always compared in full.
+
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+use crate::dist::hamming_to_distance;
+use crate::pipeline::PipelineConfig;
+use crate::sketch::{mix64, Signature};
+
+/// Fingerprints and LSH candidates of a list of genomes, indexed like the list.
+pub struct Prescreen {
+    prints: Vec<Vec<u16>>,
+    // sorted candidates of each genome, None without banding
+    candidates: Option<Vec<Vec<u32>>>,
+    background: f64,
+    saturated: f64,
+}
+
+impl Prescreen {
+    /// Fingerprints over the first registers of each sketch and LSH bands, as set in `config`.
+    pub fn new(config: &PipelineConfig, sketches: &HashMap<String, Signature>, genomes: &[String]) -> Self {
+        let nb_registers = config.prescreen;
+        let mut saturated = 0.0;
+        let registers: Vec<Option<&[f32]>> = genomes
+            .iter()
+            .map(|g| match &sketches[g] {
+                Signature::Registers(registers) => {
+                    saturated = saturated_distance(registers.len(), config.kmer_size);
+                    Some(registers.as_slice())
+                }
+                _ => None,
+            })
+            .collect();
+        let prints = registers
+            .iter()
+            .map(|r| match r {
+                Some(registers) if nb_registers > 0 => {
+                    registers.iter().take(nb_registers).map(|&v| (mix64(v.to_bits() as u64) >> 48) as u16).collect()
+                }
+                _ => Vec::new(),
+            })
+            .collect();
+        let registers: Option<Vec<&[f32]>> = registers.into_iter().collect();
+        let candidates = match registers {
+            Some(registers) if config.lsh_bands > 0 => {
+                let candidates = band_candidates(&registers, config.lsh_bands, config.lsh_rows);
+                let nb_candidates: usize = candidates.iter().map(|c| c.len()).sum();
+                let nb_pairs = genomes.len() * genomes.len().saturating_sub(1) / 2;
+                log::info!("{} candidate pairs out of {} from LSH banding", nb_candidates / 2, nb_pairs);
+                Some(candidates)
+            }
+            _ => None,
+        };
+        Prescreen { prints, candidates, background: config.lsh_background, saturated }
+    }
+
+    /// The saturated distance if the pair `(i, j)` can be skipped, None if it needs a full comparison.
+    pub fn skip(&self, i: usize, j: usize) -> Option<f64> {
+        if let Some(candidates) = self.candidates.as_ref() {
+            if candidates[i].binary_search(&(j as u32)).is_err() && !self.in_background(i, j) {
+                return Some(self.saturated);
+            }
+        }
+        let (a, b) = (&self.prints[i], &self.prints[j]);
+        if a.is_empty() || b.is_empty() || a.iter().zip(b.iter()).any(|(x, y)| x == y) {
+            None
+        } else {
+            Some(self.saturated)
+        }
+    }
+
+    // pairs of the background are drawn by hashing, the same for (i, j) and (j, i) in any run
+    fn in_background(&self, i: usize, j: usize) -> bool {
+        let (i, j) = if i < j { (i, j) } else { (j, i) };
+        let h = mix64(((i as u64) << 32) ^ j as u64);
+        ((h >> 11) as f64 / (1u64 << 53) as f64) < self.background
+    }
+}
+
+// Candidates of each genome: the genomes with equal registers over at least one of the bands
+fn band_candidates(registers: &[&[f32]], nb_bands: usize, band_rows: usize) -> Vec<Vec<u32>> {
+    let buckets: Vec<Vec<Vec<u32>>> = (0..nb_bands)
+        .into_par_iter()
+        .map(|band| {
+            let mut buckets: HashMap<u64, Vec<u32>> = HashMap::new();
+            for (i, r) in registers.iter().enumerate() {
+                let key = r[band * band_rows..(band + 1) * band_rows]
+                    .iter()
+                    .fold(band as u64, |h, v| mix64(h ^ v.to_bits() as u64));
+                buckets.entry(key).or_default().push(i as u32);
+            }
+            buckets.into_values().filter(|b| b.len() > 1).collect()
+        })
+        .collect();
+    let mut candidates = vec![Vec::new(); registers.len()];
+    for band in buckets {
+        for bucket in band {
+            for &i in &bucket {
+                candidates[i as usize].extend(bucket.iter().filter(|&&j| j != i));
+            }
+        }
+        // duplicates across bands are dropped as they come, keeping memory to the distinct candidates
+        candidates.par_iter_mut().for_each(|c| {
+            c.sort_unstable();
+            c.dedup();
+        });
+    }
+    candidates
+}
+
+/// Distance given to pairs dropped by the prescreen: the largest finite distance the sketches
+/// can produce, that of a single shared register.
+pub fn saturated_distance(sketch_size: usize, kmer_size: usize) -> f64 {
+    hamming_to_distance(1.0 - 1.0 / sketch_size as f32, kmer_size)
+}
@@ -0,0 +1,43 @@
+//! Per genome QC table.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use crate::dist::genome_label;
+use crate::sketch::SeqStats;
+
+/// Writes one tab separated line per genome with its sequence accounting and sketching cost.
+pub fn write_stats(stats: &[(String, SeqStats)], path: &str) -> Result<(), String> {
+    let write_error = |e: std::io::Error| format!("Error writing stats file {}: {}", path, e);
+    let mut f = BufWriter::new(File::create(path).map_err(|e| format!("Cannot create stats file {}: {}", path, e))?);
+    writeln!(
+        f,
+        "genome\tpath\tsequences\tbases\tshort_sequences\tshort_bases\tkmers\tlow_complexity_kmers\tfile_size\tsketch_time_s\tstatus"
+    )
+    .map_err(write_error)?;
+    for (genome, s) in stats {
+        writeln!(
+            f,
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{:.3}\t{}",
+            genome_label(genome),
+            genome,
+            s.nb_seqs,
+            s.nb_bases,
+            s.nb_short_seqs,
+            s.nb_short_bases,
+            s.nb_kmers,
+            s.nb_low_complexity,
+            s.file_size,
+            s.sketch_time,
+            if s.error.is_some() {
+                "failed"
+            } else if s.skipped {
+                "skipped"
+            } else {
+                "ok"
+            }
+        )
+        .map_err(write_error)?;
+    }
+    f.flush().map_err(write_error)
+}
@@ -0,0 +1,139 @@
+//! Support of the tree splits from resampled sketch registers, by bootstrap or jackknife.
+//!
+//! The registers of a sketch play the part of the alignment columns of the classic bootstrap: each
+//! bootstrap replicate draws as many register positions as the sketch size, with replacement, and each
+//! jackknife replicate drops a fraction of the positions. The same positions are kept for every genome,
+//! so that distances and trees of the replicates are computed from the sketches already made.
+//! The support of a split of the main tree is the percentage of replicate trees having it, and the
+//! majority-rule consensus tree holds the splits found in more than half of them.
+
+use std::collections::{HashMap, HashSet};
+
+use rand::rngs::StdRng;
+use rand::seq::index::sample;
+use rand::{Rng, SeedableRng};
+
+use crate::phylo::Tree;
+use crate::sketch::Signature;
+
+/// How the registers of the replicates are drawn.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resampling {
+    /// as many positions as registers, with replacement
+    Bootstrap,
+    /// positions without replacement, dropping this fraction of them
+    Jackknife { drop: f64 },
+}
+
+/// Register positions of replicate `replicate`.
+pub fn resample_positions(nb_registers: usize, resampling: Resampling, seed: u64, replicate: usize) -> Vec<usize> {
+    let mut rng = StdRng::seed_from_u64(seed.wrapping_add(replicate as u64));
+    match resampling {
+        Resampling::Bootstrap => (0..nb_registers).map(|_| rng.gen_range(0..nb_registers)).collect(),
+        Resampling::Jackknife { drop } => {
+            let kept = ((nb_registers as f64 * (1.0 - drop)).round() as usize).clamp(1, nb_registers);
+            let mut positions = sample(&mut rng, nb_registers, kept).into_vec();
+            positions.sort_unstable();
+            positions
+        }
+    }
+}
+
+/// Sketches made of the registers at `positions`, for the genomes given.
+pub fn resample_sketches(
+    sketches: &HashMap<String, Signature>,
+    genomes: &[String],
+    positions: &[usize],
+) -> Result<HashMap<String, Signature>, String> {
+    genomes
+        .iter()
+        .map(|genome| match &sketches[genome] {
+            Signature::Registers(registers) => {
+                let resampled = positions.iter().map(|&p| registers[p]).collect();
+                Ok((genome.clone(), Signature::Registers(resampled)))
+            }
+            Signature::SetSketch { b, a, registers, .. } => {
+                let resampled = positions.iter().map(|&p| registers[p]).collect();
+                Ok((genome.clone(), Signature::setsketch(*b, *a, resampled)))
+            }
+            _ => Err("register resampling needs full register sketches".to_string()),
+        })
+        .collect()
+}
+
+/// Labels the internal nodes of the Newick tree `newick` with the percentage of `replicates` (Newick
+/// trees of the same leaves) having their split, and returns it as Newick.
+pub fn support(newick: &str, replicates: &[String]) -> Result<String, String> {
+    let mut tree = Tree::from_newick(newick)?;
+    let leaves: HashMap<String, usize> = (0..tree.len())
+        .filter(|&i| tree.node(i).children.is_empty())
+        .enumerate()
+        .map(|(k, i)| (tree.node(i).label.clone().unwrap_or_default(), k))
+        .collect();
+    let splits = tree.splits(&leaves)?;
+    let mut counts: HashMap<&Vec<u64>, usize> = splits.iter().flatten().map(|split| (split, 0)).collect();
+    for replicate in replicates {
+        // a split found on both sides of the root of a replicate counts once
+        let replicate_splits: HashSet<Vec<u64>> = Tree::from_newick(replicate)?.splits(&leaves)?.into_iter().flatten().collect();
+        for split in &replicate_splits {
+            if let Some(count) = counts.get_mut(split) {
+                *count += 1;
+            }
+        }
+    }
+    let nb_replicates = replicates.len().max(1);
+    let support: Vec<Option<usize>> = splits
+        .iter()
+        .map(|split| split.as_ref().map(|split| (100 * counts[split] + nb_replicates / 2) / nb_replicates))
+        .collect();
+    for (node, support) in support.into_iter().enumerate() {
+        if let Some(support) = support.filter(|_| !tree.node(node).children.is_empty()) {
+            tree.set_label(node, Some(support.to_string()));
+        }
+    }
+    Ok(tree.to_newick())
+}
+
+/// Majority-rule consensus of `replicates` (Newick trees of the same leaves), without branch lengths,
+/// its internal nodes labelled with the percentage of replicates having their split.
+pub fn consensus(replicates: &[String]) -> Result<String, String> {
+    let trees: Vec<Tree> = replicates.iter().map(|r| Tree::from_newick(r)).collect::<Result<_, _>>()?;
+    let first = trees.first().ok_or("no replicate tree for the consensus")?;
+    let labels: Vec<String> =
+        (0..first.len()).filter(|&i| first.node(i).children.is_empty()).map(|i| first.node(i).label.clone().unwrap_or_default()).collect();
+    let leaves: HashMap<String, usize> = labels.iter().enumerate().map(|(k, label)| (label.clone(), k)).collect();
+    let mut counts: HashMap<Vec<u64>, usize> = HashMap::new();
+    for tree in &trees {
+        let splits: HashSet<Vec<u64>> = tree.splits(&leaves)?.into_iter().flatten().collect();
+        for split in splits {
+            *counts.entry(split).or_insert(0) += 1;
+        }
+    }
+    // splits in more than half of the trees are compatible: as clusters without the first leaf,
+    // each one holds the smaller ones it overlaps
+    let size = |split: &Vec<u64>| split.iter().map(|w| w.count_ones()).sum::<u32>();
+    let mut majority: Vec<(Vec<u64>, usize)> =
+        counts.into_iter().filter(|(split, count)| 2 * count > trees.len() && (2..labels.len() - 1).contains(&(size(split) as usize))).collect();
+    majority.sort_by_key(|(split, _)| size(split));
+    let mut tree = Tree::new();
+    // top node holding each leaf so far
+    let mut owner: Vec<usize> = labels.iter().map(|label| tree.add_leaf(label)).collect();
+    let add_node = |tree: &mut Tree, owner: &mut Vec<usize>, members: &[usize]| {
+        let mut children: Vec<usize> = members.iter().map(|&leaf| owner[leaf]).collect();
+        children.sort_unstable();
+        children.dedup();
+        let node = tree.join_unweighted(&children);
+        for &leaf in members {
+            owner[leaf] = node;
+        }
+        node
+    };
+    for (split, count) in &majority {
+        let members: Vec<usize> = (0..labels.len()).filter(|&leaf| split[leaf / 64] >> (leaf % 64) & 1 == 1).collect();
+        let node = add_node(&mut tree, &mut owner, &members);
+        tree.set_label(node, Some(((100 * count + trees.len() / 2) / trees.len()).to_string()));
+    }
+    let all: Vec<usize> = (0..labels.len()).collect();
+    add_node(&mut tree, &mut owner, &all);
+    Ok(tree.to_newick())
+}
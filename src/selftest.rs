@@ -0,0 +1,134 @@
+//! End to end check of the installed binary on embedded miniature genomes.
+//!
+//! Five 50 kb genomes are generated deterministically: an ancestor, a close relative (1% substitutions),
+//! a more distant one (5%), a close relative of the latter and an outgroup (25%). The full pipeline is run
+//! on them and distances and tree topology are compared with the values of a reference build.
+
+use std::fs::File;
+use std::io::Write;
+
+use crate::pipeline::PipelineBuilder;
+use crate::tree::TreeAlgo;
+
+const GENOME_LENGTH: usize = 50_000;
+const TOLERANCE: f64 = 1e-6;
+
+// rows of the expected distance matrix, in the order of GENOMES
+const EXPECTED_DISTANCES: [[f64; 5]; 5] = [
+    [0.000000000, 0.010056479, 0.051790214, 0.061260522, 0.283683328],
+    [0.010056479, 0.000000000, 0.060939699, 0.070163133, 0.283683328],
+    [0.051790214, 0.060939699, 0.000000000, 0.009472185, 0.321414859],
+    [0.061260522, 0.070163133, 0.009472185, 0.000000000, 0.332779520],
+    [0.283683328, 0.283683328, 0.321414859, 0.332779520, 0.000000000],
+];
+const EXPECTED_TOPOLOGY: &str = "((outgroup.fa,(ancestor.fa,close.fa)),distant.fa,distant_close.fa);";
+
+// (name, index of the parent genome, substitution rate), the ancestor being its own parent
+const GENOMES: [(&str, usize, f64); 5] = [
+    ("ancestor", 0, 0.0),
+    ("close", 0, 0.01),
+    ("distant", 0, 0.05),
+    ("distant_close", 2, 0.01),
+    ("outgroup", 0, 0.25),
+];
+
+// xorshift64*, self contained so that the genomes never depend on a dependency version
+struct XorShift(u64);
+
+impl XorShift {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    fn uniform(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+fn generate_genomes() -> Vec<Vec<u8>> {
+    let mut rng = XorShift(0x9e3779b97f4a7c15);
+    let mut genomes: Vec<Vec<u8>> = Vec::new();
+    for &(_, parent, rate) in GENOMES.iter() {
+        let genome = if genomes.is_empty() {
+            (0..GENOME_LENGTH).map(|_| b"ACGT"[(rng.next() % 4) as usize]).collect()
+        } else {
+            genomes[parent]
+                .iter()
+                .map(|&base| {
+                    if rng.uniform() < rate {
+                        // substitute by one of the three other bases
+                        let others: Vec<u8> = b"ACGT".iter().cloned().filter(|&b| b != base).collect();
+                        others[(rng.next() % 3) as usize]
+                    } else {
+                        base
+                    }
+                })
+                .collect()
+        };
+        genomes.push(genome);
+    }
+    genomes
+}
+
+// Newick string without branch lengths
+fn topology(newick: &str) -> String {
+    let mut topology = String::new();
+    let mut in_length = false;
+    for c in newick.chars() {
+        match c {
+            ':' => in_length = true,
+            ',' | ')' | '(' | ';' => {
+                in_length = false;
+                topology.push(c);
+            }
+            _ if !in_length => topology.push(c),
+            _ => {}
+        }
+    }
+    topology
+}
+
+/// Runs the pipeline on the embedded genomes and returns the observed distances and topology.
+pub fn run_embedded() -> Result<(Vec<Vec<f64>>, String), String> {
+    let dir = std::env::temp_dir().join(format!("bindashtree_selftest_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).map_err(|e| format!("Cannot create {}: {}", dir.display(), e))?;
+    let mut paths = Vec::new();
+    for ((name, _, _), genome) in GENOMES.iter().zip(generate_genomes()) {
+        let path = dir.join(format!("{}.fa", name));
+        let mut f = File::create(&path).map_err(|e| format!("Cannot create {}: {}", path.display(), e))?;
+        writeln!(f, ">{}", name).and_then(|_| f.write_all(&genome)).and_then(|_| writeln!(f)).map_err(|e| e.to_string())?;
+        paths.push(path.to_string_lossy().to_string());
+    }
+    let config = PipelineBuilder::new().kmer(16).sketch_size(2048).tree(TreeAlgo::Naive).build()?;
+    let sketched = config.sketch(&paths);
+    let _ = std::fs::remove_dir_all(&dir);
+    let (sketches, _) = sketched?;
+    let matrix = config.distance_matrix(&sketches, &paths)?;
+    let newick = config.tree(&matrix)?;
+    Ok((matrix.rows(), topology(&newick)))
+}
+
+/// Checks the embedded run against the expected values, listing every discrepancy.
+pub fn selftest() -> Result<(), String> {
+    let (distances, observed_topology) = run_embedded()?;
+    let mut errors = Vec::new();
+    for (i, row) in distances.iter().enumerate() {
+        for (j, &d) in row.iter().enumerate() {
+            let expected = EXPECTED_DISTANCES[i][j];
+            if (d - expected).abs() > TOLERANCE {
+                errors.push(format!("d({}, {}) = {:.9}, expected {:.9}", GENOMES[i].0, GENOMES[j].0, d, expected));
+            }
+        }
+    }
+    if observed_topology != EXPECTED_TOPOLOGY {
+        errors.push(format!("tree topology {}, expected {}", observed_topology, EXPECTED_TOPOLOGY));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("\n"))
+    }
+}
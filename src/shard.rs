@@ -0,0 +1,123 @@
+//! Distance matrix computed in shards, e.g. by the jobs of a cluster job array.
+//!
+//! The rows of the symmetric matrix are split into `N` consecutive ranges holding about the same
+//! number of pairs. Each worker sketches the genomes, computes the rows of its shard `i/N` and writes
+//! them to a shard file: a header line `#shard <n> <i> <N> <fingerprint>`, the `n` labels one per line,
+//! then the rows as little-endian `f64` values in the order of the matrix storage. The shard files of
+//! all workers are then merged into the full matrix, see [merge_shards], for the outputs and the tree.
+
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::matrix::DistMatrix;
+
+const SHARD_MARKER: &str = "#shard";
+const VALUE_BYTES: usize = std::mem::size_of::<f64>();
+
+/// Shard `index` (from 0) out of `count`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub count: usize,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Shard, String> {
+        let (index, count) = s.split_once('/').ok_or(format!("Shard must be given as i/N, got {}", s))?;
+        let index: usize = index.trim().parse().map_err(|_| format!("Invalid shard index: {}", index))?;
+        let count: usize = count.trim().parse().map_err(|_| format!("Invalid number of shards: {}", count))?;
+        if index >= count {
+            return Err(format!("Shard index must be between 0 and {}, got {}", count.saturating_sub(1), index));
+        }
+        Ok(Shard { index, count })
+    }
+}
+
+impl Shard {
+    /// Rows of a symmetric `n x n` matrix computed by this shard, the shards sharing the pairs evenly.
+    pub fn rows(&self, n: usize) -> Range<usize> {
+        // pairs (i, j), i < j, in the rows before `row`
+        let pairs_before = |row: usize| row * n - row * (row + 1) / 2;
+        let total = pairs_before(n);
+        let boundary = |shard: usize| {
+            if shard == self.count {
+                return n;
+            }
+            let target = total * shard / self.count;
+            (0..n).find(|&row| pairs_before(row) >= target).unwrap_or(n)
+        };
+        boundary(self.index)..boundary(self.index + 1)
+    }
+}
+
+/// Writes the rows of `shard` of `matrix` to the shard file at `path`.
+pub fn write_shard(path: &str, matrix: &DistMatrix, shard: Shard, fingerprint: u64) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("Cannot create shard file {}: {}", path, e))?;
+    let mut writer = BufWriter::new(file);
+    let header = format!("{}\t{}\t{}\t{}\t{:016x}", SHARD_MARKER, matrix.len(), shard.index, shard.count, fingerprint);
+    writeln!(writer, "{}", header).map_err(|e| e.to_string())?;
+    for label in matrix.labels() {
+        writeln!(writer, "{}", label).map_err(|e| e.to_string())?;
+    }
+    for value in matrix.rows_values(shard.rows(matrix.len())) {
+        writer.write_all(&value.to_le_bytes()).map_err(|e| e.to_string())?;
+    }
+    writer.flush().map_err(|e| e.to_string())
+}
+
+/// Assembles the full matrix from the shard files of all workers, checking that they hold
+/// every shard once, computed for the same genomes and settings.
+pub fn merge_shards(paths: &[String]) -> Result<DistMatrix, String> {
+    let mut matrix: Option<DistMatrix> = None;
+    let mut run: Option<(usize, usize, String)> = None;
+    let mut seen = Vec::new();
+    for path in paths {
+        let file = File::open(path).map_err(|e| format!("Cannot open shard file {}: {}", path, e))?;
+        let mut reader = BufReader::new(file);
+        let mut header = String::new();
+        reader.read_line(&mut header).map_err(|e| e.to_string())?;
+        let fields: Vec<&str> = header.split_whitespace().collect();
+        if fields.len() != 5 || fields[0] != SHARD_MARKER {
+            return Err(format!("{} is not a shard file", path));
+        }
+        let parse = |field: &str| field.parse::<usize>().map_err(|_| format!("Malformed shard header in {}", path));
+        let (n, index, count) = (parse(fields[1])?, parse(fields[2])?, parse(fields[3])?);
+        let this_run = (n, count, fields[4].to_string());
+        if run.get_or_insert_with(|| this_run.clone()) != &this_run {
+            return Err(format!("{} was computed for other genomes, settings or number of shards", path));
+        }
+        if index >= count || seen.contains(&index) {
+            return Err(format!("{} holds shard {}/{}, missing or given twice", path, index, count));
+        }
+        seen.push(index);
+        let mut labels = Vec::with_capacity(n);
+        for _ in 0..n {
+            let mut label = String::new();
+            reader.read_line(&mut label).map_err(|e| e.to_string())?;
+            labels.push(label.trim_end_matches('\n').to_string());
+        }
+        let matrix = matrix.get_or_insert_with(|| DistMatrix::new(labels.clone(), true));
+        if matrix.labels() != labels.as_slice() {
+            return Err(format!("{} was computed for other genomes", path));
+        }
+        let rows = Shard { index, count }.rows(n);
+        let values = matrix.rows_values_mut(rows);
+        let mut bytes = Vec::with_capacity(values.len() * VALUE_BYTES);
+        reader.read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+        if bytes.len() != values.len() * VALUE_BYTES {
+            return Err(format!("{} is truncated", path));
+        }
+        for (v, b) in values.iter_mut().zip(bytes.chunks_exact(VALUE_BYTES)) {
+            *v = f64::from_le_bytes(b.try_into().unwrap());
+        }
+    }
+    let count = run.map_or(0, |(_, count, _)| count);
+    if seen.len() != count {
+        let missing: Vec<String> = (0..count).filter(|i| !seen.contains(i)).map(|i| format!("{}/{}", i, count)).collect();
+        return Err(format!("Missing shards: {}", missing.join(", ")));
+    }
+    matrix.ok_or("No shard file given".to_string())
+}
@@ -0,0 +1,125 @@
+//! Register comparison kernels with runtime CPU feature detection.
+//!
+//! Sketch registers are compared 16 (AVX-512), 8 (AVX2) or 4 (NEON) at a time, with a scalar loop
+//! for the tail and for other CPUs. Equality is that of `f32`, as in the scalar comparison.
+
+/// Number of positions where `a` and `b` hold equal registers.
+pub fn count_equal_f32(a: &[f32], b: &[f32]) -> usize {
+    assert_eq!(a.len(), b.len(), "Cannot compare sketches of different sizes");
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("avx512f") {
+            return unsafe { count_equal_avx512(a, b) };
+        }
+        if is_x86_feature_detected!("avx2") {
+            return unsafe { count_equal_avx2(a, b) };
+        }
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        if std::arch::is_aarch64_feature_detected!("neon") {
+            return unsafe { count_equal_neon(a, b) };
+        }
+    }
+    count_equal_scalar(a, b)
+}
+
+/// Fraction of positions where `a` and `b` differ, the Hamming distance of two sketches.
+pub fn hamming_f32(a: &[f32], b: &[f32]) -> f32 {
+    let nb_differ = a.len() - count_equal_f32(a, b);
+    (nb_differ as f64 / a.len() as f64) as f32
+}
+
+fn count_equal_scalar(a: &[f32], b: &[f32]) -> usize {
+    a.iter().zip(b.iter()).filter(|(x, y)| x == y).count()
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx2")]
+unsafe fn count_equal_avx2(a: &[f32], b: &[f32]) -> usize {
+    use std::arch::x86_64::*;
+    const LANES: usize = 8;
+    let nb_chunks = a.len() / LANES;
+    let mut count = 0;
+    for c in 0..nb_chunks {
+        let va = _mm256_loadu_ps(a.as_ptr().add(c * LANES));
+        let vb = _mm256_loadu_ps(b.as_ptr().add(c * LANES));
+        let mask = _mm256_movemask_ps(_mm256_cmp_ps::<_CMP_EQ_OQ>(va, vb));
+        count += (mask as u32).count_ones() as usize;
+    }
+    count + count_equal_scalar(&a[nb_chunks * LANES..], &b[nb_chunks * LANES..])
+}
+
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "avx512f")]
+unsafe fn count_equal_avx512(a: &[f32], b: &[f32]) -> usize {
+    use std::arch::x86_64::*;
+    const LANES: usize = 16;
+    let nb_chunks = a.len() / LANES;
+    let mut count = 0;
+    for c in 0..nb_chunks {
+        let va = _mm512_loadu_ps(a.as_ptr().add(c * LANES));
+        let vb = _mm512_loadu_ps(b.as_ptr().add(c * LANES));
+        count += _mm512_cmp_ps_mask::<_CMP_EQ_OQ>(va, vb).count_ones() as usize;
+    }
+    count + count_equal_scalar(&a[nb_chunks * LANES..], &b[nb_chunks * LANES..])
+}
+
+#[cfg(target_arch = "aarch64")]
+#[target_feature(enable = "neon")]
+unsafe fn count_equal_neon(a: &[f32], b: &[f32]) -> usize {
+    use std::arch::aarch64::*;
+    const LANES: usize = 4;
+    let nb_chunks = a.len() / LANES;
+    let mut count = 0;
+    for c in 0..nb_chunks {
+        let va = vld1q_f32(a.as_ptr().add(c * LANES));
+        let vb = vld1q_f32(b.as_ptr().add(c * LANES));
+        // equal lanes are all ones, their top bit counts them
+        count += vaddvq_u32(vshrq_n_u32::<31>(vceqq_f32(va, vb))) as usize;
+    }
+    count + count_equal_scalar(&a[nb_chunks * LANES..], &b[nb_chunks * LANES..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Registers equal, different, NaN on either or both sides and signed zeros, at every position
+    // modulo 5 so that lengths cover partial chunks of every lane width
+    fn registers(len: usize) -> (Vec<f32>, Vec<f32>) {
+        (0..len)
+            .map(|i| match i % 5 {
+                0 => (i as f32, i as f32),
+                1 => (i as f32, i as f32 + 0.5),
+                2 => (f32::NAN, f32::NAN),
+                3 => (f32::NAN, i as f32),
+                _ => (0.0, -0.0),
+            })
+            .unzip()
+    }
+
+    #[test]
+    fn kernels_match_scalar() {
+        for len in (0..=70).chain([1021, 4099]) {
+            let (a, b) = registers(len);
+            let expected = count_equal_scalar(&a, &b);
+            assert_eq!(count_equal_f32(&a, &b), expected, "length {}", len);
+            #[cfg(target_arch = "x86_64")]
+            {
+                if is_x86_feature_detected!("avx2") {
+                    assert_eq!(unsafe { count_equal_avx2(&a, &b) }, expected, "avx2, length {}", len);
+                }
+                if is_x86_feature_detected!("avx512f") {
+                    assert_eq!(unsafe { count_equal_avx512(&a, &b) }, expected, "avx512, length {}", len);
+                }
+            }
+            #[cfg(target_arch = "aarch64")]
+            {
+                if std::arch::is_aarch64_feature_detected!("neon") {
+                    assert_eq!(unsafe { count_equal_neon(&a, &b) }, expected, "neon, length {}", len);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,952 @@
+//! Reading genomes and sketching them with densified MinHash and related sketches.
+
+use rayon::prelude::*;
+use needletail::errors::ParseErrorKind;
+use needletail::{parse_fastx_file, parse_fastx_reader, Sequence};
+use needletail::sequence::normalize;
+use std::collections::HashMap;
+use kmerutils::base::{
+    CompressedKmerT, KmerBuilder,
+    kmergenerator::{KmerSeqIterator, KmerSeqIteratorT},
+    alphabet::Alphabet2b,
+    sequence::Sequence as SequenceStruct,
+    kmer::{Kmer32bit, Kmer16b32bit, Kmer64bit}
+};
+use kmerutils::nohasher::NoHashHasher;
+use probminhash::densminhash::{OptDensMinHash, RevOptDensMinHash};
+use probminhash::superminhasher::SuperMinHash;
+use probminhash::probminhasher::ProbMinHash3a;
+use probminhash::setsketcher::{MleJaccard, SetSketchParams, SetSketcher};
+use std::hash::{BuildHasherDefault, Hash};
+use std::str::FromStr;
+use std::fmt::Debug;
+use std::time::Instant;
+use num::ToPrimitive;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::dist::DistanceKind;
+use crate::pipeline::PipelineConfig;
+
+/// A genome sketch: fixed size registers, or for FracMinHash the sorted retained hashes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Signature {
+    Registers(Vec<f32>),
+    Scaled(Vec<u64>),
+    /// registers reduced to their lowest `bits` bits (after mixing), packed into 64 bit words
+    BBit { bits: u32, nb_registers: usize, words: Vec<u64> },
+    /// no sketch but the full canonical k-mer counts, sorted by k-mer, and the GC fraction,
+    /// for composition distances
+    Profile { counts: Vec<(u64, u32)>, gc: f64 },
+    /// SetSketch registers, with the base `b` and rate `a` they were drawn with and the number of
+    /// distinct k-mers estimated from them, compared by joint estimation, see
+    /// [crate::dist::setsketch_jaccard]
+    SetSketch { b: f64, a: f64, cardinality: f64, registers: Vec<u16> },
+}
+
+impl Signature {
+    /// Memory taken by the sketch data.
+    pub fn size_in_bytes(&self) -> usize {
+        match self {
+            Signature::Registers(registers) => registers.len() * std::mem::size_of::<f32>(),
+            Signature::Scaled(hashes) => hashes.len() * std::mem::size_of::<u64>(),
+            Signature::BBit { words, .. } => words.len() * std::mem::size_of::<u64>(),
+            Signature::Profile { counts, .. } => counts.len() * std::mem::size_of::<(u64, u32)>(),
+            Signature::SetSketch { registers, .. } => registers.len() * std::mem::size_of::<u16>(),
+        }
+    }
+
+    /// Name of the kind of sketch, for error messages.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Signature::Registers(_) => "register",
+            Signature::Scaled(_) => "FracMinHash",
+            Signature::BBit { .. } => "b-bit",
+            Signature::Profile { .. } => "composition profile",
+            Signature::SetSketch { .. } => "SetSketch",
+        }
+    }
+
+    /// SetSketch signature of `registers` drawn with base `b` and rate `a`, with their cardinality.
+    pub fn setsketch(b: f64, a: f64, registers: Vec<u16>) -> Signature {
+        let cardinality = MleJaccard::new(b, registers.len() as u64, a).get_cardinal_estimate(&registers);
+        Signature::SetSketch { b, a, cardinality, registers }
+    }
+}
+
+/// Keeps the lowest `bits` bits of each mixed register, `bits` dividing 64.
+pub fn to_bbit(registers: &[f32], bits: u32) -> Signature {
+    let per_word = (64 / bits) as usize;
+    let mask = if bits == 64 { u64::MAX } else { (1u64 << bits) - 1 };
+    let words = registers
+        .chunks(per_word)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0u64, |word, (i, &v)| word | ((mix64(v.to_bits() as u64) & mask) << (i as u32 * bits)))
+        })
+        .collect();
+    Signature::BBit { bits, nb_registers: registers.len(), words }
+}
+
+/// splitmix64 finalizer, used wherever k-mer or register values need a well mixed hash.
+pub fn mix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Hash function applied to canonical k-mer values before sketching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashFunction {
+    /// seeded bijection of the k-mer value, the identity with seed 0
+    Permutation,
+    /// XXH3 64 bits of the little endian k-mer value
+    Xxh3,
+    /// wyhash of the little endian k-mer value
+    WyHash,
+}
+
+impl FromStr for HashFunction {
+    type Err = String;
+    fn from_str(s: &str) -> Result<HashFunction, String> {
+        match s.to_lowercase().as_str() {
+            "permutation" => Ok(HashFunction::Permutation),
+            "xxh3" | "xxhash3" => Ok(HashFunction::Xxh3),
+            "wyhash" => Ok(HashFunction::WyHash),
+            _ => Err(format!("Unknown hash function: {}", s)),
+        }
+    }
+}
+
+impl HashFunction {
+    // Values of 32 bits or less (k <= 16) keep the low 32 bits of the 64 bits hashes, which can collide,
+    // the permutation never does.
+    fn hash<V: num::PrimInt>(self, value: V, seed: u64) -> V {
+        let x = value.to_u64().unwrap();
+        let hashed = match self {
+            HashFunction::Permutation => return permute(value, seed),
+            HashFunction::Xxh3 => twox_hash::XxHash3_64::oneshot_with_seed(seed, &x.to_le_bytes()),
+            HashFunction::WyHash => wyhash::wyhash(&x.to_le_bytes(), seed),
+        };
+        if std::mem::size_of::<V>() <= 4 {
+            num::NumCast::from(hashed as u32).unwrap()
+        } else {
+            num::NumCast::from(hashed).unwrap()
+        }
+    }
+}
+
+// Seeded bijection of k-mer values, so that a seed changes the hash functions without creating collisions.
+// Seed 0 is the identity, k-mers are then sketched from their canonical value as they always were.
+fn permute<V: num::PrimInt>(value: V, seed: u64) -> V {
+    if seed == 0 {
+        return value;
+    }
+    let x = value.to_u64().unwrap();
+    let permuted = if std::mem::size_of::<V>() <= 4 {
+        // lowbias32 finalizer
+        let mut x = (x as u32) ^ (mix64(seed) as u32);
+        x ^= x >> 16;
+        x = x.wrapping_mul(0x7feb352d);
+        x ^= x >> 15;
+        x = x.wrapping_mul(0x846ca68b);
+        x ^= x >> 16;
+        x as u64
+    } else {
+        mix64(x ^ seed)
+    };
+    num::NumCast::from(permuted).unwrap()
+}
+
+// Common interface of the sketchers, fed one k-mer at a time
+trait KmerSketcher<D> {
+    fn new(config: &PipelineConfig) -> Self;
+    fn sketch(&mut self, to_sketch: &D);
+    fn end_sketch(&mut self);
+    fn get_signature(&self) -> Signature;
+    // fraction of one permutation hashing bins left empty before densification, when known
+    fn empty_fraction(&self) -> f64 {
+        0.0
+    }
+}
+
+impl<D: Hash + Copy> KmerSketcher<D> for OptDensMinHash<f32, D, NoHashHasher> {
+    fn new(config: &PipelineConfig) -> Self {
+        OptDensMinHash::new(config.sketch_size, BuildHasherDefault::<NoHashHasher>::default())
+    }
+    fn sketch(&mut self, to_sketch: &D) {
+        OptDensMinHash::sketch(self, to_sketch)
+    }
+    fn end_sketch(&mut self) {
+        OptDensMinHash::end_sketch(self)
+    }
+    fn get_signature(&self) -> Signature {
+        Signature::Registers(self.get_hsketch().clone())
+    }
+}
+
+impl<D: Hash + Copy> KmerSketcher<D> for RevOptDensMinHash<f32, D, NoHashHasher> {
+    fn new(config: &PipelineConfig) -> Self {
+        RevOptDensMinHash::new(config.sketch_size, BuildHasherDefault::<NoHashHasher>::default())
+    }
+    fn sketch(&mut self, to_sketch: &D) {
+        RevOptDensMinHash::sketch(self, to_sketch)
+    }
+    fn end_sketch(&mut self) {
+        RevOptDensMinHash::end_sketch(self)
+    }
+    fn get_signature(&self) -> Signature {
+        Signature::Registers(self.get_hsketch().clone())
+    }
+}
+
+impl<D: Hash> KmerSketcher<D> for SuperMinHash<f32, D, NoHashHasher> {
+    fn new(config: &PipelineConfig) -> Self {
+        SuperMinHash::new(config.sketch_size, BuildHasherDefault::<NoHashHasher>::default())
+    }
+    fn sketch(&mut self, to_sketch: &D) {
+        SuperMinHash::sketch(self, to_sketch).unwrap();
+    }
+    // SuperMinHash has no empty register to fill
+    fn end_sketch(&mut self) {}
+    fn get_signature(&self) -> Signature {
+        Signature::Registers(self.get_hsketch().clone())
+    }
+}
+
+// ProbMinHash needs the whole weighted set, k-mers are counted first and hashed at end_sketch
+struct WeightedSketcher<D> {
+    sketch_size: usize,
+    counts: HashMap<D, u32>,
+    hsketch: Vec<f32>,
+}
+
+impl<D: Hash + Copy + Eq + Debug + ToPrimitive> KmerSketcher<D> for WeightedSketcher<D> {
+    fn new(config: &PipelineConfig) -> Self {
+        WeightedSketcher { sketch_size: config.sketch_size, counts: HashMap::new(), hsketch: Vec::new() }
+    }
+    fn sketch(&mut self, to_sketch: &D) {
+        *self.counts.entry(*to_sketch).or_insert(0) += 1;
+    }
+    fn end_sketch(&mut self) {
+        let init = *self.counts.keys().next().unwrap();
+        let mut hasher = ProbMinHash3a::<D, NoHashHasher>::new(self.sketch_size.max(2), init);
+        hasher.hash_weigthed_hashmap(&self.counts);
+        // k-mer values are stored as 24 bit fingerprints, exactly representable in a f32 register,
+        // so that signatures compare with the same Hamming distance as the other sketches
+        self.hsketch = hasher
+            .get_signature()
+            .iter()
+            .take(self.sketch_size)
+            .map(|v| (mix64(v.to_u64().unwrap()) >> 40) as f32)
+            .collect();
+    }
+    fn get_signature(&self) -> Signature {
+        Signature::Registers(self.hsketch.clone())
+    }
+}
+
+struct SetSketchSketcher<D> {
+    params: SetSketchParams,
+    sketcher: SetSketcher<u16, D, NoHashHasher>,
+    signature: Option<Signature>,
+}
+
+impl<D: Hash> KmerSketcher<D> for SetSketchSketcher<D> {
+    fn new(config: &PipelineConfig) -> Self {
+        let mut params = SetSketchParams::default();
+        params.set_m(config.sketch_size);
+        SetSketchSketcher {
+            params,
+            sketcher: SetSketcher::new(params, BuildHasherDefault::<NoHashHasher>::default()),
+            signature: None,
+        }
+    }
+    fn sketch(&mut self, to_sketch: &D) {
+        self.sketcher.sketch(to_sketch).unwrap();
+    }
+    fn end_sketch(&mut self) {
+        if self.sketcher.get_nb_overflow() > 0 {
+            log::warn!("setsketch: {} register overflows", self.sketcher.get_nb_overflow());
+        }
+        let registers = self.sketcher.get_signature().clone();
+        self.signature = Some(Signature::setsketch(self.params.get_b(), self.params.get_a(), registers));
+    }
+    fn get_signature(&self) -> Signature {
+        self.signature.clone().unwrap()
+    }
+}
+
+// Fraction of empty bins above which a genome is considered too small for the sketch size,
+// and above which automatic densification switches to reverse optimal
+const MAX_EMPTY_FRACTION: f64 = 0.5;
+
+// Our own one permutation hashing, for the densifications probminhash does not provide or
+// when the fill rate must be known before choosing one. Empty bins hold u64::MAX.
+struct OphSketcher {
+    densification: Densification,
+    bins: Vec<u64>,
+    empty_fraction: f64,
+    hsketch: Vec<f32>,
+}
+
+impl OphSketcher {
+    // Optimal densification (Shrivastava 2017): an empty bin copies the first non empty bin of
+    // its own pseudo random probe sequence
+    fn densify_optimal(&self) -> Vec<u64> {
+        let m = self.bins.len() as u64;
+        (0..m)
+            .map(|k| {
+                let mut attempt = 0u64;
+                loop {
+                    let j = ((mix64(k ^ (attempt << 32)) >> 32) * m) >> 32;
+                    if self.bins[j as usize] != u64::MAX {
+                        return self.bins[j as usize];
+                    }
+                    attempt += 1;
+                }
+            })
+            .collect()
+    }
+
+    // Reverse optimal densification (Mai et al. 2020): in successive passes each filled bin
+    // pushes its value to a pseudo random bin, filling it if still empty
+    fn densify_reverse(&self) -> Vec<u64> {
+        let m = self.bins.len() as u64;
+        let mut values = self.bins.clone();
+        let mut filled: Vec<bool> = values.iter().map(|&v| v != u64::MAX).collect();
+        let mut nb_empty = filled.iter().filter(|&&f| !f).count();
+        let mut pass = 1u64;
+        while nb_empty > 0 {
+            for k in 0..m as usize {
+                if filled[k] {
+                    let j = (((mix64((k as u64 + 1) * m + pass) >> 32) * m) >> 32) as usize;
+                    if !filled[j] {
+                        values[j] = values[k];
+                        filled[j] = true;
+                        nb_empty -= 1;
+                    }
+                }
+            }
+            pass += 1;
+        }
+        values
+    }
+
+    // Densification by rotation (Shrivastava & Li 2014): an empty bin borrows the minimum of the
+    // next non empty bin to its right, salted with the distance so that borrowed values only match
+    // between sketches borrowing from the same bin at the same distance
+    fn densify_rotation(&self) -> Vec<u64> {
+        let m = self.bins.len();
+        let mut values = vec![0; m];
+        // walking twice right to left around the circle, the second round sees every next non empty bin
+        let mut next: Option<usize> = None;
+        for k in (0..2 * m).rev() {
+            let i = k % m;
+            if self.bins[i] != u64::MAX {
+                next = Some(i);
+            }
+            if k < m {
+                let j = next.unwrap();
+                let distance = ((j + m - i) % m) as u64;
+                values[i] = self.bins[j] | (distance << 32);
+            }
+        }
+        values
+    }
+}
+
+impl<D: ToPrimitive> KmerSketcher<D> for OphSketcher {
+    fn new(config: &PipelineConfig) -> Self {
+        OphSketcher {
+            densification: config.densification,
+            bins: vec![u64::MAX; config.sketch_size],
+            empty_fraction: 0.0,
+            hsketch: Vec::new(),
+        }
+    }
+    fn sketch(&mut self, to_sketch: &D) {
+        let hash = mix64(to_sketch.to_u64().unwrap());
+        let bin = (((hash >> 32) * self.bins.len() as u64) >> 32) as usize;
+        let value = hash & 0xffff_ffff;
+        if value < self.bins[bin] {
+            self.bins[bin] = value;
+        }
+    }
+    fn end_sketch(&mut self) {
+        let nb_empty = self.bins.iter().filter(|&&v| v == u64::MAX).count();
+        let empty_fraction = nb_empty as f64 / self.bins.len() as f64;
+        self.empty_fraction = empty_fraction;
+        let densification = match self.densification {
+            Densification::Auto if empty_fraction > MAX_EMPTY_FRACTION => Densification::ReverseOptimal,
+            Densification::Auto => Densification::Optimal,
+            densification => densification,
+        };
+        let values = match densification {
+            _ if nb_empty == 0 => self.bins.clone(),
+            Densification::Rotation => self.densify_rotation(),
+            Densification::ReverseOptimal => self.densify_reverse(),
+            _ => self.densify_optimal(),
+        };
+        // 24 bit fingerprints are exactly representable in a f32 register
+        self.hsketch = values.iter().map(|&v| (mix64(v) >> 40) as f32).collect();
+    }
+    fn get_signature(&self) -> Signature {
+        Signature::Registers(self.hsketch.clone())
+    }
+    fn empty_fraction(&self) -> f64 {
+        self.empty_fraction
+    }
+}
+
+// FracMinHash keeps every k-mer whose hash falls in the lowest 1/scaled of the hash space
+struct ScaledSketcher {
+    max_hash: u64,
+    hashes: Vec<u64>,
+}
+
+impl<D: ToPrimitive> KmerSketcher<D> for ScaledSketcher {
+    fn new(config: &PipelineConfig) -> Self {
+        ScaledSketcher { max_hash: u64::MAX / config.scaled as u64, hashes: Vec::new() }
+    }
+    fn sketch(&mut self, to_sketch: &D) {
+        let hash = mix64(to_sketch.to_u64().unwrap());
+        if hash <= self.max_hash {
+            self.hashes.push(hash);
+        }
+    }
+    fn end_sketch(&mut self) {
+        self.hashes.sort_unstable();
+        self.hashes.dedup();
+    }
+    fn get_signature(&self) -> Signature {
+        Signature::Scaled(self.hashes.clone())
+    }
+}
+
+// Counts every canonical k-mer, for genomes small enough to need no sketching
+struct ProfileSketcher {
+    kmer_size: usize,
+    counts: HashMap<u64, u32>,
+    profile: Vec<(u64, u32)>,
+}
+
+impl<D: ToPrimitive> KmerSketcher<D> for ProfileSketcher {
+    fn new(config: &PipelineConfig) -> Self {
+        ProfileSketcher { kmer_size: config.kmer_size, counts: HashMap::new(), profile: Vec::new() }
+    }
+    fn sketch(&mut self, to_sketch: &D) {
+        *self.counts.entry(to_sketch.to_u64().unwrap()).or_insert(0) += 1;
+    }
+    fn end_sketch(&mut self) {
+        self.profile = self.counts.drain().collect();
+        self.profile.sort_unstable();
+    }
+    fn get_signature(&self) -> Signature {
+        let (mut nb_gc, mut nb_bases) = (0u64, 0u64);
+        for &(kmer, count) in &self.profile {
+            nb_gc += gc_count(kmer, self.kmer_size) as u64 * count as u64;
+            nb_bases += (self.kmer_size as u64) * count as u64;
+        }
+        Signature::Profile { counts: self.profile.clone(), gc: nb_gc as f64 / nb_bases.max(1) as f64 }
+    }
+}
+
+/// Number of G and C bases of a 2-bit encoded k-mer (A=0, C=1, G=2, T=3).
+pub fn gc_count(kmer: u64, kmer_size: usize) -> usize {
+    (0..kmer_size).filter(|i| matches!((kmer >> (2 * i)) & 3, 1 | 2)).count()
+}
+
+/// Sketching algorithm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SketchAlgo {
+    /// One permutation hashing with densification, see [Densification]
+    OptDens,
+    /// SuperMinHash, slower to build but with a lower estimator variance for small sets
+    SuperMinHash,
+    /// ProbMinHash over k-mer counts, abundance weighted (probability Jaccard)
+    ProbMinHash,
+    /// SetSketch (HyperLogLog like registers), mergeable and compact, slightly less precise per pair
+    SetSketch,
+}
+
+impl FromStr for SketchAlgo {
+    type Err = String;
+    fn from_str(s: &str) -> Result<SketchAlgo, String> {
+        match s.to_lowercase().as_str() {
+            "optdens" => Ok(SketchAlgo::OptDens),
+            "superminhash" => Ok(SketchAlgo::SuperMinHash),
+            "probminhash" => Ok(SketchAlgo::ProbMinHash),
+            "setsketch" => Ok(SketchAlgo::SetSketch),
+            _ => Err(format!("Unknown sketching algorithm: {}", s)),
+        }
+    }
+}
+
+/// Densification strategy applied to empty bins after one permutation hashing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Densification {
+    /// Optimal Densification, the most accurate
+    Optimal,
+    /// Reverse Optimal Densification, faster for large sketch sizes with the same accuracy
+    ReverseOptimal,
+    /// densification by rotation, a single linear pass but a higher variance when many bins are empty
+    Rotation,
+    /// optimal, or reverse optimal for genomes leaving more than half of the bins empty.
+    /// Bins empty in two genomes densified differently no longer agree by chance,
+    /// which slightly overestimates distances between two genomes much smaller than the sketch.
+    Auto,
+}
+
+impl FromStr for Densification {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Densification, String> {
+        // 0 and 1 are the historical values of -d
+        match s.to_lowercase().as_str() {
+            "0" | "optimal" => Ok(Densification::Optimal),
+            "1" | "reverse" => Ok(Densification::ReverseOptimal),
+            "rotation" => Ok(Densification::Rotation),
+            "auto" => Ok(Densification::Auto),
+            _ => Err(format!("Unknown densification: {}", s)),
+        }
+    }
+}
+
+/// What to do with k-mers containing ambiguous (non ACGT) bases.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AmbigPolicy {
+    /// drop k-mers overlapping an ambiguous base by splitting the record there
+    Skip,
+    /// replace each ambiguous base by a random nucleotide
+    Random,
+    /// abort on the first ambiguous base
+    Fail,
+}
+
+impl FromStr for AmbigPolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<AmbigPolicy, String> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(AmbigPolicy::Skip),
+            "random" => Ok(AmbigPolicy::Random),
+            "fail" => Ok(AmbigPolicy::Fail),
+            _ => Err(format!("Unknown ambiguous base policy: {}", s)),
+        }
+    }
+}
+
+/// Per genome accounting of what could actually be turned into k-mers, and what it cost.
+#[derive(Debug, Clone, Default)]
+pub struct SeqStats {
+    pub nb_seqs: usize,
+    /// bases read from the file, ambiguous ones included
+    pub nb_bases: usize,
+    pub nb_short_seqs: usize,
+    pub nb_short_bases: usize,
+    pub nb_kmers: usize,
+    pub nb_low_complexity: usize,
+    /// size of the input file on disk (compressed if gzipped)
+    pub file_size: u64,
+    /// wall time spent reading and sketching, in seconds
+    pub sketch_time: f64,
+    /// genome dropped because it yields no k-mer, or because it could not be read or sketched
+    pub skipped: bool,
+    /// why the genome could not be read or sketched, when skipped as a bad file
+    pub error: Option<String>,
+}
+
+impl SeqStats {
+    fn add(&mut self, len: usize, kmer_size: usize) {
+        self.nb_seqs += 1;
+        if len < kmer_size {
+            self.nb_short_seqs += 1;
+            self.nb_short_bases += len;
+        } else {
+            self.nb_kmers += len - kmer_size + 1;
+        }
+    }
+
+    // Same statistics with the sequence counts recomputed for another k-mer size
+    fn for_kmer_size(&self, sequences: &[SequenceStruct], kmer_size: usize) -> SeqStats {
+        let mut stats = SeqStats { nb_seqs: 0, nb_short_seqs: 0, nb_short_bases: 0, nb_kmers: 0, ..self.clone() };
+        for seq in sequences {
+            stats.add(seq.size(), kmer_size);
+        }
+        stats
+    }
+}
+
+fn is_acgt(base: u8) -> bool {
+    matches!(base, b'A' | b'C' | b'G' | b'T')
+}
+
+fn ascii_to_seq(bases: &[u8]) -> Result<SequenceStruct, ()> {
+    let alphabet = Alphabet2b::new();
+    let mut seq = SequenceStruct::with_capacity(2, bases.len());
+    seq.encode_and_add(bases, &alphabet);
+    Ok(seq)
+}
+
+// Applies the ambiguous base policy to a stretch of bases and stores the result
+fn push_bases(
+    mut bases: Vec<u8>,
+    record_id: &[u8],
+    path: &str,
+    config: &PipelineConfig,
+    rng: &mut StdRng,
+    sequences: &mut Vec<SequenceStruct>,
+    stats: &mut SeqStats,
+) -> Result<(), String> {
+    match config.ambig {
+        AmbigPolicy::Skip => {
+            for fragment in bases.split(|b| !is_acgt(*b)).filter(|f| !f.is_empty()) {
+                stats.add(fragment.len(), config.kmer_size);
+                sequences.push(ascii_to_seq(fragment).unwrap());
+            }
+            return Ok(());
+        }
+        AmbigPolicy::Random => {
+            for base in bases.iter_mut().filter(|b| !is_acgt(**b)) {
+                *base = b"ACGT"[rng.gen_range(0..4)];
+            }
+        }
+        AmbigPolicy::Fail => {
+            if let Some(pos) = bases.iter().position(|b| !is_acgt(*b)) {
+                return Err(format!(
+                    "{}: ambiguous base at position {} of record {} (see --ambig)",
+                    path,
+                    pos + 1,
+                    String::from_utf8_lossy(record_id)
+                ));
+            }
+        }
+    }
+    stats.add(bases.len(), config.kmer_size);
+    sequences.push(ascii_to_seq(&bases).unwrap());
+    Ok(())
+}
+
+/// Reads a FASTA/FASTQ file (gz supported) into 2-bit encoded sequences.
+pub fn read_sequences(path: &str, config: &PipelineConfig) -> Result<(Vec<SequenceStruct>, SeqStats), String> {
+    let mut sequences = Vec::new();
+    let mut stats = SeqStats::default();
+    // fixed seed so that a genome always gets the same substitutions
+    let mut rng = StdRng::seed_from_u64(0);
+    let (reader, failure) = match &config.preprocessor {
+        Some(preprocessor) => (parse_fastx_reader(preprocessor.open(path)?), "invalid FASTA/Q output of the preprocessing"),
+        None => (parse_fastx_file(path), "cannot read FASTA/Q file"),
+    };
+    let mut reader = match reader {
+        Ok(reader) => reader,
+        // an empty genome is left to the sketching, which reports or skips it as one without k-mer
+        Err(e) if e.kind == ParseErrorKind::EmptyFile => return Ok((sequences, stats)),
+        Err(e) => return Err(format!("{}: {}: {}", path, failure, e)),
+    };
+    while let Some(record) = reader.next() {
+        let seq_record = record.map_err(|e| format!("{}: invalid FASTA/Q record: {}", path, e))?;
+        stats.nb_bases += seq_record.num_bases();
+        if config.skip_softmasked {
+            // lowercase stretches are repeats masked by RepeatMasker & co, keep only uppercase runs
+            let raw = seq_record.seq();
+            for run in raw.split(|b| b.is_ascii_lowercase()).filter(|r| !r.is_empty()) {
+                // normalize turns every non ACGT (IUPAC codes included) into N
+                let bases = normalize(run, false).unwrap_or_else(|| run.to_vec());
+                push_bases(bases, seq_record.id(), path, config, &mut rng, &mut sequences, &mut stats)?;
+            }
+        } else {
+            let bases = seq_record.normalize(false).into_owned();
+            push_bases(bases, seq_record.id(), path, config, &mut rng, &mut sequences, &mut stats)?;
+        }
+    }
+    Ok((sequences, stats))
+}
+
+/// Checks that the genome file `path` can be read by parsing its first record only, without
+/// sketching it, and returns the size of the file in bytes. An empty genome is an error unless
+/// skipped with `skip_empty`.
+pub fn check_genome(path: &str, config: &PipelineConfig) -> Result<u64, String> {
+    let size = std::fs::metadata(path).map_err(|e| format!("{}: {}", path, e))?.len();
+    let (reader, failure) = match &config.preprocessor {
+        Some(preprocessor) => (parse_fastx_reader(preprocessor.open(path)?), "invalid FASTA/Q output of the preprocessing"),
+        None => (parse_fastx_file(path), "cannot read FASTA/Q file"),
+    };
+    let first = match reader {
+        Ok(mut reader) => reader.next().map(|record| record.map(|_| ())),
+        Err(e) if e.kind == ParseErrorKind::EmptyFile => None,
+        Err(e) => return Err(format!("{}: {}: {}", path, failure, e)),
+    };
+    match first {
+        Some(Ok(())) => Ok(size),
+        Some(Err(e)) => Err(format!("{}: invalid FASTA/Q record: {}", path, e)),
+        None if config.skip_empty => Ok(size),
+        None => Err(format!("{}: no sequence; use --skip_empty to drop it", path)),
+    }
+}
+
+/// Sketches keyed by genome path and per genome statistics in input order.
+pub type SketchResult = (HashMap<String, Signature>, Vec<(String, SeqStats)>);
+
+// Shannon entropy (in bits, at most 2) of the base composition of a 2-bit encoded k-mer
+fn kmer_entropy(value: u64, kmer_size: usize) -> f64 {
+    let mut counts = [0usize; 4];
+    for i in 0..kmer_size {
+        counts[((value >> (2 * i)) & 0b11) as usize] += 1;
+    }
+    counts
+        .iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / kmer_size as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn sketch_with<Kmer, Sketcher>(
+    config: &PipelineConfig,
+    path: &str,
+    sequences: &[SequenceStruct],
+    stats: &mut SeqStats,
+) -> Result<Option<Signature>, String>
+where
+    Kmer: CompressedKmerT + KmerBuilder<Kmer> + Send + Sync,
+    <Kmer as CompressedKmerT>::Val: num::PrimInt + Send + Sync + Debug,
+    Sketcher: KmerSketcher<<Kmer as CompressedKmerT>::Val>,
+{
+    let nb_alphabet_bits = 2;
+    let hash_fn = move |kmer: &Kmer| -> <Kmer as CompressedKmerT>::Val {
+        let mask: <Kmer as CompressedKmerT>::Val =
+            num::NumCast::from::<u64>((1u64 << (nb_alphabet_bits * kmer.get_nb_base())) - 1).unwrap();
+        let canonical = kmer.reverse_complement().min(*kmer);
+        canonical.get_compressed_value() & mask
+    };
+
+    if stats.nb_short_seqs > 0 {
+        log::warn!(
+            "{}: {} of {} sequences ({} bases) shorter than k={} contribute no k-mer",
+            path,
+            stats.nb_short_seqs,
+            stats.nb_seqs,
+            stats.nb_short_bases,
+            config.kmer_size
+        );
+    }
+    let mut sketcher = Sketcher::new(config);
+    let mut nb_sketched = 0usize;
+    for seq in sequences {
+        let mut kmergen = KmerSeqIterator::<Kmer>::new(config.kmer_size as u8, seq);
+        kmergen.set_range(0, seq.size()).unwrap();
+        while let Some(kmer) = kmergen.next() {
+            let hashval = hash_fn(&kmer);
+            if config.min_entropy > 0.0
+                && kmer_entropy(hashval.to_u64().unwrap(), config.kmer_size) < config.min_entropy
+            {
+                stats.nb_low_complexity += 1;
+                continue;
+            }
+            sketcher.sketch(&config.hash.hash(hashval, config.seed));
+            nb_sketched += 1;
+        }
+    }
+    if stats.nb_low_complexity > 0 {
+        log::info!("{}: {} low complexity k-mers filtered out", path, stats.nb_low_complexity);
+    }
+    // an empty k-mer set cannot be densified, such a genome must not reach end_sketch
+    if nb_sketched == 0 {
+        return without_kmer(config, path, stats, no_kmer_reason(stats, config.kmer_size));
+    }
+    log::info!("{}: {} sequences, {} k-mers", path, stats.nb_seqs, stats.nb_kmers);
+    sketcher.end_sketch();
+    if sketcher.empty_fraction() > MAX_EMPTY_FRACTION {
+        log::warn!(
+            "{}: {:.0}% of the {} bins are empty, the genome is too small for this sketch size",
+            path,
+            100.0 * sketcher.empty_fraction(),
+            config.sketch_size
+        );
+    }
+    match sketcher.get_signature() {
+        Signature::Registers(registers) if config.bbits > 0 => Ok(Some(to_bbit(&registers, config.bbits))),
+        // every distance to an empty FracMinHash set would be the largest one
+        Signature::Scaled(hashes) if hashes.is_empty() => {
+            let reason = format!("none of its {} k-mers is kept at scale {}, lower --scaled", nb_sketched, config.scaled);
+            without_kmer(config, path, stats, reason)
+        }
+        signature => Ok(Some(signature)),
+    }
+}
+
+// Why a genome yields no k-mer, from its sequence accounting
+fn no_kmer_reason(stats: &SeqStats, kmer_size: usize) -> String {
+    if stats.nb_bases == 0 {
+        "it holds no sequence".to_string()
+    } else if stats.nb_seqs == 0 {
+        format!("all its {} bases are ambiguous (see --ambig)", stats.nb_bases)
+    } else if stats.nb_short_seqs == stats.nb_seqs {
+        format!("all its {} sequences ({} bases) are shorter than k={}", stats.nb_seqs, stats.nb_short_bases, kmer_size)
+    } else {
+        format!("all its {} k-mers are low complexity (see --min_entropy)", stats.nb_low_complexity)
+    }
+}
+
+// Skips a genome without k-mer, or fails on it, naming the file and the reason
+fn without_kmer(config: &PipelineConfig, path: &str, stats: &mut SeqStats, reason: String) -> Result<Option<Signature>, String> {
+    if config.skip_empty {
+        log::warn!("{}: no k-mer could be sketched, {}, genome skipped", path, reason);
+        stats.skipped = true;
+        return Ok(None);
+    }
+    Err(format!("{}: no k-mer could be sketched, {}; use --skip_empty to drop it", path, reason))
+}
+
+// Dispatches on the sketching algorithm for a given k-mer representation
+fn sketch_with_kmer<Kmer>(
+    config: &PipelineConfig,
+    path: &str,
+    sequences: &[SequenceStruct],
+    stats: &mut SeqStats,
+) -> Result<Option<Signature>, String>
+where
+    Kmer: CompressedKmerT + KmerBuilder<Kmer> + Send + Sync,
+    <Kmer as CompressedKmerT>::Val: num::PrimInt + Hash + Send + Sync + Debug,
+{
+    type Val<Kmer> = <Kmer as CompressedKmerT>::Val;
+    if config.distance_kind != DistanceKind::MinHash {
+        return sketch_with::<Kmer, ProfileSketcher>(config, path, sequences, stats);
+    }
+    if config.scaled > 0 {
+        return sketch_with::<Kmer, ScaledSketcher>(config, path, sequences, stats);
+    }
+    match (config.algo, config.densification) {
+        (SketchAlgo::SuperMinHash, _) => {
+            sketch_with::<Kmer, SuperMinHash<f32, Val<Kmer>, NoHashHasher>>(config, path, sequences, stats)
+        }
+        (SketchAlgo::ProbMinHash, _) => {
+            sketch_with::<Kmer, WeightedSketcher<Val<Kmer>>>(config, path, sequences, stats)
+        }
+        (SketchAlgo::SetSketch, _) => {
+            sketch_with::<Kmer, SetSketchSketcher<Val<Kmer>>>(config, path, sequences, stats)
+        }
+        (SketchAlgo::OptDens, Densification::Optimal) => {
+            sketch_with::<Kmer, OptDensMinHash<f32, Val<Kmer>, NoHashHasher>>(config, path, sequences, stats)
+        }
+        (SketchAlgo::OptDens, Densification::ReverseOptimal) => {
+            sketch_with::<Kmer, RevOptDensMinHash<f32, Val<Kmer>, NoHashHasher>>(config, path, sequences, stats)
+        }
+        (SketchAlgo::OptDens, Densification::Rotation | Densification::Auto) => {
+            sketch_with::<Kmer, OphSketcher>(config, path, sequences, stats)
+        }
+    }
+}
+
+// Dispatches on the k-mer representation fitting the k-mer size
+fn sketch_sequences(
+    config: &PipelineConfig,
+    path: &str,
+    sequences: &[SequenceStruct],
+    stats: &mut SeqStats,
+) -> Result<Option<Signature>, String> {
+    let kmer_size = config.kmer_size;
+    if kmer_size <= 14 {
+        sketch_with_kmer::<Kmer32bit>(config, path, sequences, stats)
+    } else if kmer_size == 16 {
+        sketch_with_kmer::<Kmer16b32bit>(config, path, sequences, stats)
+    } else if kmer_size <= 32 {
+        sketch_with_kmer::<Kmer64bit>(config, path, sequences, stats)
+    } else {
+        Err(format!("k-mer size {} is not supported, k must be at most 32", kmer_size))
+    }
+}
+
+/// Sketches every genome of the list, keyed by its path, along with per genome statistics in input order.
+/// Genomes skipped because they yield no k-mer, or as bad files, are absent from the sketches.
+/// Without skipping bad files, fails on the first genome, in input order, that cannot be read or sketched.
+pub fn sketch_genomes(config: &PipelineConfig, genomes: &[String]) -> Result<SketchResult, String> {
+    Ok(sketch_genomes_multi_k(std::slice::from_ref(config), genomes)?.pop().unwrap())
+}
+
+/// Sketches every genome once per configuration, typically differing by their k-mer size,
+/// reading each file only once. Results are in the order of `configs`, see [sketch_genomes].
+pub fn sketch_genomes_multi_k(configs: &[PipelineConfig], genomes: &[String]) -> Result<Vec<SketchResult>, String> {
+    let sketched: Vec<Result<Vec<_>, String>> = genomes
+        .par_iter()
+        .map(|path| {
+            let start = Instant::now();
+            let file_size = std::fs::metadata(path).map(|m| m.len()).unwrap_or(0);
+            let sketched = read_sequences(path, &configs[0]).and_then(|(sequences, mut read_stats)| {
+                read_stats.file_size = file_size;
+                let read_time = start.elapsed().as_secs_f64();
+                configs
+                    .iter()
+                    .map(|config| {
+                        let start = Instant::now();
+                        let mut stats = read_stats.for_kmer_size(&sequences, config.kmer_size);
+                        let signature = sketch_sequences(config, path, &sequences, &mut stats)?;
+                        stats.sketch_time = read_time + start.elapsed().as_secs_f64();
+                        Ok((signature, stats))
+                    })
+                    .collect()
+            });
+            match sketched {
+                Err(e) if configs[0].skip_bad_files => {
+                    log::warn!("{}, genome skipped", e);
+                    let stats = SeqStats { file_size, skipped: true, error: Some(e), ..Default::default() };
+                    Ok(configs.iter().map(|_| (None, stats.clone())).collect())
+                }
+                sketched => sketched,
+            }
+        })
+        .collect();
+    // the first failing genome in input order, whichever thread failed first
+    let sketched = sketched.into_iter().collect::<Result<Vec<_>, String>>()?;
+
+    let mut results: Vec<SketchResult> = configs
+        .iter()
+        .map(|_| (HashMap::with_capacity(genomes.len()), Vec::with_capacity(genomes.len())))
+        .collect();
+    for (path, per_config) in genomes.iter().zip(sketched) {
+        for ((sketches, all_stats), (signature, stats)) in results.iter_mut().zip(per_config) {
+            if let Some(signature) = signature {
+                sketches.insert(path.clone(), signature);
+            }
+            all_stats.push((path.clone(), stats));
+        }
+    }
+    Ok(results)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dist::setsketch_jaccard;
+    use crate::pipeline::PipelineBuilder;
+
+    // SetSketch of the hashed values of `set`
+    fn setsketch(config: &PipelineConfig, set: std::ops::Range<u64>) -> (f64, f64, Vec<u16>) {
+        let mut sketcher = SetSketchSketcher::<u64>::new(config);
+        for value in set {
+            sketcher.sketch(&mix64(value));
+        }
+        sketcher.end_sketch();
+        match sketcher.get_signature() {
+            Signature::SetSketch { b, cardinality, registers, .. } => (b, cardinality, registers),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn setsketch_known_overlap() {
+        let config = PipelineBuilder::new().sketch_size(4096).algo(SketchAlgo::SetSketch).build().unwrap();
+        let (b, cardinality, a) = setsketch(&config, 0..20000);
+        assert!((cardinality / 20000.0 - 1.0).abs() < 0.05, "cardinality {}", cardinality);
+        // |A ∩ B| / |A ∪ B| for B shifted from A
+        for (start, end, expected) in [(0, 20000, 1.0), (10000, 30000, 1.0 / 3.0), (5000, 45000, 15000.0 / 45000.0), (20000, 40000, 0.0)] {
+            let (_, reference_cardinality, reference) = setsketch(&config, start..end);
+            let jaccard = setsketch_jaccard(b, (cardinality, &a), (reference_cardinality, &reference));
+            assert!((jaccard - expected).abs() < 0.03, "{}..{}: {} instead of {}", start, end, jaccard, expected);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+//! Neighbor-joining on a sparse graph of distances, e.g. the k nearest neighbors of each genome found
+//! in an HNSW index, for collections whose dense matrix would not fit in memory.
+//!
+//! The missing distances of a node are imputed with its estimated mean distance to all the genomes,
+//! updated at each join as a distance by neighbor-joining, so that the row sum of a node in the
+//! neighbor-joining criterion is the sum of its known distances plus its mean for each missing one,
+//! and a join changes the known sums of the neighbors of the joined nodes only. Only pairs at a
+//! known distance are candidate joins. Each node caches its best candidate, whose criterion is
+//! evaluated again at each step; the winner's candidate is then searched again over its whole
+//! neighborhood, which can only lower it, before it is joined. A step thus costs time linear in the
+//! number of nodes and in the degrees of the joined nodes and of their neighbors, instead of
+//! quadratic. When no known pair is left, the remaining nodes are joined in a multifurcation at the
+//! largest known distance of each other.
+
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+
+use crate::phylo::Tree;
+
+/// Unrooted neighbor-joining tree of the genomes of `labels` from their distances to their
+/// `neighbors` (distance graph given as adjacency lists, symmetrized first) and their `means`
+/// distances to all the genomes, with a trifurcation (or a multifurcation, see above) at the top.
+pub fn sparse_nj(labels: &[String], neighbors: &[Vec<(usize, f64)>], means: &[f64]) -> Tree {
+    let n = labels.len();
+    let mut tree = Tree::new();
+    let mut nodes: Vec<usize> = labels.iter().map(|label| tree.add_leaf(label)).collect();
+    let mut adjacency: Vec<BTreeMap<usize, f64>> = vec![BTreeMap::new(); n];
+    for (i, row) in neighbors.iter().enumerate() {
+        for &(j, d) in row.iter().filter(|&&(j, _)| j != i) {
+            let d = adjacency[i].get(&j).map_or(d, |&known| known.min(d));
+            adjacency[i].insert(j, d);
+            adjacency[j].insert(i, d);
+        }
+    }
+    let far = adjacency.iter().flat_map(|row| row.values()).fold(0.0, |far: f64, &d| far.max(d));
+    let mut means = means.to_vec();
+    let mut known_sums: Vec<f64> = adjacency.iter().map(|row| row.values().sum()).collect();
+    let mut best: Vec<Option<usize>> = vec![None; n];
+    let mut active: Vec<usize> = (0..n).collect();
+    // row sums of the neighbor-joining criterion with m nodes left
+    let sum = |known_sums: &[f64], adjacency: &[BTreeMap<usize, f64>], means: &[f64], i: usize, m: usize| {
+        known_sums[i] + (m - 1 - adjacency[i].len()) as f64 * means[i]
+    };
+    let criterion = |known_sums: &[f64], adjacency: &[BTreeMap<usize, f64>], means: &[f64], i: usize, j: usize, m: usize| {
+        (m - 2) as f64 * adjacency[i][&j] - sum(known_sums, adjacency, means, i, m) - sum(known_sums, adjacency, means, j, m)
+    };
+    let best_candidate = |known_sums: &[f64], adjacency: &[BTreeMap<usize, f64>], means: &[f64], i: usize, m: usize| {
+        adjacency[i]
+            .keys()
+            .map(|&j| (criterion(known_sums, adjacency, means, i, j, m), j))
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)))
+            .map(|(_, j)| j)
+    };
+    while active.len() > 3 {
+        let m = active.len();
+        // candidates of the nodes whose cached one was joined are searched again
+        let refreshed: Vec<(usize, Option<usize>)> = active
+            .par_iter()
+            .filter(|&&i| best[i].is_none_or(|j| !adjacency[i].contains_key(&j)))
+            .map(|&i| (i, best_candidate(&known_sums, &adjacency, &means, i, m)))
+            .collect();
+        for (i, candidate) in refreshed {
+            best[i] = candidate;
+        }
+        let winner = active
+            .par_iter()
+            .filter_map(|&i| best[i].map(|j| (criterion(&known_sums, &adjacency, &means, i, j, m), i)))
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        let Some((_, i)) = winner else {
+            break;
+        };
+        let j = best_candidate(&known_sums, &adjacency, &means, i, m).unwrap();
+        let dij = adjacency[i][&j];
+        // the distances to the other nodes known for only one of i and j are left out of the split of
+        // dij, as their difference with an imputed distance would depend on the degrees
+        let common: Vec<f64> =
+            adjacency[i].iter().filter_map(|(k, &dik)| adjacency[j].get(k).map(|&djk| dik - djk)).collect();
+        let others = (m - 2 - common.len()) as f64 * (means[i] - means[j]);
+        let li = dij / 2.0 + (common.iter().sum::<f64>() + others) / (2.0 * (m - 2) as f64);
+        nodes[i] = tree.join(&[(nodes[i], li), (nodes[j], dij - li)]);
+        // the new node takes the slot of i, its distances being those of neighbor-joining
+        let (row_i, row_j) = (std::mem::take(&mut adjacency[i]), std::mem::take(&mut adjacency[j]));
+        let mut row: BTreeMap<usize, f64> = BTreeMap::new();
+        for &k in row_i.keys().chain(row_j.keys()).filter(|&&k| k != i && k != j) {
+            let (dik, djk) = (row_i.get(&k).copied().unwrap_or(means[i]), row_j.get(&k).copied().unwrap_or(means[j]));
+            row.insert(k, (dik + djk - dij) / 2.0);
+        }
+        for (&k, &duk) in &row {
+            let removed = adjacency[k].remove(&i).unwrap_or(0.0) + adjacency[k].remove(&j).unwrap_or(0.0);
+            adjacency[k].insert(i, duk);
+            known_sums[k] += duk - removed;
+            if best[k] == Some(j) {
+                best[k] = None;
+            }
+        }
+        means[i] = (means[i] + means[j] - dij) / 2.0;
+        known_sums[i] = row.values().sum();
+        known_sums[j] = 0.0;
+        adjacency[i] = row;
+        best[i] = None;
+        best[j] = None;
+        active.retain(|&k| k != j);
+    }
+    let distance = |a: usize, b: usize| adjacency[a].get(&b).copied().unwrap_or(far);
+    match active.len() {
+        0 | 1 => {}
+        2 => {
+            let d = distance(active[0], active[1]);
+            tree.join(&[(nodes[active[0]], d / 2.0), (nodes[active[1]], d / 2.0)]);
+        }
+        3 => {
+            let (a, b, c) = (active[0], active[1], active[2]);
+            let (dab, dac, dbc) = (distance(a, b), distance(a, c), distance(b, c));
+            tree.join(&[
+                (nodes[a], (dab + dac - dbc) / 2.0),
+                (nodes[b], (dab + dbc - dac) / 2.0),
+                (nodes[c], (dac + dbc - dab) / 2.0),
+            ]);
+        }
+        m => {
+            log::info!("{} subtrees without known distances between them joined at the top", m);
+            let children: Vec<(usize, f64)> = active.iter().map(|&k| (nodes[k], far / 2.0)).collect();
+            tree.join(&children);
+        }
+    }
+    tree
+}
@@ -0,0 +1,668 @@
+//! Tree construction from a distance matrix: neighbor-joining, or UPGMA for ultrametric dendrograms.
+
+use rayon::prelude::*;
+use speedytree::DistanceMatrix;
+use speedytree::{Canonical, Hybrid, NeighborJoiningSolver, RapidBtrees};
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use crate::bme::bme;
+use crate::dist::{fill_matrix, DEFAULT_TILE};
+use crate::mad::mad_root;
+use crate::matrix::DistMatrix;
+use crate::nni::{nni, ols_lengths, Nni};
+use crate::phylo::{newick_label, Property, Tree};
+
+/// Method used to build the tree.
+#[derive(Debug, Clone)]
+pub enum TreeAlgo {
+    Naive,
+    RapidNJ,
+    Hybrid,
+    /// average linkage clustering, giving a rooted ultrametric tree
+    Upgma,
+    /// neighbor-joining weighting the merged distances by their variances
+    BioNJ,
+    /// balanced minimum evolution search from the BIONJ tree, see [crate::bme]
+    Bme,
+}
+
+impl FromStr for TreeAlgo {
+    type Err = String;
+    fn from_str(s: &str) -> Result<TreeAlgo, String> {
+        match s.to_lowercase().as_str() {
+            "naive" => Ok(TreeAlgo::Naive),
+            "rapidnj" => Ok(TreeAlgo::RapidNJ),
+            "hybrid" => Ok(TreeAlgo::Hybrid),
+            "upgma" => Ok(TreeAlgo::Upgma),
+            "bionj" => Ok(TreeAlgo::BioNJ),
+            "bme" => Ok(TreeAlgo::Bme),
+            _ => Err(format!("Unknown tree method: {}", s)),
+        }
+    }
+}
+
+/// Where the tree is rooted.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rooting {
+    /// as built: the last join of neighbor-joining, the top of UPGMA
+    None,
+    /// midpoint of the longest path between two leaves
+    Midpoint,
+    /// minimal ancestor deviation, see [crate::mad]
+    Mad,
+    /// branch separating these taxa from the others
+    Outgroup(Vec<String>),
+}
+
+impl FromStr for Rooting {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Rooting, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Rooting::None),
+            "midpoint" => Ok(Rooting::Midpoint),
+            "mad" => Ok(Rooting::Mad),
+            _ => Err(format!("Unknown rooting: {}", s)),
+        }
+    }
+}
+
+/// What is done with the negative branch lengths of neighbor-joining.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NegativeBranches {
+    Keep,
+    /// set to zero
+    Zero,
+    /// set to zero, taking the negative length from the sibling branch
+    Redistribute,
+}
+
+impl FromStr for NegativeBranches {
+    type Err = String;
+    fn from_str(s: &str) -> Result<NegativeBranches, String> {
+        match s.to_lowercase().as_str() {
+            "keep" => Ok(NegativeBranches::Keep),
+            "zero" => Ok(NegativeBranches::Zero),
+            "redistribute" => Ok(NegativeBranches::Redistribute),
+            _ => Err(format!("Unknown negative branch policy: {}", s)),
+        }
+    }
+}
+
+/// Applies the negative branch policy to the Newick tree `newick`.
+pub fn fix_negative_branches(policy: NegativeBranches, newick: String) -> Result<String, String> {
+    if policy == NegativeBranches::Keep {
+        return Ok(newick);
+    }
+    let mut tree = Tree::from_newick(&newick)?;
+    let nb_negative = tree.clamp_negative_branches(policy == NegativeBranches::Redistribute);
+    if nb_negative > 0 {
+        let redistributed = if policy == NegativeBranches::Redistribute { ", taken from their sibling branch" } else { "" };
+        log::warn!("{} negative branch lengths set to zero{}", nb_negative, redistributed);
+    }
+    Ok(tree.to_newick())
+}
+
+/// How the branch lengths of the output tree are estimated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BranchLengths {
+    /// by the tree method, or the NNI criterion
+    Tree,
+    /// refitted by ordinary least squares on the final topology
+    Ols,
+}
+
+impl FromStr for BranchLengths {
+    type Err = String;
+    fn from_str(s: &str) -> Result<BranchLengths, String> {
+        match s.to_lowercase().as_str() {
+            "tree" => Ok(BranchLengths::Tree),
+            "ols" => Ok(BranchLengths::Ols),
+            _ => Err(format!("Unknown branch length estimation: {}", s)),
+        }
+    }
+}
+
+/// Estimates the branch lengths of the Newick tree `newick` of `matrix` again, see [ols_lengths].
+pub fn refit_branch_lengths(lengths: BranchLengths, matrix: &DistMatrix, exact: bool, newick: String) -> Result<String, String> {
+    if lengths == BranchLengths::Tree {
+        return Ok(newick);
+    }
+    Ok(ols_lengths(&Tree::from_newick(&newick)?, matrix, exact)?.to_newick())
+}
+
+/// Collapses into polytomies the internal branches of the Newick tree `newick` shorter than `min_length`
+/// or, for nodes labelled with their support, supported by less than `min_support` percent, see [Tree::collapse].
+pub fn collapse_branches(min_length: f64, min_support: f64, newick: String) -> Result<String, String> {
+    if min_length <= 0.0 && min_support <= 0.0 {
+        return Ok(newick);
+    }
+    let tree = Tree::from_newick(&newick)?;
+    let (tree, nb_collapsed) = tree.collapse(|node| {
+        let node = tree.node(node);
+        let support = node.label.as_deref().and_then(|label| label.parse::<f64>().ok());
+        node.length.is_some_and(|length| length < min_length) || support.is_some_and(|support| support < min_support)
+    });
+    log::info!("{} internal branches collapsed", nb_collapsed);
+    Ok(tree.to_newick())
+}
+
+/// Order of the children in the output tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ladderize {
+    /// as built
+    None,
+    /// smallest clades first
+    Asc,
+    /// largest clades first
+    Desc,
+}
+
+impl FromStr for Ladderize {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Ladderize, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Ladderize::None),
+            "asc" => Ok(Ladderize::Asc),
+            "desc" => Ok(Ladderize::Desc),
+            _ => Err(format!("Unknown ladderize order: {}", s)),
+        }
+    }
+}
+
+/// Rotates the internal nodes of the Newick tree `newick` by clade size.
+pub fn ladderize_tree(order: Ladderize, newick: String) -> Result<String, String> {
+    if order == Ladderize::None {
+        return Ok(newick);
+    }
+    let mut tree = Tree::from_newick(&newick)?;
+    tree.ladderize(order == Ladderize::Asc);
+    Ok(tree.to_newick())
+}
+
+/// Labels of the internal nodes of the output tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InternalLabels {
+    /// split support, when computed from bootstrap or jackknife replicates
+    Support,
+    /// identifiers N1, N2... in pre-order from the root
+    Ids,
+    None,
+}
+
+impl FromStr for InternalLabels {
+    type Err = String;
+    fn from_str(s: &str) -> Result<InternalLabels, String> {
+        match s.to_lowercase().as_str() {
+            "support" => Ok(InternalLabels::Support),
+            "ids" => Ok(InternalLabels::Ids),
+            "none" => Ok(InternalLabels::None),
+            _ => Err(format!("Unknown internal node labels: {}", s)),
+        }
+    }
+}
+
+/// Labels the internal nodes of the Newick tree `newick` as asked, support labels being left as they are.
+pub fn label_internal_nodes(labels: InternalLabels, newick: String) -> Result<String, String> {
+    if labels == InternalLabels::Support {
+        return Ok(newick);
+    }
+    let mut tree = Tree::from_newick(&newick)?;
+    let mut stack = vec![tree.root()];
+    let mut nb_internal = 0;
+    while let Some(node) = stack.pop() {
+        let children = tree.node(node).children.clone();
+        if children.is_empty() {
+            continue;
+        }
+        nb_internal += 1;
+        tree.set_label(node, (labels == InternalLabels::Ids).then(|| format!("N{}", nb_internal)));
+        stack.extend(children.into_iter().rev());
+    }
+    Ok(tree.to_newick())
+}
+
+/// Refines the Newick tree `newick` built from `matrix` by nearest neighbor interchanges, see [crate::nni].
+pub fn refine_tree(criterion: Nni, matrix: &DistMatrix, exact: bool, newick: String) -> Result<String, String> {
+    if criterion == Nni::None {
+        return Ok(newick);
+    }
+    Ok(nni(&Tree::from_newick(&newick)?, matrix, exact, criterion)?.to_newick())
+}
+
+// branch lengths at most this are counted as zero, the PHYLIP matrix having 6 decimals
+const NEAR_ZERO: f64 = 1e-6;
+
+/// Summary of the branch lengths of a tree, e.g. many near-zero branches pointing to duplicate
+/// genomes and long ones to saturated distances.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TreeStats {
+    pub nb_leaves: usize,
+    pub nb_branches: usize,
+    pub total_length: f64,
+    /// largest sum of branch lengths from the root to a leaf
+    pub max_tip_depth: f64,
+    pub nb_near_zero: usize,
+    pub nb_negative: usize,
+    /// minimum, first quartile, median, third quartile and maximum branch length
+    pub quantiles: [f64; 5],
+    pub mean: f64,
+}
+
+impl TreeStats {
+    /// Tab separated lines of statistic and value.
+    pub fn to_tsv(&self) -> String {
+        let [min, q1, median, q3, max] = self.quantiles;
+        let rows = [
+            ("leaves", self.nb_leaves.to_string()),
+            ("branches", self.nb_branches.to_string()),
+            ("total_length", self.total_length.to_string()),
+            ("max_tip_depth", self.max_tip_depth.to_string()),
+            ("near_zero_branches", self.nb_near_zero.to_string()),
+            ("negative_branches", self.nb_negative.to_string()),
+            ("min_branch", min.to_string()),
+            ("q1_branch", q1.to_string()),
+            ("median_branch", median.to_string()),
+            ("mean_branch", self.mean.to_string()),
+            ("q3_branch", q3.to_string()),
+            ("max_branch", max.to_string()),
+        ];
+        let mut tsv = String::from("statistic\tvalue\n");
+        for (name, value) in rows {
+            tsv.push_str(&format!("{}\t{}\n", name, value));
+        }
+        tsv
+    }
+}
+
+/// Branch length statistics of the Newick tree `newick`.
+pub fn tree_stats(newick: &str) -> Result<TreeStats, String> {
+    let tree = Tree::from_newick(newick)?;
+    let mut lengths = Vec::new();
+    let mut nb_leaves = 0;
+    let mut max_tip_depth = 0.0f64;
+    let mut stack = vec![(tree.root(), 0.0)];
+    while let Some((node, depth)) = stack.pop() {
+        let children = &tree.node(node).children;
+        if children.is_empty() {
+            nb_leaves += 1;
+            max_tip_depth = max_tip_depth.max(depth);
+        }
+        for &child in children {
+            let length = tree.node(child).length.unwrap_or(0.0);
+            lengths.push(length);
+            stack.push((child, depth + length));
+        }
+    }
+    lengths.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let quantile = |q: f64| lengths.get((q * (lengths.len() as f64 - 1.0)).round() as usize).copied().unwrap_or(0.0);
+    let total_length: f64 = lengths.iter().sum();
+    Ok(TreeStats {
+        nb_leaves,
+        nb_branches: lengths.len(),
+        total_length,
+        max_tip_depth,
+        nb_near_zero: lengths.iter().filter(|l| l.abs() <= NEAR_ZERO).count(),
+        nb_negative: lengths.iter().filter(|&&l| l < -NEAR_ZERO).count(),
+        quantiles: [0.0, 0.25, 0.5, 0.75, 1.0].map(quantile),
+        mean: if lengths.is_empty() { 0.0 } else { total_length / lengths.len() as f64 },
+    })
+}
+
+/// File format of the output tree.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TreeFormat {
+    Newick,
+    /// TREES block with a translate table
+    Nexus,
+    /// XML with leaf annotations
+    PhyloXml,
+}
+
+impl FromStr for TreeFormat {
+    type Err = String;
+    fn from_str(s: &str) -> Result<TreeFormat, String> {
+        match s.to_lowercase().as_str() {
+            "newick" => Ok(TreeFormat::Newick),
+            "nexus" => Ok(TreeFormat::Nexus),
+            "phyloxml" => Ok(TreeFormat::PhyloXml),
+            _ => Err(format!("Unknown tree format: {}", s)),
+        }
+    }
+}
+
+/// The Newick tree `newick` in the given format, ending with a newline. Leaf `properties` (by label)
+/// are written in phyloXML only.
+pub fn format_tree(format: TreeFormat, newick: &str, properties: &HashMap<String, Vec<Property>>) -> Result<String, String> {
+    match format {
+        TreeFormat::Newick => Ok(format!("{}\n", newick)),
+        TreeFormat::Nexus => Ok(Tree::from_newick(newick)?.to_nexus()),
+        TreeFormat::PhyloXml => Ok(Tree::from_newick(newick)?.to_phyloxml(properties)),
+    }
+}
+
+/// Roots the Newick tree `newick` as asked.
+pub fn root_tree(rooting: &Rooting, newick: String) -> Result<String, String> {
+    match rooting {
+        Rooting::None => Ok(newick),
+        Rooting::Midpoint => Ok(Tree::from_newick(&newick)?.midpoint_root().to_newick()),
+        Rooting::Mad => Ok(mad_root(&Tree::from_newick(&newick)?).to_newick()),
+        Rooting::Outgroup(outgroup) => Ok(Tree::from_newick(&newick)?.outgroup_root(outgroup)?.to_newick()),
+    }
+}
+
+/// Builds a tree from a symmetric matrix and returns it in Newick format.
+/// Unless `exact`, distances are rounded to the default PHYLIP output precision, so that the tree is the
+/// same whether built in memory or from the written matrix.
+/// The neighbor-joining methods of speedytree (naive, RapidNJ and hybrid) take a dense copy of the
+/// matrix in memory, twice the size of its triangle, even when the matrix is in a memory-mapped file;
+/// UPGMA and BIONJ work on a copy stored like the matrix, BME reads it in place.
+pub fn build_tree(
+    tree_algo: &TreeAlgo,
+    chunk_size: usize,
+    naive_percentage: usize,
+    exact: bool,
+    matrix: &DistMatrix
+) -> Result<String, String> {
+    let graph = match tree_algo {
+        TreeAlgo::Upgma => return Ok(upgma(matrix, exact)?.to_newick()),
+        TreeAlgo::BioNJ => return Ok(bionj(matrix, exact)?.to_newick()),
+        TreeAlgo::Bme => return Ok(bme(matrix, exact)?.to_newick()),
+        TreeAlgo::Naive => {
+            NeighborJoiningSolver::<Canonical>::default(dense_matrix(matrix, exact)?).solve()
+        }
+        TreeAlgo::RapidNJ => {
+            NeighborJoiningSolver::<RapidBtrees>::build(dense_matrix(matrix, exact)?, chunk_size).solve()
+        }
+        TreeAlgo::Hybrid => {
+            let distance_matrix = dense_matrix(matrix, exact)?;
+            let naive_steps = distance_matrix.size() * naive_percentage / 100;
+            NeighborJoiningSolver::<Hybrid>::build(distance_matrix, chunk_size, naive_steps).solve()
+        }
+    }
+    .map_err(|e| format!("Error constructing tree: {}", e))?;
+
+    Ok(speedytree::to_newick(&graph))
+}
+
+// Dense copy of a symmetric matrix for speedytree, rounded as in [build_tree] unless `exact`
+fn dense_matrix(matrix: &DistMatrix, exact: bool) -> Result<DistanceMatrix, String> {
+    let n = matrix.len();
+    let rows: Vec<Vec<f64>> =
+        (0..n).into_par_iter().map(|i| (0..n).map(|j| leaf_distance(matrix, exact, i, j)).collect()).collect();
+    // speedytree writes the labels as they are
+    let labels = matrix.labels().iter().map(|label| newick_label(label)).collect();
+    DistanceMatrix::build(rows, labels).map_err(|e| format!("Error building distance matrix: {}", e))
+}
+
+// Decimal rounding, as the PHYLIP writer and reader would do. The integer nearest to the distance in
+// millionths, divided by a million, is the correctly rounded value that parsing the text would give,
+// as long as the product is far enough from a tie for its rounding error to not change the integer
+pub(crate) fn round_distance(d: f64) -> f64 {
+    let scaled = d * 1e6;
+    if scaled.abs() < 1e15 && (scaled - scaled.floor() - 0.5).abs() > 4.0 * f64::EPSILON * scaled.abs() {
+        scaled.round() / 1e6
+    } else {
+        format!("{:.6}", d).parse::<f64>().unwrap()
+    }
+}
+
+// Index of the pair (i, j), i < j, in a condensed upper triangle of n items
+pub(crate) fn pair_index(n: usize, i: usize, j: usize) -> usize {
+    let (i, j) = if i < j { (i, j) } else { (j, i) };
+    i * (2 * n - i - 1) / 2 + (j - i - 1)
+}
+
+// Distance between leaves i and j of a symmetric matrix, rounded as in [build_tree] unless `exact`
+pub(crate) fn leaf_distance(matrix: &DistMatrix, exact: bool, i: usize, j: usize) -> f64 {
+    if exact {
+        matrix.get(i, j)
+    } else {
+        round_distance(matrix.get(i, j))
+    }
+}
+
+/// UPGMA tree of a symmetric matrix: clusters at the smallest average distance are merged first,
+/// each merge at half their distance, so that all leaves are at the same depth from the root.
+/// Merges follow nearest-neighbor chains, in O(n^2) time. Distances are rounded as in [build_tree] unless `exact`.
+/// The merged distances are kept in a copy of the matrix stored like it, see [DistMatrix::scratch].
+pub fn upgma(matrix: &DistMatrix, exact: bool) -> Result<Tree, String> {
+    let n = matrix.len();
+    let mut tree = Tree::new();
+    let mut nodes: Vec<usize> = matrix.labels().iter().map(|label| tree.add_leaf(label)).collect();
+    if n < 2 {
+        return Ok(tree);
+    }
+    let mut dist = fill_matrix(matrix.scratch(true)?, |i, j| leaf_distance(matrix, exact, i, j), false, DEFAULT_TILE);
+    // clusters keep the slot of one of their members
+    let mut active = vec![true; n];
+    let mut sizes = vec![1usize; n];
+    let mut heights = vec![0.0f64; n];
+    let mut chain: Vec<usize> = Vec::new();
+    for _ in 1..n {
+        if chain.is_empty() {
+            chain.push(active.iter().position(|&a| a).unwrap());
+        }
+        loop {
+            let a = *chain.last().unwrap();
+            let previous = (chain.len() > 1).then(|| chain[chain.len() - 2]);
+            // nearest active cluster, the previous one of the chain winning ties so that the chain ends
+            let mut nearest = previous;
+            let mut best = previous.map_or(f64::INFINITY, |p| dist.get(a, p));
+            for k in (0..n).filter(|&k| active[k] && k != a) {
+                let d = dist.get(a, k);
+                if d < best {
+                    best = d;
+                    nearest = Some(k);
+                }
+            }
+            let b = nearest.unwrap();
+            if Some(b) != previous {
+                chain.push(b);
+                continue;
+            }
+            chain.truncate(chain.len() - 2);
+            let height = best / 2.0;
+            nodes[a] = tree.join(&[(nodes[a], height - heights[a]), (nodes[b], height - heights[b])]);
+            for k in (0..n).filter(|&k| active[k] && k != a && k != b) {
+                let (da, db) = (dist.get(a, k), dist.get(b, k));
+                dist.set(a, k, (sizes[a] as f64 * da + sizes[b] as f64 * db) / (sizes[a] + sizes[b]) as f64);
+            }
+            active[b] = false;
+            sizes[a] += sizes[b];
+            heights[a] = height;
+            break;
+        }
+    }
+    Ok(tree)
+}
+
+/// BIONJ tree of a symmetric matrix (Gascuel, 1997), unrooted with a trifurcation at the top as for
+/// neighbor-joining. Pairs are picked as in neighbor-joining, but the distances to the new node are
+/// weighted by the variances of the distances, taken as the distances themselves at the start,
+/// which suits the noisy distances of distant sketches. Runs in O(n^3) time.
+/// Distances are rounded as in [build_tree] unless `exact`. The merged distances and their variances
+/// are kept in a square copy of the matrix stored like it, see [DistMatrix::scratch], the distances
+/// above the diagonal and the variances below.
+pub fn bionj(matrix: &DistMatrix, exact: bool) -> Result<Tree, String> {
+    let n = matrix.len();
+    let mut tree = Tree::new();
+    let mut nodes: Vec<usize> = matrix.labels().iter().map(|label| tree.add_leaf(label)).collect();
+    if n < 2 {
+        return Ok(tree);
+    }
+    if n == 2 {
+        let d = leaf_distance(matrix, exact, 0, 1);
+        tree.join(&[(nodes[0], d / 2.0), (nodes[1], d / 2.0)]);
+        return Ok(tree);
+    }
+    let mut work = fill_matrix(matrix.scratch(false)?, |i, j| leaf_distance(matrix, exact, i, j), false, DEFAULT_TILE);
+    let d = |work: &DistMatrix, i: usize, j: usize| work.get(i.min(j), i.max(j));
+    let var = |work: &DistMatrix, i: usize, j: usize| work.get(i.max(j), i.min(j));
+    let mut active: Vec<usize> = (0..n).collect();
+    let mut sums: Vec<f64> = (0..n).into_par_iter().map(|i| (0..n).filter(|&k| k != i).map(|k| d(&work, i, k)).sum()).collect();
+    while active.len() > 3 {
+        let r = active.len();
+        // pair minimizing the neighbor-joining criterion, the first one in (i, j) order on ties
+        let (_, a, b) = (0..r)
+            .into_par_iter()
+            .flat_map_iter(|x| (x + 1..r).map(move |y| (x, y)))
+            .map(|(x, y)| {
+                let (i, j) = (active[x], active[y]);
+                ((r - 2) as f64 * d(&work, i, j) - sums[i] - sums[j], x, y)
+            })
+            .reduce(|| (f64::INFINITY, usize::MAX, usize::MAX), |p, q| if (q.0, q.1, q.2) < (p.0, p.1, p.2) { q } else { p });
+        let (i, j) = (active[a], active[b]);
+        let dij = d(&work, i, j);
+        let li = dij / 2.0 + (sums[i] - sums[j]) / (2.0 * (r - 2) as f64);
+        let lj = dij - li;
+        let vij = var(&work, i, j);
+        let lambda = if vij > 0.0 {
+            let spread: f64 = active.iter().filter(|&&k| k != i && k != j).map(|&k| var(&work, j, k) - var(&work, i, k)).sum();
+            (0.5 + spread / (2.0 * (r - 2) as f64 * vij)).clamp(0.0, 1.0)
+        } else {
+            0.5
+        };
+        nodes[i] = tree.join(&[(nodes[i], li), (nodes[j], lj)]);
+        // the new node takes the slot of i
+        sums[i] = 0.0;
+        for &k in active.iter().filter(|&&k| k != i && k != j) {
+            let (dik, djk) = (d(&work, i, k), d(&work, j, k));
+            let duk = lambda * (dik - li) + (1.0 - lambda) * (djk - lj);
+            let vuk = lambda * var(&work, i, k) + (1.0 - lambda) * var(&work, j, k) - lambda * (1.0 - lambda) * vij;
+            work.set(i.min(k), i.max(k), duk);
+            work.set(i.max(k), i.min(k), vuk);
+            sums[k] += duk - dik - djk;
+            sums[i] += duk;
+        }
+        active.remove(b);
+    }
+    let (a, b, c) = (active[0], active[1], active[2]);
+    let (dab, dac, dbc) = (d(&work, a, b), d(&work, a, c), d(&work, b, c));
+    tree.join(&[
+        (nodes[a], (dab + dac - dbc) / 2.0),
+        (nodes[b], (dab + dbc - dac) / 2.0),
+        (nodes[c], (dac + dbc - dab) / 2.0),
+    ]);
+    Ok(tree)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dist::Symmetrization;
+    use crate::fit::cophenetic_matrix;
+    use crate::sketch::mix64;
+    use std::borrow::Cow;
+    use std::collections::{HashMap, HashSet};
+
+    // pseudo-random value in [0, 1) drawn from `seed`
+    fn uniform(seed: u64) -> f64 {
+        (mix64(seed) >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn random_matrix(matrix: &mut DistMatrix) {
+        for i in 0..matrix.len() {
+            for j in i + 1..matrix.len() {
+                matrix.set(i, j, 0.05 + 0.3 * uniform((i * 1000 + j) as u64));
+            }
+        }
+    }
+
+    fn splits(tree: &Tree, labels: &[String]) -> HashSet<Vec<u64>> {
+        let leaves: HashMap<String, usize> = labels.iter().enumerate().map(|(i, label)| (label.clone(), i)).collect();
+        tree.splits(&leaves).unwrap().into_iter().flatten().collect()
+    }
+
+    // Leaves below `node`, with their heights above the parent of `node`
+    fn leaf_heights(tree: &Tree, node: usize, height: f64, leaves: &mut Vec<(String, f64)>) {
+        let node = tree.node(node);
+        let height = height + node.length.unwrap_or(0.0);
+        match &node.label {
+            Some(label) if node.children.is_empty() => leaves.push((label.clone(), height)),
+            _ => node.children.iter().for_each(|&child| leaf_heights(tree, child, height, leaves)),
+        }
+    }
+
+    // Checks that `tree` has the topology of `newick` and gives back the distances of `matrix`
+    fn assert_recovers(tree: &Tree, newick: &str, matrix: &DistMatrix) {
+        let labels = matrix.labels();
+        assert_eq!(splits(tree, labels), splits(&Tree::from_newick(newick).unwrap(), labels));
+        let fitted = cophenetic_matrix(&tree.to_newick(), labels).unwrap();
+        for i in 0..labels.len() {
+            for j in 0..labels.len() {
+                assert!((fitted.get(i, j) - matrix.get(i, j)).abs() < 1e-9, "{} {}", labels[i], labels[j]);
+            }
+        }
+    }
+
+    #[test]
+    fn upgma_recovers_ultrametric_tree() {
+        // all leaves 0.45 away from the root
+        let newick = "(((a:0.1,b:0.1):0.2,c:0.3):0.15,((d:0.2,e:0.2):0.1,(f:0.05,g:0.05):0.25):0.15);";
+        let labels: Vec<String> = "abcdefg".chars().map(|c| c.to_string()).collect();
+        let matrix = cophenetic_matrix(newick, &labels).unwrap();
+        let tree = upgma(&matrix, true).unwrap();
+        assert_recovers(&tree, newick, &matrix);
+        // rooted between the two clades, each leaf at the same height
+        let mut clades: Vec<Vec<String>> = tree
+            .node(tree.root())
+            .children
+            .iter()
+            .map(|&child| {
+                let mut leaves = Vec::new();
+                leaf_heights(&tree, child, 0.0, &mut leaves);
+                assert!(leaves.iter().all(|(_, height)| (height - 0.45).abs() < 1e-9));
+                let mut labels: Vec<String> = leaves.into_iter().map(|(label, _)| label).collect();
+                labels.sort();
+                labels
+            })
+            .collect();
+        clades.sort();
+        assert_eq!(clades, [vec!["a", "b", "c"], vec!["d", "e", "f", "g"]]);
+    }
+
+    #[test]
+    fn bionj_recovers_additive_tree() {
+        let newick = "((((a:0.1,b:0.2):0.05,c:0.3):0.1,d:0.15):0.02,((e:0.1,f:0.05):0.2,(g:0.3,h:0.1):0.08):0.03);";
+        let labels: Vec<String> = "abcdefgh".chars().map(|c| c.to_string()).collect();
+        let matrix = cophenetic_matrix(newick, &labels).unwrap();
+        assert_recovers(&bionj(&matrix, true).unwrap(), newick, &matrix);
+    }
+
+    #[test]
+    fn rounding_matches_text() {
+        let ties = [0.0, 5e-7, 0.1234565, 1.0000005, 0.3, -0.3, -1e-7, 2.5e-7, 12345.6789125, 1e20, f64::NAN, f64::INFINITY];
+        let random = (0..100_000u64).map(|k| uniform(k) * 10f64.powi((k % 7) as i32 - 3));
+        for d in ties.into_iter().chain(random) {
+            let text = format!("{:.6}", d).parse::<f64>().unwrap();
+            let rounded = round_distance(d);
+            assert!(rounded.to_bits() == text.to_bits() || (rounded.is_nan() && text.is_nan()), "{}: {} vs {}", d, rounded, text);
+        }
+    }
+
+    #[test]
+    fn mapped_matrix_trees() {
+        let labels: Vec<String> = (0..40).map(|i| format!("g{}", i)).collect();
+        let dir = std::env::temp_dir().join(format!("bindashtree_tree_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut mapped = DistMatrix::new_mapped(labels.clone(), true, &dir.join("matrix.bin")).unwrap();
+        random_matrix(&mut mapped);
+        let mut memory = DistMatrix::new(labels, true);
+        random_matrix(&mut memory);
+        // a symmetric matrix with a zero diagonal is used as it is
+        assert!(matches!(mapped.symmetrize(Symmetrization::Mean), Cow::Borrowed(_)));
+        for algo in [TreeAlgo::Upgma, TreeAlgo::BioNJ, TreeAlgo::Bme, TreeAlgo::Hybrid] {
+            let tree = build_tree(&algo, 30, 50, false, &mapped).unwrap();
+            assert_eq!(tree, build_tree(&algo, 30, 50, false, &memory).unwrap(), "{:?}", algo);
+        }
+        // scratch copies are kept next to the matrix file and removed with them
+        let scratch = mapped.scratch(false).unwrap();
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+        drop(scratch);
+        let files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(files, ["matrix.bin"]);
+        drop(mapped);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+//! Frequency weighting of sketch values across the input set (TF-IDF like).
+//!
+//! Hashes found in many genomes, e.g. from mobile elements, carry little phylogenetic signal.
+//! With IDF weighting, each hash gets the weight `1 + ln(N / df)`, `df` being the number of the
+//! `N` sketches holding it, and distances come from the weighted Jaccard index.
+
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+
+use crate::sketch::Signature;
+
+/// Weighting of shared hashes in the Jaccard index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Weighting {
+    /// every hash counts the same
+    None,
+    /// hashes weighted by their inverse document frequency over the sketches compared
+    Idf,
+}
+
+impl FromStr for Weighting {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Weighting, String> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Weighting::None),
+            "idf" | "tfidf" => Ok(Weighting::Idf),
+            _ => Err(format!("Unknown weighting: {}", s)),
+        }
+    }
+}
+
+/// Inverse document frequency weights of the hashes of a set of sketches.
+#[derive(Debug, Clone)]
+pub struct IdfWeights {
+    weights: HashMap<u64, f64>,
+}
+
+// Hashes held by a sketch, registers being keyed by their bits
+fn sketch_values(signature: &Signature) -> Result<HashSet<u64>, String> {
+    match signature {
+        Signature::Registers(registers) => Ok(registers.iter().map(|r| r.to_bits() as u64).collect()),
+        Signature::Scaled(hashes) => Ok(hashes.iter().copied().collect()),
+        _ => Err(format!(
+            "--weighting idf needs full register or FracMinHash sketches, not {} ones",
+            signature.kind()
+        )),
+    }
+}
+
+impl IdfWeights {
+    /// Weights of the hashes of the sketches of `genomes`, an error for sketches that hold no
+    /// comparable hash values (b-bit, SetSketch or composition profiles).
+    pub fn new(sketches: &HashMap<String, Signature>, genomes: &[String]) -> Result<Self, String> {
+        let values: Vec<HashSet<u64>> = genomes.par_iter().map(|g| sketch_values(&sketches[g])).collect::<Result<_, _>>()?;
+        let frequencies = values
+            .into_par_iter()
+            .fold(HashMap::new, |mut frequencies: HashMap<u64, usize>, values| {
+                for value in values {
+                    *frequencies.entry(value).or_insert(0) += 1;
+                }
+                frequencies
+            })
+            .reduce(HashMap::new, |mut a, b| {
+                for (value, count) in b {
+                    *a.entry(value).or_insert(0) += count;
+                }
+                a
+            });
+        let n = genomes.len() as f64;
+        let weights = frequencies
+            .into_iter()
+            .map(|(value, count)| (value, 1.0 + (n / count as f64).ln()))
+            .collect();
+        Ok(IdfWeights { weights })
+    }
+
+    // hashes outside the sketches the weights were computed from are as rare as can be
+    fn weight(&self, value: u64) -> f64 {
+        self.weights.get(&value).copied().unwrap_or(1.0)
+    }
+
+    /// Weighted Jaccard index of two sketches of the same kind, NaN for other sketches.
+    pub fn jaccard(&self, query_signature: &Signature, reference_signature: &Signature) -> f64 {
+        let (mut shared, mut total) = (0.0, 0.0);
+        match (query_signature, reference_signature) {
+            (Signature::Registers(query), Signature::Registers(reference)) => {
+                for (a, b) in query.iter().zip(reference.iter()) {
+                    let (wa, wb) = (self.weight(a.to_bits() as u64), self.weight(b.to_bits() as u64));
+                    if a == b {
+                        shared += wa;
+                    }
+                    total += (wa + wb) / 2.0;
+                }
+            }
+            (Signature::Scaled(query), Signature::Scaled(reference)) => {
+                let (mut i, mut j) = (0, 0);
+                while i < query.len() || j < reference.len() {
+                    if j == reference.len() || (i < query.len() && query[i] < reference[j]) {
+                        total += self.weight(query[i]);
+                        i += 1;
+                    } else if i == query.len() || reference[j] < query[i] {
+                        total += self.weight(reference[j]);
+                        j += 1;
+                    } else {
+                        let w = self.weight(query[i]);
+                        shared += w;
+                        total += w;
+                        i += 1;
+                        j += 1;
+                    }
+                }
+            }
+            _ => return f64::NAN,
+        }
+        if total == 0.0 {
+            return 0.0;
+        }
+        shared / total
+    }
+}